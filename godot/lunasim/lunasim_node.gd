@@ -1,5 +1 @@
 extends Lunasim
-
-
-func _ready() -> void:
-	print("READY")
@@ -1,10 +1,43 @@
 extends Camera3D
 
 const DELTA := 1.0 / 30
+const FRAME_DELTA := 1.0 / 10
+const FRAME_SIZE := Vector2i(640, 480)
+
+## Renders grayscale frames for the lunasimbot to detect AprilTags in, if it asked for them.
+@export var stream_frames := false
+@export var frame_id := 0
 
 var _timer := DELTA
+var _frame_timer := FRAME_DELTA
+var _frame_viewport: SubViewport
+var _frame_camera: Camera3D
+
+
+func _ready() -> void:
+	if !stream_frames or !LunasimNode.send_camera_frames:
+		return
+	# Frames are rendered separately so that their size does not depend on the window
+	_frame_viewport = SubViewport.new()
+	_frame_viewport.size = FRAME_SIZE
+	_frame_viewport.render_target_update_mode = SubViewport.UPDATE_ALWAYS
+	_frame_camera = Camera3D.new()
+	_frame_camera.fov = fov
+	_frame_camera.cull_mask = cull_mask
+	_frame_viewport.add_child(_frame_camera)
+	add_child(_frame_viewport)
+
 
 func _process(delta: float) -> void:
+	if _frame_viewport != null:
+		_frame_camera.global_transform = global_transform
+		_frame_timer -= delta
+		if _frame_timer <= 0.0:
+			_frame_timer = FRAME_DELTA
+			# The fov is vertical
+			var focal_length := FRAME_SIZE.y / 2.0 / tan(deg_to_rad(fov) / 2.0)
+			LunasimNode.send_camera_frame(frame_id, _frame_viewport.get_texture().get_image(), focal_length)
+
 	_timer -= delta
 	if _timer <= 0.0:
 		_timer = DELTA
@@ -14,4 +47,11 @@ func _process(delta: float) -> void:
 				continue
 			if !is_position_in_frustum(tag.global_position):
 				continue
-			LunasimNode.send_explicit_apriltag(get_tree().get_first_node_in_group("Robot").global_transform)
+			var to_camera: Vector3 = global_position - tag.global_position
+			# The tag faces its -Z axis
+			var viewing_angle := (-tag.global_basis.z).angle_to(to_camera)
+			LunasimNode.send_explicit_apriltag(
+				get_tree().get_first_node_in_group("Robot").global_transform,
+				to_camera.length(),
+				viewing_angle
+			)
@@ -6,6 +6,18 @@ const TERRAIN_ROTATION_LERP_SPEED := 150.0
 const SPEED := 0.3
 const WHEEL_SEPARATION := 0.6
 const DELTA := 1.0 / 60
+const LEFT_WHEEL := 0
+const RIGHT_WHEEL := 1
+const WHEEL_RADIUS := 0.1
+const TICKS_PER_REVOLUTION := 1024
+# The current drawn while the wheels turn freely at the slowest and fastest speeds
+const MOTOR_IDLE_CURRENT := 0.5
+const MOTOR_RUNNING_CURRENT := 4.0
+const MOTOR_STALL_CURRENT := 20.0
+const BATTERY_FULL_VOLTAGE := 25.2
+const BATTERY_EMPTY_VOLTAGE := 19.8
+const BATTERY_CAPACITY_AH := 20.0
+const BATTERY_RESISTANCE := 0.05
 
 @export var estimate_material: StandardMaterial3D
 
@@ -13,6 +25,11 @@ var _timer := DELTA
 var _left := 0.0
 var _right := 0.0
 var _drive_noise := FastNoiseLite.new()
+var _left_distance := 0.0
+var _right_distance := 0.0
+var _left_stalled := false
+var _right_stalled := false
+var _battery_used_ah := 0.0
 
 @onready var raycast: RayCast3D = $RaycastOrigin/RayCast3D
 @onready var estimate: Node3D = $Estimate
@@ -30,6 +47,25 @@ func _ready() -> void:
 		func(transform: Transform3D):
 			estimate.global_transform = transform
 	)
+	@warning_ignore("shadowed_variable_base_class")
+	LunasimNode.reset.connect(
+		func(transform: Transform3D):
+			global_transform = transform
+			velocity = Vector3.ZERO
+			_left = 0.0
+			_right = 0.0
+			_left_distance = 0.0
+			_right_distance = 0.0
+			_left_stalled = false
+			_right_stalled = false
+			_battery_used_ah = 0.0
+			_timer = DELTA
+			_last_quat = quaternion
+	)
+	LunasimNode.seeded.connect(
+		func(new_seed: int):
+			_drive_noise.seed = new_seed
+	)
 	for node in get_children():
 		if node is not MeshInstance3D:
 			continue
@@ -66,11 +102,43 @@ func _physics_process(delta: float) -> void:
 	
 	rotation.y += drive_diff * SPEED * delta / WHEEL_SEPARATION
 	velocity = -global_basis.z * drive_mean
-	move_and_collide(velocity * delta)
+	var motion := velocity * delta
+	var collision := move_and_collide(motion)
+	# The wheels cannot turn if the robot is pushing against something
+	var blocked := collision != null and collision.get_travel().length() < motion.length() / 2
+	_left_stalled = blocked and absf(_left) > 0.0
+	_right_stalled = blocked and absf(_right) > 0.0
+	if !_left_stalled:
+		_left_distance += left * delta
+	if !_right_stalled:
+		_right_distance += right * delta
+	
+	var left_current := _motor_current(_left, _left_stalled)
+	var right_current := _motor_current(_right, _right_stalled)
+	_battery_used_ah += (left_current + right_current) * delta / 3600
 	
 	_timer -= delta
 	if _timer <= 0.0:
-		LunasimNode.send_accelerometer(0, global_basis.inverse() * Vector3.DOWN * 9.81)
+		LunasimNode.send_accelerometer(0, global_basis.inverse() * Vector3.DOWN * 9.81, DELTA - _timer)
 		LunasimNode.send_gyroscope(0, quaternion * _last_quat.inverse(), DELTA - _timer)
+		LunasimNode.send_wheel_encoder(LEFT_WHEEL, _ticks(_left_distance), TICKS_PER_REVOLUTION)
+		LunasimNode.send_wheel_encoder(RIGHT_WHEEL, _ticks(_right_distance), TICKS_PER_REVOLUTION)
+		LunasimNode.send_motor_current(LEFT_WHEEL, left_current, _left_stalled)
+		LunasimNode.send_motor_current(RIGHT_WHEEL, right_current, _right_stalled)
+		var charge := clampf(1.0 - _battery_used_ah / BATTERY_CAPACITY_AH, 0.0, 1.0)
+		var open_voltage := lerpf(BATTERY_EMPTY_VOLTAGE, BATTERY_FULL_VOLTAGE, charge)
+		LunasimNode.send_battery_voltage(open_voltage - (left_current + right_current) * BATTERY_RESISTANCE)
 		_timer = DELTA
 		_last_quat = quaternion
+
+
+func _ticks(distance: float) -> int:
+	return roundi(distance / (TAU * WHEEL_RADIUS) * TICKS_PER_REVOLUTION)
+
+
+func _motor_current(power: float, stalled: bool) -> float:
+	if power == 0.0:
+		return 0.0
+	if stalled:
+		return MOTOR_STALL_CURRENT * absf(power)
+	return lerpf(MOTOR_IDLE_CURRENT, MOTOR_RUNNING_CURRENT, absf(power))
@@ -0,0 +1,61 @@
+//! Lets the lunabase find lunabots on the local network, so that lunabots do not need to be configured with the
+//! address of the lunabase.
+//!
+//! A lunabot that does not know the address of the lunabase broadcasts a [`DiscoveryMessage::Beacon`] to
+//! [`DISCOVERY_PORT`] every second, from the socket that it will talk to the lunabase with. When the operator
+//! picks a lunabot, the lunabase sends a [`DiscoveryMessage::Claim`] to the address of the beacon, from the socket
+//! that the lunabot should talk to. The lunabot then connects to the address of the claim, and stops broadcasting.
+//!
+//! Claims are not authenticated, so anyone on the network can claim a lunabot that is broadcasting. With a
+//! pre-shared key, the lunabot will not accept anything else from a lunabase that does not have the key.
+use bitcode::{Decode, Encode};
+
+/// The port that the lunabase listens for beacons on.
+pub const DISCOVERY_PORT: u16 = 10601;
+/// How often a lunabot broadcasts a beacon.
+pub const BEACON_INTERVAL_MS: u64 = 1000;
+
+/// Comes before every discovery message, so that other broadcasts on the same port are ignored.
+const MAGIC: [u8; 4] = *b"LBDS";
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq, Eq)]
+pub enum DiscoveryMessage {
+    /// Broadcast by a lunabot that does not know the address of the lunabase.
+    Beacon { name: String },
+    /// Sent by the lunabase to a lunabot that should connect to it.
+    Claim,
+}
+
+impl DiscoveryMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&bitcode::encode(self));
+        bytes
+    }
+
+    /// Returns `None` if `bytes` is not a discovery message.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        bitcode::decode(bytes.strip_prefix(&MAGIC)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let beacon = DiscoveryMessage::Beacon {
+            name: "lunabot".into(),
+        };
+        assert_eq!(DiscoveryMessage::decode(&beacon.encode()), Some(beacon));
+        assert_eq!(
+            DiscoveryMessage::decode(&DiscoveryMessage::Claim.encode()),
+            Some(DiscoveryMessage::Claim)
+        );
+        assert_eq!(
+            DiscoveryMessage::decode(&bitcode::encode(&DiscoveryMessage::Claim)),
+            None
+        );
+    }
+}
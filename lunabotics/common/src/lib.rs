@@ -2,8 +2,12 @@ use std::io::Write;
 
 use bitcode::{Decode, Encode};
 
+pub mod discovery;
 pub mod lunasim;
 
+/// How often the lunabot pings the lunabase, and the lunabase pings the lunabot.
+pub const PING_INTERVAL_MS: u64 = 800;
+
 #[derive(Debug, Encode, Decode, Clone, Copy, PartialEq, Eq)]
 pub enum LunabotStage {
     TeleOp,
@@ -20,6 +24,9 @@ pub enum FromLunabase {
     Steering(Steering),
     TraverseObstacles,
     SoftStop,
+    /// Asks the lunabot to respond with [`FromLunabot::Pong`] with the same id, so that the lunabase can measure
+    /// the round-trip time.
+    Ping(u32),
 }
 
 impl FromLunabase {
@@ -45,6 +52,7 @@ impl FromLunabase {
 #[derive(Debug, Encode, Decode, Clone, Copy, PartialEq, Eq)]
 pub enum FromLunabot {
     Ping(LunabotStage),
+    Pong(u32),
 }
 
 impl FromLunabot {
@@ -1,5 +1,9 @@
 use bitcode::{Decode, Encode};
 
+pub mod frame;
+pub mod noise;
+pub mod transport;
+
 #[derive(Debug, Encode, Decode, Clone)]
 pub enum FromLunasim {
     Accelerometer {
@@ -17,8 +21,44 @@ pub enum FromLunasim {
         robot_angle: f32,
         robot_origin: [f32; 3],
     },
+    /// The ticks that the encoder of a wheel has counted since the simulation started, which count down while
+    /// the wheel turns backwards.
+    WheelEncoder {
+        /// [`LEFT_WHEEL`] or [`RIGHT_WHEEL`].
+        id: usize,
+        ticks: i64,
+        ticks_per_revolution: u32,
+    },
+    /// The current drawn by the motor of a wheel.
+    MotorCurrent {
+        /// [`LEFT_WHEEL`] or [`RIGHT_WHEEL`].
+        id: usize,
+        amps: f32,
+        /// Whether the motor is driven but cannot turn, such as when the robot is pushing against a rock.
+        stalled: bool,
+    },
+    BatteryVoltage {
+        volts: f32,
+    },
+    /// A frame from a simulated camera, for detecting AprilTags the same way as the real lunabot.
+    CameraFrame {
+        id: usize,
+        width: u32,
+        height: u32,
+        focal_length_px: f32,
+        /// 8-bit grayscale pixels, compressed with [`frame::pack`].
+        packed: Box<[u8]>,
+    },
+    /// Sent after the physics frames requested by [`FromLunasimbot::Step`] have run, and the simulation is
+    /// paused again.
+    Stepped,
 }
 
+/// The id of the wheels, and their motors, on the left side of the robot.
+pub const LEFT_WHEEL: usize = 0;
+/// The id of the wheels, and their motors, on the right side of the robot.
+pub const RIGHT_WHEEL: usize = 1;
+
 #[derive(Debug, Encode, Decode, Clone)]
 pub enum FromLunasimbot {
     PointCloud(Box<[[f32; 3]]>),
@@ -32,4 +72,18 @@ pub enum FromLunasimbot {
         left: f32,
         right: f32,
     },
+    /// Moves the robot to the given pose and stops it. Its wheel encoders and battery also start over, so
+    /// that a new episode can begin.
+    Reset {
+        axis: [f32; 3],
+        angle: f32,
+        origin: [f32; 3],
+    },
+    /// Pauses or resumes the simulation. Messages are still received while paused.
+    SetPaused(bool),
+    /// Runs the given number of physics frames, then pauses the simulation and sends
+    /// [`FromLunasim::Stepped`]. Physics frames have a fixed delta, so stepping is deterministic.
+    Step(u32),
+    /// Restarts every source of randomness in the simulation from the given seed, including sensor noise.
+    Seed(u64),
 }
@@ -0,0 +1,88 @@
+//! The encoding of simulated camera frames.
+//!
+//! Frames are 8-bit grayscale, row by row, compressed with PackBits. Rendered frames have large areas of the
+//! same shade, such as the sky and the walls of the arena, so runs of repeated pixels compress well, while
+//! noisy areas grow by at most 1 byte per 128 pixels.
+
+/// The longest run that one header byte can describe.
+const MAX_RUN: usize = 128;
+
+/// Compresses pixels with PackBits.
+pub fn pack(pixels: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(pixels.len() / 4);
+    let mut i = 0;
+    while i < pixels.len() {
+        let repeated = pixels[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&x| x == pixels[i])
+            .count();
+        if repeated >= 2 {
+            packed.push((1 - repeated as i16) as i8 as u8);
+            packed.push(pixels[i]);
+            i += repeated;
+            continue;
+        }
+        // Copy literally until the next run of at least 3, which is worth encoding as a run
+        let start = i;
+        while i < pixels.len() && i - start < MAX_RUN {
+            if i + 2 < pixels.len() && pixels[i] == pixels[i + 1] && pixels[i] == pixels[i + 2] {
+                break;
+            }
+            i += 1;
+        }
+        packed.push((i - start - 1) as u8);
+        packed.extend_from_slice(&pixels[start..i]);
+    }
+    packed
+}
+
+/// Decompresses pixels that were compressed with [`pack`], returning `None` if they are not exactly `len`
+/// pixels long.
+pub fn unpack(packed: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut pixels = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < packed.len() {
+        let header = packed[i] as i8;
+        i += 1;
+        if header >= 0 {
+            let n = header as usize + 1;
+            pixels.extend_from_slice(packed.get(i..i + n)?);
+            i += n;
+        } else if header != i8::MIN {
+            let n = (1 - header as i16) as usize;
+            pixels.extend(std::iter::repeat(*packed.get(i)?).take(n));
+            i += 1;
+        }
+        if pixels.len() > len {
+            return None;
+        }
+    }
+    (pixels.len() == len).then_some(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut pixels = vec![200; 1000];
+        pixels.extend((0..=255).cycle().take(300));
+        pixels.extend([0, 0, 1, 1, 1, 2]);
+        let packed = pack(&pixels);
+        assert!(packed.len() < 330);
+        assert_eq!(unpack(&packed, pixels.len()), Some(pixels));
+
+        assert!(pack(&[]).is_empty());
+        assert_eq!(unpack(&pack(&[7]), 1), Some(vec![7]));
+    }
+
+    #[test]
+    fn wrong_length() {
+        let packed = pack(&[1, 2, 3, 3, 3, 3]);
+        assert_eq!(unpack(&packed, 5), None);
+        assert_eq!(unpack(&packed, 7), None);
+        assert_eq!(unpack(&packed[..packed.len() - 1], 6), None);
+    }
+}
@@ -0,0 +1,435 @@
+//! Noise that makes simulated sensors behave like real ones.
+//!
+//! Each sensor has a model, which is plain configuration that may be changed at any time, and a state, which
+//! owns the random number generator and whatever the noise accumulates, such as IMU bias. States are created
+//! from [`SensorNoise::new`] with a seed, so that a simulation with the same seed and the same inputs is
+//! noised the same way. Every parameter defaults to 0, which disables that kind of noise.
+use std::time::{Duration, Instant};
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rand_distr::{StandardNormal, UnitSphere};
+
+/// The noise of one 3-axis inertial sensor, such as an accelerometer or a gyroscope.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct InertialNoiseModel {
+    /// The standard deviation of the white noise of each reading.
+    pub deviation: f32,
+    /// How quickly the bias wanders, as the standard deviation of its change over one second.
+    pub bias_walk: f32,
+    /// How much the bias changes per degree Celsius that the sensor has warmed up.
+    pub temperature_drift: f32,
+    /// How many degrees Celsius the sensor warms up by after being turned on.
+    pub warmup_temperature_rise: f32,
+    /// The time constant of warming up, in seconds. The sensor is 63% of the way to its final temperature
+    /// after this long.
+    pub warmup_time_constant: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct InertialNoise {
+    rng: SmallRng,
+    bias: [f32; 3],
+    /// The direction that the bias drifts in as the sensor warms up, which is different for every sensor.
+    drift_direction: [f32; 3],
+    /// Seconds since the sensor was turned on.
+    elapsed: f32,
+}
+
+impl InertialNoise {
+    fn new(mut rng: SmallRng) -> Self {
+        let drift_direction = rng.sample(UnitSphere);
+        Self {
+            rng,
+            bias: [0.0; 3],
+            drift_direction,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Returns how many degrees Celsius the sensor has warmed up by.
+    pub fn temperature_rise(&self, model: &InertialNoiseModel) -> f32 {
+        if model.warmup_time_constant <= 0.0 {
+            return model.warmup_temperature_rise;
+        }
+        model.warmup_temperature_rise * (1.0 - (-self.elapsed / model.warmup_time_constant).exp())
+    }
+
+    /// Adds noise to a reading that was taken `delta` seconds after the previous one.
+    pub fn apply(&mut self, model: &InertialNoiseModel, value: [f32; 3], delta: f32) -> [f32; 3] {
+        self.elapsed += delta;
+        let walk = model.bias_walk * delta.max(0.0).sqrt();
+        for bias in &mut self.bias {
+            *bias += normal(&mut self.rng, walk);
+        }
+        let drift = model.temperature_drift * self.temperature_rise(model);
+        std::array::from_fn(|i| {
+            value[i]
+                + self.bias[i]
+                + drift * self.drift_direction[i]
+                + normal(&mut self.rng, model.deviation)
+        })
+    }
+}
+
+/// The noise of a depth camera, such as a stereo camera.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DepthNoiseModel {
+    /// The standard deviation of each depth, per square meter of depth.
+    pub deviation: f32,
+    /// The smallest step between depths at 1 meter, in meters. Like a stereo camera, which measures disparity
+    /// instead of depth, the step grows with the square of the depth.
+    pub quantization: f32,
+    /// The probability that a depth is missing, which is reported as 0.
+    pub missing_probability: f32,
+    /// The probability that a depth at an edge is somewhere between the depths on either side of the edge,
+    /// instead of on either side.
+    pub flying_pixel_probability: f32,
+    /// How much neighbouring depths must differ by, in meters, for there to be an edge between them.
+    pub edge_threshold: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DepthNoise {
+    rng: SmallRng,
+    original: Vec<f32>,
+}
+
+impl DepthNoise {
+    /// Adds noise to a depth map in meters, stored row by row with the given width. Depths of 0 are missing,
+    /// and stay missing.
+    pub fn apply(&mut self, model: &DepthNoiseModel, depths: &mut [f32], width: usize) {
+        self.original.clear();
+        self.original.extend_from_slice(depths);
+        let original = &self.original;
+
+        for (i, depth) in depths.iter_mut().enumerate() {
+            let d = original[i];
+            if d <= 0.0 {
+                continue;
+            }
+            if model.flying_pixel_probability > 0.0 && model.edge_threshold > 0.0 && width > 0 {
+                let (x, y) = (i % width, i / width);
+                let neighbours = [
+                    (x > 0).then(|| i - 1),
+                    (x + 1 < width).then(|| i + 1),
+                    (y > 0).then(|| i - width),
+                    Some(i + width).filter(|&j| j < original.len()),
+                ];
+                let far = neighbours
+                    .into_iter()
+                    .flatten()
+                    .map(|j| original[j])
+                    .filter(|&other| other > 0.0 && (other - d).abs() > model.edge_threshold)
+                    .max_by(|a, b| (a - d).abs().total_cmp(&(b - d).abs()));
+                if let Some(far) = far {
+                    if self
+                        .rng
+                        .gen_bool(model.flying_pixel_probability.clamp(0.0, 1.0) as f64)
+                    {
+                        *depth = d + (far - d) * self.rng.gen::<f32>();
+                    }
+                }
+            }
+            let d = *depth;
+            *depth = (d + normal(&mut self.rng, d * d * model.deviation)).abs();
+            if model.quantization > 0.0 && *depth > 0.0 {
+                let disparity = (1.0 / *depth / model.quantization).round() * model.quantization;
+                *depth = if disparity > 0.0 {
+                    1.0 / disparity
+                } else {
+                    0.0
+                };
+            }
+            if model.missing_probability > 0.0
+                && self
+                    .rng
+                    .gen_bool(model.missing_probability.clamp(0.0, 1.0) as f64)
+            {
+                *depth = 0.0;
+            }
+        }
+    }
+}
+
+/// The noise of detecting AprilTags.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ApriltagNoiseModel {
+    /// The standard deviation of the error in the angle of each detection, in radians.
+    pub rotation_deviation: f32,
+    /// The standard deviation of the error in the position of each detection, in meters, along each axis.
+    pub translation_deviation: f32,
+    /// The probability that a tag in plain view is not detected.
+    pub dropout_probability: f32,
+    /// The distance in meters at which tags are no longer detected. Tags are detected less often as they get
+    /// further away. If 0, distance does not matter.
+    pub max_distance: f32,
+    /// The angle in radians between the normal of a tag and the camera at which the tag is no longer detected.
+    /// Tags are detected less often as they are seen more from the side. If 0, the angle does not matter.
+    pub max_viewing_angle: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApriltagNoise {
+    rng: SmallRng,
+}
+
+impl ApriltagNoise {
+    /// Returns `true` if a tag at the given distance in meters, and seen at the given angle in radians from its
+    /// normal, is detected this time.
+    pub fn detect(
+        &mut self,
+        model: &ApriltagNoiseModel,
+        distance: f32,
+        viewing_angle: f32,
+    ) -> bool {
+        let falloff = |value: f32, max: f32| {
+            if max <= 0.0 {
+                1.0
+            } else {
+                (1.0 - (value / max).powi(2)).max(0.0)
+            }
+        };
+        let probability = (1.0 - model.dropout_probability)
+            * falloff(distance, model.max_distance)
+            * falloff(viewing_angle.abs(), model.max_viewing_angle);
+        self.rng.gen_bool(probability.clamp(0.0, 1.0) as f64)
+    }
+
+    /// Returns the error in the angle of a detection, as a unit axis and an angle in radians.
+    pub fn rotation_error(&mut self, model: &ApriltagNoiseModel) -> ([f32; 3], f32) {
+        let axis = self.rng.sample(UnitSphere);
+        (axis, normal(&mut self.rng, model.rotation_deviation))
+    }
+
+    /// Returns the error in the position of a detection.
+    pub fn translation_error(&mut self, model: &ApriltagNoiseModel) -> [f32; 3] {
+        std::array::from_fn(|_| normal(&mut self.rng, model.translation_deviation))
+    }
+}
+
+/// Jitter in the time that frames from a sensor arrive.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameJitterModel {
+    /// The standard deviation of how late each frame is, in seconds. Frames are never early.
+    pub deviation: f32,
+    /// The latest that a frame can be, in seconds. If 0, frames can be arbitrarily late.
+    pub max_delay: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameJitter {
+    rng: SmallRng,
+    last_release: Option<Instant>,
+}
+
+impl FrameJitter {
+    /// Returns when a frame that was captured at `now` should be released. Frames are released in the order
+    /// that they were captured.
+    pub fn release_time(&mut self, model: &FrameJitterModel, now: Instant) -> Instant {
+        let mut delay = normal(&mut self.rng, model.deviation).abs();
+        if model.max_delay > 0.0 {
+            delay = delay.min(model.max_delay);
+        }
+        let mut release = now + Duration::from_secs_f32(delay);
+        if let Some(last_release) = self.last_release {
+            release = release.max(last_release);
+        }
+        self.last_release = Some(release);
+        release
+    }
+}
+
+/// The noise state of every simulated sensor.
+#[derive(Debug, Clone)]
+pub struct SensorNoise {
+    pub accelerometer: InertialNoise,
+    pub gyroscope: InertialNoise,
+    pub depth: DepthNoise,
+    pub apriltag: ApriltagNoise,
+    pub depth_jitter: FrameJitter,
+    pub imu_jitter: FrameJitter,
+    pub apriltag_jitter: FrameJitter,
+    pub drivetrain_jitter: FrameJitter,
+    pub camera_jitter: FrameJitter,
+}
+
+impl SensorNoise {
+    /// Every sensor gets its own generator, so that noising one sensor more often does not change the noise of
+    /// the others.
+    pub fn new(seed: u64) -> Self {
+        let mut seeds = SmallRng::seed_from_u64(seed);
+        let mut rng = || SmallRng::seed_from_u64(seeds.gen());
+        let jitter = |rng: SmallRng| FrameJitter {
+            rng,
+            last_release: None,
+        };
+        Self {
+            accelerometer: InertialNoise::new(rng()),
+            gyroscope: InertialNoise::new(rng()),
+            depth: DepthNoise {
+                rng: rng(),
+                original: vec![],
+            },
+            apriltag: ApriltagNoise { rng: rng() },
+            depth_jitter: jitter(rng()),
+            imu_jitter: jitter(rng()),
+            apriltag_jitter: jitter(rng()),
+            drivetrain_jitter: jitter(rng()),
+            camera_jitter: jitter(rng()),
+        }
+    }
+
+    /// Returns the latest time that a frame of any sensor will be released, if any were.
+    pub fn last_release(&self) -> Option<Instant> {
+        [
+            &self.depth_jitter,
+            &self.imu_jitter,
+            &self.apriltag_jitter,
+            &self.drivetrain_jitter,
+            &self.camera_jitter,
+        ]
+        .into_iter()
+        .filter_map(|jitter| jitter.last_release)
+        .max()
+    }
+}
+
+fn normal(rng: &mut SmallRng, deviation: f32) -> f32 {
+    if deviation == 0.0 {
+        return 0.0;
+    }
+    rng.sample::<f32, _>(StandardNormal) * deviation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproducible() {
+        let model = InertialNoiseModel {
+            deviation: 0.1,
+            bias_walk: 0.01,
+            ..Default::default()
+        };
+        let mut a = SensorNoise::new(7);
+        let mut b = SensorNoise::new(7);
+        for _ in 0..10 {
+            assert_eq!(
+                a.accelerometer.apply(&model, [0.0, -9.81, 0.0], 0.1),
+                b.accelerometer.apply(&model, [0.0, -9.81, 0.0], 0.1)
+            );
+        }
+        let mut c = SensorNoise::new(8);
+        assert_ne!(
+            a.accelerometer.apply(&model, [0.0; 3], 0.1),
+            c.accelerometer.apply(&model, [0.0; 3], 0.1)
+        );
+    }
+
+    #[test]
+    fn no_noise_by_default() {
+        let mut noise = SensorNoise::new(0);
+        let value = [1.0, 2.0, 3.0];
+        assert_eq!(
+            noise.gyroscope.apply(&Default::default(), value, 0.1),
+            value
+        );
+        let mut depths = vec![1.0, 0.0, 2.5, 3.0];
+        noise.depth.apply(&Default::default(), &mut depths, 2);
+        assert_eq!(depths, [1.0, 0.0, 2.5, 3.0]);
+        assert!(noise.apriltag.detect(&Default::default(), 100.0, 1.5));
+    }
+
+    #[test]
+    fn temperature_drift() {
+        let model = InertialNoiseModel {
+            temperature_drift: 0.01,
+            warmup_temperature_rise: 10.0,
+            warmup_time_constant: 60.0,
+            ..Default::default()
+        };
+        let mut noise = SensorNoise::new(0);
+        let first = noise.gyroscope.apply(&model, [0.0; 3], 1.0);
+        let mut last = first;
+        for _ in 0..600 {
+            last = noise.gyroscope.apply(&model, [0.0; 3], 1.0);
+        }
+        let norm = |v: [f32; 3]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+        assert!(norm(first) < 0.01);
+        // Fully warmed up
+        assert!((norm(last) - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn depth() {
+        let mut noise = SensorNoise::new(0);
+        let model = DepthNoiseModel {
+            quantization: 0.01,
+            ..Default::default()
+        };
+        let mut depths = vec![2.013; 4];
+        noise.depth.apply(&model, &mut depths, 2);
+        for depth in depths {
+            assert!((depth - 2.0).abs() < 1e-5);
+        }
+
+        let model = DepthNoiseModel {
+            missing_probability: 1.0,
+            ..Default::default()
+        };
+        let mut depths = vec![1.0; 4];
+        noise.depth.apply(&model, &mut depths, 2);
+        assert_eq!(depths, [0.0; 4]);
+
+        // Only the edge in the middle has flying pixels
+        let model = DepthNoiseModel {
+            flying_pixel_probability: 1.0,
+            edge_threshold: 0.5,
+            ..Default::default()
+        };
+        let mut depths = vec![1.0, 1.0, 3.0, 3.0];
+        noise.depth.apply(&model, &mut depths, 4);
+        assert_eq!(depths[0], 1.0);
+        assert!((1.0..=3.0).contains(&depths[1]));
+        assert!((1.0..=3.0).contains(&depths[2]));
+        assert_eq!(depths[3], 3.0);
+    }
+
+    #[test]
+    fn apriltag_dropouts() {
+        let mut noise = SensorNoise::new(0);
+        let model = ApriltagNoiseModel {
+            max_distance: 5.0,
+            max_viewing_angle: 1.0,
+            ..Default::default()
+        };
+        assert!(noise.apriltag.detect(&model, 0.0, 0.0));
+        assert!(!noise.apriltag.detect(&model, 5.0, 0.0));
+        assert!(!noise.apriltag.detect(&model, 1.0, -1.2));
+        let detected = (0..1000)
+            .filter(|_| noise.apriltag.detect(&model, 2.5, 0.0))
+            .count();
+        assert!((650..850).contains(&detected), "{detected}");
+    }
+
+    #[test]
+    fn jitter_keeps_order() {
+        let mut noise = SensorNoise::new(0);
+        let model = FrameJitterModel {
+            deviation: 0.05,
+            max_delay: 0.1,
+        };
+        let start = Instant::now();
+        let mut last = start;
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i * 10);
+            let release = noise.depth_jitter.release_time(&model, now);
+            assert!(release >= now && release >= last);
+            assert!(release <= now + Duration::from_millis(101));
+            last = release;
+        }
+        assert_eq!(noise.last_release(), Some(last));
+        assert_eq!(SensorNoise::new(0).last_release(), None);
+    }
+}
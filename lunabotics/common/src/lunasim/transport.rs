@@ -0,0 +1,292 @@
+//! The framing of messages between the lunabot and lunasim, over the stdin and stdout of lunasim or over a
+//! socket.
+//!
+//! Each side first sends [`MAGIC`] followed by its [`VERSION`] as a little-endian `u16`. Anything before that
+//! is skipped, as Godot prints to stdout while it starts. After that, every message is sent as a frame of
+//! [`SYNC`], the length of the payload, its CRC-32, and the CRC-32 of the header before it, all as little-endian
+//! `u32`s, then the payload. If a frame is corrupt, such as when a script prints to stdout, the decoder skips
+//! ahead to the next [`SYNC`]. The header is checked before waiting for the payload, so a corrupt length does
+//! not hold up the frames after it.
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr};
+
+pub const MAGIC: [u8; 8] = *b"LUNASIM\0";
+/// Incremented whenever the framing, or the messages in [`crate::lunasim`], change incompatibly.
+pub const VERSION: u16 = 3;
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+/// Frames that claim to have longer payloads are assumed to be corrupt.
+pub const MAX_PAYLOAD_LEN: usize = 64 * 1024 * 1024;
+
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const FRAME_HEADER_LEN: usize = SYNC.len() + 12;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TransportError {
+    #[error(
+        "The other side uses version {0} of the protocol, but this side uses version {VERSION}"
+    )]
+    VersionMismatch(u16),
+    #[error("Skipped {0} bytes of corrupt frames")]
+    Corrupt(usize),
+    #[error("The payload is {0} bytes long, which is too long to send")]
+    TooLong(usize),
+}
+
+/// Returns the bytes that must be sent before any frames.
+pub fn header() -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..MAGIC.len()].copy_from_slice(&MAGIC);
+    header[MAGIC.len()..].copy_from_slice(&VERSION.to_le_bytes());
+    header
+}
+
+/// Appends a frame containing `payload` to `into`.
+pub fn encode_frame(payload: &[u8], into: &mut Vec<u8>) -> Result<(), TransportError> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(TransportError::TooLong(payload.len()));
+    }
+    into.reserve(FRAME_HEADER_LEN + payload.len());
+    let start = into.len();
+    into.extend_from_slice(&SYNC);
+    into.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    into.extend_from_slice(&crc32(payload).to_le_bytes());
+    let header_crc = crc32(&into[start..]);
+    into.extend_from_slice(&header_crc.to_le_bytes());
+    into.extend_from_slice(payload);
+    Ok(())
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 used by zlib and Ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Splits received bytes into the payloads of frames.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    bytes: Vec<u8>,
+    received_header: bool,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Whether the header of the other side was received, and its version matched.
+    pub fn received_header(&self) -> bool {
+        self.received_header
+    }
+
+    /// Returns the payload of the next frame, or an error if bytes had to be skipped. Returns `None` if more
+    /// bytes are needed.
+    pub fn next_frame(&mut self) -> Option<Result<Vec<u8>, TransportError>> {
+        if !self.received_header {
+            let Some(i) = self
+                .bytes
+                .windows(MAGIC.len())
+                .position(|window| window == MAGIC)
+            else {
+                // The end could be the start of the magic
+                let keep = self.bytes.len().min(MAGIC.len() - 1);
+                self.bytes.drain(..self.bytes.len() - keep);
+                return None;
+            };
+            self.bytes.drain(..i);
+            if self.bytes.len() < HEADER_LEN {
+                return None;
+            }
+            let version =
+                u16::from_le_bytes([self.bytes[MAGIC.len()], self.bytes[MAGIC.len() + 1]]);
+            self.bytes.drain(..HEADER_LEN);
+            if version != VERSION {
+                return Some(Err(TransportError::VersionMismatch(version)));
+            }
+            self.received_header = true;
+        }
+
+        if self.bytes.len() < SYNC.len() {
+            return None;
+        }
+        if self.bytes[..SYNC.len()] != SYNC {
+            return Some(Err(self.resync(0)));
+        }
+        if self.bytes.len() < FRAME_HEADER_LEN {
+            return None;
+        }
+        let [_, _, l0, l1, l2, l3, c0, c1, c2, c3, h0, h1, h2, h3] = self.bytes[..FRAME_HEADER_LEN]
+        else {
+            unreachable!()
+        };
+        if crc32(&self.bytes[..FRAME_HEADER_LEN - 4]) != u32::from_le_bytes([h0, h1, h2, h3]) {
+            return Some(Err(self.resync(1)));
+        }
+        let len = u32::from_le_bytes([l0, l1, l2, l3]) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Some(Err(self.resync(1)));
+        }
+        if self.bytes.len() < FRAME_HEADER_LEN + len {
+            return None;
+        }
+        let payload = &self.bytes[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+        if crc32(payload) != u32::from_le_bytes([c0, c1, c2, c3]) {
+            return Some(Err(self.resync(1)));
+        }
+        let payload = payload.to_vec();
+        self.bytes.drain(..FRAME_HEADER_LEN + len);
+        Some(Ok(payload))
+    }
+
+    /// Skips to the next [`SYNC`] at or after `from`.
+    fn resync(&mut self, from: usize) -> TransportError {
+        let skipped = self.bytes[from..]
+            .windows(SYNC.len())
+            .position(|window| window == SYNC)
+            .map(|i| from + i)
+            .unwrap_or_else(|| {
+                // The last byte could be the start of the next sync
+                if self.bytes.last() == Some(&SYNC[0]) {
+                    self.bytes.len() - 1
+                } else {
+                    self.bytes.len()
+                }
+            });
+        self.bytes.drain(..skipped);
+        TransportError::Corrupt(skipped)
+    }
+}
+
+/// The address that the lunabot listens on for lunasim to connect to, instead of running lunasim itself.
+///
+/// Written as `<ip>:<port>` or `tcp:<ip>:<port>` for TCP, or as `unix:<path>` for a Unix socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LunasimAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for LunasimAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+        s.strip_prefix("tcp:").unwrap_or(s).parse().map(Self::Tcp)
+    }
+}
+
+impl fmt::Display for LunasimAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(payloads: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for payload in payloads {
+            encode_frame(payload, &mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    fn decode_all(decoder: &mut FrameDecoder) -> Vec<Result<Vec<u8>, TransportError>> {
+        std::iter::from_fn(|| decoder.next_frame()).collect()
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn skips_text_before_header() {
+        let mut bytes = b"Godot Engine v4.3.stable\nLUNA".to_vec();
+        bytes.extend_from_slice(&header());
+        bytes.extend(frames(&[b"hello", b""]));
+
+        // One byte at a time, to split every part of the frames
+        let mut decoder = FrameDecoder::default();
+        let mut decoded = vec![];
+        for byte in bytes {
+            decoder.push(&[byte]);
+            decoded.extend(decode_all(&mut decoder));
+        }
+        assert!(decoder.received_header());
+        assert_eq!(decoded, [Ok(b"hello".to_vec()), Ok(vec![])]);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&MAGIC);
+        decoder.push(&(VERSION + 1).to_le_bytes());
+        decoder.push(&frames(&[b"hello"]));
+        assert_eq!(
+            decode_all(&mut decoder),
+            [Err(TransportError::VersionMismatch(VERSION + 1))]
+        );
+        assert!(!decoder.received_header());
+    }
+
+    #[test]
+    fn resynchronizes() {
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&header());
+        let mut corrupted = frames(&[b"first"]);
+        *corrupted.last_mut().unwrap() ^= 1;
+        decoder.push(&corrupted);
+        decoder.push(b"printed\n");
+        decoder.push(&frames(&[b"second"]));
+        // A length that is too long
+        decoder.push(&SYNC);
+        decoder.push(&u32::MAX.to_le_bytes());
+        decoder.push(&frames(&[b"third"]));
+        // A length that is short enough to wait for, but is not the length that was sent
+        let mut wrong_length = frames(&[b"fourth"]);
+        wrong_length[SYNC.len()] = 200;
+        decoder.push(&wrong_length);
+        decoder.push(&frames(&[b"fifth"]));
+
+        assert_eq!(
+            decode_all(&mut decoder),
+            [
+                Err(TransportError::Corrupt(corrupted.len() + 8)),
+                Ok(b"second".to_vec()),
+                Err(TransportError::Corrupt(6)),
+                Ok(b"third".to_vec()),
+                Err(TransportError::Corrupt(wrong_length.len())),
+                Ok(b"fifth".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,313 @@
+//! A lunabase for the terminal, for field testing over SSH where Godot is not available.
+//!
+//! Commands are read from standard input one line at a time. Run `help` for the list of commands.
+use std::{
+    io::BufRead,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    sync::mpsc::{self, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use cakap2::auth::{PreSharedKey, Protection};
+use common::{discovery::DISCOVERY_PORT, FromLunabase, FromLunabot, LunabotStage, Steering};
+use lunabase_lib::{
+    client::{ClientEvent, LunabaseClient},
+    recording::{Entry, SessionPlayer, SessionRecorder},
+};
+
+const HELP: &str = "\
+list                    list connected lunabots, marking the controlled one with *
+control <addr>          send commands to the lunabot with the given address
+drive <drive> <steer>   steer, where both are from -1 to 1
+tank <left> <right>     drive each side, where both are from -1 to 1
+stop                    stop driving
+continue                continue the mission
+traverse                traverse obstacles
+soft-stop               soft stop
+discover [port]         listen for lunabots that are looking for a lunabase
+discovered              list lunabots that are looking for a lunabase
+claim <addr>            ask a discovered lunabot to connect, and control it once it does
+record <path>           record every message sent and received to a new file
+stop-recording          stop recording
+play <path> [send]      print a recorded session as it happened, and send its commands if `send` is given
+stop-playback           stop playing back
+stats                   show statistics and the health of the link to the controlled lunabot
+max-pong-delay <ms>     set how long the lunabots wait for pongs, as configured on the lunabots
+key <hex> [encrypt]     authenticate packets with a pre-shared key, and encrypt them if `encrypt` is given
+quit                    exit";
+
+fn main() {
+    let mut args = std::env::args();
+    let _exe = args.next().expect("No executable name");
+    let bind: SocketAddr = match args.next().as_deref().unwrap_or("0.0.0.0:10600").parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid bind address: {e}");
+            return;
+        }
+    };
+    let socket = match UdpSocket::bind(bind) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to bind to {bind}: {e}");
+            return;
+        }
+    };
+    let mut client = match LunabaseClient::new(socket) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to set non-blocking: {e}");
+            return;
+        }
+    };
+    println!("Listening on {bind}. Type `help` for commands.");
+
+    let (line_tx, line_rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if line_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Pings are sent several times a second, so stages are only printed when they change
+    let mut last_stage: Option<LunabotStage> = None;
+    // The session being played back, and whether its commands are sent
+    let mut playback: Option<(SessionPlayer, bool)> = None;
+    loop {
+        for event in client.poll(Instant::now()) {
+            match event {
+                ClientEvent::Connected(addr) => println!("{addr} connected"),
+                ClientEvent::Disconnected(addr) => {
+                    println!("{addr} disconnected");
+                    last_stage = None;
+                }
+                ClientEvent::Discovered(lunabot) => {
+                    println!("Discovered {} at {}", lunabot.name, lunabot.addr)
+                }
+                ClientEvent::Received {
+                    addr,
+                    msg: FromLunabot::Ping(stage),
+                    controlled: true,
+                } => {
+                    if last_stage != Some(stage) {
+                        println!("{addr} entered {stage:?}");
+                        last_stage = Some(stage);
+                    }
+                }
+                ClientEvent::Received { .. } => {}
+                ClientEvent::LinkStateChanged {
+                    addr,
+                    state,
+                    controlled: true,
+                } => println!("Link to {addr} is {state:?}"),
+                ClientEvent::LinkStateChanged { .. } => {}
+                ClientEvent::Error(e) => eprintln!("{e}"),
+            }
+        }
+
+        if let Some((player, send_commands)) = &mut playback {
+            for record in player.poll(Instant::now()) {
+                println!(
+                    "[{:.1}s] {:?}",
+                    record.elapsed().as_secs_f64(),
+                    record.entry
+                );
+                if let (true, Entry::Sent { msg, .. }) = (*send_commands, &record.entry) {
+                    if let Err(e) = client.resend(msg) {
+                        eprintln!("{e}");
+                    }
+                }
+            }
+            if player.is_finished() {
+                println!("Playback finished");
+                playback = None;
+            }
+        }
+
+        match line_rx.try_recv() {
+            Ok(line) => {
+                if !run_command(&mut client, &line, &mut last_stage, &mut playback) {
+                    break;
+                }
+            }
+            // Standard input was closed, but the lunabot may still be driving
+            Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => std::thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    if let Err(e) = client.set_steering(Steering::default()) {
+        eprintln!("{e}");
+    }
+    client.poll(Instant::now());
+}
+
+/// Runs one line of input, returning `false` if the lunabase should exit.
+fn run_command(
+    client: &mut LunabaseClient,
+    line: &str,
+    last_stage: &mut Option<LunabotStage>,
+    playback: &mut Option<(SessionPlayer, bool)>,
+) -> bool {
+    let mut words = line.split_whitespace();
+    let Some(command) = words.next() else {
+        return true;
+    };
+    let args: Vec<&str> = words.collect();
+    let result = match (command, &args[..]) {
+        ("help", []) => {
+            println!("{HELP}");
+            Ok(())
+        }
+        ("quit" | "exit", []) => return false,
+        ("list", []) => {
+            for addr in client.lunabots() {
+                let marker = if client.controlled() == Some(addr) {
+                    "*"
+                } else {
+                    " "
+                };
+                println!("{marker} {addr}");
+            }
+            Ok(())
+        }
+        ("control", [addr]) => {
+            match addr.parse() {
+                Ok(addr) if client.control(addr) => *last_stage = None,
+                Ok(addr) => eprintln!("{addr} is not connected"),
+                Err(e) => eprintln!("Invalid address: {e}"),
+            }
+            Ok(())
+        }
+        ("drive", [drive, steering]) => match (drive.parse(), steering.parse()) {
+            (Ok(drive), Ok(steering)) => client.set_steering(Steering::new(drive, steering)),
+            _ => {
+                eprintln!("Expected two numbers");
+                Ok(())
+            }
+        },
+        ("tank", [left, right]) => match (left.parse(), right.parse()) {
+            (Ok(left), Ok(right)) => client.set_steering(Steering::new_left_right(left, right)),
+            _ => {
+                eprintln!("Expected two numbers");
+                Ok(())
+            }
+        },
+        ("discover", [] | [_]) => {
+            let port = match args.first() {
+                Some(port) => port.parse(),
+                None => Ok(DISCOVERY_PORT),
+            };
+            match port {
+                Ok(port) => {
+                    let bind = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+                    match UdpSocket::bind(bind).and_then(|socket| client.start_discovery(socket)) {
+                        Ok(()) => println!("Listening for lunabots on {bind}"),
+                        Err(e) => eprintln!("Failed to listen on {bind}: {e}"),
+                    }
+                }
+                Err(e) => eprintln!("Invalid port: {e}"),
+            }
+            Ok(())
+        }
+        ("discovered", []) => {
+            for lunabot in client.discovered() {
+                println!("{}\t{}", lunabot.addr, lunabot.name);
+            }
+            Ok(())
+        }
+        ("claim", [addr]) => {
+            match addr.parse() {
+                Ok(addr) => {
+                    client.claim(addr);
+                    *last_stage = None;
+                }
+                Err(e) => eprintln!("Invalid address: {e}"),
+            }
+            Ok(())
+        }
+        ("record", [path]) => {
+            match SessionRecorder::create(path, Instant::now()) {
+                Ok(recorder) => client.start_recording(recorder),
+                Err(e) => eprintln!("Failed to create {path}: {e}"),
+            }
+            Ok(())
+        }
+        ("stop-recording", []) => {
+            client.stop_recording();
+            Ok(())
+        }
+        ("play", [path] | [path, "send"]) => {
+            match SessionPlayer::open(path) {
+                Ok(player) => {
+                    println!("Playing {:.1}s", player.duration().as_secs_f64());
+                    *playback = Some((player, args.len() == 2));
+                }
+                Err(e) => eprintln!("Failed to open {path}: {e}"),
+            }
+            Ok(())
+        }
+        ("stop-playback", []) => {
+            *playback = None;
+            Ok(())
+        }
+        ("stop", []) => client.set_steering(Steering::default()),
+        ("continue", []) => client.send_command(&FromLunabase::ContinueMission),
+        ("traverse", []) => client.send_command(&FromLunabase::TraverseObstacles),
+        ("soft-stop", []) => client.send_command(&FromLunabase::SoftStop),
+        ("stats", []) => {
+            match client.stats() {
+                Some(stats) => println!("{stats:#?}"),
+                None => eprintln!("No lunabot is controlled"),
+            }
+            if let Some(health) = client.link_health() {
+                let rtt = health
+                    .rtt()
+                    .map(|rtt| format!("{}ms", rtt.as_millis()))
+                    .unwrap_or_else(|| "unknown".into());
+                println!(
+                    "{:?}, round-trip time {rtt}, {:.0}% of pings lost, last pinged {}ms ago",
+                    health.state(),
+                    health.loss() * 100.0,
+                    health.since_pinged(Instant::now()).as_millis()
+                );
+            }
+            if let Some(auth_stats) = client.auth_stats() {
+                println!("{auth_stats:#?}");
+            }
+            Ok(())
+        }
+        ("max-pong-delay", [ms]) => {
+            match ms.parse() {
+                Ok(ms) => client.set_max_pong_delay(Duration::from_millis(ms)),
+                Err(e) => eprintln!("Invalid delay: {e}"),
+            }
+            Ok(())
+        }
+        ("key", [key, rest @ ..]) if rest.len() <= 1 => {
+            let protection = match rest {
+                [] => Some(Protection::Authenticate),
+                ["encrypt"] => Some(Protection::Encrypt),
+                _ => None,
+            };
+            match (PreSharedKey::from_hex(key), protection) {
+                (Some(key), Some(protection)) => client.set_pre_shared_key(Some((key, protection))),
+                (None, _) => eprintln!("Pre-shared key should be 64 hexadecimal digits"),
+                (_, None) => eprintln!("Expected `encrypt` after the key"),
+            }
+            Ok(())
+        }
+        _ => {
+            eprintln!("Unknown command. Type `help` for commands.");
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
+    }
+    true
+}
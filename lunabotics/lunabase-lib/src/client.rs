@@ -0,0 +1,829 @@
+//! The connection to the lunabots, independent of Godot so that it can be tested, and shared by the Godot node
+//! and the command line lunabase.
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use bitcode::encode;
+use cakap2::{
+    auth::{AuthStats, PreSharedKey, Protection},
+    channel::{Channel, Priority, Reliability},
+    error::BuildPacketError,
+    packet::Action,
+    stats::ConnectionStats,
+    udp::{DriverError, PeerEvent, PolledServer, ServerEvent},
+    PeerStateMachine,
+};
+use common::{discovery::DiscoveryMessage, FromLunabase, FromLunabot, Steering};
+
+use crate::{
+    discovery::{DiscoveredLunabot, DiscoveryListener},
+    health::{LinkHealth, LinkState},
+    input::{Axis, Button, InputMapper, InputProfile},
+    recording::{Entry, SessionRecorder},
+};
+
+/// Commands to the lunabot are sent ahead of anything else that is waiting to be sent.
+const CONTROL_CHANNEL: Channel = Channel::new(1, Priority::Control, Reliability::Ordered);
+/// Only the latest steering matters, so older steering is dropped instead of being retransmitted.
+const STEERING_CHANNEL: Channel = Channel::new(2, Priority::Control, Reliability::Latest);
+/// How often a claim is sent until the lunabot connects.
+const CLAIM_INTERVAL: Duration = Duration::from_millis(250);
+/// How long to wait for a claimed lunabot to connect.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(5);
+/// The default of `max_pong_delay_ms` in the configuration of the lunabot.
+const DEFAULT_MAX_PONG_DELAY: Duration = Duration::from_millis(1500);
+
+thread_local! {
+    static PONG_MESSAGE: Box<[u8]> = {
+        encode(&FromLunabase::Pong).into()
+    };
+}
+
+/// Something that happened since the last time the client was polled.
+#[derive(Debug)]
+pub enum ClientEvent {
+    /// A lunabot connected. It is controlled if no other lunabot is, or if it was claimed.
+    Connected(SocketAddr),
+    /// Nothing was heard from a lunabot for a while, so it was forgotten. If it was controlled, no lunabot is
+    /// controlled until one is chosen with [`LunabaseClient::control`], or another one connects.
+    Disconnected(SocketAddr),
+    /// A lunabot that is looking for a lunabase was heard from for the first time. It connects once it is
+    /// claimed with [`LunabaseClient::claim`].
+    Discovered(DiscoveredLunabot),
+    /// A message from a lunabot, which the client has already responded to if it needed a response.
+    Received {
+        addr: SocketAddr,
+        msg: FromLunabot,
+        /// Whether the lunabot is the controlled lunabot.
+        controlled: bool,
+    },
+    /// The link to a lunabot became healthier or less healthy.
+    LinkStateChanged {
+        addr: SocketAddr,
+        state: LinkState,
+        /// Whether the lunabot is the controlled lunabot.
+        controlled: bool,
+    },
+    /// An error that did not stop the client.
+    Error(ClientError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("No lunabot to send to")]
+    NotConnected,
+    #[error("Failed to build packet: {0}")]
+    BuildPacket(#[from] BuildPacketError),
+    #[error("Failed to decode message from {addr}: {error}")]
+    Decode {
+        addr: SocketAddr,
+        error: bitcode::Error,
+    },
+    #[error("{addr}: {error}")]
+    Peer {
+        addr: SocketAddr,
+        error: DriverError,
+    },
+    #[error("Socket error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0} did not connect after being claimed")]
+    ClaimTimedOut(SocketAddr),
+    #[error("Failed to record session, so recording stopped: {0}")]
+    Recording(io::Error),
+}
+
+/// A lunabot that was claimed, and has not connected yet.
+struct Claim {
+    addr: SocketAddr,
+    started: Instant,
+    last_sent: Option<Instant>,
+}
+
+fn new_server(socket: UdpSocket) -> io::Result<PolledServer> {
+    let mut server = PolledServer::new(socket, || {
+        PeerStateMachine::new(Duration::from_millis(150), 1024)
+    })?;
+    // The lunabot pings several times a second
+    server.set_peer_timeout(Duration::from_secs(5));
+    Ok(server)
+}
+
+/// Talks to every lunabot that connects, and sends commands to one of them.
+///
+/// The client never blocks, so [`LunabaseClient::poll`] should be called often, such as once per frame.
+pub struct LunabaseClient {
+    server: PolledServer,
+    bitcode_buffer: bitcode::Buffer,
+    /// The lunabot that commands are sent to. Every other lunabot is only observed.
+    controlled: Option<SocketAddr>,
+    last_steering: Option<Steering>,
+    input: InputMapper,
+    key: Option<(PreSharedKey, Protection)>,
+    discovery: Option<DiscoveryListener>,
+    claims: Vec<Claim>,
+    recorder: Option<SessionRecorder>,
+    health: HashMap<SocketAddr, LinkHealth>,
+    max_pong_delay: Duration,
+    /// Errors that happened outside of [`LunabaseClient::poll`], which are reported by the next poll.
+    errors: Vec<ClientError>,
+}
+
+impl LunabaseClient {
+    /// Serves lunabots over the given socket, which is made non-blocking.
+    pub fn new(socket: UdpSocket) -> io::Result<Self> {
+        Ok(Self {
+            server: new_server(socket)?,
+            bitcode_buffer: bitcode::Buffer::new(),
+            controlled: None,
+            last_steering: None,
+            input: InputMapper::default(),
+            key: None,
+            discovery: None,
+            claims: vec![],
+            recorder: None,
+            health: HashMap::new(),
+            max_pong_delay: DEFAULT_MAX_PONG_DELAY,
+            errors: vec![],
+        })
+    }
+
+    /// Serves lunabots over another socket, keeping the pre-shared key and input profile.
+    ///
+    /// Every lunabot is forgotten, and has to connect to the new socket.
+    pub fn rebind(&mut self, socket: UdpSocket) -> io::Result<()> {
+        let mut server = new_server(socket)?;
+        server.set_pre_shared_key(self.key.clone());
+        self.server = server;
+        self.controlled = None;
+        self.last_steering = None;
+        self.claims.clear();
+        self.health.clear();
+        Ok(())
+    }
+
+    /// Listens for beacons from lunabots that are looking for a lunabase on the given socket, which is made
+    /// non-blocking. It is usually bound to [`DISCOVERY_PORT`](common::discovery::DISCOVERY_PORT).
+    pub fn start_discovery(&mut self, socket: UdpSocket) -> io::Result<()> {
+        self.discovery = Some(DiscoveryListener::new(socket)?);
+        Ok(())
+    }
+
+    /// Returns the lunabots that are looking for a lunabase, if discovery was started.
+    pub fn discovered(&self) -> &[DiscoveredLunabot] {
+        self.discovery
+            .as_ref()
+            .map(DiscoveryListener::discovered)
+            .unwrap_or_default()
+    }
+
+    /// Asks the lunabot that is broadcasting from `addr` to connect, and controls it once it does.
+    pub fn claim(&mut self, addr: SocketAddr) {
+        self.claims.retain(|claim| claim.addr != addr);
+        self.claims.push(Claim {
+            addr,
+            started: Instant::now(),
+            last_sent: None,
+        });
+    }
+
+    /// Records every message that is sent to or received from any lunabot from now on, replacing the current
+    /// recording if there is one.
+    pub fn start_recording(&mut self, recorder: SessionRecorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recorder = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    fn record(&mut self, now: Instant, entry: Entry) {
+        let Some(recorder) = &mut self.recorder else {
+            return;
+        };
+        if let Err(e) = recorder.record(now, entry) {
+            self.recorder = None;
+            self.errors.push(ClientError::Recording(e));
+        }
+    }
+
+    /// Receives and responds to everything the lunabots sent, and sends whatever is due.
+    pub fn poll(&mut self, now: Instant) -> Vec<ClientEvent> {
+        let mut events = vec![];
+        for event in self.server.poll(now) {
+            match event {
+                ServerEvent::Connected(addr) => {
+                    let claimed = self.claims.iter().any(|claim| claim.addr == addr);
+                    self.claims.retain(|claim| claim.addr != addr);
+                    if claimed {
+                        self.controlled = Some(addr);
+                        self.last_steering = None;
+                    } else if self.controlled.is_none() {
+                        self.controlled = Some(addr);
+                    }
+                    self.health.insert(addr, LinkHealth::new(now));
+                    events.push(ClientEvent::Connected(addr));
+                }
+                ServerEvent::Disconnected(addr) => {
+                    if self.controlled == Some(addr) {
+                        // Control is never handed to a lunabot that the operator did not choose
+                        self.controlled = None;
+                        self.last_steering = None;
+                    }
+                    self.health.remove(&addr);
+                    events.push(ClientEvent::Disconnected(addr));
+                }
+                ServerEvent::Peer {
+                    addr,
+                    event: PeerEvent::Received { data, .. },
+                } => match self.bitcode_buffer.decode::<FromLunabot>(&data) {
+                    Ok(msg) => {
+                        let controlled = self.controlled == Some(addr);
+                        self.record(
+                            now,
+                            Entry::Received {
+                                from: addr.to_string(),
+                                msg,
+                                controlled,
+                            },
+                        );
+                        if let Err(e) = self.respond(now, addr, msg) {
+                            events.push(ClientEvent::Error(e));
+                        }
+                        events.push(ClientEvent::Received {
+                            addr,
+                            msg,
+                            controlled,
+                        });
+                    }
+                    Err(error) => {
+                        events.push(ClientEvent::Error(ClientError::Decode { addr, error }))
+                    }
+                },
+                ServerEvent::Peer {
+                    addr,
+                    event: PeerEvent::Error(error),
+                } => events.push(ClientEvent::Error(ClientError::Peer { addr, error })),
+                ServerEvent::Error(e) => events.push(ClientEvent::Error(e.into())),
+            }
+        }
+        if let Some(discovery) = &mut self.discovery {
+            let (discovered, error) = discovery.poll(now);
+            events.extend(discovered.into_iter().map(ClientEvent::Discovered));
+            events.extend(error.map(|e| ClientEvent::Error(e.into())));
+        }
+        self.send_claims(now, &mut events);
+        self.send_pings(now, &mut events);
+        events.extend(self.errors.drain(..).map(ClientEvent::Error));
+        events
+    }
+
+    fn send_claims(&mut self, now: Instant, events: &mut Vec<ClientEvent>) {
+        let claim_msg = DiscoveryMessage::Claim.encode();
+        let socket = self.server.socket();
+        self.claims.retain_mut(|claim| {
+            if now.duration_since(claim.started) > CLAIM_TIMEOUT {
+                events.push(ClientEvent::Error(ClientError::ClaimTimedOut(claim.addr)));
+                return false;
+            }
+            if claim
+                .last_sent
+                .is_some_and(|last_sent| now.duration_since(last_sent) < CLAIM_INTERVAL)
+            {
+                return true;
+            }
+            claim.last_sent = Some(now);
+            match socket.send_to(&claim_msg, claim.addr) {
+                Ok(_) => {}
+                // Like any other lost datagram
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => events.push(ClientEvent::Error(e.into())),
+            }
+            true
+        });
+    }
+
+    /// Pings every lunabot that is due for a ping, and reports links whose health changed.
+    fn send_pings(&mut self, now: Instant, events: &mut Vec<ClientEvent>) {
+        let mut due = vec![];
+        for (&addr, health) in &mut self.health {
+            if let Some(state) = health.update(now, self.max_pong_delay) {
+                events.push(ClientEvent::LinkStateChanged {
+                    addr,
+                    state,
+                    controlled: self.controlled == Some(addr),
+                });
+            }
+            if let Some(id) = health.ping_due(now) {
+                due.push((addr, id));
+            }
+        }
+        for (addr, id) in due {
+            if let Err(e) = self.send_unreliable(now, addr, FromLunabase::Ping(id)) {
+                events.push(ClientEvent::Error(e));
+            }
+        }
+    }
+
+    /// Sends `msg` to the given lunabot without retransmitting it, since it is only useful if it arrives soon.
+    fn send_unreliable(
+        &mut self,
+        now: Instant,
+        addr: SocketAddr,
+        msg: FromLunabase,
+    ) -> Result<(), ClientError> {
+        let Some(builder) = self.server.packet_builder(addr) else {
+            return Ok(());
+        };
+        let packet = match msg {
+            FromLunabase::Pong => {
+                PONG_MESSAGE.with(|pong| builder.new_unreliable(pong.to_vec().into()))?
+            }
+            _ => builder.new_unreliable(encode(&msg).into())?,
+        };
+        if self.server.send(addr, Action::SendUnreliable(packet)) {
+            self.record(
+                now,
+                Entry::Sent {
+                    to: addr.to_string(),
+                    msg,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        now: Instant,
+        addr: SocketAddr,
+        msg: FromLunabot,
+    ) -> Result<(), ClientError> {
+        match msg {
+            FromLunabot::Ping(_) => {
+                if let Some(health) = self.health.get_mut(&addr) {
+                    health.pinged(now);
+                }
+                self.send_unreliable(now, addr, FromLunabase::Pong)?;
+            }
+            FromLunabot::Pong(id) => {
+                if let Some(health) = self.health.get_mut(&addr) {
+                    health.ponged(id, now);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the addresses of every connected lunabot.
+    pub fn lunabots(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.server.peers()
+    }
+
+    /// Returns the address of the lunabot that commands are sent to, if any lunabot is connected.
+    pub fn controlled(&self) -> Option<SocketAddr> {
+        self.controlled
+    }
+
+    /// Sends commands to the lunabot with the given address from now on.
+    ///
+    /// Returns `false` if no lunabot is connected from that address.
+    pub fn control(&mut self, addr: SocketAddr) -> bool {
+        if !self.server.is_peer(addr) {
+            return false;
+        }
+        if self.controlled != Some(addr) {
+            self.controlled = Some(addr);
+            self.last_steering = None;
+        }
+        true
+    }
+
+    /// Builds a message on `channel` and sends it to the controlled lunabot.
+    fn send_to_controlled(
+        &mut self,
+        channel: Channel,
+        msg: &FromLunabase,
+    ) -> Result<(), ClientError> {
+        let Some(addr) = self.controlled else {
+            return Err(ClientError::NotConnected);
+        };
+        let Some(builder) = self.server.packet_builder(addr) else {
+            return Err(ClientError::NotConnected);
+        };
+        let action = builder
+            .with_channel(channel)
+            .new_message(encode(msg).into())?;
+        if self.server.send(addr, action) {
+            self.record(
+                Instant::now(),
+                Entry::Sent {
+                    to: addr.to_string(),
+                    msg: *msg,
+                },
+            );
+            Ok(())
+        } else {
+            Err(ClientError::NotConnected)
+        }
+    }
+
+    /// Sends `msg` reliably to the controlled lunabot, such that it handles it after every command sent before it.
+    pub fn send_command(&mut self, msg: &FromLunabase) -> Result<(), ClientError> {
+        self.send_to_controlled(CONTROL_CHANNEL, msg)
+    }
+
+    /// Steers the controlled lunabot, if the steering changed.
+    ///
+    /// Does nothing if no lunabot is controlled, since there is nothing to steer.
+    pub fn set_steering(&mut self, steering: Steering) -> Result<(), ClientError> {
+        if self.last_steering == Some(steering) {
+            return Ok(());
+        }
+        match self.send_to_controlled(STEERING_CHANNEL, &FromLunabase::Steering(steering)) {
+            Ok(()) => {
+                self.last_steering = Some(steering);
+                Ok(())
+            }
+            Err(ClientError::NotConnected) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends a message that was sent to a lunabot before, such as in a recording, to the controlled lunabot the
+    /// same way. Pings and pongs are not sent, since the client sends them by itself.
+    pub fn resend(&mut self, msg: &FromLunabase) -> Result<(), ClientError> {
+        match msg {
+            FromLunabase::Pong | FromLunabase::Ping(_) => Ok(()),
+            FromLunabase::Steering(steering) => self.set_steering(*steering),
+            FromLunabase::ContinueMission
+            | FromLunabase::TraverseObstacles
+            | FromLunabase::SoftStop => self.send_command(msg),
+        }
+    }
+
+    /// Maps gamepad input with the given profile from now on.
+    pub fn set_input_profile(&mut self, profile: InputProfile) {
+        self.input.set_profile(profile);
+    }
+
+    /// Updates the position of a gamepad axis, and steers accordingly.
+    pub fn set_gamepad_axis(&mut self, axis: Axis, value: f64) -> Result<(), ClientError> {
+        self.input.set_axis(axis, value);
+        self.set_steering(self.input.steering())
+    }
+
+    /// Updates whether a gamepad button is pressed, sending the command bound to it when it is pressed.
+    pub fn set_gamepad_button(&mut self, button: Button, pressed: bool) -> Result<(), ClientError> {
+        if let Some(command) = self.input.set_button(button, pressed) {
+            self.send_command(&command)?;
+        }
+        // The button may change the speed
+        self.set_steering(self.input.steering())
+    }
+
+    /// Returns statistics about the link to the controlled lunabot.
+    pub fn stats(&self) -> Option<ConnectionStats> {
+        self.server.stats(self.controlled?)
+    }
+
+    /// Returns statistics about the authentication of datagrams from the controlled lunabot, if there is a
+    /// pre-shared key.
+    pub fn auth_stats(&self) -> Option<AuthStats> {
+        self.server.auth_stats(self.controlled?)
+    }
+
+    /// Authenticates all packets to and from the lunabots with the given key, or stops authenticating them
+    /// if `None`. The lunabots must be configured the same way.
+    pub fn set_pre_shared_key(&mut self, key: Option<(PreSharedKey, Protection)>) {
+        self.server.set_pre_shared_key(key.clone());
+        self.key = key;
+    }
+
+    /// Returns the health of the link to the controlled lunabot.
+    pub fn link_health(&self) -> Option<&LinkHealth> {
+        self.health.get(&self.controlled?)
+    }
+
+    /// Sets how long a lunabot waits for a pong before it considers itself disconnected, which should match
+    /// `max_pong_delay_ms` in the configuration of the lunabots. Links are lost after this long without a ping, and
+    /// pings that are not answered within this long are counted as lost.
+    pub fn set_max_pong_delay(&mut self, max_pong_delay: Duration) {
+        self.max_pong_delay = max_pong_delay;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.server.socket().local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cakap2::udp::{PolledPeer, Remote};
+    use common::LunabotStage;
+
+    use super::*;
+    use crate::recording::SessionPlayer;
+
+    fn lunabot(client: &LunabaseClient) -> PolledPeer {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(client.local_addr().unwrap()).unwrap();
+        PolledPeer::new(
+            socket,
+            Remote::Connected,
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+        )
+        .unwrap()
+    }
+
+    fn client() -> LunabaseClient {
+        LunabaseClient::new(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap()
+    }
+
+    /// Polls everything until `done` returns `true` for the messages received by `lunabot`.
+    fn poll_until(
+        client: &mut LunabaseClient,
+        lunabots: &mut [&mut PolledPeer],
+        mut done: impl FnMut(usize, &[FromLunabase]) -> bool,
+    ) -> Vec<ClientEvent> {
+        let mut events = vec![];
+        let mut received = vec![vec![]; lunabots.len()];
+        for _ in 0..100 {
+            events.extend(client.poll(Instant::now()));
+            for (i, lunabot) in lunabots.iter_mut().enumerate() {
+                for event in lunabot.poll(Instant::now()) {
+                    if let PeerEvent::Received { data, .. } = event {
+                        received[i].push(bitcode::decode(&data).unwrap());
+                    }
+                }
+                if done(i, &received[i]) {
+                    return events;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("Timed out with {events:?} and {received:?}");
+    }
+
+    #[test]
+    fn ping_pong() {
+        let mut client = client();
+        let mut lunabot = lunabot(&client);
+        let addr = lunabot.socket().local_addr().unwrap();
+        lunabot
+            .send_unreliable(encode(&FromLunabot::Ping(LunabotStage::Dig)))
+            .unwrap();
+        let events = poll_until(&mut client, &mut [&mut lunabot], |_, received| {
+            received.contains(&FromLunabase::Pong)
+        });
+        assert!(matches!(
+            &events[..],
+            [
+                ClientEvent::Connected(connected),
+                ClientEvent::Received {
+                    msg: FromLunabot::Ping(LunabotStage::Dig),
+                    controlled: true,
+                    ..
+                }
+            ] if *connected == addr
+        ));
+        assert_eq!(client.controlled(), Some(addr));
+    }
+
+    #[test]
+    fn link_health() {
+        let mut client = client();
+        let mut lunabot = lunabot(&client);
+        let addr = lunabot.socket().local_addr().unwrap();
+        lunabot
+            .send_unreliable(encode(&FromLunabot::Ping(LunabotStage::TeleOp)))
+            .unwrap();
+        let mut id = None;
+        poll_until(&mut client, &mut [&mut lunabot], |_, received| {
+            id = received.iter().find_map(|msg| match msg {
+                FromLunabase::Ping(id) => Some(*id),
+                _ => None,
+            });
+            id.is_some()
+        });
+        assert_eq!(client.link_health().unwrap().rtt(), None);
+
+        lunabot
+            .send_unreliable(encode(&FromLunabot::Pong(id.unwrap())))
+            .unwrap();
+        lunabot.poll(Instant::now());
+        for _ in 0..100 {
+            client.poll(Instant::now());
+            if client.link_health().unwrap().rtt().is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let health = client.link_health().unwrap();
+        assert!(health.rtt().is_some());
+        assert_eq!(health.state(), LinkState::Healthy);
+
+        // The lunabot stopped pinging
+        let events = client.poll(Instant::now() + Duration::from_secs(2));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ClientEvent::LinkStateChanged {
+                addr: lost,
+                state: LinkState::Lost,
+                controlled: true,
+            } if *lost == addr
+        )));
+    }
+
+    #[test]
+    fn recording() {
+        let path = std::env::temp_dir().join(format!("lunabase-session-{}", std::process::id()));
+        let mut client = client();
+        client.start_recording(SessionRecorder::create(&path, Instant::now()).unwrap());
+        let mut lunabot = lunabot(&client);
+        let addr = lunabot.socket().local_addr().unwrap().to_string();
+        lunabot
+            .send_unreliable(encode(&FromLunabot::Ping(LunabotStage::Dig)))
+            .unwrap();
+        poll_until(&mut client, &mut [&mut lunabot], |_, received| {
+            received.contains(&FromLunabase::Pong)
+        });
+        client.send_command(&FromLunabase::SoftStop).unwrap();
+        client.stop_recording();
+
+        let player = SessionPlayer::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries: Vec<_> = player.records().iter().map(|r| r.entry.clone()).collect();
+        assert_eq!(
+            entries,
+            [
+                Entry::Received {
+                    from: addr.clone(),
+                    msg: FromLunabot::Ping(LunabotStage::Dig),
+                    controlled: true
+                },
+                Entry::Sent {
+                    to: addr.clone(),
+                    msg: FromLunabase::Pong
+                },
+                Entry::Sent {
+                    to: addr.clone(),
+                    msg: FromLunabase::Ping(0)
+                },
+                Entry::Sent {
+                    to: addr,
+                    msg: FromLunabase::SoftStop
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn commands_go_to_controlled_lunabot() {
+        let mut client = client();
+        assert!(matches!(
+            client.send_command(&FromLunabase::SoftStop),
+            Err(ClientError::NotConnected)
+        ));
+        // Steering nothing is not an error
+        client.set_steering(Steering::new(1.0, 0.0)).unwrap();
+
+        let mut first = lunabot(&client);
+        let mut second = lunabot(&client);
+        let second_addr = second.socket().local_addr().unwrap();
+        for lunabot in [&mut first, &mut second] {
+            lunabot
+                .send_unreliable(encode(&FromLunabot::Ping(LunabotStage::TeleOp)))
+                .unwrap();
+        }
+        poll_until(
+            &mut client,
+            &mut [&mut first, &mut second],
+            |i, received| i == 1 && !received.is_empty(),
+        );
+        assert_eq!(client.lunabots().count(), 2);
+
+        assert!(client.control(second_addr));
+        client.send_command(&FromLunabase::SoftStop).unwrap();
+        client.set_gamepad_button(Button::Start, true).unwrap();
+        poll_until(
+            &mut client,
+            &mut [&mut first, &mut second],
+            |i, received| {
+                assert!(i == 1 || !received.contains(&FromLunabase::SoftStop));
+                i == 1 && received.contains(&FromLunabase::ContinueMission)
+            },
+        );
+        assert!(!client.control("127.0.0.1:1".parse().unwrap()));
+    }
+
+    #[test]
+    fn control_is_not_handed_off() {
+        let mut client = client();
+        let mut first = lunabot(&client);
+        let mut second = lunabot(&client);
+        let first_addr = first.socket().local_addr().unwrap();
+        let second_addr = second.socket().local_addr().unwrap();
+        let ping = encode(&FromLunabot::Ping(LunabotStage::TeleOp));
+        for lunabot in [&mut first, &mut second] {
+            lunabot.send_unreliable(ping.clone()).unwrap();
+        }
+        poll_until(
+            &mut client,
+            &mut [&mut first, &mut second],
+            |i, received| i == 1 && !received.is_empty(),
+        );
+        assert!(client.control(first_addr));
+
+        // Only the second lunabot is still heard from
+        let later = Instant::now() + Duration::from_secs(3);
+        second.send_unreliable(ping).unwrap();
+        second.poll(Instant::now());
+        std::thread::sleep(Duration::from_millis(20));
+        client.poll(later);
+        let events = client.poll(later + Duration::from_secs(3));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, ClientEvent::Disconnected(addr) if *addr == first_addr)));
+        assert_eq!(client.lunabots().collect::<Vec<_>>(), [second_addr]);
+        assert_eq!(client.controlled(), None);
+        assert!(matches!(
+            client.send_command(&FromLunabase::SoftStop),
+            Err(ClientError::NotConnected)
+        ));
+    }
+
+    #[test]
+    fn discovery() {
+        let mut client = client();
+        client
+            .start_discovery(UdpSocket::bind("127.0.0.1:0").unwrap())
+            .unwrap();
+        let discovery_addr = client.discovery.as_ref().unwrap().local_addr().unwrap();
+        let ping = encode(&FromLunabot::Ping(LunabotStage::TeleOp));
+        // Another lunabot is already controlled
+        let mut first = lunabot(&client);
+        first.send_unreliable(ping.clone()).unwrap();
+        poll_until(&mut client, &mut [&mut first], |_, received| {
+            !received.is_empty()
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let beacon = DiscoveryMessage::Beacon {
+            name: "second".into(),
+        };
+        socket.send_to(&beacon.encode(), discovery_addr).unwrap();
+        let mut discovered = vec![];
+        for _ in 0..100 {
+            discovered.extend(client.poll(Instant::now()).into_iter().filter_map(
+                |event| match event {
+                    ClientEvent::Discovered(lunabot) => Some(lunabot),
+                    _ => None,
+                },
+            ));
+            if !discovered.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(discovered.len(), 1);
+        assert_eq!((discovered[0].addr, &*discovered[0].name), (addr, "second"));
+        assert_eq!(client.discovered(), discovered);
+
+        // The claim comes from the address to connect to
+        client.claim(addr);
+        client.poll(Instant::now());
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut buf = [0; 64];
+        let (n, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            DiscoveryMessage::decode(&buf[..n]),
+            Some(DiscoveryMessage::Claim)
+        );
+        assert_eq!(from, client.local_addr().unwrap());
+
+        socket.connect(from).unwrap();
+        let mut second = PolledPeer::new(
+            socket,
+            Remote::Connected,
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+        )
+        .unwrap();
+        second.send_unreliable(ping).unwrap();
+        poll_until(&mut client, &mut [&mut second], |_, received| {
+            !received.is_empty()
+        });
+        // Claimed lunabots are controlled as soon as they connect
+        assert_eq!(client.controlled(), Some(addr));
+    }
+}
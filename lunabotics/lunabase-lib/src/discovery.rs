@@ -0,0 +1,89 @@
+//! Listens for the beacons of lunabots that are looking for a lunabase. See [`common::discovery`] for the protocol.
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use common::discovery::{DiscoveryMessage, BEACON_INTERVAL_MS};
+
+/// Lunabots that have not broadcast for this many beacon intervals are forgotten.
+const FORGET_AFTER_BEACONS: u32 = 5;
+
+/// A lunabot that is broadcasting beacons.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredLunabot {
+    /// The address that the lunabot broadcasts from, which is also the address that it will connect from.
+    pub addr: SocketAddr,
+    pub name: String,
+    last_seen: Instant,
+}
+
+pub(crate) struct DiscoveryListener {
+    socket: UdpSocket,
+    discovered: Vec<DiscoveredLunabot>,
+    buf: Box<[u8]>,
+}
+
+impl DiscoveryListener {
+    /// Wraps the given socket, which is made non-blocking.
+    pub(crate) fn new(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            discovered: vec![],
+            buf: vec![0; 512].into_boxed_slice(),
+        })
+    }
+
+    /// Receives every beacon that arrived, and forgets lunabots that stopped broadcasting.
+    ///
+    /// Returns the lunabots that were not known before, along with the error that stopped receiving, if any.
+    /// Lunabots that were received before the error are still returned.
+    pub(crate) fn poll(&mut self, now: Instant) -> (Vec<DiscoveredLunabot>, Option<io::Error>) {
+        let mut new = vec![];
+        let mut error = None;
+        loop {
+            let (n, addr) = match self.socket.recv_from(&mut self.buf) {
+                Ok(x) => x,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Windows reports that an earlier datagram could not be delivered when receiving, which
+                // does not stop later beacons from arriving
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            let Some(DiscoveryMessage::Beacon { name }) = DiscoveryMessage::decode(&self.buf[..n])
+            else {
+                continue;
+            };
+            if let Some(lunabot) = self.discovered.iter_mut().find(|x| x.addr == addr) {
+                lunabot.name = name;
+                lunabot.last_seen = now;
+            } else {
+                let lunabot = DiscoveredLunabot {
+                    addr,
+                    name,
+                    last_seen: now,
+                };
+                new.push(lunabot.clone());
+                self.discovered.push(lunabot);
+            }
+        }
+        let forget_after = Duration::from_millis(BEACON_INTERVAL_MS) * FORGET_AFTER_BEACONS;
+        self.discovered
+            .retain(|lunabot| now.duration_since(lunabot.last_seen) < forget_after);
+        (new, error)
+    }
+
+    pub(crate) fn discovered(&self) -> &[DiscoveredLunabot] {
+        &self.discovered
+    }
+
+    #[cfg(test)]
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
@@ -0,0 +1,208 @@
+//! Tracks the health of the link to a lunabot from the pings that it sends, and the pings that it answers.
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use common::PING_INTERVAL_MS;
+
+/// How many of the latest pings to the lunabot that packet loss is measured over.
+const LOSS_WINDOW: usize = 20;
+/// The link is degraded if more than this fraction of the pings in the loss window were not answered.
+const DEGRADED_LOSS: f64 = 0.25;
+/// Loss is not judged until this many pings were answered or lost.
+const MIN_LOSS_SAMPLES: usize = 4;
+/// The link is degraded if the lunabot has not pinged for this many ping intervals.
+const DEGRADED_MISSED_PINGS: f64 = 1.5;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    Healthy,
+    /// The lunabot missed a ping, or too many of its pongs were lost.
+    Degraded,
+    /// The lunabot has not pinged for longer than it waits for pongs, so it considers itself disconnected.
+    Lost,
+}
+
+#[derive(Debug)]
+pub struct LinkHealth {
+    next_ping_id: u32,
+    last_ping_sent: Option<Instant>,
+    /// Pings to the lunabot that have not been answered, oldest first.
+    in_flight: VecDeque<(u32, Instant)>,
+    /// Whether each of the latest pings to the lunabot was answered, newest last.
+    answered: VecDeque<bool>,
+    smoothed_rtt: Option<Duration>,
+    /// When the lunabot last pinged, or when it connected.
+    last_pinged: Instant,
+    state: LinkState,
+}
+
+impl LinkHealth {
+    pub(crate) fn new(now: Instant) -> Self {
+        Self {
+            next_ping_id: 0,
+            last_ping_sent: None,
+            in_flight: VecDeque::new(),
+            answered: VecDeque::new(),
+            smoothed_rtt: None,
+            last_pinged: now,
+            state: LinkState::Healthy,
+        }
+    }
+
+    /// Returns the id of the ping to send to the lunabot, if one is due.
+    pub(crate) fn ping_due(&mut self, now: Instant) -> Option<u32> {
+        if self.last_ping_sent.is_some_and(|last_ping_sent| {
+            now.duration_since(last_ping_sent) < Duration::from_millis(PING_INTERVAL_MS)
+        }) {
+            return None;
+        }
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.last_ping_sent = Some(now);
+        self.in_flight.push_back((id, now));
+        Some(id)
+    }
+
+    pub(crate) fn ponged(&mut self, id: u32, now: Instant) {
+        let Some(i) = self.in_flight.iter().position(|&(x, _)| x == id) else {
+            // Duplicated, or too late
+            return;
+        };
+        let (_, sent) = self.in_flight.remove(i).unwrap();
+        let rtt = now.duration_since(sent);
+        self.smoothed_rtt = Some(match self.smoothed_rtt {
+            Some(smoothed_rtt) => (smoothed_rtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        self.push_answered(true);
+    }
+
+    pub(crate) fn pinged(&mut self, now: Instant) {
+        self.last_pinged = now;
+    }
+
+    fn push_answered(&mut self, answered: bool) {
+        if self.answered.len() == LOSS_WINDOW {
+            self.answered.pop_front();
+        }
+        self.answered.push_back(answered);
+    }
+
+    /// Counts pings that were not answered within `max_pong_delay` as lost, and returns the new state of the
+    /// link if it changed.
+    pub(crate) fn update(&mut self, now: Instant, max_pong_delay: Duration) -> Option<LinkState> {
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|&(_, sent)| now.duration_since(sent) > max_pong_delay)
+        {
+            self.in_flight.pop_front();
+            self.push_answered(false);
+        }
+        let since_pinged = now.duration_since(self.last_pinged);
+        let state = if since_pinged > max_pong_delay {
+            LinkState::Lost
+        } else if since_pinged
+            > Duration::from_millis(PING_INTERVAL_MS).mul_f64(DEGRADED_MISSED_PINGS)
+            || (self.answered.len() >= MIN_LOSS_SAMPLES && self.loss() > DEGRADED_LOSS)
+        {
+            LinkState::Degraded
+        } else {
+            LinkState::Healthy
+        };
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    /// Returns the smoothed round-trip time of pings to the lunabot, once one has been answered.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.smoothed_rtt
+    }
+
+    /// Returns the fraction of the latest pings to the lunabot that were not answered in time.
+    pub fn loss(&self) -> f64 {
+        if self.answered.is_empty() {
+            return 0.0;
+        }
+        let lost = self.answered.iter().filter(|&&answered| !answered).count();
+        lost as f64 / self.answered.len() as f64
+    }
+
+    /// Returns how long ago the lunabot last pinged.
+    pub fn since_pinged(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_pinged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_PONG_DELAY: Duration = Duration::from_millis(1500);
+
+    #[test]
+    fn rtt() {
+        let start = Instant::now();
+        let mut health = LinkHealth::new(start);
+        assert_eq!(health.ping_due(start), Some(0));
+        assert_eq!(health.ping_due(start + Duration::from_millis(100)), None);
+        health.ponged(0, start + Duration::from_millis(40));
+        // Duplicates are ignored
+        health.ponged(0, start + Duration::from_millis(90));
+        assert_eq!(health.rtt(), Some(Duration::from_millis(40)));
+
+        let next = start + Duration::from_millis(PING_INTERVAL_MS);
+        assert_eq!(health.ping_due(next), Some(1));
+        health.ponged(1, next + Duration::from_millis(120));
+        assert_eq!(health.rtt(), Some(Duration::from_millis(50)));
+        assert_eq!(health.loss(), 0.0);
+    }
+
+    #[test]
+    fn states() {
+        let start = Instant::now();
+        let mut health = LinkHealth::new(start);
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(health.update(at(500), MAX_PONG_DELAY), None);
+        // A ping was missed
+        assert_eq!(
+            health.update(at(1300), MAX_PONG_DELAY),
+            Some(LinkState::Degraded)
+        );
+        assert_eq!(
+            health.update(at(1600), MAX_PONG_DELAY),
+            Some(LinkState::Lost)
+        );
+        health.pinged(at(1700));
+        assert_eq!(
+            health.update(at(1700), MAX_PONG_DELAY),
+            Some(LinkState::Healthy)
+        );
+
+        // Every other ping to the lunabot is lost
+        for i in 0..6 {
+            let now = at(1700 + i * PING_INTERVAL_MS);
+            health.pinged(now);
+            let id = health.ping_due(now).unwrap();
+            if i % 2 == 0 {
+                health.ponged(id, now + Duration::from_millis(10));
+            }
+            health.update(now, MAX_PONG_DELAY);
+        }
+        let now = at(1700 + 6 * PING_INTERVAL_MS);
+        health.pinged(now);
+        health.update(now, MAX_PONG_DELAY);
+        // The last ping may still be answered
+        assert_eq!(health.loss(), 0.4);
+        assert_eq!(health.state(), LinkState::Degraded);
+    }
+}
@@ -0,0 +1,457 @@
+//! Maps gamepad input to steering and commands for the lunabot, according to a profile.
+//!
+//! This module does not depend on Godot, so that it can be tested without the engine. Buttons and axes are
+//! numbered the same way as Godot's `JoyButton` and `JoyAxis`.
+use common::{FromLunabase, Steering};
+use serde::Deserialize;
+
+/// A gamepad button, numbered like Godot's `JoyButton`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Button {
+    A,
+    B,
+    X,
+    Y,
+    Back,
+    Guide,
+    Start,
+    LeftStick,
+    RightStick,
+    LeftShoulder,
+    RightShoulder,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+}
+
+impl Button {
+    const ALL: [Self; 15] = [
+        Self::A,
+        Self::B,
+        Self::X,
+        Self::Y,
+        Self::Back,
+        Self::Guide,
+        Self::Start,
+        Self::LeftStick,
+        Self::RightStick,
+        Self::LeftShoulder,
+        Self::RightShoulder,
+        Self::DpadUp,
+        Self::DpadDown,
+        Self::DpadLeft,
+        Self::DpadRight,
+    ];
+
+    pub fn from_index(index: i64) -> Option<Self> {
+        Self::ALL.get(usize::try_from(index).ok()?).copied()
+    }
+}
+
+/// A gamepad axis, numbered like Godot's `JoyAxis`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    TriggerLeft,
+    TriggerRight,
+}
+
+impl Axis {
+    const ALL: [Self; 6] = [
+        Self::LeftX,
+        Self::LeftY,
+        Self::RightX,
+        Self::RightY,
+        Self::TriggerLeft,
+        Self::TriggerRight,
+    ];
+
+    pub fn from_index(index: i64) -> Option<Self> {
+        Self::ALL.get(usize::try_from(index).ok()?).copied()
+    }
+
+    /// Vertical axes are negative when pushed up, so they are flipped to make forward positive.
+    fn sign(self) -> f64 {
+        match self {
+            Self::LeftY | Self::RightY => -1.0,
+            _ => 1.0,
+        }
+    }
+}
+
+/// How the position of a stick past the deadzone maps to speed.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    Linear,
+    /// Blends between linear and cubic, where 0 is linear and 1 is cubic, for finer control near the center.
+    Expo(f64),
+    /// Raises the position to the given power, keeping its sign.
+    Power(f64),
+}
+
+impl Curve {
+    fn apply(self, x: f64) -> f64 {
+        match self {
+            Self::Linear => x,
+            Self::Expo(expo) => {
+                let expo = expo.clamp(0.0, 1.0);
+                (1.0 - expo) * x + expo * x.powi(3)
+            }
+            Self::Power(power) => x.signum() * x.abs().powf(power),
+        }
+    }
+}
+
+/// Which sticks drive the lunabot.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "mode")]
+pub enum DriveMode {
+    /// One axis drives forwards and backwards, and another turns.
+    Arcade { drive: Axis, steering: Axis },
+    /// Each axis drives the wheels on one side.
+    Tank { left: Axis, right: Axis },
+}
+
+/// Buttons that send commands, or change the speed while held.
+///
+/// Buttons that are not bound in a profile keep their default bindings.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bindings {
+    pub soft_stop: Option<Button>,
+    pub continue_mission: Option<Button>,
+    pub traverse_obstacles: Option<Button>,
+    /// Drives at `turbo_speed` while held.
+    pub turbo: Option<Button>,
+    /// Drives at `precision_speed` while held, which takes precedence over turbo.
+    pub precision: Option<Button>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            soft_stop: Some(Button::B),
+            continue_mission: Some(Button::Start),
+            traverse_obstacles: Some(Button::Y),
+            turbo: Some(Button::RightShoulder),
+            precision: Some(Button::LeftShoulder),
+        }
+    }
+}
+
+/// How gamepad input is mapped, usually loaded from a TOML file.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputProfile {
+    /// Positions of an axis closer to the center than this are treated as the center.
+    pub deadzone: f64,
+    pub curve: Curve,
+    pub drive: DriveMode,
+    /// The fastest the lunabot is driven normally, from 0 to 1.
+    pub max_speed: f64,
+    pub turbo_speed: f64,
+    pub precision_speed: f64,
+    pub bindings: Bindings,
+}
+
+impl Default for InputProfile {
+    fn default() -> Self {
+        Self {
+            deadzone: 0.1,
+            curve: Curve::Expo(0.3),
+            drive: DriveMode::Arcade {
+                drive: Axis::LeftY,
+                steering: Axis::RightX,
+            },
+            max_speed: 0.6,
+            turbo_speed: 1.0,
+            precision_speed: 0.25,
+            bindings: Bindings::default(),
+        }
+    }
+}
+
+impl InputProfile {
+    /// Parses a profile, returning an error if it is malformed or any of its values are out of range.
+    pub fn from_toml(toml: &str) -> Result<Self, toml::de::Error> {
+        let profile: Self = toml::from_str(toml)?;
+        profile.validate().map_err(serde::de::Error::custom)?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..1.0).contains(&self.deadzone) {
+            return Err(format!(
+                "deadzone must be at least 0 and less than 1, but is {}",
+                self.deadzone
+            ));
+        }
+        for (name, speed) in [
+            ("max_speed", self.max_speed),
+            ("turbo_speed", self.turbo_speed),
+            ("precision_speed", self.precision_speed),
+        ] {
+            if !(0.0..=1.0).contains(&speed) {
+                return Err(format!("{name} must be from 0 to 1, but is {speed}"));
+            }
+        }
+        match self.curve {
+            Curve::Linear => {}
+            Curve::Expo(expo) if !expo.is_finite() => {
+                return Err(format!("expo must be finite, but is {expo}"));
+            }
+            Curve::Expo(_) => {}
+            // A power of 0 or less would push small positions past the speed caps
+            Curve::Power(power) if !(power.is_finite() && power > 0.0) => {
+                return Err(format!(
+                    "power must be finite and more than 0, but is {power}"
+                ));
+            }
+            Curve::Power(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Removes the deadzone and applies the curve to the raw position of an axis.
+    fn shape(&self, raw: f64) -> f64 {
+        let magnitude = raw.abs().min(1.0);
+        if magnitude <= self.deadzone {
+            return 0.0;
+        }
+        let magnitude = (magnitude - self.deadzone) / (1.0 - self.deadzone);
+        self.curve.apply(raw.signum() * magnitude)
+    }
+}
+
+/// Tracks the state of a gamepad, and turns it into steering and commands.
+#[derive(Debug, Default)]
+pub struct InputMapper {
+    profile: InputProfile,
+    axes: [f64; Axis::ALL.len()],
+    held: Vec<Button>,
+}
+
+impl InputMapper {
+    pub fn set_profile(&mut self, profile: InputProfile) {
+        self.profile = profile;
+    }
+
+    pub fn profile(&self) -> &InputProfile {
+        &self.profile
+    }
+
+    pub fn set_axis(&mut self, axis: Axis, value: f64) {
+        self.axes[axis as usize] = value;
+    }
+
+    /// Records that `button` was pressed or released, returning the command bound to it if it was just pressed.
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> Option<FromLunabase> {
+        let was_held = self.held.contains(&button);
+        if !pressed {
+            self.held.retain(|&held| held != button);
+            return None;
+        }
+        if was_held {
+            return None;
+        }
+        self.held.push(button);
+
+        let bindings = &self.profile.bindings;
+        if bindings.soft_stop == Some(button) {
+            Some(FromLunabase::SoftStop)
+        } else if bindings.continue_mission == Some(button) {
+            Some(FromLunabase::ContinueMission)
+        } else if bindings.traverse_obstacles == Some(button) {
+            Some(FromLunabase::TraverseObstacles)
+        } else {
+            None
+        }
+    }
+
+    fn is_held(&self, button: Option<Button>) -> bool {
+        button.is_some_and(|button| self.held.contains(&button))
+    }
+
+    /// Returns the speed limit given the modifier buttons that are held.
+    fn speed(&self) -> f64 {
+        let bindings = &self.profile.bindings;
+        if self.is_held(bindings.precision) {
+            self.profile.precision_speed
+        } else if self.is_held(bindings.turbo) {
+            self.profile.turbo_speed
+        } else {
+            self.profile.max_speed
+        }
+    }
+
+    fn axis(&self, axis: Axis) -> f64 {
+        self.profile.shape(self.axes[axis as usize] * axis.sign())
+    }
+
+    /// Returns the steering for the current state of the gamepad.
+    pub fn steering(&self) -> Steering {
+        let speed = self.speed();
+        match self.profile.drive {
+            DriveMode::Arcade { drive, steering } => {
+                Steering::new(self.axis(drive) * speed, self.axis(steering))
+            }
+            DriveMode::Tank { left, right } => {
+                Steering::new_left_right(self.axis(left) * speed, self.axis(right) * speed)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn deadzone_and_curve() {
+        let profile = InputProfile {
+            deadzone: 0.2,
+            curve: Curve::Linear,
+            ..Default::default()
+        };
+        assert_eq!(profile.shape(0.15), 0.0);
+        assert_eq!(profile.shape(-0.2), 0.0);
+        assert_close(profile.shape(0.6), 0.5);
+        assert_close(profile.shape(-1.0), -1.0);
+        assert_close(profile.shape(1.5), 1.0);
+
+        let profile = InputProfile {
+            deadzone: 0.0,
+            curve: Curve::Expo(1.0),
+            ..Default::default()
+        };
+        assert_close(profile.shape(0.5), 0.125);
+        assert_close(profile.shape(-1.0), -1.0);
+        let profile = InputProfile {
+            deadzone: 0.0,
+            curve: Curve::Power(2.0),
+            ..Default::default()
+        };
+        assert_close(profile.shape(-0.5), -0.25);
+    }
+
+    #[test]
+    fn speed_modes() {
+        let mut mapper = InputMapper::default();
+        mapper.set_profile(InputProfile {
+            deadzone: 0.0,
+            curve: Curve::Linear,
+            ..Default::default()
+        });
+        // Up is negative on the left stick
+        mapper.set_axis(Axis::LeftY, -1.0);
+        assert_eq!(mapper.steering(), Steering::new(0.6, 0.0));
+
+        mapper.set_button(Button::RightShoulder, true);
+        assert_eq!(mapper.steering(), Steering::new(1.0, 0.0));
+        // Precision wins over turbo
+        mapper.set_button(Button::LeftShoulder, true);
+        assert_eq!(mapper.steering(), Steering::new(0.25, 0.0));
+        mapper.set_button(Button::LeftShoulder, false);
+        mapper.set_button(Button::RightShoulder, false);
+        assert_eq!(mapper.steering(), Steering::new(0.6, 0.0));
+    }
+
+    #[test]
+    fn tank() {
+        let mut mapper = InputMapper::default();
+        mapper.set_profile(InputProfile {
+            deadzone: 0.0,
+            curve: Curve::Linear,
+            drive: DriveMode::Tank {
+                left: Axis::LeftY,
+                right: Axis::RightY,
+            },
+            max_speed: 1.0,
+            ..Default::default()
+        });
+        mapper.set_axis(Axis::LeftY, -1.0);
+        mapper.set_axis(Axis::RightY, 1.0);
+        assert_eq!(mapper.steering(), Steering::new_left_right(1.0, -1.0));
+    }
+
+    #[test]
+    fn commands_on_press() {
+        let mut mapper = InputMapper::default();
+        assert_eq!(
+            mapper.set_button(Button::B, true),
+            Some(FromLunabase::SoftStop)
+        );
+        // Holding the button does not repeat the command
+        assert_eq!(mapper.set_button(Button::B, true), None);
+        assert_eq!(mapper.set_button(Button::B, false), None);
+        assert_eq!(
+            mapper.set_button(Button::B, true),
+            Some(FromLunabase::SoftStop)
+        );
+        assert_eq!(mapper.set_button(Button::A, true), None);
+        assert_eq!(Button::from_index(6), Some(Button::Start));
+        assert_eq!(Button::from_index(15), None);
+        assert_eq!(Button::from_index(-1), None);
+        assert_eq!(Axis::from_index(3), Some(Axis::RightY));
+    }
+
+    #[test]
+    fn profile_from_toml() {
+        let profile = InputProfile::from_toml(
+            r#"
+            deadzone = 0.05
+            curve = { power = 2.0 }
+            max_speed = 0.5
+            drive = { mode = "tank", left = "left_y", right = "right_y" }
+
+            [bindings]
+            soft_stop = "a"
+            turbo = "right_shoulder"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(profile.deadzone, 0.05);
+        assert_eq!(profile.curve, Curve::Power(2.0));
+        assert_eq!(
+            profile.drive,
+            DriveMode::Tank {
+                left: Axis::LeftY,
+                right: Axis::RightY
+            }
+        );
+        assert_eq!(profile.bindings.soft_stop, Some(Button::A));
+        // Missing fields keep their defaults, even within bindings
+        assert_eq!(profile.bindings.continue_mission, Some(Button::Start));
+        assert_eq!(profile.bindings.precision, Some(Button::LeftShoulder));
+        assert_eq!(profile.precision_speed, 0.25);
+
+        assert!(InputProfile::from_toml("deadzone = \"wide\"").is_err());
+        assert!(InputProfile::from_toml("dead_zone = 0.1").is_err());
+    }
+
+    #[test]
+    fn profile_out_of_range() {
+        assert!(InputProfile::from_toml("deadzone = 1.0").is_err());
+        assert!(InputProfile::from_toml("deadzone = -0.1").is_err());
+        assert!(InputProfile::from_toml("deadzone = nan").is_err());
+        assert!(InputProfile::from_toml("max_speed = 1.5").is_err());
+        assert!(InputProfile::from_toml("turbo_speed = -1.0").is_err());
+        assert!(InputProfile::from_toml("precision_speed = 0.0").is_ok());
+        assert!(InputProfile::from_toml("curve = { expo = nan }").is_err());
+        assert!(InputProfile::from_toml("curve = { power = nan }").is_err());
+        assert!(InputProfile::from_toml("curve = { power = 0.0 }").is_err());
+        assert!(InputProfile::from_toml("curve = { power = -1.0 }").is_err());
+        assert!(InputProfile::from_toml("curve = { power = 2.0 }").is_ok());
+    }
+}
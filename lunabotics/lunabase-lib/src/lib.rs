@@ -1,18 +1,26 @@
 #![feature(backtrace_frames)]
 use std::{
-    collections::VecDeque,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
     sync::Once,
     time::{Duration, Instant},
 };
 
-use bitcode::encode;
 use cakap2::{
-    packet::{Action, ReliableIndex},
-    Event, PeerStateMachine, RecommendedAction,
+    auth::{PreSharedKey, Protection},
+    udp::DriverError,
 };
-use common::{FromLunabase, FromLunabot, LunabotStage, Steering};
+use client::{ClientError, ClientEvent, LunabaseClient};
+use common::{discovery::DISCOVERY_PORT, FromLunabase, FromLunabot, LunabotStage, Steering};
 use godot::{classes::Engine, prelude::*};
+use health::LinkState;
+use input::{Axis, Button, InputProfile};
+use recording::{Entry, SessionPlayer, SessionRecorder};
+
+pub mod client;
+pub mod discovery;
+pub mod health;
+pub mod input;
+pub mod recording;
 
 struct LunabaseLib;
 
@@ -60,227 +68,215 @@ pub fn init_panic_hook() {
     });
 }
 
-struct LunabotConnInner {
-    cakap_sm: PeerStateMachine,
-    udp: UdpSocket,
-    to_lunabot: VecDeque<Action>,
-    bitcode_buffer: bitcode::Buffer,
-    did_reconnection: bool,
-    last_steering: Option<(Steering, ReliableIndex)>,
-    send_to: Option<SocketAddr>,
-}
-
 #[derive(GodotClass)]
 #[class(base=Node)]
 struct LunabotConn {
-    inner: Option<LunabotConnInner>,
+    client: Option<LunabaseClient>,
+    /// The address that lunabots connect to, which is bound when the node is ready. Set it in `_init` to use
+    /// another port or interface.
+    #[var]
+    bind_address: GString,
+    /// How long the lunabots wait for a pong before they consider themselves disconnected, which should match
+    /// `max_pong_delay_ms` in their configuration.
+    #[var]
+    max_pong_delay_ms: i64,
+    /// Kept so that it can be applied when the client is created by `listen`.
+    input_profile: InputProfile,
+    /// Set by `set_pre_shared_key`, and likewise applied by `listen`.
+    pre_shared_key: Option<(PreSharedKey, Protection)>,
+    playback: Option<Playback>,
     base: Base<Node>,
 }
 
-thread_local! {
-    static PONG_MESSAGE: Box<[u8]> = {
-        encode(&FromLunabase::Pong).into()
-    };
+/// A recorded session that is being played back.
+struct Playback {
+    player: SessionPlayer,
+    /// Whether recorded messages to the lunabot are sent to the controlled lunabot.
+    send_commands: bool,
 }
 
 #[godot_api]
 impl INode for LunabotConn {
     fn init(base: Base<Node>) -> Self {
+        Self {
+            client: None,
+            bind_address: GString::from("0.0.0.0:10600"),
+            max_pong_delay_ms: 1500,
+            input_profile: InputProfile::default(),
+            pre_shared_key: None,
+            playback: None,
+            base,
+        }
+    }
+
+    fn ready(&mut self) {
         if Engine::singleton().is_editor_hint() {
-            return Self { inner: None, base };
+            return;
         }
         init_panic_hook();
-
-        let udp = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 10600))
-            .expect("Failed to bind to 10600");
-
-        udp.set_nonblocking(true)
-            .expect("Failed to set non-blocking");
-
-        let cakap_sm = PeerStateMachine::new(Duration::from_millis(150), 1024);
+        let bind_address = self.bind_address.clone();
+        self.listen(bind_address);
         // godot_warn!("LunabotConn initialized");
-
-        Self {
-            inner: Some(LunabotConnInner {
-                udp,
-                cakap_sm,
-                to_lunabot: VecDeque::new(),
-                bitcode_buffer: bitcode::Buffer::new(),
-                did_reconnection: false,
-                last_steering: None,
-                send_to: None,
-            }),
-            base,
-        }
     }
 
     fn process(&mut self, _delta: f64) {
-        if let Some(mut inner) = self.inner.as_mut() {
-            let mut received = false;
-
-            macro_rules! on_msg {
-                ($msg: ident) => {{
-                    received = true;
-                    match $msg {
-                        FromLunabot::Ping(stage) => {
-                            match stage {
-                                LunabotStage::TeleOp => {
-                                    self.base_mut().emit_signal("entered_manual", &[])
-                                }
-                                LunabotStage::SoftStop => {
-                                    self.base_mut().emit_signal("entered_soft_stop", &[])
-                                }
-                                LunabotStage::TraverseObstacles => self
-                                    .base_mut()
-                                    .emit_signal("entered_traverse_obstacles", &[]),
-                                LunabotStage::Dig => {
-                                    self.base_mut().emit_signal("entered_dig", &[])
-                                }
-                                LunabotStage::Dump => {
-                                    self.base_mut().emit_signal("entered_dump", &[])
-                                }
-                            };
-                            inner = self.inner.as_mut().unwrap();
-
-                            PONG_MESSAGE.with(|pong| {
-                                inner.to_lunabot.push_back(Action::SendUnreliable(
-                                    inner
-                                        .cakap_sm
-                                        .get_packet_builder()
-                                        .new_unreliable(pong.to_vec().into())
-                                        .unwrap(),
-                                ));
-                            });
-                        }
-                    }
-                }};
+        let now = Instant::now();
+        let mut something_received = false;
+        let max_pong_delay = Duration::from_millis(self.max_pong_delay_ms.max(0) as u64);
+        let events = match &mut self.client {
+            Some(client) => {
+                client.set_max_pong_delay(max_pong_delay);
+                client.poll(now)
             }
-
-            macro_rules! handle {
-                ($action: ident) => {
-                    match $action {
-                        RecommendedAction::HandleError(cakap_error) => {
-                            godot_error!("{cakap_error}")
-                        }
-                        RecommendedAction::HandleData(received) => {
-                            match inner.bitcode_buffer.decode::<FromLunabot>(received) {
-                                Ok(x) => {
-                                    on_msg!(x);
-                                }
-                                Err(e) => {
-                                    godot_error!("Failed to decode message: {e}")
-                                }
-                            }
-                        }
-                        RecommendedAction::HandleDataAndSend { received, to_send } => {
-                            match inner.bitcode_buffer.decode::<FromLunabot>(received) {
-                                Ok(x) => {
-                                    if let Some(addr) = inner.send_to {
-                                        if let Err(e) = inner.udp.send_to(&to_send, addr) {
-                                            godot_error!("Failed to send ack: {e}");
-                                        }
-                                        on_msg!(x);
-                                    }
-                                }
-                                Err(e) => {
-                                    godot_error!("Failed to decode message: {e}")
-                                }
-                            }
-                        }
-                        RecommendedAction::SendData(hot_packet) => {
-                            if let Some(addr) = inner.send_to {
-                                if let Err(e) = inner.udp.send_to(&hot_packet, addr) {
-                                    godot_error!("Failed to send hot packet: {e}");
-                                }
-                            }
-                        }
-                        RecommendedAction::WaitForData | RecommendedAction::WaitForDuration(_) => {}
-                    }
-                };
+            None => vec![],
+        };
+        for event in events {
+            match event {
+                ClientEvent::Connected(addr) => {
+                    self.base_mut().emit_signal(
+                        "lunabot_connected",
+                        &[GString::from(addr.to_string()).to_variant()],
+                    );
+                }
+                ClientEvent::Disconnected(addr) => {
+                    self.base_mut().emit_signal(
+                        "lunabot_disconnected",
+                        &[GString::from(addr.to_string()).to_variant()],
+                    );
+                }
+                ClientEvent::Discovered(lunabot) => {
+                    self.base_mut().emit_signal(
+                        "lunabot_discovered",
+                        &[
+                            GString::from(lunabot.addr.to_string()).to_variant(),
+                            GString::from(lunabot.name).to_variant(),
+                        ],
+                    );
+                }
+                ClientEvent::Received {
+                    msg, controlled, ..
+                } => {
+                    something_received = true;
+                    self.handle_received(msg, controlled);
+                }
+                ClientEvent::LinkStateChanged {
+                    state,
+                    controlled: true,
+                    ..
+                } => {
+                    let signal = match state {
+                        LinkState::Healthy => "connection_restored",
+                        LinkState::Degraded => "connection_degraded",
+                        LinkState::Lost => "connection_lost",
+                    };
+                    self.base_mut().emit_signal(signal, &[]);
+                }
+                ClientEvent::LinkStateChanged { .. } => {}
+                ClientEvent::Error(e) => self.report(e),
             }
+        }
+        something_received |= self.play(now);
+        if something_received {
+            self.base_mut().emit_signal("something_received", &[]);
+        }
+    }
+}
 
-            let now = Instant::now();
-
-            while let Some(to_lunabot) = inner.to_lunabot.pop_front() {
-                let action = inner.cakap_sm.poll(Event::Action(to_lunabot), now);
-                handle!(action);
+impl LunabotConn {
+    /// Emits the signals for a message from a lunabot.
+    fn handle_received(&mut self, msg: FromLunabot, controlled: bool) {
+        if !controlled {
+            return;
+        }
+        match msg {
+            FromLunabot::Ping(stage) => {
+                let signal = match stage {
+                    LunabotStage::TeleOp => "entered_manual",
+                    LunabotStage::SoftStop => "entered_soft_stop",
+                    LunabotStage::TraverseObstacles => "entered_traverse_obstacles",
+                    LunabotStage::Dig => "entered_dig",
+                    LunabotStage::Dump => "entered_dump",
+                };
+                self.base_mut().emit_signal(signal, &[]);
             }
+            FromLunabot::Pong(_) => {}
+        }
+    }
 
-            let mut buf = [0u8; 1408];
-            loop {
-                match inner.udp.recv_from(&mut buf) {
-                    Ok((n, addr)) => {
-                        // godot_warn!("{:?}", &buf[..n]);
-                        inner.send_to = Some(addr);
-                        if !inner.did_reconnection {
-                            let tmp_action = inner.cakap_sm.send_reconnection_msg(now).0;
-                            handle!(tmp_action);
-                            inner.did_reconnection = true;
-                        }
-                        let action = inner.cakap_sm.poll(Event::IncomingData(&buf[..n]), now);
-                        handle!(action);
-                    }
-                    Err(e) => match e.kind() {
-                        std::io::ErrorKind::WouldBlock => break,
-                        _ => godot_error!("Failed to receive data: {e}"),
-                    },
+    /// Plays back the records that are due, returning `true` if any of them were received from a lunabot.
+    fn play(&mut self, now: Instant) -> bool {
+        let Some(playback) = &mut self.playback else {
+            return false;
+        };
+        let records = playback.player.poll(now).to_vec();
+        let send_commands = playback.send_commands;
+        if playback.player.is_finished() {
+            self.playback = None;
+        }
+        let mut something_received = false;
+        for record in records {
+            match record.entry {
+                Entry::Received {
+                    msg, controlled, ..
+                } => {
+                    something_received = true;
+                    self.handle_received(msg, controlled);
                 }
-            }
-
-            loop {
-                let action = inner.cakap_sm.poll(Event::NoEvent, now);
-                match action {
-                    RecommendedAction::HandleError(cakap_error) => godot_error!("{cakap_error}"),
-                    RecommendedAction::SendData(hot_packet) => {
-                        if let Some(addr) = inner.send_to {
-                            if let Err(e) = inner.udp.send_to(&hot_packet, addr) {
-                                godot_error!("Failed to send hot packet: {e}");
-                            }
-                        }
+                Entry::Sent { msg, .. } => {
+                    if !send_commands {
+                        continue;
+                    }
+                    let Some(client) = &mut self.client else {
+                        continue;
+                    };
+                    if let Err(e) = client.resend(&msg) {
+                        self.report(e);
                     }
-                    RecommendedAction::WaitForData | RecommendedAction::WaitForDuration(_) => break,
-                    _ => unreachable!(),
                 }
             }
+        }
+        if self.playback.is_none() {
+            self.base_mut().emit_signal("playback_finished", &[]);
+        }
+        something_received
+    }
 
-            if received {
-                self.base_mut()
-                    .emit_signal("something_received", &[]);
+    /// Logs an error from the client, and emits `connection_error` if it is not just a warning.
+    fn report(&mut self, e: ClientError) {
+        match e {
+            ClientError::NotConnected => godot_warn!("{e}"),
+            ClientError::Peer {
+                error: DriverError::Rejected(_),
+                ..
+            } => godot_warn!("{e}"),
+            e => {
+                godot_error!("{e}");
+                self.base_mut().emit_signal(
+                    "connection_error",
+                    &[GString::from(e.to_string()).to_variant()],
+                );
             }
         }
     }
-}
 
-impl LunabotConn {
+    /// Sends `msg` reliably to the controlled lunabot, such that it handles it after everything sent before it.
     fn send_reliable(&mut self, msg: &FromLunabase) {
-        if let Some(inner) = &mut self.inner {
-            match inner
-                .cakap_sm
-                .get_packet_builder()
-                .new_reliable(encode(msg).into())
-            {
-                Ok(packet) => {
-                    inner.to_lunabot.push_back(Action::SendReliable(packet));
-                }
-                Err(e) => {
-                    godot_error!("Failed to build reliable packet: {e}");
-                }
-            }
+        let Some(client) = &mut self.client else {
+            godot_warn!("Not listening for lunabots");
+            return;
+        };
+        if let Err(e) = client.send_command(msg) {
+            self.report(e);
         }
     }
 
-    // fn send_unreliable(&mut self, msg: &FromLunabase) {
-    //     if let Some(inner) = &mut self.inner {
-    //         match inner.cakap_sm.get_packet_builder().new_unreliable(encode(msg).into()) {
-    //             Ok(packet) => {
-    //                 inner.to_lunabot.push_back(Action::SendUnreliable(packet));
-    //             }
-    //             Err(e) => {
-    //                 godot_error!("Failed to build reliable packet: {e}");
-    //             }
-    //         }
-    //     }
-    // }
+    fn set_steering(&mut self, new_steering: Steering) {
+        if let Some(client) = &mut self.client {
+            if let Err(e) = client.set_steering(new_steering) {
+                self.report(e);
+            }
+        }
+    }
 }
 
 #[godot_api]
@@ -288,6 +284,27 @@ impl LunabotConn {
     #[signal]
     fn something_received(&self);
     #[signal]
+    fn lunabot_connected(&self, addr: GString);
+    #[signal]
+    fn lunabot_disconnected(&self, addr: GString);
+    #[signal]
+    fn lunabot_discovered(&self, addr: GString, name: GString);
+    /// Emitted when a recorded session has been played back completely.
+    #[signal]
+    fn playback_finished(&self);
+    /// Emitted for errors that the lunabase recovers from, such as failing to bind or to decode a message.
+    #[signal]
+    fn connection_error(&self, message: GString);
+    /// Emitted when the controlled lunabot misses a ping, or too many pings to it are lost.
+    #[signal]
+    fn connection_degraded(&self);
+    /// Emitted when the controlled lunabot has not pinged for `max_pong_delay_ms`, so it has stopped itself.
+    #[signal]
+    fn connection_lost(&self);
+    /// Emitted when the link to the controlled lunabot is healthy again after being degraded or lost.
+    #[signal]
+    fn connection_restored(&self);
+    #[signal]
     fn entered_manual(&self);
     #[signal]
     fn entered_soft_stop(&self);
@@ -298,43 +315,244 @@ impl LunabotConn {
     #[signal]
     fn entered_dump(&self);
 
-    fn set_steering(&mut self, new_steering: Steering) {
-        if let Some(inner) = &mut self.inner {
-            let mut last_steering_reliable_idx = None;
-            if let Some((old_steering, old_idx)) = inner.last_steering {
-                last_steering_reliable_idx = Some(old_idx);
-                if old_steering == new_steering {
-                    return;
-                }
+    #[func]
+    fn set_steering_drive_steering(&mut self, drive: f64, steering: f64) {
+        self.set_steering(Steering::new(drive, steering));
+    }
+
+    #[func]
+    fn set_steering_left_right(&mut self, left: f64, right: f64) {
+        self.set_steering(Steering::new_left_right(left, right));
+    }
+
+    /// Maps gamepad input with the profile in the given TOML instead of the default profile.
+    ///
+    /// Returns `false` if the profile is invalid.
+    #[func]
+    fn set_input_profile(&mut self, toml: GString) -> bool {
+        let profile = match InputProfile::from_toml(&toml.to_string()) {
+            Ok(profile) => profile,
+            Err(e) => {
+                godot_error!("Invalid input profile: {e}");
+                return false;
             }
-            let msg = FromLunabase::Steering(new_steering);
-            match inner
-                .cakap_sm
-                .get_packet_builder()
-                .new_reliable(encode(&msg).into())
-            {
-                Ok(packet) => {
-                    if let Some(old_idx) = last_steering_reliable_idx {
-                        inner.to_lunabot.push_back(Action::CancelReliable(old_idx));
-                    }
-                    inner.last_steering = Some((new_steering, packet.get_index()));
-                    inner.to_lunabot.push_back(Action::SendReliable(packet));
+        };
+        if let Some(client) = &mut self.client {
+            client.set_input_profile(profile.clone());
+        }
+        self.input_profile = profile;
+        true
+    }
+
+    /// Updates the position of a gamepad axis, numbered like `JoyAxis`, and steers accordingly.
+    #[func]
+    fn set_gamepad_axis(&mut self, axis: i64, value: f64) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+        let Some(axis) = Axis::from_index(axis) else {
+            return;
+        };
+        if let Err(e) = client.set_gamepad_axis(axis, value) {
+            self.report(e);
+        }
+    }
+
+    /// Updates whether a gamepad button, numbered like `JoyButton`, is pressed, sending the command bound to it
+    /// when it is pressed.
+    #[func]
+    fn set_gamepad_button(&mut self, button: i64, pressed: bool) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+        let Some(button) = Button::from_index(button) else {
+            return;
+        };
+        if let Err(e) = client.set_gamepad_button(button, pressed) {
+            self.report(e);
+        }
+    }
+
+    /// Listens for lunabots on the given address, such as `0.0.0.0:10600`, instead of the current address.
+    ///
+    /// Lunabots that were connected have to connect again. Returns `false` and emits `connection_error` if the
+    /// address could not be bound, in which case the current address is kept.
+    #[func]
+    fn listen(&mut self, address: GString) -> bool {
+        let result = address
+            .to_string()
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid bind address {address}: {e}"))
+            .and_then(|addr| {
+                UdpSocket::bind(addr).map_err(|e| format!("Failed to bind to {addr}: {e}"))
+            })
+            .and_then(|socket| {
+                match &mut self.client {
+                    Some(client) => client.rebind(socket),
+                    None => LunabaseClient::new(socket).map(|mut client| {
+                        client.set_input_profile(self.input_profile.clone());
+                        client.set_pre_shared_key(self.pre_shared_key.clone());
+                        self.client = Some(client);
+                    }),
                 }
-                Err(e) => {
-                    godot_error!("Failed to build reliable packet: {e}");
+                .map_err(|e| format!("Failed to set non-blocking: {e}"))
+            });
+        match result {
+            Ok(()) => {
+                self.bind_address = address;
+                true
+            }
+            Err(e) => {
+                godot_error!("{e}");
+                // Deferred so that nodes that connect in `_ready` hear about failures while the lunabase starts
+                self.base_mut().call_deferred(
+                    "emit_signal",
+                    &[
+                        "connection_error".to_variant(),
+                        GString::from(e).to_variant(),
+                    ],
+                );
+                false
+            }
+        }
+    }
+
+    /// Returns `true` iff the lunabase is bound to an address, so lunabots can connect.
+    #[func]
+    fn is_listening(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Listens for lunabots that are looking for a lunabase on the given port, or the default discovery port if
+    /// `port` is 0. Each lunabot is reported once with `lunabot_discovered`, and connects once it is claimed with
+    /// `claim_lunabot`.
+    ///
+    /// Returns `false` if the port could not be bound.
+    #[func]
+    fn start_discovery(&mut self, port: i64) -> bool {
+        let Some(client) = &mut self.client else {
+            godot_error!("Not listening for lunabots");
+            return false;
+        };
+        let port = if port == 0 {
+            DISCOVERY_PORT
+        } else {
+            match u16::try_from(port) {
+                Ok(port) => port,
+                Err(_) => {
+                    godot_error!("Invalid port: {port}");
+                    return false;
                 }
             }
+        };
+        let bind = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port);
+        match UdpSocket::bind(bind).and_then(|socket| client.start_discovery(socket)) {
+            Ok(()) => true,
+            Err(e) => {
+                self.report(ClientError::Io(e));
+                false
+            }
         }
     }
 
+    /// Returns the names of the lunabots that are looking for a lunabase, keyed by their addresses.
     #[func]
-    fn set_steering_drive_steering(&mut self, drive: f64, steering: f64) {
-        self.set_steering(Steering::new(drive, steering));
+    fn get_discovered_lunabots(&self) -> Dictionary {
+        let mut dict = Dictionary::new();
+        let Some(client) = &self.client else {
+            return dict;
+        };
+        for lunabot in client.discovered() {
+            dict.set(
+                GString::from(lunabot.addr.to_string()),
+                GString::from(lunabot.name.as_str()),
+            );
+        }
+        dict
     }
 
+    /// Asks the lunabot that was discovered at the given address to connect, and controls it once it does.
+    ///
+    /// Returns `false` if the address is invalid.
     #[func]
-    fn set_steering_left_right(&mut self, left: f64, right: f64) {
-        self.set_steering(Steering::new_left_right(left, right));
+    fn claim_lunabot(&mut self, addr: GString) -> bool {
+        let Some(client) = &mut self.client else {
+            return false;
+        };
+        let Ok(addr) = addr.to_string().parse::<SocketAddr>() else {
+            godot_error!("Invalid address: {addr}");
+            return false;
+        };
+        client.claim(addr);
+        true
+    }
+
+    /// Records every message sent to and received from the lunabots in a new file at the given absolute path,
+    /// such as from `ProjectSettings.globalize_path`.
+    ///
+    /// Returns `false` if the file could not be created.
+    #[func]
+    fn start_recording(&mut self, path: GString) -> bool {
+        let Some(client) = &mut self.client else {
+            godot_error!("Not listening for lunabots");
+            return false;
+        };
+        match SessionRecorder::create(path.to_string(), Instant::now()) {
+            Ok(recorder) => {
+                client.start_recording(recorder);
+                true
+            }
+            Err(e) => {
+                godot_error!("Failed to create {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    fn stop_recording(&mut self) {
+        if let Some(client) = &mut self.client {
+            client.stop_recording();
+        }
+    }
+
+    #[func]
+    fn is_recording(&self) -> bool {
+        self.client
+            .as_ref()
+            .is_some_and(LunabaseClient::is_recording)
+    }
+
+    /// Plays back the session recorded at the given absolute path, emitting the signals for messages from the
+    /// controlled lunabot as they were emitted live. If `send_commands` is `true`, recorded messages to the
+    /// lunabot are also sent to the controlled lunabot, at the same pace.
+    ///
+    /// Returns `false` if the file is not a recording.
+    #[func]
+    fn play_session(&mut self, path: GString, send_commands: bool) -> bool {
+        match SessionPlayer::open(path.to_string()) {
+            Ok(player) => {
+                self.playback = Some(Playback {
+                    player,
+                    send_commands,
+                });
+                true
+            }
+            Err(e) => {
+                godot_error!("Failed to open {path}: {e}");
+                false
+            }
+        }
+    }
+
+    #[func]
+    fn stop_playback(&mut self) {
+        self.playback = None;
+    }
+
+    #[func]
+    fn is_playing(&self) -> bool {
+        self.playback.is_some()
     }
 
     #[func]
@@ -351,4 +569,147 @@ impl LunabotConn {
     fn soft_stop(&mut self) {
         self.send_reliable(&FromLunabase::SoftStop);
     }
+
+    /// Returns the addresses of every connected lunabot.
+    #[func]
+    fn get_lunabots(&self) -> PackedStringArray {
+        let Some(client) = &self.client else {
+            return PackedStringArray::new();
+        };
+        client
+            .lunabots()
+            .map(|addr| GString::from(addr.to_string()))
+            .collect()
+    }
+
+    /// Sends commands to the lunabot with the given address from now on, instead of the lunabot that connected
+    /// first. Only the controlled lunabot emits stage signals.
+    ///
+    /// Returns `false` if no lunabot is connected from that address.
+    #[func]
+    fn control_lunabot(&mut self, addr: GString) -> bool {
+        let Some(client) = &mut self.client else {
+            return false;
+        };
+        let Ok(addr) = addr.to_string().parse::<SocketAddr>() else {
+            godot_error!("Invalid address: {addr}");
+            return false;
+        };
+        client.control(addr)
+    }
+
+    /// Returns statistics about the link to the controlled lunabot, for displaying its health.
+    ///
+    /// Durations are in milliseconds, and `rtt_ms` is -1 until the round-trip time has been measured.
+    #[func]
+    fn get_link_stats(&self) -> Dictionary {
+        let mut dict = Dictionary::new();
+        let Some(client) = &self.client else {
+            return dict;
+        };
+        let Some(stats) = client.stats() else {
+            return dict;
+        };
+        dict.set(
+            "rtt_ms",
+            stats
+                .smoothed_rtt
+                .map(|rtt| rtt.as_secs_f64() * 1000.0)
+                .unwrap_or(-1.0),
+        );
+        dict.set("jitter_ms", stats.jitter.as_secs_f64() * 1000.0);
+        dict.set(
+            "retransmission_timeout_ms",
+            stats.retransmission_timeout.as_secs_f64() * 1000.0,
+        );
+        dict.set("packets_sent", stats.packets_sent as i64);
+        dict.set("packets_received", stats.packets_received as i64);
+        dict.set("packets_acknowledged", stats.packets_acknowledged as i64);
+        dict.set("packets_retransmitted", stats.packets_retransmitted as i64);
+        dict.set("packets_in_flight", stats.packets_in_flight as i64);
+        dict.set("bytes_in_flight", stats.bytes_in_flight as i64);
+        dict.set("packets_queued", stats.packets_queued as i64);
+        dict.set("retransmission_rate", stats.retransmission_rate());
+        if let Some(auth_stats) = client.auth_stats() {
+            dict.set("auth_accepted", auth_stats.accepted as i64);
+            dict.set("auth_malformed", auth_stats.malformed as i64);
+            dict.set("auth_forged", auth_stats.forged as i64);
+            dict.set("auth_replayed", auth_stats.replayed as i64);
+            dict.set("auth_stale_session", auth_stats.stale_session as i64);
+        }
+        dict
+    }
+
+    /// Returns the smoothed round-trip time of pings to the controlled lunabot in milliseconds, or -1 until it has
+    /// been measured.
+    #[func]
+    fn get_ping_rtt_ms(&self) -> f64 {
+        self.client
+            .as_ref()
+            .and_then(LunabaseClient::link_health)
+            .and_then(|health| health.rtt())
+            .map(|rtt| rtt.as_secs_f64() * 1000.0)
+            .unwrap_or(-1.0)
+    }
+
+    /// Returns the fraction of the latest pings to the controlled lunabot that were not answered in time.
+    #[func]
+    fn get_ping_loss(&self) -> f64 {
+        self.client
+            .as_ref()
+            .and_then(LunabaseClient::link_health)
+            .map(|health| health.loss())
+            .unwrap_or(0.0)
+    }
+
+    /// Returns how many milliseconds ago the controlled lunabot last pinged, or -1 if no lunabot is connected.
+    #[func]
+    fn get_last_ping_age_ms(&self) -> f64 {
+        self.client
+            .as_ref()
+            .and_then(LunabaseClient::link_health)
+            .map(|health| health.since_pinged(Instant::now()).as_secs_f64() * 1000.0)
+            .unwrap_or(-1.0)
+    }
+
+    /// Returns `healthy`, `degraded` or `lost` for the link to the controlled lunabot, or an empty string if no
+    /// lunabot is connected.
+    #[func]
+    fn get_link_state(&self) -> GString {
+        let state = self
+            .client
+            .as_ref()
+            .and_then(LunabaseClient::link_health)
+            .map(|health| health.state());
+        GString::from(match state {
+            Some(LinkState::Healthy) => "healthy",
+            Some(LinkState::Degraded) => "degraded",
+            Some(LinkState::Lost) => "lost",
+            None => "",
+        })
+    }
+
+    /// Authenticates all packets to and from the lunabot with the given key, which must be 64 hexadecimal
+    /// digits, and encrypts them if `encrypt` is `true`. The lunabot must be configured with the same key. If
+    /// the lunabase is not listening yet, the key is used as soon as it is.
+    ///
+    /// Returns `false` if the key is invalid.
+    #[func]
+    fn set_pre_shared_key(&mut self, key: GString, encrypt: bool) -> bool {
+        let Some(key) = PreSharedKey::from_hex(&key.to_string()) else {
+            godot_error!("Pre-shared key should be 64 hexadecimal digits");
+            return false;
+        };
+        let protection = if encrypt {
+            Protection::Encrypt
+        } else {
+            Protection::Authenticate
+        };
+        let key = Some((key, protection));
+        if let Some(client) = &mut self.client {
+            client.set_pre_shared_key(key.clone());
+        }
+        self.pre_shared_key = key;
+        true
+    }
 }
@@ -0,0 +1,317 @@
+//! Records every message sent to and received from the lunabots during a session, and plays sessions back.
+//!
+//! A recording starts with `LBSN`, a little-endian `u16` version and the time that the recording started, as
+//! milliseconds since the Unix epoch in a little-endian `u64`. Each message follows as a little-endian `u32`
+//! length and a bitcode [`Record`]. A recording that was cut short, such as by a crash, is read up to the last
+//! whole record.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bitcode::{Decode, Encode};
+use common::{FromLunabase, FromLunabot};
+
+const MAGIC: [u8; 4] = *b"LBSN";
+const VERSION: u16 = 1;
+/// Records that claim to be longer are assumed to be corrupt. This is far longer than any message.
+const MAX_RECORD_LEN: usize = 64 * 1024;
+
+/// A message that was sent or received, as recorded.
+#[derive(Debug, Encode, Decode, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Sent {
+        /// The address of the lunabot.
+        to: String,
+        msg: FromLunabase,
+    },
+    Received {
+        /// The address of the lunabot.
+        from: String,
+        msg: FromLunabot,
+        /// Whether the lunabot was the controlled lunabot.
+        controlled: bool,
+    },
+}
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Microseconds since the recording started.
+    pub elapsed_micros: u64,
+    pub entry: Entry,
+}
+
+impl Record {
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_micros(self.elapsed_micros)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecordingError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("Not a session recording")]
+    NotARecording,
+    #[error("Unsupported recording version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Corrupt record: {0}")]
+    Corrupt(String),
+}
+
+/// Writes records as they happen.
+///
+/// Every record is flushed immediately, so that a recording survives a crash of the lunabase.
+pub struct SessionRecorder {
+    writer: Box<dyn Write + Send>,
+    started: Instant,
+}
+
+impl SessionRecorder {
+    /// Starts a recording in the given writer, where `now` is the time that records are timestamped from.
+    pub fn new(mut writer: Box<dyn Write + Send>, now: Instant) -> io::Result<Self> {
+        let unix_millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&unix_millis.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Self {
+            writer,
+            started: now,
+        })
+    }
+
+    /// Creates the file at `path`, replacing it if it exists, and starts a recording in it.
+    pub fn create(path: impl AsRef<Path>, now: Instant) -> io::Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)), now)
+    }
+
+    pub fn record(&mut self, now: Instant, entry: Entry) -> io::Result<()> {
+        let record = Record {
+            elapsed_micros: now.saturating_duration_since(self.started).as_micros() as u64,
+            entry,
+        };
+        let bytes = bitcode::encode(&record);
+        if bytes.len() > MAX_RECORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "The record is {} bytes long, which is too long to play back",
+                    bytes.len()
+                ),
+            ));
+        }
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&bytes)?;
+        self.writer.flush()
+    }
+}
+
+/// A recorded session, which hands out its records as they become due.
+pub struct SessionPlayer {
+    /// When the recording started, in milliseconds since the Unix epoch.
+    pub started_unix_millis: u64,
+    records: Vec<Record>,
+    next: usize,
+    /// When playback started, which is set by the first poll.
+    playback_started: Option<Instant>,
+}
+
+impl SessionPlayer {
+    pub fn new(mut reader: impl Read) -> Result<Self, RecordingError> {
+        let mut header = [0; MAGIC.len() + 2 + 8];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(RecordingError::NotARecording)
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if header[..MAGIC.len()] != MAGIC {
+            return Err(RecordingError::NotARecording);
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            return Err(RecordingError::UnsupportedVersion(version));
+        }
+        let started_unix_millis = u64::from_le_bytes(header[6..].try_into().unwrap());
+
+        let mut records = vec![];
+        let mut buf = vec![];
+        loop {
+            let mut len = [0; 4];
+            if !read_whole(&mut reader, &mut len)? {
+                break;
+            }
+            let len = u32::from_le_bytes(len) as usize;
+            if len > MAX_RECORD_LEN {
+                return Err(RecordingError::Corrupt(format!(
+                    "The record claims to be {len} bytes long"
+                )));
+            }
+            buf.resize(len, 0);
+            if !read_whole(&mut reader, &mut buf)? {
+                break;
+            }
+            let record =
+                bitcode::decode(&buf).map_err(|e| RecordingError::Corrupt(e.to_string()))?;
+            records.push(record);
+        }
+        Ok(Self {
+            started_unix_millis,
+            records,
+            next: 0,
+            playback_started: None,
+        })
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, RecordingError> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Returns how long the recording is.
+    pub fn duration(&self) -> Duration {
+        self.records.last().map(Record::elapsed).unwrap_or_default()
+    }
+
+    /// Returns every record that became due since the last poll, at the same pace as they were recorded. The
+    /// first poll starts playback.
+    pub fn poll(&mut self, now: Instant) -> &[Record] {
+        let elapsed = now.saturating_duration_since(*self.playback_started.get_or_insert(now));
+        let start = self.next;
+        while self
+            .records
+            .get(self.next)
+            .is_some_and(|record| record.elapsed() <= elapsed)
+        {
+            self.next += 1;
+        }
+        &self.records[start..self.next]
+    }
+
+    /// Returns `true` iff every record has been played.
+    pub fn is_finished(&self) -> bool {
+        self.next == self.records.len()
+    }
+}
+
+/// Fills `buf`, returning `false` if the reader ended before anything, or part of `buf`, was read.
+fn read_whole(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use common::{LunabotStage, Steering};
+
+    use super::*;
+
+    /// A writer that can be read after it is given to a recorder.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn entries() -> Vec<Entry> {
+        vec![
+            Entry::Received {
+                from: "10.0.0.2:43000".into(),
+                msg: FromLunabot::Ping(LunabotStage::TeleOp),
+                controlled: true,
+            },
+            Entry::Sent {
+                to: "10.0.0.2:43000".into(),
+                msg: FromLunabase::Steering(Steering::new(1.0, 0.0)),
+            },
+            Entry::Sent {
+                to: "10.0.0.2:43000".into(),
+                msg: FromLunabase::SoftStop,
+            },
+        ]
+    }
+
+    fn record(buffer: &SharedBuffer, start: Instant) {
+        let mut recorder = SessionRecorder::new(Box::new(buffer.clone()), start).unwrap();
+        for (i, entry) in entries().into_iter().enumerate() {
+            recorder
+                .record(start + Duration::from_millis(100 * i as u64), entry)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let buffer = SharedBuffer::default();
+        let start = Instant::now();
+        record(&buffer, start);
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        let mut player = SessionPlayer::new(bytes.as_slice()).unwrap();
+        assert_eq!(player.duration(), Duration::from_millis(200));
+        let recorded: Vec<_> = player.records().iter().map(|r| r.entry.clone()).collect();
+        assert_eq!(recorded, entries());
+
+        // Records are played at the pace they were recorded
+        let start = Instant::now();
+        assert_eq!(player.poll(start).len(), 1);
+        assert!(player.poll(start + Duration::from_millis(50)).is_empty());
+        assert_eq!(player.poll(start + Duration::from_millis(250)).len(), 2);
+        assert!(player.is_finished());
+    }
+
+    #[test]
+    fn truncated() {
+        let buffer = SharedBuffer::default();
+        record(&buffer, Instant::now());
+        let mut bytes = buffer.0.lock().unwrap().clone();
+        bytes.truncate(bytes.len() - 1);
+        let player = SessionPlayer::new(bytes.as_slice()).unwrap();
+        assert_eq!(player.records().len(), 2);
+
+        assert!(matches!(
+            SessionPlayer::new(b"LBSN".as_slice()),
+            Err(RecordingError::NotARecording)
+        ));
+        assert!(matches!(
+            SessionPlayer::new(b"not a recording".as_slice()),
+            Err(RecordingError::NotARecording)
+        ));
+    }
+
+    #[test]
+    fn forged_length() {
+        let buffer = SharedBuffer::default();
+        record(&buffer, Instant::now());
+        let mut bytes = buffer.0.lock().unwrap().clone();
+        // Only a few bytes follow, but the next record claims to be almost 4 GiB long
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(b"short");
+        assert!(matches!(
+            SessionPlayer::new(bytes.as_slice()),
+            Err(RecordingError::Corrupt(_))
+        ));
+    }
+}
@@ -1,13 +1,31 @@
 use ares_bt::{
     action::AlwaysSucceed, branching::IfElse, converters::AssertCancelSafe, sequence::Sequence,
-    Behavior, CancelSafe, Status,
+    Behavior, CancelSafe, InfallibleStatus, Status,
 };
 use common::LunabotStage;
+use nalgebra::{Point3, Vector2};
+use pathfinding::{coverage::CoveragePlanner, Pathfinder};
 
-use crate::{blackboard::LunabotBlackboard, Action};
+use crate::{blackboard::LunabotBlackboard, drive_along, Action};
 
 use super::{Autonomy, AutonomyStage};
 
+/// The width of ground swept by the digging implement in meters.
+const IMPLEMENT_WIDTH: f64 = 0.5;
+/// The corners of the dig zone in the xz plane of the arena, in meters.
+const DIG_ZONE: [[f64; 2]; 4] = [[-3.0, -7.0], [-1.0, -7.0], [-1.0, -5.0], [-3.0, -5.0]];
+/// The spacing between the points checked along each pass, in meters.
+const STEP_SIZE: f64 = 0.1;
+
+fn coverage_planner() -> CoveragePlanner {
+    let zone = DIG_ZONE.map(Vector2::from);
+    let min = zone.iter().fold(zone[0], |min, p| min.inf(p));
+    let max = zone.iter().fold(zone[0], |max, p| max.sup(p));
+    let mut pathfinder = Pathfinder::<()>::new(max - min, STEP_SIZE);
+    pathfinder.offset = min;
+    CoveragePlanner::new(pathfinder, IMPLEMENT_WIDTH)
+}
+
 pub(super) fn dig() -> impl Behavior<LunabotBlackboard> + CancelSafe {
     IfElse::new(
         AssertCancelSafe(|blackboard: &mut LunabotBlackboard| {
@@ -21,8 +39,55 @@ pub(super) fn dig() -> impl Behavior<LunabotBlackboard> + CancelSafe {
         Sequence::new((
             AssertCancelSafe(|blackboard: &mut LunabotBlackboard| {
                 blackboard.enqueue_action(Action::SetStage(LunabotStage::Dig));
+                // A pass that was interrupted was not covered, so it is planned again
+                *blackboard.get_dig_pass() = None;
+                blackboard.invalidate_path();
                 Status::Success
             }),
+            AssertCancelSafe(|blackboard: &mut LunabotBlackboard| {
+                if let Some(pass) = *blackboard.get_dig_pass() {
+                    let path = blackboard.get_path().unwrap_or_default().to_vec();
+                    if !path.is_empty()
+                        && matches!(drive_along(blackboard, &path), InfallibleStatus::Running)
+                    {
+                        return Status::Running;
+                    }
+                    blackboard
+                        .get_dig_coverage()
+                        .mark_covered(pass.from, pass.to, IMPLEMENT_WIDTH);
+                    *blackboard.get_dig_pass() = None;
+                }
+
+                // Replanning after every pass skips strips that were already covered
+                let robot = blackboard.get_robot_isometry().translation;
+                let position = Vector2::new(robot.x, robot.z);
+                let plan = coverage_planner().plan(
+                    position,
+                    &DIG_ZONE.map(Vector2::from),
+                    blackboard.get_dig_coverage(),
+                    // The ai does not know about obstacles yet
+                    |_, _| true,
+                );
+                let Some(&pass) = plan.passes.first() else {
+                    // The whole zone has been dug, so the next dig stage starts over
+                    blackboard.get_dig_coverage().clear();
+                    blackboard.invalidate_path();
+                    return Status::Success;
+                };
+                let end = plan
+                    .path
+                    .iter()
+                    .position(|&point| point == pass.to)
+                    .unwrap_or(plan.path.len() - 1);
+                blackboard.set_path(
+                    plan.path[..=end]
+                        .iter()
+                        .map(|point| Point3::new(point.x, 0.0, point.y))
+                        .collect(),
+                );
+                *blackboard.get_dig_pass() = Some(pass);
+                Status::Running
+            }),
             AssertCancelSafe(|blackboard: &mut LunabotBlackboard| {
                 blackboard.get_autonomy().advance();
                 Status::Success
@@ -31,3 +96,57 @@ pub(super) fn dig() -> impl Behavior<LunabotBlackboard> + CancelSafe {
         AlwaysSucceed,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use k::{Chain, Joint, JointType, Node};
+    use nalgebra::Isometry3;
+
+    use super::*;
+
+    /// Runs a full dig stage, moving the robot to the end of every path it is given, and returns
+    /// the number of passes that were dug.
+    fn dig_stage(
+        behavior: &mut impl Behavior<LunabotBlackboard>,
+        blackboard: &mut LunabotBlackboard,
+        chain: &Chain<f64>,
+    ) -> usize {
+        *blackboard.get_autonomy() = Autonomy::FullAutonomy(AutonomyStage::Dig);
+        let mut passes = 0;
+        for _ in 0..100 {
+            match behavior.run(blackboard) {
+                Status::Running => {
+                    let end = *blackboard.get_path().unwrap().last().unwrap();
+                    chain.set_origin(Isometry3::translation(end.x, end.y, end.z));
+                    passes += 1;
+                }
+                Status::Success => {
+                    assert_eq!(
+                        *blackboard.get_autonomy(),
+                        Autonomy::FullAutonomy(AutonomyStage::Dump)
+                    );
+                    return passes;
+                }
+                Status::Failure => panic!("Digging failed"),
+            }
+            blackboard.drain_actions().for_each(drop);
+        }
+        panic!("Digging did not finish");
+    }
+
+    #[test]
+    fn test_digs_every_cycle() {
+        let chain = Arc::new(Chain::from_nodes(vec![Node::new(Joint::new(
+            "base",
+            JointType::Fixed,
+        ))]));
+        let mut blackboard = LunabotBlackboard::new(chain.clone());
+        let mut behavior = dig();
+
+        // The zone is 2 meters wide, so it takes 4 passes to dig it
+        assert_eq!(dig_stage(&mut behavior, &mut blackboard, &chain), 4);
+        assert_eq!(dig_stage(&mut behavior, &mut blackboard, &chain), 4);
+    }
+}
@@ -3,6 +3,7 @@ use std::{collections::VecDeque, sync::Arc, time::Instant};
 use common::FromLunabase;
 use k::Chain;
 use nalgebra::{Isometry3, Point3};
+use pathfinding::coverage::{CoveragePass, CoverageProgress};
 
 use crate::{autonomy::Autonomy, Action, PollWhen};
 
@@ -19,6 +20,8 @@ pub(crate) struct LunabotBlackboard {
     autonomy: Autonomy,
     chain: Arc<Chain<f64>>,
     path: Vec<Point3<f64>>,
+    dig_coverage: CoverageProgress,
+    dig_pass: Option<CoveragePass>,
     lunabase_disconnected: bool,
     actions: Vec<Action>,
     poll_when: PollWhen,
@@ -31,6 +34,8 @@ impl LunabotBlackboard {
             from_lunabase: Default::default(),
             autonomy: Autonomy::None,
             path: vec![],
+            dig_coverage: CoverageProgress::new(0.05),
+            dig_pass: None,
             chain,
             lunabase_disconnected: true,
             actions: vec![],
@@ -68,10 +73,27 @@ impl LunabotBlackboard {
         }
     }
 
+    pub fn set_path(&mut self, path: Vec<Point3<f64>>) {
+        self.path = path;
+    }
+
     pub fn invalidate_path(&mut self) {
         self.path.clear();
     }
 
+    /// The parts of the dig zone that have already been dug.
+    ///
+    /// This persists across autonomy cycles so that digging can resume where it left off, and is
+    /// cleared once the whole zone has been dug.
+    pub fn get_dig_coverage(&mut self) -> &mut CoverageProgress {
+        &mut self.dig_coverage
+    }
+
+    /// The pass that is currently being dug, which is marked as covered once it is driven to the end.
+    pub fn get_dig_pass(&mut self) -> &mut Option<CoveragePass> {
+        &mut self.dig_pass
+    }
+
     pub fn lunabase_disconnected(&mut self) -> &mut bool {
         &mut self.lunabase_disconnected
     }
@@ -152,6 +152,14 @@ fn follow_path(blackboard: &mut LunabotBlackboard) -> InfallibleStatus {
         OPoint::<f64, Const<3>>::new(-1.0, 0.0, -5.0),
     ];
 
+    drive_along(blackboard, path)
+}
+
+/// Steers towards the next point of `path`, returning `Success` once the robot is at the last point.
+pub(crate) fn drive_along(
+    blackboard: &mut LunabotBlackboard,
+    path: &[Point3<f64>],
+) -> InfallibleStatus {
     let robot = blackboard.get_robot_isometry();
     let pos = Point2::new(robot.translation.x, robot.translation.z);
     let heading = robot
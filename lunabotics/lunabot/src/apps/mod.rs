@@ -4,6 +4,7 @@ mod sim;
 
 use std::{fs::File, net::SocketAddr, sync::Arc, time::Duration};
 
+use cakap2::auth::{Authenticator, PreSharedKey, Protection};
 use common::{FromLunabase, FromLunabot, LunabotStage};
 use crossbeam::atomic::AtomicCell;
 use k::Chain;
@@ -23,6 +24,22 @@ fn default_max_pong_delay_ms() -> u64 {
     1500
 }
 
+fn default_name() -> String {
+    "lunabot".into()
+}
+
+/// Creates the authenticator for packets to and from the lunabase, if a pre-shared key is configured.
+fn create_authenticator(pre_shared_key: Option<&str>, encrypt: bool) -> Option<Authenticator> {
+    let key = PreSharedKey::from_hex(pre_shared_key?)
+        .expect("pre_shared_key should be 64 hexadecimal digits");
+    let protection = if encrypt {
+        Protection::Encrypt
+    } else {
+        Protection::Authenticate
+    };
+    Some(Authenticator::new(&key, protection))
+}
+
 fn wait_for_ctrl_c() {
     match tokio::signal::ctrl_c().block_on() {
         Ok(()) => {
@@ -72,9 +89,11 @@ impl LunabotConnected {
 }
 
 fn create_packet_builder(
-    lunabase_address: SocketAddr,
+    lunabase_address: Option<SocketAddr>,
+    name: String,
     lunabot_stage: Arc<AtomicCell<LunabotStage>>,
     max_pong_delay_ms: u64,
+    authenticator: Option<Authenticator>,
 ) -> (
     PacketBuilder,
     mpsc::UnboundedReceiver<FromLunabase>,
@@ -86,6 +105,7 @@ fn create_packet_builder(
 
     let packet_builder = LunabaseConn {
         lunabase_address,
+        name,
         on_msg: move |bytes: &[u8]| match bitcode_buffer.decode(bytes) {
             Ok(msg) => {
                 if msg == FromLunabase::Pong {
@@ -93,14 +113,13 @@ fn create_packet_builder(
                 } else {
                     let _ = from_lunabase_tx.send(msg);
                 }
-                true
             }
             Err(e) => {
                 error!("Failed to decode from lunabase: {e}");
-                false
             }
         },
         lunabot_stage,
+        authenticator,
     }
     .connect_to_lunabase();
 
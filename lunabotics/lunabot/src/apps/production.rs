@@ -21,9 +21,22 @@ use super::{create_packet_builder, create_robot_chain, wait_for_ctrl_c};
 
 #[derive(Serialize, Deserialize)]
 pub struct LunabotApp {
-    pub lunabase_address: SocketAddr,
+    /// The address of the lunabase. If not set, the lunabot broadcasts beacons on the local network until a
+    /// lunabase claims it.
+    #[serde(default)]
+    pub lunabase_address: Option<SocketAddr>,
+    /// The name that the lunabase lists the lunabot by while it is broadcasting beacons.
+    #[serde(default = "super::default_name")]
+    pub name: String,
     #[serde(default = "super::default_max_pong_delay_ms")]
     pub max_pong_delay_ms: u64,
+    /// The key that authenticates packets to and from the lunabase, as 64 hexadecimal digits.
+    /// The lunabase must be configured with the same key.
+    #[serde(default)]
+    pub pre_shared_key: Option<String>,
+    /// Whether packets to and from the lunabase are also encrypted. Only used with a pre-shared key.
+    #[serde(default)]
+    pub encrypt_teleop: bool,
 }
 
 // const PROJECTION_SIZE: Vector2<u32> = Vector2::new(36, 24);
@@ -61,8 +74,10 @@ impl Application for LunabotApp {
 
         // let (packet_builder, mut from_lunabase_rx, mut connected) = create_packet_builder(
         //     self.lunabase_address,
+        //     self.name.clone(),
         //     lunabot_stage.clone(),
         //     self.max_pong_delay_ms,
+        //     create_authenticator(self.pre_shared_key.as_deref(), self.encrypt_teleop),
         // );
 
         // std::thread::spawn(move || {
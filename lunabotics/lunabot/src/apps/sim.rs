@@ -1,10 +1,13 @@
 use core::str;
 use std::{
-    cmp::Ordering, collections::VecDeque, net::SocketAddr, process::Stdio, sync::{Arc, Mutex}
+    future::Future, net::SocketAddr, process::Stdio, sync::{Arc, Mutex}
 };
 
 use common::{
-    lunasim::{FromLunasim, FromLunasimbot},
+    lunasim::{
+        transport::{encode_frame, header, FrameDecoder, LunasimAddress},
+        FromLunasim, FromLunasimbot,
+    },
     LunabotStage,
 };
 use crossbeam::atomic::AtomicCell;
@@ -17,11 +20,13 @@ use urobotics::{
     app::Application,
     callbacks::caller::CallbacksStorage,
     define_callbacks, fn_alias, get_tokio_handle,
-    log::{error, warn},
+    log::{error, info, warn},
     tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
-        process::{ChildStdin, Command},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpListener,
+        process::Command,
         runtime::Handle,
+        sync::watch,
     },
     BlockOn,
 };
@@ -29,7 +34,8 @@ use urobotics::{
 use crate::{localization::Localizer, pipelines::thalassic::spawn_thalassic_pipeline};
 
 use super::{
-    create_packet_builder, create_robot_chain, log_teleop_messages, wait_for_ctrl_c,
+    create_authenticator, create_packet_builder, create_robot_chain, log_teleop_messages,
+    wait_for_ctrl_c,
 };
 
 fn_alias! {
@@ -37,48 +43,310 @@ fn_alias! {
 }
 define_callbacks!(FromLunasimCallbacks => CloneFn(msg: FromLunasim) + Send);
 
+/// The stream to lunasim, which is its stdin unless lunasim connected over a socket.
 #[derive(Clone)]
-pub struct LunasimStdin(Arc<Mutex<ChildStdin>>);
+pub struct LunasimStdin {
+    writer: Arc<Mutex<LunasimWriter>>,
+    /// The number of times that lunasim sent [`FromLunasim::Stepped`].
+    stepped: Arc<watch::Sender<u64>>,
+}
+
+struct LunasimWriter {
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Starts with the header, which is sent with the first frame.
+    frames: Vec<u8>,
+}
 
 impl LunasimStdin {
+    fn new(writer: impl AsyncWrite + Send + Unpin + 'static) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(LunasimWriter {
+                writer: Box::new(writer),
+                frames: header().to_vec(),
+            })),
+            stepped: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
     pub fn write(&self, bytes: &[u8]) {
-        self.0.clear_poison();
-        let mut stdin = self.0.lock().unwrap();
-        if let Err(e) = stdin
-            .write_all(&u32::to_ne_bytes(bytes.len() as u32))
-            .block_on()
-        {
-            if e.kind() != std::io::ErrorKind::BrokenPipe {
-                error!("Failed to send to lunasim: {e}");
-            }
+        self.writer.clear_poison();
+        let mut inner = self.writer.lock().unwrap();
+        let LunasimWriter { writer, frames } = &mut *inner;
+        if let Err(e) = encode_frame(bytes, frames) {
+            error!("Failed to send to lunasim: {e}");
             return;
         }
-        if let Err(e) = stdin.write_all(bytes).block_on() {
+        let result = async {
+            writer.write_all(frames).await?;
+            writer.flush().await
+        }
+        .block_on();
+        frames.clear();
+        if let Err(e) = result {
             if e.kind() != std::io::ErrorKind::BrokenPipe {
                 error!("Failed to send to lunasim: {e}");
             }
         }
     }
+
+    /// Restarts every source of randomness in lunasim from the given seed.
+    pub fn seed(&self, seed: u64) {
+        self.write(&bitcode::encode(&FromLunasimbot::Seed(seed)));
+    }
+
+    /// Finishes the futures returned by [`step`](Self::step), as lunasim sent [`FromLunasim::Stepped`].
+    fn notify_stepped(&self) {
+        self.stepped.send_modify(|count| *count += 1);
+    }
+}
+
+/// Controls episodes of the simulation, such as for automated runs.
+impl LunasimStdin {
+    /// Moves the robot to `isometry` and stops it, so that a new episode can begin.
+    pub fn reset(&self, isometry: Isometry3<f64>) {
+        let (axis, angle) = isometry
+            .rotation
+            .axis_angle()
+            .unwrap_or((UnitVector3::new_normalize(Vector3::new(0.0, 0.0, 1.0)), 0.0));
+        let translation = isometry.translation;
+        self.write(&bitcode::encode(&FromLunasimbot::Reset {
+            axis: [axis.x as f32, axis.y as f32, axis.z as f32],
+            angle: angle as f32,
+            origin: [
+                translation.x as f32,
+                translation.y as f32,
+                translation.z as f32,
+            ],
+        }));
+    }
+
+    /// Pauses or resumes the simulation. Either one cancels any steps that have not finished.
+    pub fn set_paused(&self, paused: bool) {
+        self.write(&bitcode::encode(&FromLunasimbot::SetPaused(paused)));
+    }
+
+    /// Runs the given number of physics frames, then pauses the simulation.
+    ///
+    /// The returned future finishes once lunasim has run the frames. If the steps are cancelled, it only
+    /// finishes when the next steps do.
+    pub fn step(&self, frames: u32) -> impl Future<Output = ()> + Send + 'static {
+        // Subscribing before sending the steps means that no `Stepped` can be missed
+        let mut stepped = self.stepped.subscribe();
+        self.write(&bitcode::encode(&FromLunasimbot::Step(frames)));
+        async move {
+            let _ = stepped.changed().await;
+        }
+    }
 }
 
-#[cfg(target_os = "windows")]
-const DELIMIT: &[u8] = b"READY\r\n";
+type LunasimHalves = (
+    Box<dyn AsyncRead + Send + Unpin>,
+    Box<dyn AsyncWrite + Send + Unpin>,
+);
+
+/// Waits for lunasim to connect to the given address.
+async fn accept_lunasim(address: &LunasimAddress) -> std::io::Result<LunasimHalves> {
+    match address {
+        LunasimAddress::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Waiting for lunasim to connect to {address}");
+            let (stream, _) = listener.accept().await?;
+            stream.set_nodelay(true)?;
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        #[cfg(unix)]
+        LunasimAddress::Unix(path) => {
+            // The socket of a previous run would stop the bind
+            let _ = std::fs::remove_file(path);
+            let listener = tokio::net::UnixListener::bind(path)?;
+            info!("Waiting for lunasim to connect to {address}");
+            let (stream, _) = listener.accept().await?;
+            let (reader, writer) = stream.into_split();
+            Ok((Box::new(reader), Box::new(writer)))
+        }
+        #[cfg(not(unix))]
+        LunasimAddress::Unix(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+    }
+}
 
-#[cfg(not(target_os = "windows"))]
-const DELIMIT: &[u8] = b"READY\n";
+/// Reads frames from lunasim until it disconnects, calling `callbacks` with each message.
+async fn read_lunasim(mut reader: impl AsyncRead + Unpin, mut callbacks: FromLunasimCallbacks) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut decoder = FrameDecoder::default();
+    let mut bitcode_buffer = bitcode::Buffer::new();
+
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => {
+                error!(target: "lunasim", "Lunasim disconnected");
+                break;
+            }
+            Ok(n) => n,
+            Err(e) => {
+                match e.kind() {
+                    std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::Other
+                    | std::io::ErrorKind::UnexpectedEof => {}
+                    _ => {
+                        error!(target: "lunasim", "Faced the following error while reading from lunasim: {e}");
+                    }
+                }
+                break;
+            }
+        };
+        decoder.push(&buf[..n]);
+        while let Some(result) = decoder.next_frame() {
+            match result {
+                Ok(payload) => match bitcode_buffer.decode(&payload) {
+                    Ok(msg) => callbacks.call(msg),
+                    Err(e) => {
+                        error!(target: "lunasim", "Failed to deserialize from lunasim: {e}");
+                    }
+                },
+                Err(e) => error!(target: "lunasim", "Failed to read from lunasim: {e}"),
+            }
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct LunasimbotApp {
-    pub lunabase_address: SocketAddr,
+    /// The address of the lunabase. If not set, the lunabot broadcasts beacons on the local network until a
+    /// lunabase claims it.
+    #[serde(default)]
+    pub lunabase_address: Option<SocketAddr>,
+    /// The name that the lunabase lists the lunabot by while it is broadcasting beacons.
+    #[serde(default = "super::default_name")]
+    pub name: String,
     #[serde(default = "super::default_max_pong_delay_ms")]
     pub max_pong_delay_ms: u64,
+    /// The key that authenticates packets to and from the lunabase, as 64 hexadecimal digits.
+    /// The lunabase must be configured with the same key.
+    #[serde(default)]
+    pub pre_shared_key: Option<String>,
+    /// Whether packets to and from the lunabase are also encrypted. Only used with a pre-shared key.
+    #[serde(default)]
+    pub encrypt_teleop: bool,
+    /// Whether AprilTags are detected in camera frames rendered by lunasim, the same way as on the real
+    /// lunabot. Otherwise, lunasim sends the pose that a detection would produce. Needs the `sim-apriltag`
+    /// feature.
+    #[serde(default)]
+    pub detect_apriltags: bool,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     simulation_command: Vec<String>,
+    /// The address to wait for lunasim to connect to, as `<ip>:<port>` or `unix:<path>`, instead of running
+    /// the simulation command. Lunasim connects when it is run with `-- --connect=<address>`, which lets it
+    /// run on another host.
+    #[serde(default)]
+    pub lunasim_address: Option<String>,
+    /// Seeds every source of randomness in lunasim, such as sensor noise, so that runs can be reproduced.
+    #[serde(default)]
+    pub simulation_seed: Option<u64>,
+    /// Starts the simulation from a known state, for automated runs.
+    #[serde(default)]
+    pub simulation_episode: Option<SimulationEpisode>,
+}
+
+/// The state that the simulation starts from, and how it advances.
+#[derive(Serialize, Deserialize)]
+pub struct SimulationEpisode {
+    /// Where the robot is moved to, in meters.
+    pub origin: [f64; 3],
+    /// The rotation of the robot about the vertical axis, in radians.
+    #[serde(default)]
+    pub yaw: f64,
+    /// If set, the simulation is paused, and only runs this many physics frames whenever the ai waits to act
+    /// again. Runs then do not depend on how fast the host is, but the simulation stands still while the ai
+    /// waits for the lunabase.
+    #[serde(default)]
+    pub lockstep_frames: Option<u32>,
+}
+
+impl SimulationEpisode {
+    fn isometry(&self) -> Isometry3<f64> {
+        Isometry3::new(Vector3::from(self.origin), Vector3::y() * self.yaw)
+    }
 }
 
 const PROJECTION_SIZE: Vector2<u32> = Vector2::new(36, 24);
 
+/// The ids and positions of the AprilTags in `godot/lunasim/main.tscn`.
+#[cfg(feature = "urobotics-apriltag")]
+const SIM_APRILTAGS: &[(usize, [f64; 3])] = &[(0, [0.0, 0.610044, 0.50416])];
+/// The width of the black border of the AprilTags in lunasim, which is 6/8 of their decals.
+#[cfg(feature = "urobotics-apriltag")]
+const SIM_APRILTAG_WIDTH: f64 = 0.45;
+
+/// Returns a callback that runs an AprilTag detector on camera frames from lunasim, and gives the localizer
+/// the isometry of the robot whenever a tag is detected. The detector is created on the first frame, as that
+/// is when the size and focal length of the camera are known.
+#[cfg(feature = "urobotics-apriltag")]
+fn apriltag_detection(
+    localizer_ref: crate::localization::LocalizerRef,
+    camera_origin: Isometry3<f64>,
+) -> Box<dyn FnMut(FromLunasim) + Send> {
+    use std::f64::consts::PI;
+
+    use common::lunasim::frame::unpack;
+    use nalgebra::Point3;
+    use urobotics_apriltag::{
+        image::{DynamicImage, GrayImage},
+        AprilTagDetector,
+    };
+
+    let mut feed_image: Option<Box<dyn Fn(Arc<DynamicImage>) + Send + Sync>> = None;
+
+    Box::new(move |msg| {
+        let FromLunasim::CameraFrame {
+            id: _,
+            width,
+            height,
+            focal_length_px,
+            packed,
+        } = msg
+        else {
+            return;
+        };
+        let feed_image = feed_image.get_or_insert_with(|| {
+            let mut detector = AprilTagDetector::new(focal_length_px as f64, width, height);
+            for &(tag_id, [x, y, z]) in SIM_APRILTAGS {
+                // AprilTag frames point x right, y down and z into the tag, and the tags in lunasim face -Z
+                detector.add_tag(
+                    Point3::new(x, y, z),
+                    UnitQuaternion::from_axis_angle(&Vector3::z_axis(), PI),
+                    SIM_APRILTAG_WIDTH,
+                    tag_id,
+                );
+            }
+            let localizer_ref = localizer_ref.clone();
+            detector
+                .detection_callbacks_ref()
+                .add_fn(move |observation| {
+                    // AprilTag camera frames point y down and z forward, while the camera link points y up
+                    // and z backward
+                    let camera = observation.tag_global_isometry
+                        * observation.tag_local_isometry.inverse()
+                        * UnitQuaternion::from_axis_angle(&Vector3::x_axis(), PI);
+                    localizer_ref.set_april_tag_isometry(camera * camera_origin.inverse());
+                });
+            let feed_image = detector.create_image_callback();
+            std::thread::spawn(|| detector.run());
+            Box::new(feed_image)
+        });
+        match unpack(&packed, width as usize * height as usize)
+            .and_then(|pixels| GrayImage::from_raw(width, height, pixels))
+        {
+            Some(image) => feed_image(Arc::new(DynamicImage::ImageLuma8(image))),
+            None => error!(target: "lunasim", "Received a malformed camera frame"),
+        }
+    })
+}
+
 impl Application for LunasimbotApp {
     const APP_NAME: &'static str = "sim";
 
@@ -89,6 +357,24 @@ impl Application for LunasimbotApp {
         if let Err(e) = init_gputter_blocking() {
             error!("Failed to initialize gputter: {e}");
         }
+        let lunasim_address = match self
+            .lunasim_address
+            .as_deref()
+            .map(str::parse::<LunasimAddress>)
+            .transpose()
+        {
+            Ok(lunasim_address) => lunasim_address,
+            Err(e) => {
+                error!("Invalid lunasim_address: {e}");
+                return;
+            }
+        };
+        if self.detect_apriltags && !cfg!(feature = "urobotics-apriltag") {
+            error!(
+                "Detecting AprilTags needs the sim-apriltag feature. Using explicit AprilTag poses instead"
+            );
+            self.detect_apriltags = false;
+        }
 
         let mut cmd = if self.simulation_command.is_empty() {
             let mut cmd = Command::new("godot");
@@ -99,6 +385,9 @@ impl Application for LunasimbotApp {
             cmd.args(self.simulation_command);
             cmd
         };
+        if self.detect_apriltags {
+            cmd.args(["--", "--camera-frames"]);
+        }
 
         cmd.stdin(Stdio::piped())
             .stdout(Stdio::piped())
@@ -106,116 +395,101 @@ impl Application for LunasimbotApp {
             .kill_on_drop(true);
 
         let _guard = get_tokio_handle().enter();
+        let callbacks = FromLunasimCallbacks::default();
+        let from_lunasim_ref = callbacks.get_ref();
         let child;
-        let (lunasim_stdin, from_lunasim_ref) = match cmd.spawn() {
-            Ok(tmp) => {
-                child = tmp;
-                let stdin = child.stdin.unwrap();
-                let mut stdout = child.stdout.unwrap();
-                let mut stderr = child.stderr.unwrap();
-                macro_rules! handle_err {
-                    ($msg: literal, $err: ident) => {{
-                        match $err.kind() {
-                            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::Other | std::io::ErrorKind::UnexpectedEof => {}
-                            _ => {
-                                error!(target: "lunasim", "Faced the following error while reading {}: {}", $msg, $err);
-                            }
-                        }
-                        break;
-                    }}
+        let lunasim_stdin = match lunasim_address {
+            Some(address) => match accept_lunasim(&address).block_on() {
+                Ok((reader, writer)) => {
+                    Handle::current().spawn(read_lunasim(reader, callbacks));
+                    LunasimStdin::new(writer)
                 }
-
-                // Log stderr from Lunasim
-                let handle = Handle::current();
-                handle.spawn(async move {
-                    let mut bytes = Vec::with_capacity(1024);
-                    let mut buf = [0u8; 1024];
-
-                    loop {
-                        if bytes.len() == bytes.capacity() {
-                            bytes.reserve(bytes.len());
-                        }
-                        match stderr.read(&mut buf).await {
-                            Ok(0) => {}
-                            Ok(n) => {
-                                bytes.extend_from_slice(&buf[0..n]);
-                                if let Ok(string) = std::str::from_utf8(&bytes) {
-                                    if let Some(i) = string.find('\n') {
-                                        warn!(target: "lunasim", "{}", &string[0..i]);
-                                        bytes.drain(0..=i);
-                                    }
+                Err(e) => {
+                    error!("Failed to accept lunasim at {address}: {e}");
+                    return;
+                }
+            },
+            None => match cmd.spawn() {
+                Ok(tmp) => {
+                    child = tmp;
+                    let stdin = child.stdin.unwrap();
+                    let stdout = child.stdout.unwrap();
+                    let mut stderr = child.stderr.unwrap();
+                    macro_rules! handle_err {
+                        ($msg: literal, $err: ident) => {{
+                            match $err.kind() {
+                                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::Other | std::io::ErrorKind::UnexpectedEof => {}
+                                _ => {
+                                    error!(target: "lunasim", "Faced the following error while reading {}: {}", $msg, $err);
                                 }
                             }
-                            Err(e) => handle_err!("stderr", e),
-                        }
+                            break;
+                        }}
                     }
-                });
 
-                let mut callbacks = FromLunasimCallbacks::default();
-                let callbacks_ref = callbacks.get_ref();
+                    // Log stderr from Lunasim
+                    let handle = Handle::current();
+                    handle.spawn(async move {
+                        let mut bytes = Vec::with_capacity(1024);
+                        let mut buf = [0u8; 1024];
 
-                // Read lunasim stdout
-                handle.spawn(async move {
-                    {
-                        let mut bytes = VecDeque::with_capacity(DELIMIT.len());
-                        let mut buf = [0u8];
                         loop {
-                            match stdout.read_exact(&mut buf).await {
-                                Ok(0) => unreachable!("godot program should not exit"),
-                                Ok(_) => {
-                                    bytes.push_back(buf[0]);
-                                }
-                                Err(e) => error!(target: "lunasim", "Faced the following error while reading stdout: {e}"),
-                            }
-                            match bytes.len().cmp(&DELIMIT.len()) {
-                                Ordering::Equal => {}
-                                Ordering::Greater => {bytes.pop_front();}
-                                Ordering::Less => continue,
-                            }
-                            if bytes == DELIMIT {
-                                break;
+                            if bytes.len() == bytes.capacity() {
+                                bytes.reserve(bytes.len());
                             }
-                        }
-                    }
-                    let mut size_buf = [0u8; 4];
-                    let mut bytes = Vec::with_capacity(1024);
-                    let mut bitcode_buffer = bitcode::Buffer::new();
-                    loop {
-                        let size = match stdout.read_exact(&mut size_buf).await {
-                            Ok(_) => u32::from_ne_bytes(size_buf),
-                            Err(e) => handle_err!("stdout", e)
-                        };
-                        bytes.resize(size as usize, 0u8);
-                        match stdout.read_exact(&mut bytes).await {
-                            Ok(_) => {},
-                            Err(e) => handle_err!("stdout", e)
-                        }
-
-                        match bitcode_buffer.decode(&bytes) {
-                            Ok(msg) => {
-                                callbacks.call(msg);
-                            }
-                            Err(e) => {
-                                error!(target: "lunasim", "Failed to deserialize from lunasim: {e}");
-                                continue;
+                            match stderr.read(&mut buf).await {
+                                Ok(0) => {}
+                                Ok(n) => {
+                                    bytes.extend_from_slice(&buf[0..n]);
+                                    if let Ok(string) = std::str::from_utf8(&bytes) {
+                                        if let Some(i) = string.find('\n') {
+                                            warn!(target: "lunasim", "{}", &string[0..i]);
+                                            bytes.drain(0..=i);
+                                        }
+                                    }
+                                }
+                                Err(e) => handle_err!("stderr", e),
                             }
                         }
-                    }
-                });
+                    });
 
-                (LunasimStdin(Arc::new(stdin.into())), callbacks_ref)
-            }
-            Err(e) => {
-                error!("Failed to run simulation command: {e}");
-                return;
-            }
+                    handle.spawn(read_lunasim(stdout, callbacks));
+                    LunasimStdin::new(stdin)
+                }
+                Err(e) => {
+                    error!("Failed to run simulation command: {e}");
+                    return;
+                }
+            },
         };
+        if let Some(seed) = self.simulation_seed {
+            lunasim_stdin.seed(seed);
+        }
+        if let Some(episode) = &self.simulation_episode {
+            // The robot is placed while paused, so that it does not move until the episode starts
+            lunasim_stdin.set_paused(true);
+            lunasim_stdin.reset(episode.isometry());
+            if episode.lockstep_frames.is_none() {
+                lunasim_stdin.set_paused(false);
+            }
+        }
+        let lockstep_frames = self
+            .simulation_episode
+            .as_ref()
+            .and_then(|episode| episode.lockstep_frames);
         let robot_chain = create_robot_chain();
         let localizer = Localizer::new(robot_chain.clone(), Some(lunasim_stdin.clone()));
         let localizer_ref = localizer.get_ref();
         std::thread::spawn(|| localizer.run());
 
         let camera_link = robot_chain.find_link("depth_camera_link").unwrap().clone();
+        #[cfg(feature = "urobotics-apriltag")]
+        if self.detect_apriltags {
+            from_lunasim_ref.add_dyn_fn_mut(apriltag_detection(
+                localizer_ref.clone(),
+                camera_link.origin(),
+            ));
+        }
         let (depth_map_buffer, pcl_callbacks, heightmap_callbacks) =
             spawn_thalassic_pipeline(10.392, 0.01, PROJECTION_SIZE, camera_link);
 
@@ -238,6 +512,8 @@ impl Application for LunasimbotApp {
             lunasim_stdin2.write(bytes);
         }));
 
+        let detect_apriltags = self.detect_apriltags;
+        let lunasim_stdin2 = lunasim_stdin.clone();
         from_lunasim_ref.add_fn(move |msg| match msg {
             common::lunasim::FromLunasim::Accelerometer {
                 id: _,
@@ -262,7 +538,7 @@ impl Application for LunasimbotApp {
                 robot_origin,
                 robot_axis,
                 robot_angle,
-            } => {
+            } if !detect_apriltags => {
                 let isometry = Isometry3::from_parts(
                     Vector3::new(
                         robot_origin[0] as f64,
@@ -274,6 +550,14 @@ impl Application for LunasimbotApp {
                 );
                 localizer_ref.set_april_tag_isometry(isometry);
             }
+            // Odometry and motor faults are not handled yet
+            common::lunasim::FromLunasim::WheelEncoder { .. }
+            | common::lunasim::FromLunasim::MotorCurrent { .. }
+            | common::lunasim::FromLunasim::BatteryVoltage { .. } => {}
+            // Either AprilTags are detected in camera frames, or the explicit poses are used
+            common::lunasim::FromLunasim::ExplicitApriltag { .. }
+            | common::lunasim::FromLunasim::CameraFrame { .. } => {}
+            common::lunasim::FromLunasim::Stepped => lunasim_stdin2.notify_stepped(),
         });
 
         let lunasim_stdin2 = lunasim_stdin.clone();
@@ -289,11 +573,14 @@ impl Application for LunasimbotApp {
 
         let (packet_builder, mut from_lunabase_rx, mut connected) = create_packet_builder(
             self.lunabase_address,
+            self.name.clone(),
             lunabot_stage.clone(),
             self.max_pong_delay_ms,
+            create_authenticator(self.pre_shared_key.as_deref(), self.encrypt_teleop),
         );
 
         let mut bitcode_buffer = bitcode::Buffer::new();
+        let lunasim_stepper = lunasim_stdin.clone();
 
         std::thread::spawn(move || {
             run_ai(
@@ -350,7 +637,16 @@ impl Application for LunasimbotApp {
                             }
                         }
                         PollWhen::Instant(deadline) => {
+                            // In lockstep, the simulation runs while the ai waits instead of the clock
+                            let stepped =
+                                lockstep_frames.map(|frames| lunasim_stepper.step(frames));
                             async {
+                                let wait = async {
+                                    match stepped {
+                                        Some(stepped) => stepped.await,
+                                        None => tokio::time::sleep_until(deadline.into()).await,
+                                    }
+                                };
                                 tokio::select! {
                                     result = from_lunabase_rx.recv() => {
                                         let Some(msg) = result else {
@@ -360,7 +656,7 @@ impl Application for LunasimbotApp {
                                         };
                                         inputs.push(Input::FromLunabase(msg));
                                     }
-                                    _ = tokio::time::sleep_until(deadline.into()) => {}
+                                    _ = wait => {}
                                     _ = wait_disconnect => {
                                         inputs.push(Input::LunabaseDisconnected);
                                     }
@@ -380,3 +676,33 @@ impl Application for LunasimbotApp {
         wait_for_ctrl_c();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn episode_controls() {
+        let (writer, mut reader) = tokio::io::duplex(4096);
+        let lunasim_stdin = LunasimStdin::new(writer);
+        lunasim_stdin.set_paused(true);
+        lunasim_stdin.reset(Isometry3::translation(1.0, 2.0, 3.0));
+        let stepped = lunasim_stdin.step(4);
+        lunasim_stdin.notify_stepped();
+        stepped.block_on();
+
+        let mut buf = vec![0; 4096];
+        let n = reader.read(&mut buf).block_on().unwrap();
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&buf[..n]);
+        let msgs: Vec<FromLunasimbot> = std::iter::from_fn(|| decoder.next_frame())
+            .map(|frame| bitcode::decode(&frame.unwrap()).unwrap())
+            .collect();
+        assert!(matches!(msgs[0], FromLunasimbot::SetPaused(true)));
+        let FromLunasimbot::Reset { origin, .. } = msgs[1] else {
+            panic!("Expected a reset");
+        };
+        assert_eq!(origin, [1.0, 2.0, 3.0]);
+        assert!(matches!(msgs[2], FromLunasimbot::Step(4)));
+    }
+}
@@ -1,178 +1,199 @@
 use std::{
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     ops::Deref,
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use cakap2::{packet::Action, Event, PeerStateMachine, RecommendedAction};
-use common::{FromLunabot, LunabotStage};
+use cakap2::{
+    auth::Authenticator,
+    packet::Action,
+    stats::ConnectionStats,
+    udp::{AsyncDriver, DriverError, PeerEvent, PeerSender, Remote},
+    PeerStateMachine,
+};
+use common::{
+    discovery::{DiscoveryMessage, BEACON_INTERVAL_MS, DISCOVERY_PORT},
+    FromLunabase, FromLunabot, LunabotStage, PING_INTERVAL_MS,
+};
 use crossbeam::atomic::AtomicCell;
 use urobotics::{
     get_tokio_handle,
-    log::{error, warn},
-    tokio::{self, net::UdpSocket, sync::mpsc},
+    log::{error, info, warn},
+    tokio::{self, net::UdpSocket},
 };
 
 #[derive(Clone)]
 pub struct PacketBuilder {
-    builder: cakap2::packet::PacketBuilder,
-    packet_tx: mpsc::UnboundedSender<Action>,
+    sender: PeerSender,
 }
 
 impl Deref for PacketBuilder {
     type Target = cakap2::packet::PacketBuilder;
 
     fn deref(&self) -> &Self::Target {
-        &self.builder
+        self.sender.packet_builder()
     }
 }
 
 impl PacketBuilder {
     pub fn send_packet(&self, packet: Action) {
-        let _ = self.packet_tx.send(packet);
+        self.sender.send(packet);
+    }
+
+    /// Returns the statistics of the link to the lunabase, as of the last time the connection was polled.
+    pub fn link_stats(&self) -> ConnectionStats {
+        self.sender.stats()
     }
 }
 
 pub struct LunabaseConn<F> {
-    pub lunabase_address: SocketAddr,
+    /// The address of the lunabase, or `None` to broadcast beacons until a lunabase claims the lunabot.
+    pub lunabase_address: Option<SocketAddr>,
+    /// The name that the lunabase lists the lunabot by while it is broadcasting beacons.
+    pub name: String,
     pub on_msg: F,
     pub lunabot_stage: Arc<AtomicCell<LunabotStage>>,
+    /// Authenticates packets to and from the lunabase, if set.
+    pub authenticator: Option<Authenticator>,
 }
 
-impl<F: FnMut(&[u8]) -> bool + Send + 'static> LunabaseConn<F> {
+impl<F: FnMut(&[u8]) + Send + 'static> LunabaseConn<F> {
     /// Connect to the lunabase and return a [`PacketBuilder`] to send packets to the lunabase.
     ///
-    /// The `on_msg` closure is called whenever a message is received from the lunabase, and must
-    /// return `true` if the message was successfully parsed, and `false` otherwise.
-    pub fn connect_to_lunabase(mut self) -> PacketBuilder {
-        let mut cakap_sm = PeerStateMachine::new(Duration::from_millis(150), 1024);
-        let packet_builder = cakap_sm.get_packet_builder();
-        let (packet_tx, mut packet_rx) = mpsc::unbounded_channel();
+    /// The `on_msg` closure is called whenever a message is received from the lunabase.
+    pub fn connect_to_lunabase(self) -> PacketBuilder {
+        let Self {
+            lunabase_address,
+            name,
+            mut on_msg,
+            lunabot_stage,
+            authenticator,
+        } = self;
+        let (mut driver, sender, mut receiver) = AsyncDriver::new(
+            PeerStateMachine::new(Duration::from_millis(150), 1024),
+            Remote::Connected,
+        );
+        driver.set_authenticator(authenticator);
+        let ping_sender = sender.clone();
+        let pong_sender = sender.clone();
 
         get_tokio_handle().spawn(async move {
-            let udp = loop {
-                let udp = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Failed to bind to lunabase address: {e}");
+            let udp = match lunabase_address {
+                Some(lunabase_address) => loop {
+                    let udp =
+                        match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await {
+                            Ok(x) => x,
+                            Err(e) => {
+                                error!("Failed to bind to lunabase address: {e}");
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                                continue;
+                            }
+                        };
+                    if let Err(e) = udp.connect(lunabase_address).await {
+                        error!("Failed to connect to lunabase: {e}");
                         tokio::time::sleep(Duration::from_secs(1)).await;
                         continue;
                     }
-                };
-                if let Err(e) = udp.connect(self.lunabase_address).await {
-                    error!("Failed to connect to lunabase: {e}");
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                    continue;
-                }
-                break udp;
+                    break udp;
+                },
+                None => discover_lunabase(&name).await,
             };
 
-            let mut action: RecommendedAction<'_, '_> = cakap_sm.send_reconnection_msg(Instant::now()).0;
-            let mut wait_for: Option<Duration>;
-
-            macro_rules! send {
-                ($data: expr) => {{
-                    loop {
-                        if let Err(e) = udp.send($data).await {
-                            error!("Failed to send data to lunabase: {e}");
-                            continue;
-                        }
-                        action = cakap_sm.poll(Event::NoEvent, Instant::now());
-                        break;
-                    }
-                }};
-            }
-
-            let mut buf= [0u8; 1408];
-            macro_rules! handle {
-                () => {
-                    loop {
-                        match action {
-                            RecommendedAction::WaitForData => {
-                                wait_for = None;
-                                break;
-                            }
-                            RecommendedAction::WaitForDuration(duration) => {
-                                wait_for = Some(duration);
-                                break;
-                            }
-                            RecommendedAction::HandleError(cakap_error) => {
-                                error!("{cakap_error}");
-                                action = cakap_sm.poll(Event::NoEvent, Instant::now());
-                            }
-                            RecommendedAction::HandleData(received) => {
-                                (self.on_msg)(&received);
-                                action = cakap_sm.poll(Event::NoEvent, Instant::now());
-                            }
-                            RecommendedAction::HandleDataAndSend { received, to_send } =>  if (self.on_msg)(&received) {
-                                send!(&to_send);
-                            }
-                            RecommendedAction::SendData(hot_packet) => {
-                                send!(&hot_packet);
-                            }
-                        }
+            tokio::spawn(async move {
+                let mut bitcode_buffer = bitcode::Buffer::new();
+                loop {
+                    let bytes = bitcode_buffer.encode(&FromLunabot::Ping(lunabot_stage.load()));
+                    if let Err(e) = ping_sender.send_unreliable(bytes.to_vec()) {
+                        error!("Failed to build ping: {e}");
                     }
+                    tokio::time::sleep(Duration::from_millis(PING_INTERVAL_MS)).await;
                 }
-            }
-            handle!();
-            let mut bitcode_buffer = bitcode::Buffer::new();
-            let mut ping_at = tokio::time::Instant::now();
+            });
 
-            loop {
-                tokio::select! {
-                    _ = tokio::time::sleep_until(ping_at) => {
-                        let bytes = bitcode_buffer.encode(&FromLunabot::Ping(self.lunabot_stage.load()));
-                        let packet = cakap_sm.get_packet_builder().new_unreliable(bytes.to_vec().into()).unwrap();
-                        action = cakap_sm.poll(Event::Action(Action::SendUnreliable(packet)), Instant::now());
-                        handle!();
-                        ping_at = tokio::time::Instant::now() + Duration::from_millis(800);
-                        continue;
-                    }
-                    _ = async {
-                        if let Some(duration) = wait_for {
-                            tokio::time::sleep(duration).await;
-                        } else {
-                            std::future::pending::<()>().await;
-                        }
-                    } => {
-                        action = cakap_sm.poll(Event::NoEvent, Instant::now());
-                    }
-                    packet = async {
-                        if let Some(packet) = packet_rx.recv().await {
-                            packet
-                        } else {
-                            warn!("Packet channel closed");
-                            std::future::pending().await
-                        }
-                    } => {
-                        action = cakap_sm.poll(Event::Action(packet), Instant::now());
-                    }
-                    result = udp.recv(&mut buf) => {
-                        let n = match result {
-                            Ok(n) => n,
-                            Err(e) => {
-                                if e.kind() == std::io::ErrorKind::ConnectionRefused {
-                                    match self.lunabase_address.ip() {
-                                        IpAddr::V4(Ipv4Addr::LOCALHOST) | IpAddr::V6(Ipv6Addr::LOCALHOST) => continue,
-                                        _ => {}
-                                    }
+            tokio::spawn(async move {
+                let mut bitcode_buffer = bitcode::Buffer::new();
+                while let Some(event) = receiver.recv().await {
+                    match event {
+                        PeerEvent::Received { data, .. } => {
+                            // Answered here so that the round-trip time does not include the rest of the lunabot
+                            if let Ok(FromLunabase::Ping(id)) = bitcode_buffer.decode(&data) {
+                                let bytes = bitcode_buffer.encode(&FromLunabot::Pong(id));
+                                if let Err(e) = pong_sender.send_unreliable(bytes.to_vec()) {
+                                    error!("Failed to build pong: {e}");
                                 }
-                                error!("Failed to receive data from lunabase: {e}");
                                 continue;
                             }
-                        };
-                        // println!("{:?}", &buf[..n]);
-                        action = cakap_sm.poll(Event::IncomingData(&buf[..n]), Instant::now());
+                            on_msg(&data)
+                        }
+                        PeerEvent::Error(DriverError::Rejected(e)) => {
+                            warn!("Rejected packet from lunabase: {e}")
+                        }
+                        PeerEvent::Error(e) => error!("Lunabase connection error: {e}"),
                     }
                 }
-                handle!();
-            }
+                warn!("Lunabase connection closed");
+            });
+
+            driver.run(udp).await;
         });
 
-        PacketBuilder {
-            builder: packet_builder,
-            packet_tx,
+        PacketBuilder { sender }
+    }
+}
+
+/// Broadcasts beacons until a lunabase claims the lunabot, returning a socket that is connected to that lunabase.
+async fn discover_lunabase(name: &str) -> UdpSocket {
+    let beacon = DiscoveryMessage::Beacon {
+        name: name.to_owned(),
+    }
+    .encode();
+    let broadcast = SocketAddrV4::new(Ipv4Addr::BROADCAST, DISCOVERY_PORT);
+    let mut buf = [0; 64];
+    info!("Broadcasting as {name} until a lunabase claims this lunabot");
+    loop {
+        let udp = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to bind to discovery address: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        if let Err(e) = udp.set_broadcast(true) {
+            error!("Failed to enable broadcasting: {e}");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        loop {
+            if let Err(e) = udp.send_to(&beacon, broadcast).await {
+                warn!("Failed to broadcast beacon: {e}");
+            }
+            let claimed_by = match tokio::time::timeout(
+                Duration::from_millis(BEACON_INTERVAL_MS),
+                udp.recv_from(&mut buf),
+            )
+            .await
+            {
+                Ok(Ok((n, from))) => (DiscoveryMessage::decode(&buf[..n])
+                    == Some(DiscoveryMessage::Claim))
+                .then_some(from),
+                Ok(Err(e)) => {
+                    warn!("Failed to receive claim: {e}");
+                    None
+                }
+                Err(_) => None,
+            };
+            let Some(lunabase_address) = claimed_by else {
+                continue;
+            };
+            // The beacons were sent from this socket, so the lunabase expects the lunabot to connect from it
+            if let Err(e) = udp.connect(lunabase_address).await {
+                error!("Failed to connect to lunabase: {e}");
+                continue;
+            }
+            info!("Claimed by lunabase at {lunabase_address}");
+            return udp;
         }
     }
 }
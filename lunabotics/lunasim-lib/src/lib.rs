@@ -1,15 +1,28 @@
 #![feature(try_blocks)]
 
 use std::{
-    io::{stdin, stdout, BufReader, Read, Write},
-    sync::{mpsc::Sender, Arc},
+    collections::VecDeque,
+    io::{stdin, stdout, Read, Write},
+    net::TcpStream,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    time::Instant,
 };
 
-use common::lunasim::{FromLunasim, FromLunasimbot};
+use common::lunasim::{
+    frame,
+    noise::{
+        ApriltagNoiseModel, DepthNoiseModel, FrameJitterModel, InertialNoiseModel, SensorNoise,
+    },
+    transport::{encode_frame, header, FrameDecoder, LunasimAddress},
+    FromLunasim, FromLunasimbot,
+};
 use crossbeam::queue::SegQueue;
 use godot::{
-    classes::Engine,
-    global::{randf_range, randfn},
+    classes::{image::Format, node::ProcessMode, Engine, Image, Os},
+    global::randi,
     prelude::*,
 };
 
@@ -22,23 +35,171 @@ const DEPTH_SCALE: f32 = 0.01;
 
 struct LunasimShared {
     from_lunasimbot: SegQueue<FromLunasimbot>,
-    to_lunasimbot: Sender<FromLunasim>,
+    /// Messages to the lunasimbot, which are held back until the given time to simulate frame-time jitter.
+    to_lunasimbot: Sender<(Instant, FromLunasim)>,
+}
+
+/// Connects to a lunasimbot that is listening at the given [`LunasimAddress`], returning the halves of the
+/// connection.
+fn connect(address: &str) -> std::io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+    let address: LunasimAddress = address
+        .parse()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    match address {
+        LunasimAddress::Tcp(addr) => {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+        }
+        #[cfg(unix)]
+        LunasimAddress::Unix(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path)?;
+            Ok((Box::new(stream.try_clone()?), Box::new(stream)))
+        }
+        #[cfg(not(unix))]
+        LunasimAddress::Unix(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets are not supported on this platform",
+        )),
+    }
+}
+
+fn read_lunasimbot(mut reader: impl Read, shared: &LunasimShared) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut decoder = FrameDecoder::default();
+    let mut bitcode_buffer = bitcode::Buffer::new();
+
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::Interrupted => continue,
+                std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::UnexpectedEof => break,
+                _ => {
+                    godot_error!(
+                        "Faced the following error while reading from the lunasimbot: {e}"
+                    );
+                    break;
+                }
+            },
+        };
+        decoder.push(&buf[..n]);
+        while let Some(result) = decoder.next_frame() {
+            match result {
+                Ok(payload) => match bitcode_buffer.decode(&payload) {
+                    Ok(msg) => shared.from_lunasimbot.push(msg),
+                    Err(e) => godot_error!("Failed to deserialize from the lunasimbot: {e}"),
+                },
+                Err(e) => godot_error!("Failed to read from the lunasimbot: {e}"),
+            }
+        }
+    }
 }
 
+fn write_lunasimbot(mut writer: impl Write, to_lunasimbot_rx: Receiver<(Instant, FromLunasim)>) {
+    let mut bitcode_buffer = bitcode::Buffer::new();
+    let mut frames = header().to_vec();
+    // Sorted by release time
+    let mut pending: VecDeque<(Instant, FromLunasim)> = VecDeque::new();
+
+    loop {
+        if !frames.is_empty() {
+            let result: std::io::Result<()> = try {
+                writer.write_all(&frames)?;
+                writer.flush()?
+            };
+            frames.clear();
+            if let Err(e) = result {
+                godot_error!("Faced the following error while writing to the lunasimbot: {e}");
+            }
+        }
+
+        let received = match pending.front() {
+            Some(&(release, _)) => {
+                match to_lunasimbot_rx
+                    .recv_timeout(release.saturating_duration_since(Instant::now()))
+                {
+                    Ok(x) => Some(x),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match to_lunasimbot_rx.recv() {
+                Ok(x) => Some(x),
+                Err(_) => break,
+            },
+        };
+        if let Some((release, msg)) = received {
+            let i = pending.partition_point(|&(other, _)| other <= release);
+            pending.insert(i, (release, msg));
+        }
+        let now = Instant::now();
+        let due = pending.partition_point(|&(release, _)| release <= now);
+
+        for (_, msg) in pending.drain(..due) {
+            if let Err(e) = encode_frame(bitcode_buffer.encode(&msg), &mut frames) {
+                godot_error!("Failed to send to the lunasimbot: {e}");
+            }
+        }
+    }
+}
+
+/// The noise parameters are described in [`common::lunasim::noise`]. Every parameter is 0 by default, which
+/// disables that kind of noise.
 #[derive(GodotClass)]
 #[class(base=Node)]
 struct Lunasim {
+    /// Whether cameras should render frames for the lunasimbot to detect AprilTags in. Set by passing
+    /// `--camera-frames` after `--` on the command line, as rendering extra frames is not free.
+    #[var]
+    send_camera_frames: bool,
+    /// In m/s².
     #[var]
     accelerometer_deviation: f64,
     #[var]
+    accelerometer_bias_walk: f64,
+    #[var]
+    accelerometer_temperature_drift: f64,
+    /// In rad/s.
+    #[var]
     gyroscope_deviation: f64,
     #[var]
+    gyroscope_bias_walk: f64,
+    #[var]
+    gyroscope_temperature_drift: f64,
+    #[var]
+    imu_warmup_temperature_rise: f64,
+    #[var]
+    imu_warmup_time_constant: f64,
+    #[var]
     depth_deviation: f64,
     #[var]
+    depth_quantization: f64,
+    #[var]
+    depth_missing_probability: f64,
+    #[var]
+    depth_flying_pixel_probability: f64,
+    #[var]
+    depth_edge_threshold: f64,
+    #[var]
     explicit_apriltag_rotation_deviation: f64,
     #[var]
     explicit_apriltag_translation_deviation: f64,
+    #[var]
+    apriltag_dropout_probability: f64,
+    #[var]
+    apriltag_max_distance: f64,
+    #[var]
+    apriltag_max_viewing_angle: f64,
+    #[var]
+    frame_jitter_deviation: f64,
+    #[var]
+    frame_jitter_max_delay: f64,
 
+    noise: SensorNoise,
+    /// The physics frames left to run before pausing, if the lunasimbot asked to step.
+    steps_remaining: Option<u32>,
     shared: Arc<LunasimShared>,
     base: Base<Node>,
 }
@@ -53,84 +214,101 @@ impl INode for Lunasim {
         });
 
         if !Engine::singleton().is_editor_hint() {
+            let connect_to = Os::singleton()
+                .get_cmdline_user_args()
+                .as_slice()
+                .iter()
+                .find_map(|arg| {
+                    arg.to_string()
+                        .strip_prefix("--connect=")
+                        .map(str::to_owned)
+                });
             let shared2 = shared.clone();
             std::thread::spawn(move || {
-                let mut stdin = BufReader::new(stdin().lock());
-                let mut size_buf = [0u8; 4];
-                let mut bytes = Vec::with_capacity(1024);
-                let mut bitcode_buffer = bitcode::Buffer::new();
-
-                macro_rules! handle_err {
-                    ($err: ident) => {{
-                        match $err.kind() {
-                            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::UnexpectedEof => {}
-                            _ => {
-                                godot_error!(
-                                    "Faced the following error while reading stdin: {:?}",
-                                    $err
-                                );
-                            }
-                        }
-                        break;
-                    }};
-                }
-
-                loop {
-                    let size = match stdin.read_exact(&mut size_buf) {
-                        Ok(_) => u32::from_ne_bytes(size_buf),
-                        Err(e) => handle_err!(e),
-                    };
-                    bytes.resize(size as usize, 0u8);
-                    match stdin.read_exact(&mut bytes) {
-                        Ok(_) => {}
-                        Err(e) => handle_err!(e),
-                    }
-                    match bitcode_buffer.decode(&bytes) {
-                        Ok(msg) => shared2.from_lunasimbot.push(msg),
+                let (reader, writer) = match connect_to {
+                    Some(address) => match connect(&address) {
+                        Ok(x) => x,
                         Err(e) => {
-                            godot_error!("Failed to deserialize from lunasim: {e}");
-                            continue;
+                            godot_error!("Failed to connect to the lunasimbot at {address}: {e}");
+                            return;
                         }
-                    }
-                }
+                    },
+                    None => (
+                        Box::new(stdin()) as Box<dyn Read + Send>,
+                        Box::new(stdout()) as Box<dyn Write + Send>,
+                    ),
+                };
+                std::thread::spawn(move || write_lunasimbot(writer, to_lunasimbot_rx));
+                read_lunasimbot(reader, &shared2);
             });
+        }
 
-            std::thread::spawn(move || {
-                let mut stdout = stdout().lock();
-                let mut bitcode_buffer = bitcode::Buffer::new();
+        let seed = randi() as u64;
+        godot_warn!("Using sensor noise seed: {seed}");
 
-                loop {
-                    let Ok(msg) = to_lunasimbot_rx.recv() else {
-                        break;
-                    };
-                    let bytes = bitcode_buffer.encode(&msg);
-                    if bytes.len() > u32::MAX as usize {
-                        godot_error!("Message is too large");
-                    } else {
-                        let len = bytes.len() as u32;
-                        let result: std::io::Result<()> = try {
-                            stdout.write_all(&len.to_ne_bytes())?;
-                            stdout.write_all(bytes)?
-                        };
-                        if let Err(e) = result {
-                            godot_error!("Faced the following error while writing to stdout: {e}");
-                        }
-                    }
-                }
-            });
-        }
+        let send_camera_frames = Os::singleton()
+            .get_cmdline_user_args()
+            .as_slice()
+            .iter()
+            .any(|arg| arg.to_string() == "--camera-frames");
 
         Self {
+            send_camera_frames,
             accelerometer_deviation: 0.0,
+            accelerometer_bias_walk: 0.0,
+            accelerometer_temperature_drift: 0.0,
             gyroscope_deviation: 0.0,
+            gyroscope_bias_walk: 0.0,
+            gyroscope_temperature_drift: 0.0,
+            imu_warmup_temperature_rise: 0.0,
+            imu_warmup_time_constant: 0.0,
             depth_deviation: 0.0,
+            depth_quantization: 0.0,
+            depth_missing_probability: 0.0,
+            depth_flying_pixel_probability: 0.0,
+            depth_edge_threshold: 0.0,
             explicit_apriltag_rotation_deviation: 0.0,
             explicit_apriltag_translation_deviation: 0.0,
+            apriltag_dropout_probability: 0.0,
+            apriltag_max_distance: 0.0,
+            apriltag_max_viewing_angle: 0.0,
+            frame_jitter_deviation: 0.0,
+            frame_jitter_max_delay: 0.0,
+            noise: SensorNoise::new(seed),
+            steps_remaining: None,
             shared,
             base,
         }
     }
 
+    fn ready(&mut self) {
+        // The lunasimbot must be heard while the simulation is paused
+        self.base_mut().set_process_mode(ProcessMode::ALWAYS);
+    }
+
+    fn physics_process(&mut self, _delta: f64) {
+        // This node processes before the rest of the scene, so pausing here stops the frame after the last step
+        let Some(steps_remaining) = self.steps_remaining else {
+            return;
+        };
+        if steps_remaining > 0 {
+            self.steps_remaining = Some(steps_remaining - 1);
+            return;
+        }
+        self.steps_remaining = None;
+        self.set_paused(true);
+        // Sent after every frame that was captured during the steps
+        let now = Instant::now();
+        let release = self
+            .noise
+            .last_release()
+            .map_or(now, |last_release| last_release.max(now));
+        let _ = self
+            .shared
+            .to_lunasimbot
+            .send((release, FromLunasim::Stepped));
+    }
+
     fn process(&mut self, _delta: f64) {
         while let Some(msg) = self.shared.from_lunasimbot.pop() {
             match msg {
@@ -171,27 +349,90 @@ impl INode for Lunasim {
                     self.base_mut()
                         .emit_signal("heightmap", &[heights.to_variant()]);
                 }
+                FromLunasimbot::Reset {
+                    axis,
+                    angle,
+                    origin,
+                } => {
+                    let [x, y, z] = axis;
+                    let basis = Basis::from_axis_angle(Vector3 { x, y, z }, angle);
+                    let [x, y, z] = origin;
+                    let origin = Vector3 { x, y, z };
+
+                    self.base_mut()
+                        .emit_signal("reset", &[Transform3D { basis, origin }.to_variant()]);
+                }
+                FromLunasimbot::SetPaused(paused) => {
+                    self.steps_remaining = None;
+                    self.set_paused(paused);
+                }
+                FromLunasimbot::Step(steps) => {
+                    self.steps_remaining = Some(steps);
+                    self.set_paused(false);
+                }
+                FromLunasimbot::Seed(seed) => {
+                    self.set_noise_seed(seed as i64);
+                    godot::global::seed(seed as i64);
+                    self.base_mut()
+                        .emit_signal("seeded", &[(seed as i64).to_variant()]);
+                }
             }
         }
     }
 }
 
-fn rand_quat(deviation: f64) -> Quaternion {
-    let mut rand_axis = Vector3 {
-        x: randf_range(-1.0, 1.0) as f32,
-        y: randf_range(-1.0, 1.0) as f32,
-        z: randf_range(-1.0, 1.0) as f32,
-    };
-    rand_axis = rand_axis.normalized();
-    let rand_angle = randfn(0.0, deviation) as f32;
-    Quaternion::from_axis_angle(rand_axis, rand_angle)
-}
+impl Lunasim {
+    fn accelerometer_model(&self) -> InertialNoiseModel {
+        InertialNoiseModel {
+            deviation: self.accelerometer_deviation as f32,
+            bias_walk: self.accelerometer_bias_walk as f32,
+            temperature_drift: self.accelerometer_temperature_drift as f32,
+            warmup_temperature_rise: self.imu_warmup_temperature_rise as f32,
+            warmup_time_constant: self.imu_warmup_time_constant as f32,
+        }
+    }
+
+    fn gyroscope_model(&self) -> InertialNoiseModel {
+        InertialNoiseModel {
+            deviation: self.gyroscope_deviation as f32,
+            bias_walk: self.gyroscope_bias_walk as f32,
+            temperature_drift: self.gyroscope_temperature_drift as f32,
+            warmup_temperature_rise: self.imu_warmup_temperature_rise as f32,
+            warmup_time_constant: self.imu_warmup_time_constant as f32,
+        }
+    }
+
+    fn depth_model(&self) -> DepthNoiseModel {
+        DepthNoiseModel {
+            deviation: self.depth_deviation as f32,
+            quantization: self.depth_quantization as f32,
+            missing_probability: self.depth_missing_probability as f32,
+            flying_pixel_probability: self.depth_flying_pixel_probability as f32,
+            edge_threshold: self.depth_edge_threshold as f32,
+        }
+    }
 
-fn rand_vec(deviation: f64) -> Vector3 {
-    Vector3 {
-        x: randfn(0.0, deviation) as f32,
-        y: randfn(0.0, deviation) as f32,
-        z: randfn(0.0, deviation) as f32,
+    fn apriltag_model(&self) -> ApriltagNoiseModel {
+        ApriltagNoiseModel {
+            rotation_deviation: self.explicit_apriltag_rotation_deviation as f32,
+            translation_deviation: self.explicit_apriltag_translation_deviation as f32,
+            dropout_probability: self.apriltag_dropout_probability as f32,
+            max_distance: self.apriltag_max_distance as f32,
+            max_viewing_angle: self.apriltag_max_viewing_angle as f32,
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        if let Some(mut tree) = self.base().get_tree() {
+            tree.set_pause(paused);
+        }
+    }
+
+    fn jitter_model(&self) -> FrameJitterModel {
+        FrameJitterModel {
+            deviation: self.frame_jitter_deviation as f32,
+            max_delay: self.frame_jitter_max_delay as f32,
+        }
     }
 }
 
@@ -205,36 +446,79 @@ impl Lunasim {
     fn transform(transform: Transform3D);
     #[signal]
     fn drive(left: f32, right: f32);
+    /// The robot should move to the given transform, and start over as if the simulation had just started.
+    #[signal]
+    fn reset(transform: Transform3D);
+    /// Anything random in the simulation should be seeded with the given seed.
+    #[signal]
+    fn seeded(seed: i64);
+
+    /// Restarts the noise of every sensor from the given seed, so that a run can be reproduced.
+    #[func]
+    fn set_noise_seed(&mut self, seed: i64) {
+        self.noise = SensorNoise::new(seed as u64);
+    }
 
+    /// Sends depths in meters, stored row by row with the given width, where 0 is no depth.
     #[func]
-    fn send_depth_map(&mut self, depth: Vec<f32>) {
+    fn send_depth_map(&mut self, mut depth: Vec<f32>, width: i64) {
+        let model = self.depth_model();
+        self.noise
+            .depth
+            .apply(&model, &mut depth, width.max(0) as usize);
         let depth = depth
             .into_iter()
-            .map(|d| {
-                (randfn(d as f64, (d as f64).powi(2) * self.depth_deviation).abs() as f32
-                    / DEPTH_SCALE)
-                    .round() as u32
-            })
+            .map(|d| (d / DEPTH_SCALE).round() as u32)
             .collect();
 
-        let _ = self.shared.to_lunasimbot.send(FromLunasim::DepthMap(depth));
+        let release = self
+            .noise
+            .depth_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self
+            .shared
+            .to_lunasimbot
+            .send((release, FromLunasim::DepthMap(depth)));
     }
 
+    /// Sends an acceleration that was measured `delta` seconds after the previous one.
     #[func]
-    fn send_accelerometer(&mut self, id: u64, mut accel: Vector3) {
-        accel += rand_vec(self.accelerometer_deviation);
-        let _ = self.shared.to_lunasimbot.send(FromLunasim::Accelerometer {
-            id: id as usize,
-            acceleration: [accel.x, accel.y, accel.z],
-        });
+    fn send_accelerometer(&mut self, id: u64, accel: Vector3, delta: f32) {
+        let model = self.accelerometer_model();
+        let acceleration =
+            self.noise
+                .accelerometer
+                .apply(&model, [accel.x, accel.y, accel.z], delta);
+        let release = self
+            .noise
+            .imu_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self.shared.to_lunasimbot.send((
+            release,
+            FromLunasim::Accelerometer {
+                id: id as usize,
+                acceleration,
+            },
+        ));
     }
 
     #[func]
-    fn send_gyroscope(&mut self, id: u64, mut angular_difference: Quaternion, delta: f32) {
-        let mut angle = angular_difference.get_angle() / delta;
-
-        angular_difference = rand_quat(self.gyroscope_deviation) * angular_difference;
-        let mut axis = angular_difference.get_axis();
+    fn send_gyroscope(&mut self, id: u64, angular_difference: Quaternion, delta: f32) {
+        let rate = angular_difference.get_angle() / delta;
+        let axis = angular_difference.get_axis();
+        let velocity = if rate.is_finite() && axis.is_finite() {
+            axis * rate
+        } else {
+            Vector3::ZERO
+        };
+        let model = self.gyroscope_model();
+        let [x, y, z] =
+            self.noise
+                .gyroscope
+                .apply(&model, [velocity.x, velocity.y, velocity.z], delta);
+        let velocity = Vector3::new(x, y, z);
+        let mut angle = velocity.length();
+        let mut axis = velocity / angle;
 
         if !angle.is_finite()
             || angle.abs() < 0.001
@@ -247,28 +531,124 @@ impl Lunasim {
             angle = 0.0;
         }
 
-        let _ = self.shared.to_lunasimbot.send(FromLunasim::Gyroscope {
-            id: id as usize,
-            axis: [axis.x, axis.y, axis.z],
-            angle,
-        });
+        let release = self
+            .noise
+            .imu_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self.shared.to_lunasimbot.send((
+            release,
+            FromLunasim::Gyroscope {
+                id: id as usize,
+                axis: [axis.x, axis.y, axis.z],
+                angle,
+            },
+        ));
     }
 
+    /// Sends the ticks that the encoder of the given wheel has counted since the simulation started.
     #[func]
-    fn send_explicit_apriltag(&mut self, robot_transform: Transform3D) {
-        let quat =
-            rand_quat(self.explicit_apriltag_rotation_deviation) * robot_transform.basis.to_quat();
-        let robot_axis = quat.get_axis();
-        let robot_axis = [robot_axis.x, robot_axis.y, robot_axis.z];
-        let origin =
-            robot_transform.origin + rand_vec(self.explicit_apriltag_translation_deviation);
+    fn send_wheel_encoder(&mut self, id: u64, ticks: i64, ticks_per_revolution: i64) {
+        let release = self
+            .noise
+            .drivetrain_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self.shared.to_lunasimbot.send((
+            release,
+            FromLunasim::WheelEncoder {
+                id: id as usize,
+                ticks,
+                ticks_per_revolution: ticks_per_revolution.max(1) as u32,
+            },
+        ));
+    }
+
+    /// Sends the current drawn by the motor of the given wheel, and whether it is stalled.
+    #[func]
+    fn send_motor_current(&mut self, id: u64, amps: f32, stalled: bool) {
+        let release = self
+            .noise
+            .drivetrain_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self.shared.to_lunasimbot.send((
+            release,
+            FromLunasim::MotorCurrent {
+                id: id as usize,
+                amps,
+                stalled,
+            },
+        ));
+    }
+
+    #[func]
+    fn send_battery_voltage(&mut self, volts: f32) {
+        let release = self
+            .noise
+            .drivetrain_jitter
+            .release_time(&self.jitter_model(), Instant::now());
         let _ = self
             .shared
             .to_lunasimbot
-            .send(FromLunasim::ExplicitApriltag {
+            .send((release, FromLunasim::BatteryVoltage { volts }));
+    }
+
+    /// Sends a frame rendered by a camera, converted to grayscale. The focal length is in pixels.
+    #[func]
+    fn send_camera_frame(&mut self, id: u64, mut image: Gd<Image>, focal_length_px: f32) {
+        if !self.send_camera_frames {
+            return;
+        }
+        image.convert(Format::L8);
+        let width = image.get_width() as u32;
+        let height = image.get_height() as u32;
+        let packed = frame::pack(image.get_data().as_slice()).into_boxed_slice();
+        let release = self
+            .noise
+            .camera_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self.shared.to_lunasimbot.send((
+            release,
+            FromLunasim::CameraFrame {
+                id: id as usize,
+                width,
+                height,
+                focal_length_px,
+                packed,
+            },
+        ));
+    }
+
+    /// Sends the transform of the robot as seen from a tag at the given distance, if the tag is detected. The
+    /// viewing angle is the angle between the normal of the tag and the direction to the camera.
+    #[func]
+    fn send_explicit_apriltag(
+        &mut self,
+        robot_transform: Transform3D,
+        distance: f32,
+        viewing_angle: f32,
+    ) {
+        let model = self.apriltag_model();
+        if !self.noise.apriltag.detect(&model, distance, viewing_angle) {
+            return;
+        }
+        let (axis, angle) = self.noise.apriltag.rotation_error(&model);
+        let [x, y, z] = axis;
+        let quat = Quaternion::from_axis_angle(Vector3 { x, y, z }, angle)
+            * robot_transform.basis.to_quat();
+        let robot_axis = quat.get_axis();
+        let robot_axis = [robot_axis.x, robot_axis.y, robot_axis.z];
+        let [x, y, z] = self.noise.apriltag.translation_error(&model);
+        let origin = robot_transform.origin + Vector3 { x, y, z };
+        let release = self
+            .noise
+            .apriltag_jitter
+            .release_time(&self.jitter_model(), Instant::now());
+        let _ = self.shared.to_lunasimbot.send((
+            release,
+            FromLunasim::ExplicitApriltag {
                 robot_axis,
                 robot_angle: quat.get_angle(),
                 robot_origin: [origin.x, origin.y, origin.z],
-            });
+            },
+        ));
     }
 }
@@ -1,16 +1,26 @@
-use std::{cmp::Ordering, collections::BinaryHeap};
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, BinaryHeap},
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use fxhash::FxHashMap;
 use nalgebra::Vector2;
 
+use crate::footprint::{self, Footprint};
+
 struct HeapElement {
-    node: Vector2<u32>,
+    state: State,
     cost: Cost,
 }
 
 impl PartialEq for HeapElement {
     fn eq(&self, other: &Self) -> bool {
-        self.node == other.node
+        self.state == other.state
     }
 }
 
@@ -28,117 +38,271 @@ impl Ord for HeapElement {
     }
 }
 
-pub(crate) fn astar(
-    mut start: Vector2<f64>,
-    mut goal: Vector2<f64>,
-    map_dimension: Vector2<f64>,
+/// Limits on how much work a single search may do.
+///
+/// When a limit is reached, the search stops and the best path found so far is returned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SearchBudget {
+    /// The maximum amount of time to spend searching.
+    pub max_duration: Option<Duration>,
+    /// The maximum number of nodes to expand.
+    pub max_expanded_nodes: Option<usize>,
+}
+
+impl SearchBudget {
+    /// A budget with no limits, which is the default.
+    pub const UNLIMITED: Self = Self {
+        max_duration: None,
+        max_expanded_nodes: None,
+    };
+
+    pub(crate) fn is_exhausted(&self, start_time: Instant, expanded_nodes: usize) -> bool {
+        if let Some(max_expanded_nodes) = self.max_expanded_nodes {
+            if expanded_nodes >= max_expanded_nodes {
+                return true;
+            }
+        }
+        if let Some(max_duration) = self.max_duration {
+            if start_time.elapsed() >= max_duration {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The raw output of [`astar`], before decimation.
+pub(crate) struct AStarOutput {
+    /// The path from the start to the goal, or to the closest reachable point to the goal.
+    pub(crate) path: Vec<Vector2<f64>>,
+    /// Whether or not the last point in `path` is the goal.
+    pub(crate) reached_goal: bool,
+    /// Whether or not the search was stopped early by its [`SearchBudget`].
+    pub(crate) budget_exhausted: bool,
+    /// The number of nodes that were expanded during the search.
+    pub(crate) expanded_nodes: usize,
+}
+
+/// The grid that a search runs on.
+#[derive(Clone, Copy)]
+pub(crate) struct Grid {
+    max_index: Vector2<u32>,
     offset: Vector2<f64>,
     step_size: f64,
-    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
-) -> Vec<Vector2<f64>> {
-    let startf = start;
-    let goalf = goal;
-    let max_index = Vector2::new(
-        (map_dimension.x as f64 / step_size).round() as u32,
-        (map_dimension.y as f64 / step_size).round() as u32,
-    );
+}
 
-    start -= offset;
-    if start.x < 0.0 {
-        start.x = 0.0;
-    }
-    if start.y < 0.0 {
-        start.y = 0.0;
+impl Grid {
+    pub(crate) fn new(map_dimension: Vector2<f64>, offset: Vector2<f64>, step_size: f64) -> Self {
+        Self {
+            max_index: Vector2::new(
+                (map_dimension.x / step_size).round() as u32,
+                (map_dimension.y / step_size).round() as u32,
+            ),
+            offset,
+            step_size,
+        }
     }
-    let mut start = Vector2::new(
-        (start.x / step_size).round() as u32,
-        (start.y / step_size).round() as u32,
-    );
-    if start.x >= max_index.x {
-        start.x = max_index.x;
+
+    fn to_index(self, point: Vector2<f64>) -> Vector2<u32> {
+        let point = point - self.offset;
+        Vector2::new(
+            ((point.x.max(0.0) / self.step_size).round() as u32).min(self.max_index.x),
+            ((point.y.max(0.0) / self.step_size).round() as u32).min(self.max_index.y),
+        )
     }
-    if start.y >= max_index.y {
-        start.y = max_index.y;
+
+    fn to_point(self, index: Vector2<u32>) -> Vector2<f64> {
+        self.step_size * index.cast() + self.offset
     }
+}
+
+enum Step {
+    /// A node was expanded.
+    Expanded,
+    ReachedGoal,
+    /// There are no more nodes to expand.
+    Exhausted,
+}
 
-    goal -= offset;
-    if goal.x < 0.0 {
-        goal.x = 0.0;
+/// A node that a search has reached, along with the direction to the node it was reached from.
+///
+/// With a footprint, the turns that are safe at a node depend on the direction it was reached from, so
+/// each direction is searched separately. Without one, `parent` is always [`Parent::Start`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct State {
+    node: Vector2<u32>,
+    parent: Parent,
+}
+
+/// The state of a single A* search, which can be advanced one node at a time.
+struct Search {
+    grid: Grid,
+    start: Vector2<u32>,
+    goal: Vector2<u32>,
+    /// If `true`, the search runs from the goal to the start, so `is_safe` is called with its
+    /// arguments swapped.
+    backward: bool,
+    footprint: Option<Footprint>,
+    /// The state each state was reached from, or `None` for the start, and the cost of reaching it.
+    parents: FxHashMap<State, (Option<State>, usize)>,
+    to_see: BinaryHeap<HeapElement>,
+    best_heuristic_so_far: usize,
+    best_so_far: State,
+    expanded_nodes: usize,
+}
+
+impl Search {
+    fn new(
+        grid: Grid,
+        start: Vector2<u32>,
+        goal: Vector2<u32>,
+        backward: bool,
+        footprint: Option<Footprint>,
+    ) -> Self {
+        let start_state = State {
+            node: start,
+            parent: Parent::Start,
+        };
+        let mut search = Self {
+            grid,
+            start,
+            goal,
+            backward,
+            footprint,
+            parents: Default::default(),
+            to_see: Default::default(),
+            best_heuristic_so_far: usize::MAX,
+            best_so_far: start_state,
+            expanded_nodes: 0,
+        };
+        search.parents.insert(start_state, (None, 0));
+        search.to_see.push(HeapElement {
+            state: start_state,
+            cost: Cost {
+                heuristic: search.heuristic(start),
+                cost: 0,
+                length: 1,
+            },
+        });
+        search
     }
-    if goal.y < 0.0 {
-        goal.y = 0.0;
+
+    fn heuristic(&self, node: Vector2<u32>) -> usize {
+        ((self.goal.cast::<f64>() - node.cast()).magnitude() * 10.0).round() as usize
     }
-    let mut goal = Vector2::new(
-        (goal.x / step_size).round() as u32,
-        (goal.y / step_size).round() as u32,
-    );
-    if goal.x >= max_index.x {
-        goal.x = max_index.x;
+
+    /// Returns the state for `node` when reached from the given direction.
+    fn state(&self, node: Vector2<u32>, parent: Parent) -> State {
+        State {
+            node,
+            parent: if self.footprint.is_some() {
+                parent
+            } else {
+                Parent::Start
+            },
+        }
     }
-    if goal.y >= max_index.y {
-        goal.y = max_index.y;
+
+    /// Returns every state of `node` that this search has reached, along with its cost.
+    fn states_of(&self, node: Vector2<u32>) -> impl Iterator<Item = (State, usize)> + '_ {
+        self.parents
+            .iter()
+            .filter(move |(state, _)| state.node == node)
+            .map(|(&state, &(_, cost))| (state, cost))
     }
-    let heuristic = |node: Vector2<u32>| {
-        ((goal.cast::<f64>() - node.cast()).magnitude() * 10.0).round() as usize
-    };
 
-    let mut parents: FxHashMap<Vector2<u32>, Parent> = FxHashMap::default();
-    parents.insert(start, Parent::Start);
-    let mut to_see: BinaryHeap<HeapElement> = BinaryHeap::default();
-    let mut best_cost_so_far = Cost {
-        heuristic: usize::MAX,
-        cost: 0,
-        length: 1,
-    };
-    to_see.push(HeapElement {
-        node: start,
-        cost: best_cost_so_far,
-    });
-    let mut best_so_far = start;
+    fn step(
+        &mut self,
+        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+        mut on_discover: impl FnMut(Vector2<u32>),
+    ) -> Step {
+        let max_index = self.grid.max_index;
 
-    while let Some(HeapElement { node, cost }) = to_see.pop() {
-        let successors = {
-            if node == goal {
-                best_cost_so_far = cost;
-                best_so_far = node;
-                break;
-            } else if cost.heuristic < best_cost_so_far.heuristic {
-                best_cost_so_far = cost;
-                best_so_far = node;
+        let (state, cost) = loop {
+            let Some(HeapElement { state, cost }) = self.to_see.pop() else {
+                return Step::Exhausted;
+            };
+            // Skip elements that were pushed before a cheaper way to their state was found
+            if cost.cost <= self.parents[&state].1 {
+                break (state, cost);
             }
+        };
+        let State {
+            node,
+            parent: node_parent,
+        } = state;
+        if cost.heuristic < self.best_heuristic_so_far {
+            self.best_heuristic_so_far = cost.heuristic;
+            self.best_so_far = state;
+        }
+        if node == self.goal {
+            return Step::ReachedGoal;
+        }
+        self.expanded_nodes += 1;
 
-            let node_parent = parents.get(&node).unwrap();
+        let successors = {
             let mut successors = heapless::Vec::<_, 8>::new();
+            let node_point = self.grid.to_point(node);
+            let incoming = node_parent.offset().map(|offset| -offset.cast::<f64>());
             let mut try_add = |next: Vector2<u32>, successor_parent: Parent, cost: usize| {
-                if is_safe(
-                    step_size * node.cast() + offset,
-                    step_size * next.cast() + offset,
+                let next_point = self.grid.to_point(next);
+                let (from, to) = if self.backward {
+                    (next_point, node_point)
+                } else {
+                    (node_point, next_point)
+                };
+                if !footprint::segment_is_safe(
+                    self.footprint,
+                    from,
+                    to,
+                    self.grid.step_size,
+                    &mut is_safe,
                 ) {
-                    successors.push((next, successor_parent, cost)).unwrap();
+                    return;
+                }
+                if let Some(incoming) = incoming {
+                    let outgoing = next_point - node_point;
+                    // The robot drives the other way along the path of a backward search
+                    let (from_heading, to_heading) = if self.backward {
+                        (-outgoing, -incoming)
+                    } else {
+                        (incoming, outgoing)
+                    };
+                    if !footprint::turn_is_safe(
+                        self.footprint,
+                        node_point,
+                        from_heading,
+                        to_heading,
+                        self.grid.step_size,
+                        &mut is_safe,
+                    ) {
+                        return;
+                    }
                 }
+                successors.push((next, successor_parent, cost)).unwrap();
             };
 
-            if *node_parent != Parent::NegX && node.x > 0 {
+            if node_parent != Parent::NegX && node.x > 0 {
                 try_add(node - Vector2::new(1, 0), Parent::PosX, 10);
             }
 
-            if *node_parent != Parent::NegY && node.y > 0 {
+            if node_parent != Parent::NegY && node.y > 0 {
                 try_add(node - Vector2::new(0, 1), Parent::PosY, 10);
             }
 
-            if *node_parent != Parent::PosX && node.x < max_index.x {
+            if node_parent != Parent::PosX && node.x < max_index.x {
                 try_add(node + Vector2::new(1, 0), Parent::NegX, 10);
             }
 
-            if *node_parent != Parent::PosY && node.y < max_index.y {
+            if node_parent != Parent::PosY && node.y < max_index.y {
                 try_add(node + Vector2::new(0, 1), Parent::NegY, 10);
             }
 
-            if *node_parent != Parent::NegXNegY && node.x > 0 && node.y > 0 {
+            if node_parent != Parent::NegXNegY && node.x > 0 && node.y > 0 {
                 try_add(node - Vector2::new(1, 1), Parent::PosXPosY, 14);
             }
 
-            if *node_parent != Parent::NegXPosY && node.x > 0 && node.y < max_index.y {
+            if node_parent != Parent::NegXPosY && node.x > 0 && node.y < max_index.y {
                 try_add(
                     node - Vector2::new(1, 0) + Vector2::new(0, 1),
                     Parent::PosXNegY,
@@ -146,7 +310,7 @@ pub(crate) fn astar(
                 );
             }
 
-            if *node_parent != Parent::PosXNegY && node.x < max_index.x && node.y > 0 {
+            if node_parent != Parent::PosXNegY && node.x < max_index.x && node.y > 0 {
                 try_add(
                     node + Vector2::new(1, 0) - Vector2::new(0, 1),
                     Parent::NegXPosY,
@@ -154,7 +318,7 @@ pub(crate) fn astar(
                 );
             }
 
-            if *node_parent != Parent::PosXPosY && node.x < max_index.x && node.y < max_index.y {
+            if node_parent != Parent::PosXPosY && node.x < max_index.x && node.y < max_index.y {
                 try_add(node + Vector2::new(1, 1), Parent::NegXNegY, 14);
             }
 
@@ -162,59 +326,264 @@ pub(crate) fn astar(
         };
 
         for (successor, parent, added_cost) in successors {
+            let successor = self.state(successor, parent);
             let new_cost = cost.cost + added_cost;
-            let successor_cost = Cost {
-                heuristic: heuristic(successor),
-                cost: new_cost,
-                length: cost.length + 1,
-            };
-
-            to_see.push(HeapElement {
-                node: successor,
-                cost: successor_cost,
+            if let Some(&(_, old_cost)) = self.parents.get(&successor) {
+                if old_cost <= new_cost {
+                    continue;
+                }
+            } else {
+                on_discover(successor.node);
+            }
+            self.parents.insert(successor, (Some(state), new_cost));
+            self.to_see.push(HeapElement {
+                state: successor,
+                cost: Cost {
+                    heuristic: self.heuristic(successor.node),
+                    cost: new_cost,
+                    length: cost.length + 1,
+                },
             });
-            let _ = parents.try_insert(successor, parent);
         }
+
+        Step::Expanded
     }
 
-    let mut path = vec![goalf; best_cost_so_far.length];
-
-    for i in (1..best_cost_so_far.length - 1).rev() {
-        macro_rules! traverse {
-            () => {
-                best_so_far = match parents.get(&best_so_far).unwrap() {
-                    Parent::NegX => best_so_far - Vector2::new(1, 0),
-                    Parent::NegY => best_so_far - Vector2::new(0, 1),
-                    Parent::PosX => best_so_far + Vector2::new(1, 0),
-                    Parent::PosY => best_so_far + Vector2::new(0, 1),
-                    Parent::NegXNegY => best_so_far - Vector2::new(1, 1),
-                    Parent::NegXPosY => best_so_far - Vector2::new(1, 0) + Vector2::new(0, 1),
-                    Parent::PosXNegY => best_so_far + Vector2::new(1, 0) - Vector2::new(0, 1),
-                    Parent::PosXPosY => best_so_far + Vector2::new(1, 1),
-                    Parent::Start => unreachable!(),
-                };
-            };
+    /// Returns the nodes between the start of this search and `state`, excluding both.
+    fn nodes_between(&self, state: State) -> Vec<Vector2<u32>> {
+        let mut nodes = vec![];
+        let mut current = state;
+
+        while let Some(parent) = self.parents[&current].0 {
+            current = parent;
+            if current.node != self.start {
+                nodes.push(current.node);
+            }
+        }
+        nodes.reverse();
+        nodes
+    }
+
+    /// Builds the output of a forward search that stopped at `self.best_so_far`.
+    fn into_output(
+        self,
+        start: Vector2<f64>,
+        goal: Vector2<f64>,
+        budget_exhausted: bool,
+    ) -> AStarOutput {
+        let reached_goal = self.best_so_far.node == self.goal;
+        let mut path = vec![start];
+        path.extend(
+            self.nodes_between(self.best_so_far)
+                .into_iter()
+                .map(|node| self.grid.to_point(node)),
+        );
+
+        if self.best_so_far.node != self.start {
+            if reached_goal {
+                path.push(goal);
+            } else {
+                path.push(self.grid.to_point(self.best_so_far.node));
+            }
+        }
+
+        AStarOutput {
+            path,
+            reached_goal,
+            budget_exhausted: budget_exhausted && !reached_goal,
+            expanded_nodes: self.expanded_nodes,
+        }
+    }
+}
+
+/// Searches for a path from `start` to `goal` on a grid with the given `step_size`.
+///
+/// `start` and `goal` are expected to already be within the map. If the goal is unreachable,
+/// or the budget runs out, the returned path leads to the node closest to the goal that was found.
+pub(crate) fn astar(
+    start: Vector2<f64>,
+    goal: Vector2<f64>,
+    grid: Grid,
+    budget: SearchBudget,
+    footprint: Option<Footprint>,
+    start_time: Instant,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> AStarOutput {
+    let mut search = Search::new(
+        grid,
+        grid.to_index(start),
+        grid.to_index(goal),
+        false,
+        footprint,
+    );
+    let mut budget_exhausted = false;
+
+    loop {
+        if budget.is_exhausted(start_time, search.expanded_nodes) {
+            budget_exhausted = true;
+            break;
+        }
+        match search.step(&mut is_safe, |_| {}) {
+            Step::Expanded => {}
+            Step::ReachedGoal | Step::Exhausted => break,
+        }
+    }
+
+    search.into_output(start, goal, budget_exhausted)
+}
+
+/// Searches from both `start` and `goal` at the same time on the rayon thread pool,
+/// stopping when the two searches meet.
+///
+/// The output has the same meaning as the output of [`astar`], except that the path is not
+/// necessarily the shortest, since the first meeting point is not necessarily on the shortest path.
+pub(crate) fn bidirectional_astar(
+    start: Vector2<f64>,
+    goal: Vector2<f64>,
+    grid: Grid,
+    budget: SearchBudget,
+    footprint: Option<Footprint>,
+    start_time: Instant,
+    is_safe: impl Fn(Vector2<f64>, Vector2<f64>) -> bool + Sync,
+) -> AStarOutput {
+    let start_index = grid.to_index(start);
+    let goal_index = grid.to_index(goal);
+    // Which search discovered each node. `true` is the forward search.
+    let discovered: Mutex<FxHashMap<Vector2<u32>, bool>> = Mutex::new(FxHashMap::from_iter([
+        (start_index, true),
+        (goal_index, false),
+    ]));
+    let meeting: Mutex<Option<Vector2<u32>>> = Mutex::new(None);
+    let stop = AtomicBool::new(false);
+    let expanded_nodes = AtomicUsize::new(0);
+    let budget_exhausted = AtomicBool::new(false);
+
+    let run = |mut search: Search| {
+        let forward = !search.backward;
+        let mut met = None;
+        let mut exhausted = false;
+        // Nodes are collected during a step, so that the other search is only blocked while they
+        // are checked against the nodes it discovered
+        let mut newly_discovered = vec![];
+
+        while !stop.load(atomic::Ordering::Relaxed) {
+            if budget.is_exhausted(start_time, expanded_nodes.load(atomic::Ordering::Relaxed)) {
+                budget_exhausted.store(true, atomic::Ordering::Relaxed);
+                stop.store(true, atomic::Ordering::Relaxed);
+                break;
+            }
+            let step = search.step(&is_safe, |node| newly_discovered.push(node));
+            if !newly_discovered.is_empty() {
+                let mut discovered = discovered.lock().unwrap();
+                for node in newly_discovered.drain(..) {
+                    match discovered.entry(node) {
+                        Entry::Vacant(entry) => {
+                            entry.insert(forward);
+                        }
+                        Entry::Occupied(entry) => {
+                            if *entry.get() != forward && met.is_none() {
+                                met = Some(node);
+                            }
+                        }
+                    }
+                }
+            }
+
+            match step {
+                Step::Expanded => {
+                    expanded_nodes.fetch_add(1, atomic::Ordering::Relaxed);
+                }
+                Step::ReachedGoal => met = Some(search.goal),
+                Step::Exhausted => {
+                    exhausted = true;
+                    stop.store(true, atomic::Ordering::Relaxed);
+                    break;
+                }
+            }
+            if let Some(node) = met {
+                meeting.lock().unwrap().get_or_insert(node);
+                stop.store(true, atomic::Ordering::Relaxed);
+                break;
+            }
         }
-        traverse!();
-        path[i] = step_size * best_so_far.cast() + offset;
-
-        #[cfg(debug_assertions)]
-        {
-            if i == 1 {
-                let pre = best_so_far;
-                traverse!();
-                assert_eq!(
-                    best_so_far,
-                    start,
-                    "pre: {pre:?} {:?}",
-                    parents.get(&pre).unwrap()
+        (search, exhausted)
+    };
+
+    let ((mut forward, forward_exhausted), (backward, _)) = rayon::join(
+        || run(Search::new(grid, start_index, goal_index, false, footprint)),
+        || run(Search::new(grid, goal_index, start_index, true, footprint)),
+    );
+
+    if let Some(meeting) = meeting.into_inner().unwrap() {
+        // With a footprint, the meeting node may have been reached from several directions by each search,
+        // so the cheapest pair of directions that the robot can turn between is used
+        let mut pairs: Vec<_> = forward
+            .states_of(meeting)
+            .flat_map(|(forward_state, forward_cost)| {
+                backward
+                    .states_of(meeting)
+                    .map(move |(backward_state, backward_cost)| {
+                        (forward_cost + backward_cost, forward_state, backward_state)
+                    })
+            })
+            .collect();
+        pairs.sort_by_key(|&(cost, _, _)| cost);
+
+        for (_, forward_state, backward_state) in pairs {
+            let mut path = vec![start];
+            let mut nodes = forward.nodes_between(forward_state);
+            let meeting_index = nodes.len() + 1;
+            if meeting != start_index && meeting != goal_index {
+                nodes.push(meeting);
+            }
+            let mut backward_nodes = backward.nodes_between(backward_state);
+            backward_nodes.reverse();
+            nodes.extend(backward_nodes);
+            path.extend(nodes.into_iter().map(|node| grid.to_point(node)));
+            if start_index != goal_index {
+                path.push(goal);
+            }
+
+            // Each search only checks the turns on its own half of the path
+            let meeting_turn_is_safe = meeting == start_index
+                || meeting == goal_index
+                || footprint::turn_is_safe(
+                    footprint,
+                    path[meeting_index],
+                    path[meeting_index] - path[meeting_index - 1],
+                    path[meeting_index + 1] - path[meeting_index],
+                    grid.step_size,
+                    &is_safe,
                 );
+            if meeting_turn_is_safe {
+                return AStarOutput {
+                    path,
+                    reached_goal: true,
+                    budget_exhausted: false,
+                    expanded_nodes: forward.expanded_nodes + backward.expanded_nodes,
+                };
             }
         }
     }
 
-    path[0] = startf;
-    path
+    let mut budget_exhausted = budget_exhausted.into_inner();
+    if !budget_exhausted && !forward_exhausted {
+        // Either the backward search ran out of nodes first, so the goal is unreachable, or the robot cannot
+        // turn where the searches met. Either way, the forward search must run to completion on its own.
+        loop {
+            if budget.is_exhausted(start_time, forward.expanded_nodes + backward.expanded_nodes) {
+                budget_exhausted = true;
+                break;
+            }
+            match forward.step(&is_safe, |_| {}) {
+                Step::Expanded => {}
+                Step::ReachedGoal | Step::Exhausted => break,
+            }
+        }
+    }
+    let mut output = forward.into_output(start, goal, budget_exhausted);
+    output.expanded_nodes += backward.expanded_nodes;
+    output
 }
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
@@ -230,6 +599,23 @@ enum Parent {
     Start,
 }
 
+impl Parent {
+    /// Returns the offset from a node to its parent, or `None` for the start node.
+    fn offset(self) -> Option<Vector2<i32>> {
+        Some(match self {
+            Parent::NegX => Vector2::new(-1, 0),
+            Parent::NegY => Vector2::new(0, -1),
+            Parent::PosX => Vector2::new(1, 0),
+            Parent::PosY => Vector2::new(0, 1),
+            Parent::NegXNegY => Vector2::new(-1, -1),
+            Parent::NegXPosY => Vector2::new(-1, 1),
+            Parent::PosXNegY => Vector2::new(1, -1),
+            Parent::PosXPosY => Vector2::new(1, 1),
+            Parent::Start => return None,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 struct Cost {
     heuristic: usize,
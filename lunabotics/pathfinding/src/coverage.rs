@@ -0,0 +1,526 @@
+use fxhash::FxHashSet;
+use nalgebra::{Rotation2, Vector2};
+
+use crate::{footprint, Pathfinder, PathfindingStatus};
+
+/// A single straight sweep of the implement over uncovered ground.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoveragePass {
+    pub from: Vector2<f64>,
+    pub to: Vector2<f64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoveragePlan {
+    /// The passes to drive, in order.
+    pub passes: Vec<CoveragePass>,
+    /// The full path from the start point through every pass, including the transitions between passes.
+    pub path: Vec<Vector2<f64>>,
+    /// Passes that could not be reached from the previous pass, and were left out of `path`.
+    pub skipped: Vec<CoveragePass>,
+}
+
+/// Tracks which parts of the map have already been swept by the implement.
+///
+/// This is meant to outlive any single [`CoveragePlan`] so that a plan can be resumed after
+/// being interrupted.
+#[derive(Clone, Debug)]
+pub struct CoverageProgress {
+    resolution: f64,
+    covered: FxHashSet<Vector2<i64>>,
+}
+
+impl CoverageProgress {
+    /// Creates an empty [`CoverageProgress`] that tracks coverage in square cells with the given side length.
+    pub fn new(resolution: f64) -> Self {
+        Self {
+            resolution,
+            covered: Default::default(),
+        }
+    }
+
+    fn to_cell(&self, point: Vector2<f64>) -> Vector2<i64> {
+        (point / self.resolution).map(|x| x.round() as i64)
+    }
+
+    /// Marks every cell within `implement_width / 2` of the segment from `from` to `to` as covered.
+    ///
+    /// Cells exactly on the edge of the swept area are not marked, so that a pass that overlaps the
+    /// edge of a previous pass is not skipped.
+    pub fn mark_covered(&mut self, from: Vector2<f64>, to: Vector2<f64>, implement_width: f64) {
+        let radius = implement_width / 2.0;
+        let min = self.to_cell(from.inf(&to).add_scalar(-radius));
+        let max = self.to_cell(from.sup(&to).add_scalar(radius));
+        let segment = to - from;
+
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                let point = Vector2::new(x, y).cast::<f64>() * self.resolution;
+                let t = if segment.magnitude_squared() == 0.0 {
+                    0.0
+                } else {
+                    ((point - from).dot(&segment) / segment.magnitude_squared()).clamp(0.0, 1.0)
+                };
+                if (from + segment * t - point).magnitude() < radius {
+                    self.covered.insert(Vector2::new(x, y));
+                }
+            }
+        }
+    }
+
+    pub fn is_covered(&self, point: Vector2<f64>) -> bool {
+        self.covered.contains(&self.to_cell(point))
+    }
+
+    /// Forgets all coverage, such as when a new dig zone is chosen.
+    pub fn clear(&mut self) {
+        self.covered.clear();
+    }
+}
+
+/// Plans boustrophedon (back-and-forth) paths that sweep an implement over a polygonal zone.
+#[derive(Clone, Copy, Debug)]
+pub struct CoveragePlanner {
+    /// Used to route between passes. The step size is also the spacing between the points
+    /// that are checked for safety along each pass.
+    pub pathfinder: Pathfinder,
+    /// The width of ground swept by the implement in a single pass. Passes are spaced this far apart.
+    pub implement_width: f64,
+    /// The angle of the passes in radians, measured counterclockwise from the x-axis.
+    ///
+    /// By default, this is `0.0`.
+    pub sweep_angle: f64,
+}
+
+impl CoveragePlanner {
+    pub fn new(pathfinder: Pathfinder, implement_width: f64) -> Self {
+        Self {
+            pathfinder,
+            implement_width,
+            sweep_angle: 0.0,
+        }
+    }
+
+    /// Plans a path starting at `start` that covers every safe and uncovered part of `zone`.
+    ///
+    /// `zone` is a simple polygon given by its vertices in order. Passes are split around
+    /// unsafe points and points already covered in `progress`, so a plan can be resumed by
+    /// replanning with the same `progress`.
+    pub fn plan(
+        &mut self,
+        start: Vector2<f64>,
+        zone: &[Vector2<f64>],
+        progress: &CoverageProgress,
+        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> CoveragePlan {
+        let mut plan = CoveragePlan {
+            path: vec![start],
+            ..Default::default()
+        };
+        if zone.len() < 3 || self.implement_width <= 0.0 {
+            return plan;
+        }
+
+        // Rotate the zone such that passes are parallel to the x-axis
+        let to_sweep = Rotation2::new(-self.sweep_angle);
+        let from_sweep = to_sweep.inverse();
+        let zone: Vec<_> = zone.iter().map(|p| to_sweep * p).collect();
+        let min_y = zone.iter().map(|p| p.y).fold(f64::INFINITY, f64::min);
+        let max_y = zone.iter().map(|p| p.y).fold(f64::NEG_INFINITY, f64::max);
+
+        // Lanes are spaced one implement width apart. If the height of the zone is not a multiple
+        // of the implement width, one more lane is swept along the far edge so it is not left uncovered
+        let height = max_y - min_y;
+        let full_lanes = ((height + 1e-9) / self.implement_width).floor() as usize;
+        let mut lane_ys: Vec<f64> = (0..full_lanes)
+            .map(|i| min_y + (i as f64 + 0.5) * self.implement_width)
+            .collect();
+        if height - full_lanes as f64 * self.implement_width > 1e-9 {
+            lane_ys.push((max_y - self.implement_width / 2.0).max(min_y + height / 2.0));
+        }
+
+        let mut lanes: Vec<Vec<CoveragePass>> = vec![];
+        for y in lane_ys {
+            let mut lane = vec![];
+            for [x0, x1] in scanline(&zone, y) {
+                self.split_interval(x0, x1, y, from_sweep, progress, &mut is_safe, &mut lane);
+            }
+            if !lane.is_empty() {
+                lanes.push(lane);
+            }
+        }
+
+        // Start from whichever corner of the zone is closest
+        let mut best_order = vec![];
+        let mut best_distance = f64::INFINITY;
+        for reverse_lanes in [false, true] {
+            for reverse_first in [false, true] {
+                let order = boustrophedon(&lanes, reverse_lanes, reverse_first);
+                let Some(first) = order.first() else {
+                    return plan;
+                };
+                let distance = (first.from - start).magnitude();
+                if distance < best_distance {
+                    best_distance = distance;
+                    best_order = order;
+                }
+            }
+        }
+
+        let mut current = start;
+        for pass in best_order {
+            if current != pass.from {
+                if footprint::segment_is_safe(
+                    self.pathfinder.footprint,
+                    current,
+                    pass.from,
+                    self.pathfinder.step_size,
+                    &mut is_safe,
+                ) {
+                    plan.path.push(pass.from);
+                } else {
+                    let result = self.pathfinder.pathfind(current, pass.from, &mut is_safe);
+                    if result.status != PathfindingStatus::Success {
+                        plan.skipped.push(pass);
+                        continue;
+                    }
+                    plan.path.extend_from_slice(&result.path[1..]);
+                }
+            }
+            if pass.to != pass.from {
+                plan.path.push(pass.to);
+            }
+            current = pass.to;
+            plan.passes.push(pass);
+        }
+
+        plan
+    }
+
+    /// Splits the interval from `x0` to `x1` along the line at `y` into passes that only contain
+    /// safe and uncovered points.
+    #[allow(clippy::too_many_arguments)]
+    fn split_interval(
+        &self,
+        x0: f64,
+        x1: f64,
+        y: f64,
+        from_sweep: Rotation2<f64>,
+        progress: &CoverageProgress,
+        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+        lane: &mut Vec<CoveragePass>,
+    ) {
+        let step_size = self.pathfinder.step_size;
+        let steps = ((x1 - x0) / step_size).floor() as usize;
+        let mut run: Option<CoveragePass> = None;
+        let mut previous: Option<Vector2<f64>> = None;
+
+        for i in 0..=steps + 1 {
+            let x = if i > steps {
+                if x0 + steps as f64 * step_size >= x1 {
+                    break;
+                }
+                x1
+            } else {
+                x0 + i as f64 * step_size
+            };
+            let point = from_sweep * Vector2::new(x, y);
+            // With a footprint, this also checks that the robot can turn in place here, since a pass may
+            // start or end at any point
+            let usable = !progress.is_covered(point)
+                && footprint::segment_is_safe(
+                    self.pathfinder.footprint,
+                    point,
+                    point,
+                    step_size,
+                    &mut is_safe,
+                );
+
+            match (&mut run, usable) {
+                (Some(pass), true)
+                    if footprint::segment_is_safe(
+                        self.pathfinder.footprint,
+                        previous.unwrap(),
+                        point,
+                        step_size,
+                        &mut is_safe,
+                    ) =>
+                {
+                    pass.to = point
+                }
+                (Some(_), _) => {
+                    lane.push(run.take().unwrap());
+                    if usable {
+                        run = Some(CoveragePass {
+                            from: point,
+                            to: point,
+                        });
+                    }
+                }
+                (None, true) => {
+                    run = Some(CoveragePass {
+                        from: point,
+                        to: point,
+                    })
+                }
+                (None, false) => {}
+            }
+            previous = Some(point);
+        }
+        lane.extend(run);
+    }
+}
+
+/// Returns the intervals along the horizontal line at `y` that are inside `polygon`.
+fn scanline(polygon: &[Vector2<f64>], y: f64) -> Vec<[f64; 2]> {
+    let mut crossings = vec![];
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        // Half-open comparison so that vertices on the line are only counted once
+        if (a.y <= y) != (b.y <= y) {
+            crossings.push(a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x));
+        }
+    }
+    crossings.sort_by(f64::total_cmp);
+    crossings
+        .chunks_exact(2)
+        .map(|pair| [pair[0], pair[1]])
+        .collect()
+}
+
+/// Orders the passes in `lanes` such that each lane is driven in the opposite direction of the last.
+fn boustrophedon(
+    lanes: &[Vec<CoveragePass>],
+    reverse_lanes: bool,
+    reverse_first: bool,
+) -> Vec<CoveragePass> {
+    let mut order = vec![];
+    let mut reverse = reverse_first;
+    let mut push_lane = |lane: &Vec<CoveragePass>| {
+        if reverse {
+            order.extend(lane.iter().rev().map(|pass| CoveragePass {
+                from: pass.to,
+                to: pass.from,
+            }));
+        } else {
+            order.extend(lane.iter().copied());
+        }
+        reverse = !reverse;
+    };
+    if reverse_lanes {
+        lanes.iter().rev().for_each(&mut push_lane);
+    } else {
+        lanes.iter().for_each(&mut push_lane);
+    }
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::footprint::Footprint;
+
+    fn planner() -> CoveragePlanner {
+        CoveragePlanner::new(Pathfinder::<()>::new(Vector2::new(10.0, 10.0), 0.5), 1.0)
+    }
+
+    fn rectangle(width: f64, height: f64) -> [Vector2<f64>; 4] {
+        [
+            Vector2::new(0.0, 0.0),
+            Vector2::new(width, 0.0),
+            Vector2::new(width, height),
+            Vector2::new(0.0, height),
+        ]
+    }
+
+    #[test]
+    fn test_rectangle_coverage() {
+        let plan = planner().plan(
+            Vector2::new(0.0, 0.0),
+            &rectangle(4.0, 2.0),
+            &CoverageProgress::new(0.1),
+            |_, _| true,
+        );
+        assert_eq!(
+            plan.passes,
+            [
+                CoveragePass {
+                    from: Vector2::new(0.0, 0.5),
+                    to: Vector2::new(4.0, 0.5)
+                },
+                CoveragePass {
+                    from: Vector2::new(4.0, 1.5),
+                    to: Vector2::new(0.0, 1.5)
+                },
+            ]
+        );
+        assert_eq!(
+            plan.path,
+            [
+                Vector2::new(0.0, 0.0),
+                Vector2::new(0.0, 0.5),
+                Vector2::new(4.0, 0.5),
+                Vector2::new(4.0, 1.5),
+                Vector2::new(0.0, 1.5),
+            ]
+        );
+        assert!(plan.skipped.is_empty());
+    }
+
+    #[test]
+    fn test_starts_at_closest_corner() {
+        let plan = planner().plan(
+            Vector2::new(4.0, 2.0),
+            &rectangle(4.0, 2.0),
+            &CoverageProgress::new(0.1),
+            |_, _| true,
+        );
+        assert_eq!(plan.passes[0].from, Vector2::new(4.0, 1.5));
+        assert_eq!(plan.passes[1].to, Vector2::new(4.0, 0.5));
+    }
+
+    #[test]
+    fn test_obstacle_coverage() {
+        // A small rock sits in the middle of the first pass
+        let is_safe = |from: Vector2<f64>, to: Vector2<f64>| {
+            let rock = Vector2::new(2.0, 0.5);
+            let segment = to - from;
+            let t = if segment.magnitude_squared() == 0.0 {
+                0.0
+            } else {
+                ((rock - from).dot(&segment) / segment.magnitude_squared()).clamp(0.0, 1.0)
+            };
+            (from + segment * t - rock).magnitude() > 0.3
+        };
+        let plan = planner().plan(
+            Vector2::new(0.0, 0.0),
+            &rectangle(4.0, 2.0),
+            &CoverageProgress::new(0.1),
+            is_safe,
+        );
+        assert_eq!(plan.passes.len(), 3);
+        assert_eq!(plan.passes[0].to, Vector2::new(1.5, 0.5));
+        assert_eq!(plan.passes[1].from, Vector2::new(2.5, 0.5));
+        for pair in plan.path.windows(2) {
+            assert!(is_safe(pair[0], pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_resume_coverage() {
+        let mut progress = CoverageProgress::new(0.1);
+        progress.mark_covered(Vector2::new(0.0, 0.5), Vector2::new(4.0, 0.5), 1.0);
+        let plan = planner().plan(
+            Vector2::new(4.0, 0.5),
+            &rectangle(4.0, 2.0),
+            &progress,
+            |_, _| true,
+        );
+        assert_eq!(
+            plan.passes,
+            [CoveragePass {
+                from: Vector2::new(4.0, 1.5),
+                to: Vector2::new(0.0, 1.5)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_footprint_coverage() {
+        // A small rock sits between the two passes, where only the sides of the robot can hit it
+        let is_safe = |from: Vector2<f64>, to: Vector2<f64>| {
+            let rock = Vector2::new(2.0, 1.0);
+            let segment = to - from;
+            let t = if segment.magnitude_squared() == 0.0 {
+                0.0
+            } else {
+                ((rock - from).dot(&segment) / segment.magnitude_squared()).clamp(0.0, 1.0)
+            };
+            (from + segment * t - rock).magnitude() > 0.1
+        };
+        let mut planner = planner();
+        let plan = planner.plan(
+            Vector2::new(0.0, 0.0),
+            &rectangle(4.0, 2.0),
+            &CoverageProgress::new(0.1),
+            is_safe,
+        );
+        assert_eq!(plan.passes.len(), 2);
+
+        planner.pathfinder.footprint = Some(Footprint::new(1.0, 1.0));
+        let plan = planner.plan(
+            Vector2::new(0.0, 0.0),
+            &rectangle(4.0, 2.0),
+            &CoverageProgress::new(0.1),
+            is_safe,
+        );
+        assert_eq!(plan.passes.len(), 4);
+        for pass in &plan.passes {
+            assert!(footprint::segment_is_safe(
+                planner.pathfinder.footprint,
+                pass.from,
+                pass.to,
+                0.5,
+                is_safe
+            ));
+        }
+    }
+
+    #[test]
+    fn test_partial_lane_coverage() {
+        // The last lane would fall half outside of the zone, so it is moved to the edge instead
+        let plan = planner().plan(
+            Vector2::new(0.0, 0.0),
+            &rectangle(4.0, 2.5),
+            &CoverageProgress::new(0.1),
+            |_, _| true,
+        );
+        let lanes: Vec<_> = plan.passes.iter().map(|pass| pass.from.y).collect();
+        assert_eq!(lanes, [0.5, 1.5, 2.0]);
+
+        // The edge lane overlaps the lane before it, but is still planned when resuming
+        let mut progress = CoverageProgress::new(0.1);
+        for pass in &plan.passes[..2] {
+            progress.mark_covered(pass.from, pass.to, 1.0);
+        }
+        let plan = planner().plan(
+            Vector2::new(0.0, 1.5),
+            &rectangle(4.0, 2.5),
+            &progress,
+            |_, _| true,
+        );
+        assert_eq!(
+            plan.passes,
+            [CoveragePass {
+                from: Vector2::new(0.0, 2.0),
+                to: Vector2::new(4.0, 2.0)
+            }]
+        );
+    }
+
+    #[test]
+    fn test_concave_coverage() {
+        // A U-shaped zone, where the middle pass is split into two passes
+        let zone = [
+            Vector2::new(0.0, 0.0),
+            Vector2::new(3.0, 0.0),
+            Vector2::new(3.0, 3.0),
+            Vector2::new(2.0, 3.0),
+            Vector2::new(2.0, 1.0),
+            Vector2::new(1.0, 1.0),
+            Vector2::new(1.0, 3.0),
+            Vector2::new(0.0, 3.0),
+        ];
+        let plan = planner().plan(
+            Vector2::new(0.0, 0.0),
+            &zone,
+            &CoverageProgress::new(0.1),
+            |_, _| true,
+        );
+        assert_eq!(plan.passes.len(), 5);
+        assert_eq!(plan.passes[1].from, Vector2::new(3.0, 1.5));
+        assert_eq!(plan.passes[1].to, Vector2::new(2.0, 1.5));
+        assert_eq!(plan.passes[2].from, Vector2::new(1.0, 1.5));
+        assert_eq!(plan.passes[2].to, Vector2::new(0.0, 1.5));
+    }
+}
@@ -2,15 +2,22 @@ use std::cell::RefCell;
 
 use nalgebra::Vector2;
 
+use crate::footprint::{self, Footprint};
+
 thread_local! {
     static DECIMATE_BUFFER: RefCell<Vec<Vector2<f64>>> = RefCell::new(Vec::new());
 }
 
 /// Simplifies the given path by taking safe shortcuts.
 ///
+/// With a footprint, a shortcut is only taken if the robot can also turn onto it, and turn from it
+/// back onto the rest of the path.
+///
 /// The capacity of the given vector may change.
 pub(crate) fn decimate(
     path: &mut Vec<Vector2<f64>>,
+    footprint: Option<Footprint>,
+    spacing: f64,
     mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
 ) {
     if path.len() < 3 {
@@ -19,6 +26,8 @@ pub(crate) fn decimate(
     DECIMATE_BUFFER.with_borrow_mut(|buffer| {
         buffer.clear();
         let mut from = path[0];
+        // The heading at `from`, which is unknown at the start
+        let mut heading = None;
         buffer.push(from);
 
         loop {
@@ -31,7 +40,15 @@ pub(crate) fn decimate(
                 if path[to_index - 1] == from {
                     break;
                 }
-                if is_safe(from, to) {
+                if shortcut_is_safe(
+                    path,
+                    from,
+                    heading,
+                    to_index,
+                    footprint,
+                    spacing,
+                    &mut is_safe,
+                ) {
                     break;
                 }
                 to_index -= 1;
@@ -39,6 +56,7 @@ pub(crate) fn decimate(
             }
 
             buffer.push(to);
+            heading = Some(to - from);
             from = to;
             if !shortened {
                 break;
@@ -47,3 +65,29 @@ pub(crate) fn decimate(
         std::mem::swap(path, buffer);
     });
 }
+
+fn shortcut_is_safe(
+    path: &[Vector2<f64>],
+    from: Vector2<f64>,
+    heading: Option<Vector2<f64>>,
+    to_index: usize,
+    footprint: Option<Footprint>,
+    spacing: f64,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> bool {
+    let to = path[to_index];
+    if !footprint::segment_is_safe(footprint, from, to, spacing, &mut is_safe) {
+        return false;
+    }
+    if let Some(heading) = heading {
+        if !footprint::turn_is_safe(footprint, from, heading, to - from, spacing, &mut is_safe) {
+            return false;
+        }
+    }
+    match path.get(to_index + 1) {
+        Some(&next) => {
+            footprint::turn_is_safe(footprint, to, to - from, next - to, spacing, is_safe)
+        }
+        None => true,
+    }
+}
@@ -0,0 +1,291 @@
+use std::f64::consts::{PI, TAU};
+
+use k::link::{Collision, Geometry};
+use nalgebra::{Isometry3, Point3, Vector2};
+
+/// A rectangle that approximates the shape of the robot on the ground.
+///
+/// The rectangle is described relative to the robot's origin and heading, so `front` is the
+/// distance from the origin to the front edge, along the direction the robot is facing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Footprint {
+    /// The distance from the origin to the front edge.
+    pub front: f64,
+    /// The distance from the origin to the back edge.
+    pub back: f64,
+    /// The distance from the origin to either side edge.
+    pub half_width: f64,
+}
+
+impl Footprint {
+    /// Creates a footprint of the given size that is centered on the robot's origin.
+    pub fn new(length: f64, width: f64) -> Self {
+        Self {
+            front: length / 2.0,
+            back: length / 2.0,
+            half_width: width / 2.0,
+        }
+    }
+
+    /// Creates the smallest footprint that contains the given collision geometry, projected onto the ground.
+    ///
+    /// `link_origin` is the transform of the link that owns the collision, relative to the robot's origin.
+    /// Like the rest of the robot, the link frame is expected to have `+Y` up and `-Z` forward.
+    ///
+    /// Returns `None` for mesh geometry, as its size is not known without loading the mesh.
+    pub fn from_collision(
+        collision: &Collision<f64>,
+        link_origin: &Isometry3<f64>,
+    ) -> Option<Self> {
+        let half_extents = match collision.geometry {
+            Geometry::Box {
+                depth,
+                width,
+                height,
+            } => [depth / 2.0, width / 2.0, height / 2.0],
+            Geometry::Cylinder { radius, length } => [radius, radius, length / 2.0],
+            Geometry::Capsule { radius, length } => [radius, radius, length / 2.0 + radius],
+            Geometry::Sphere { radius } => [radius; 3],
+            Geometry::Mesh { .. } => return None,
+        };
+        let transform = link_origin * collision.origin();
+
+        let mut footprint = Self {
+            front: f64::NEG_INFINITY,
+            back: f64::NEG_INFINITY,
+            half_width: 0.0,
+        };
+        for i in 0..8 {
+            let corner = transform
+                * Point3::new(
+                    if i & 1 == 0 { -1.0 } else { 1.0 } * half_extents[0],
+                    if i & 2 == 0 { -1.0 } else { 1.0 } * half_extents[1],
+                    if i & 4 == 0 { -1.0 } else { 1.0 } * half_extents[2],
+                );
+            footprint.front = footprint.front.max(-corner.z);
+            footprint.back = footprint.back.max(corner.z);
+            footprint.half_width = footprint.half_width.max(corner.x.abs());
+        }
+        Some(footprint)
+    }
+
+    /// Returns the smallest footprint that contains both footprints.
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            front: self.front.max(other.front),
+            back: self.back.max(other.back),
+            half_width: self.half_width.max(other.half_width),
+        }
+    }
+
+    /// Returns the distance from the origin to the furthest corner.
+    pub fn circumradius(&self) -> f64 {
+        self.front.abs().max(self.back.abs()).hypot(self.half_width)
+    }
+
+    /// Returns the offsets of the lines used to sample the footprint, from one side edge to the other.
+    fn lateral_offsets(self, spacing: f64) -> impl Iterator<Item = f64> {
+        let count = (2.0 * self.half_width / spacing).ceil().max(1.0) as usize;
+        (0..=count).map(move |i| -self.half_width + 2.0 * self.half_width * i as f64 / count as f64)
+    }
+
+    /// Returns `true` iff the robot can move from `from` to `to` while facing along `heading`, which must be
+    /// a unit vector.
+    ///
+    /// The area swept by the footprint is covered with lines along `heading` that are at most `spacing` apart,
+    /// and each line is checked with `is_safe`.
+    fn is_sweep_safe(
+        self,
+        from: Vector2<f64>,
+        to: Vector2<f64>,
+        heading: Vector2<f64>,
+        spacing: f64,
+        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> bool {
+        let normal = Vector2::new(-heading.y, heading.x);
+        self.lateral_offsets(spacing).all(|offset| {
+            is_safe(
+                from + normal * offset - heading * self.back,
+                to + normal * offset + heading * self.front,
+            )
+        })
+    }
+
+    /// Returns `true` iff the robot can turn in place at `at` by `angle` radians, starting at `start_angle`.
+    ///
+    /// The footprint is checked at intermediate angles that are close enough for the corners to move at most
+    /// `spacing` between checks.
+    fn is_rotation_safe(
+        self,
+        at: Vector2<f64>,
+        start_angle: f64,
+        angle: f64,
+        spacing: f64,
+        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> bool {
+        let samples = (angle.abs() * self.circumradius() / spacing)
+            .ceil()
+            .max(1.0) as usize;
+        (0..=samples).all(|i| {
+            let angle = start_angle + angle * i as f64 / samples as f64;
+            let heading = Vector2::new(angle.cos(), angle.sin());
+            self.is_sweep_safe(at, at, heading, spacing, &mut is_safe)
+        })
+    }
+}
+
+/// Returns `true` iff the robot can move from `from` to `to`.
+///
+/// Without a footprint, this is just `is_safe(from, to)`. With one, the area swept by the footprint
+/// is checked, and moving from a point to itself checks that the robot can turn in a full circle there.
+pub(crate) fn segment_is_safe(
+    footprint: Option<Footprint>,
+    from: Vector2<f64>,
+    to: Vector2<f64>,
+    spacing: f64,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> bool {
+    match footprint {
+        None => is_safe(from, to),
+        Some(footprint) if from == to => {
+            footprint.is_rotation_safe(from, 0.0, TAU, spacing, is_safe)
+        }
+        Some(footprint) => {
+            footprint.is_sweep_safe(from, to, (to - from).normalize(), spacing, is_safe)
+        }
+    }
+}
+
+/// Returns `true` iff the robot can turn in place at `at` from facing along `from_heading` to facing
+/// along `to_heading`, taking the shorter way around.
+///
+/// Without a footprint, turning is always safe.
+pub(crate) fn turn_is_safe(
+    footprint: Option<Footprint>,
+    at: Vector2<f64>,
+    from_heading: Vector2<f64>,
+    to_heading: Vector2<f64>,
+    spacing: f64,
+    is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> bool {
+    let Some(footprint) = footprint else {
+        return true;
+    };
+    let start_angle = from_heading.y.atan2(from_heading.x);
+    let mut angle = to_heading.y.atan2(to_heading.x) - start_angle;
+    if angle > PI {
+        angle -= TAU;
+    } else if angle < -PI {
+        angle += TAU;
+    }
+    if angle == 0.0 {
+        // The translations before and after already cover the footprint at this heading
+        return true;
+    }
+    footprint.is_rotation_safe(at, start_angle, angle, spacing, is_safe)
+}
+
+/// Returns `true` iff every segment of `path`, and every turn between segments, is safe.
+pub(crate) fn path_is_safe(
+    footprint: Option<Footprint>,
+    path: &[Vector2<f64>],
+    spacing: f64,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> bool {
+    path.windows(2)
+        .all(|pair| segment_is_safe(footprint, pair[0], pair[1], spacing, &mut is_safe))
+        && path.windows(3).all(|triple| {
+            turn_is_safe(
+                footprint,
+                triple[1],
+                triple[1] - triple[0],
+                triple[2] - triple[1],
+                spacing,
+                &mut is_safe,
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Translation3, UnitQuaternion};
+
+    use super::*;
+
+    /// An `is_safe` function for a map where everything with `x > 1.0` is an obstacle.
+    fn wall(from: Vector2<f64>, to: Vector2<f64>) -> bool {
+        from.x <= 1.0 && to.x <= 1.0
+    }
+
+    #[test]
+    fn test_from_collision() {
+        let collision = Collision::new(
+            "body".into(),
+            Isometry3::from_parts(
+                Translation3::new(0.0, 0.0, -0.1),
+                UnitQuaternion::identity(),
+            ),
+            Geometry::Box {
+                depth: 0.6,
+                width: 0.475,
+                height: 0.5,
+            },
+        );
+        let footprint = Footprint::from_collision(&collision, &Isometry3::identity()).unwrap();
+        assert!((footprint.front - 0.35).abs() < 1e-9);
+        assert!((footprint.back - 0.15).abs() < 1e-9);
+        assert!((footprint.half_width - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_translation_near_wall() {
+        let footprint = Footprint::new(1.0, 1.0);
+        // The center stays clear of the wall, but the side of the robot does not
+        assert!(wall(Vector2::new(0.8, 0.0), Vector2::new(0.8, 5.0)));
+        assert!(!segment_is_safe(
+            Some(footprint),
+            Vector2::new(0.8, 0.0),
+            Vector2::new(0.8, 5.0),
+            0.1,
+            wall
+        ));
+        assert!(segment_is_safe(
+            Some(footprint),
+            Vector2::new(0.0, 0.0),
+            Vector2::new(0.0, 5.0),
+            0.1,
+            wall
+        ));
+    }
+
+    #[test]
+    fn test_turn_near_wall() {
+        // A long, thin robot that fits along the wall, but not across it
+        let footprint = Footprint::new(2.0, 0.2);
+        let at = Vector2::new(0.5, 0.0);
+        assert!(!turn_is_safe(
+            Some(footprint),
+            at,
+            Vector2::new(0.0, 1.0),
+            Vector2::new(1.0, 0.0),
+            0.05,
+            wall
+        ));
+        assert!(turn_is_safe(
+            Some(footprint),
+            at,
+            Vector2::new(0.0, 1.0),
+            Vector2::new(0.0, 1.0),
+            0.05,
+            wall
+        ));
+        assert!(turn_is_safe(
+            None,
+            at,
+            Vector2::new(0.0, 1.0),
+            Vector2::new(1.0, 0.0),
+            0.05,
+            wall
+        ));
+    }
+}
@@ -1,10 +1,16 @@
-#![feature(map_try_insert)]
+use std::time::{Duration, Instant};
 
 use nalgebra::Vector2;
 
 mod astar;
+pub mod coverage;
 mod decimate;
+pub mod footprint;
 pub mod obstacles;
+pub mod waypoints;
+
+pub use astar::SearchBudget;
+use footprint::Footprint;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Pathfinder<F = ()> {
@@ -16,33 +22,80 @@ pub struct Pathfinder<F = ()> {
     pub offset: Vector2<f64>,
     /// The distance between points in the path.
     pub step_size: f64,
+    /// Limits how long a single search may run before the best path found so far is returned.
+    ///
+    /// By default, this is [`SearchBudget::UNLIMITED`].
+    pub budget: SearchBudget,
+    /// The shape of the robot. If this is set, `is_safe` is called on many segments that cover the area
+    /// swept by the robot, including while turning in place, instead of only on the segments of the path.
+    /// The robot must also be able to turn in a full circle at the start and goal.
+    ///
+    /// Segments are spaced at most `step_size` apart, so obstacles smaller than that may be missed.
+    ///
+    /// Each point is searched once for every direction that the robot can arrive from, so searches take
+    /// longer with a footprint.
+    ///
+    /// By default, this is `None`, so the robot is treated as a point.
+    pub footprint: Option<Footprint>,
     /// A closure that returns whether a point is safe to traverse.
     ///
     /// If this is `()`, a function must be provided when calling `pathfind`.
     pub is_safe: F,
 }
 
+/// The parts of a [`Pathfinder`] that do not depend on `is_safe`.
+#[derive(Clone, Copy)]
+struct Settings {
+    map_dimension: Vector2<f64>,
+    offset: Vector2<f64>,
+    step_size: f64,
+    budget: SearchBudget,
+    footprint: Option<Footprint>,
+}
+
+impl<F> Pathfinder<F> {
+    fn settings(&self) -> Settings {
+        Settings {
+            map_dimension: self.map_dimension,
+            offset: self.offset,
+            step_size: self.step_size,
+            budget: self.budget,
+            footprint: self.footprint,
+        }
+    }
+}
+
 impl<F: FnMut(Vector2<f64>, Vector2<f64>) -> bool> Pathfinder<F> {
     pub fn new(map_dimension: Vector2<f64>, step_size: f64, is_safe: F) -> Self {
         Self {
             map_dimension,
             offset: Vector2::new(0.0, 0.0),
             step_size,
+            budget: SearchBudget::UNLIMITED,
+            footprint: None,
             is_safe,
         }
     }
 
-    pub fn pathfind(&mut self, start: Vector2<f64>, goal: Vector2<f64>) -> Vec<Vector2<f64>> {
-        let mut path = astar::astar(
-            start,
-            goal,
-            self.map_dimension,
-            self.offset,
-            self.step_size,
-            &mut self.is_safe,
-        );
-        decimate::decimate(&mut path, &mut self.is_safe);
-        path
+    pub fn pathfind(&mut self, start: Vector2<f64>, goal: Vector2<f64>) -> PathfindingResult {
+        pathfind(start, goal, self.settings(), &mut self.is_safe)
+    }
+}
+
+impl<F: Fn(Vector2<f64>, Vector2<f64>) -> bool + Sync> Pathfinder<F> {
+    /// Like `pathfind`, except that the start and goal are searched from at the same time on the
+    /// rayon thread pool. This is usually faster on large maps.
+    ///
+    /// The search stops as soon as the two halves meet, so the path may be longer than the one
+    /// that `pathfind` returns.
+    ///
+    /// `is_safe` must be symmetric, in that `is_safe(a, b) == is_safe(b, a)`.
+    pub fn pathfind_bidirectional(
+        &self,
+        start: Vector2<f64>,
+        goal: Vector2<f64>,
+    ) -> PathfindingResult {
+        pathfind_bidirectional(start, goal, self.settings(), &self.is_safe)
     }
 }
 
@@ -52,6 +105,8 @@ impl Pathfinder<()> {
             map_dimension,
             offset: Vector2::new(0.0, 0.0),
             step_size,
+            budget: SearchBudget::UNLIMITED,
+            footprint: None,
             is_safe: (),
         }
     }
@@ -60,75 +115,550 @@ impl Pathfinder<()> {
         &mut self,
         start: Vector2<f64>,
         goal: Vector2<f64>,
-        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
-    ) -> Vec<Vector2<f64>> {
-        let mut path = astar::astar(
-            start,
-            goal,
-            self.map_dimension,
-            self.offset,
-            self.step_size,
+        is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> PathfindingResult {
+        pathfind(start, goal, self.settings(), is_safe)
+    }
+
+    /// Like `pathfind`, except that the start and goal are searched from at the same time on the
+    /// rayon thread pool. This is usually faster on large maps.
+    ///
+    /// The search stops as soon as the two halves meet, so the path may be longer than the one
+    /// that `pathfind` returns.
+    ///
+    /// `is_safe` must be symmetric, in that `is_safe(a, b) == is_safe(b, a)`.
+    pub fn pathfind_bidirectional(
+        &self,
+        start: Vector2<f64>,
+        goal: Vector2<f64>,
+        is_safe: impl Fn(Vector2<f64>, Vector2<f64>) -> bool + Sync,
+    ) -> PathfindingResult {
+        pathfind_bidirectional(start, goal, self.settings(), is_safe)
+    }
+}
+
+/// The outcome of a call to `pathfind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathfindingStatus {
+    /// The path leads from the start to the goal.
+    Success,
+    /// The goal is unreachable, so the path leads to the reachable point that is closest to the goal.
+    Partial,
+    /// The [`SearchBudget`] ran out before the goal was found, so the path leads to the point closest
+    /// to the goal that was found so far.
+    BudgetExhausted,
+    /// The start is not safe to occupy. The path is empty.
+    StartInCollision,
+    /// The goal is not safe to occupy. The path is empty.
+    GoalInCollision,
+    /// The start is outside of the map. The path is empty.
+    StartOutOfBounds,
+    /// The goal is outside of the map. The path is empty.
+    GoalOutOfBounds,
+}
+
+/// Statistics about a single search.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PathfindingStats {
+    /// The number of nodes whose successors were explored.
+    pub expanded_nodes: usize,
+    /// The time spent searching and decimating the path.
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathfindingResult {
+    pub status: PathfindingStatus,
+    /// The decimated path, starting at the given start point.
+    ///
+    /// This is only non-empty if `status` is `Success`, `Partial`, or `BudgetExhausted`.
+    pub path: Vec<Vector2<f64>>,
+    pub stats: PathfindingStats,
+}
+
+impl PathfindingResult {
+    fn failed(status: PathfindingStatus, start_time: Instant) -> Self {
+        Self {
+            status,
+            path: vec![],
+            stats: PathfindingStats {
+                expanded_nodes: 0,
+                duration: start_time.elapsed(),
+            },
+        }
+    }
+
+    /// Returns `true` iff the path reaches the goal.
+    pub fn is_success(&self) -> bool {
+        self.status == PathfindingStatus::Success
+    }
+}
+
+/// Returns `true` iff `point` is within the map after being offset.
+fn is_in_bounds(point: Vector2<f64>, map_dimension: Vector2<f64>, offset: Vector2<f64>) -> bool {
+    let point = point - offset;
+    point.x >= 0.0 && point.y >= 0.0 && point.x <= map_dimension.x && point.y <= map_dimension.y
+}
+
+/// Checks the start and goal before searching, returning an error result if either is unusable.
+fn check_endpoints(
+    start: Vector2<f64>,
+    goal: Vector2<f64>,
+    settings: Settings,
+    start_time: Instant,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> Result<(), PathfindingResult> {
+    if !is_in_bounds(start, settings.map_dimension, settings.offset) {
+        return Err(PathfindingResult::failed(
+            PathfindingStatus::StartOutOfBounds,
+            start_time,
+        ));
+    }
+    if !is_in_bounds(goal, settings.map_dimension, settings.offset) {
+        return Err(PathfindingResult::failed(
+            PathfindingStatus::GoalOutOfBounds,
+            start_time,
+        ));
+    }
+    // A point is considered occupiable if the robot can "move" from it to itself
+    let mut is_occupiable = |point| {
+        footprint::segment_is_safe(
+            settings.footprint,
+            point,
+            point,
+            settings.step_size,
             &mut is_safe,
+        )
+    };
+    if !is_occupiable(start) {
+        return Err(PathfindingResult::failed(
+            PathfindingStatus::StartInCollision,
+            start_time,
+        ));
+    }
+    if !is_occupiable(goal) {
+        return Err(PathfindingResult::failed(
+            PathfindingStatus::GoalInCollision,
+            start_time,
+        ));
+    }
+    Ok(())
+}
+
+fn finish(
+    output: astar::AStarOutput,
+    settings: Settings,
+    start_time: Instant,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> PathfindingResult {
+    let astar::AStarOutput {
+        mut path,
+        mut reached_goal,
+        budget_exhausted,
+        expanded_nodes,
+    } = output;
+    decimate::decimate(
+        &mut path,
+        settings.footprint,
+        settings.step_size,
+        &mut is_safe,
+    );
+
+    // The search checks the grid points closest to the start and goal rather than the points themselves,
+    // so the path is cut short before the first segment or turn that is not safe
+    if !footprint::path_is_safe(settings.footprint, &path, settings.step_size, &mut is_safe) {
+        let mut safe_len = 1;
+        while safe_len < path.len()
+            && footprint::path_is_safe(
+                settings.footprint,
+                &path[safe_len.saturating_sub(2)..=safe_len],
+                settings.step_size,
+                &mut is_safe,
+            )
+        {
+            safe_len += 1;
+        }
+        path.truncate(safe_len);
+        reached_goal = false;
+    }
+
+    PathfindingResult {
+        status: if reached_goal {
+            PathfindingStatus::Success
+        } else if budget_exhausted {
+            PathfindingStatus::BudgetExhausted
+        } else {
+            PathfindingStatus::Partial
+        },
+        path,
+        stats: PathfindingStats {
+            expanded_nodes,
+            duration: start_time.elapsed(),
+        },
+    }
+}
+
+fn pathfind(
+    start: Vector2<f64>,
+    goal: Vector2<f64>,
+    settings: Settings,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> PathfindingResult {
+    let start_time = Instant::now();
+    if let Err(result) = check_endpoints(start, goal, settings, start_time, &mut is_safe) {
+        return result;
+    }
+    let grid = astar::Grid::new(settings.map_dimension, settings.offset, settings.step_size);
+    let output = astar::astar(
+        start,
+        goal,
+        grid,
+        settings.budget,
+        settings.footprint,
+        start_time,
+        &mut is_safe,
+    );
+    finish(output, settings, start_time, is_safe)
+}
+
+fn pathfind_bidirectional(
+    start: Vector2<f64>,
+    goal: Vector2<f64>,
+    settings: Settings,
+    is_safe: impl Fn(Vector2<f64>, Vector2<f64>) -> bool + Sync,
+) -> PathfindingResult {
+    let start_time = Instant::now();
+    if let Err(result) = check_endpoints(start, goal, settings, start_time, &is_safe) {
+        return result;
+    }
+    let grid = astar::Grid::new(settings.map_dimension, settings.offset, settings.step_size);
+    let output = astar::bidirectional_astar(
+        start,
+        goal,
+        grid,
+        settings.budget,
+        settings.footprint,
+        start_time,
+        &is_safe,
+    );
+    finish(output, settings, start_time, is_safe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an `is_safe` function for a map with a single circular obstacle.
+    fn circle_obstacle(
+        center: Vector2<f64>,
+        radius: f64,
+    ) -> impl Fn(Vector2<f64>, Vector2<f64>) -> bool + Sync + Copy {
+        move |from, to| {
+            let segment = to - from;
+            let t = if segment.magnitude_squared() == 0.0 {
+                0.0
+            } else {
+                ((center - from).dot(&segment) / segment.magnitude_squared()).clamp(0.0, 1.0)
+            };
+            (from + segment * t - center).magnitude() > radius
+        }
+    }
+
+    fn pathfinder(map_dimension: f64) -> Pathfinder {
+        Pathfinder::<()>::new(Vector2::new(map_dimension, map_dimension), 1.0)
+    }
+
+    #[test]
+    fn test_connected_pathfind() {
+        let result =
+            pathfinder(20.0).pathfind(Vector2::new(0.0, 0.0), Vector2::new(10.0, 0.0), |_, _| true);
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(
+            result.path,
+            vec![Vector2::new(0.0, 0.0), Vector2::new(10.0, 0.0)]
         );
-        decimate::decimate(&mut path, &mut is_safe);
-        path
-    }
-}
-
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-
-//     #[test]
-//     fn test_connected_pathfind() {
-//         let path = pathfind(Vector2::new(0.0, 0.0), Vector2::new(10.0, 0.0), 1.0, |_| {
-//             true
-//         });
-//         assert_eq!(path, vec![Vector2::new(0.0, 0.0), Vector2::new(10.0, 0.0)]);
-//     }
-
-//     #[test]
-//     fn test_disconnected_pathfind() {
-//         let path = pathfind(Vector2::new(0.0, 0.0), Vector2::new(2.0, 0.0), 1.0, |_| {
-//             false
-//         });
-//         assert_eq!(path, [Vector2::new(0.0, 0.0)]);
-//     }
-
-//     #[test]
-//     fn test_diagonal_pathfind() {
-//         let path = pathfind(
-//             Vector2::new(0.0, 0.0),
-//             Vector2::new(10.0, 10.0),
-//             1.0,
-//             |_| true,
-//         );
-//         assert_eq!(path, [Vector2::new(0.0, 0.0), Vector2::new(10.0, 10.0)]);
-//     }
-
-//     #[test]
-//     fn test_centered_pathfind() {
-//         let path = pathfind(
-//             Vector2::new(5.0, 5.0),
-//             Vector2::new(1.12, 0.83),
-//             1.0,
-//             |_| true,
-//         );
-//         assert_eq!(path, [Vector2::new(5.0, 5.0), Vector2::new(1.12, 0.83)]);
-//     }
-
-//     #[test]
-//     fn test_1_obstacle_pathfind() {
-//         let path = pathfind(Vector2::new(0.0, 0.0), Vector2::new(5.0, 0.0), 1.0, |p| {
-//             p.x != 2.0 || p.y != 0.0
-//         });
-//         assert_eq!(
-//             path,
-//             vec![
-//                 Vector2::new(0.0, 0.0),
-//                 Vector2::new(4.0, 1.0),
-//                 Vector2::new(5.0, 0.0)
-//             ]
-//         );
-//     }
-// }
+    }
+
+    #[test]
+    fn test_disconnected_pathfind() {
+        let result = pathfinder(20.0).pathfind(
+            Vector2::new(0.0, 0.0),
+            Vector2::new(2.0, 0.0),
+            |from, to| from == to,
+        );
+        assert_eq!(result.status, PathfindingStatus::Partial);
+        assert_eq!(result.path, [Vector2::new(0.0, 0.0)]);
+        assert_eq!(result.stats.expanded_nodes, 1);
+    }
+
+    #[test]
+    fn test_diagonal_pathfind() {
+        let result =
+            pathfinder(20.0).pathfind(Vector2::new(0.0, 0.0), Vector2::new(10.0, 10.0), |_, _| {
+                true
+            });
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(
+            result.path,
+            [Vector2::new(0.0, 0.0), Vector2::new(10.0, 10.0)]
+        );
+    }
+
+    #[test]
+    fn test_centered_pathfind() {
+        let result =
+            pathfinder(20.0).pathfind(Vector2::new(5.0, 5.0), Vector2::new(1.12, 0.83), |_, _| {
+                true
+            });
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(
+            result.path,
+            [Vector2::new(5.0, 5.0), Vector2::new(1.12, 0.83)]
+        );
+    }
+
+    #[test]
+    fn test_1_obstacle_pathfind() {
+        let mut pathfinder = Pathfinder {
+            map_dimension: Vector2::new(20.0, 20.0),
+            offset: Vector2::new(0.0, 0.0),
+            step_size: 1.0,
+            budget: SearchBudget::UNLIMITED,
+            footprint: None,
+            is_safe: circle_obstacle(Vector2::new(2.5, 0.0), 0.5),
+        };
+        let result = pathfinder.pathfind(Vector2::new(0.0, 0.0), Vector2::new(5.0, 0.0));
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(result.path.first(), Some(&Vector2::new(0.0, 0.0)));
+        assert_eq!(result.path.last(), Some(&Vector2::new(5.0, 0.0)));
+        assert!(result.path.len() > 2);
+        for pair in result.path.windows(2) {
+            assert!((pathfinder.is_safe)(pair[0], pair[1]));
+        }
+    }
+
+    #[test]
+    fn test_offset_pathfind() {
+        let mut pathfinder = pathfinder(10.0);
+        pathfinder.offset = Vector2::new(-5.0, -5.0);
+        let result =
+            pathfinder.pathfind(Vector2::new(-4.0, -4.0), Vector2::new(4.0, 4.0), |_, _| {
+                true
+            });
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(
+            result.path,
+            [Vector2::new(-4.0, -4.0), Vector2::new(4.0, 4.0)]
+        );
+    }
+
+    #[test]
+    fn test_partial_pathfind() {
+        // A wall at x = 5 separates the start from the goal
+        let result = pathfinder(10.0).pathfind(
+            Vector2::new(1.0, 5.0),
+            Vector2::new(8.0, 5.0),
+            |from: Vector2<f64>, to: Vector2<f64>| from == to || from.x.max(to.x) < 5.0,
+        );
+        assert_eq!(result.status, PathfindingStatus::Partial);
+        assert_eq!(result.path.first(), Some(&Vector2::new(1.0, 5.0)));
+        assert_eq!(result.path.last(), Some(&Vector2::new(4.0, 5.0)));
+        assert!(result.stats.expanded_nodes > 0);
+    }
+
+    #[test]
+    fn test_collision_pathfind() {
+        let mut pathfinder = pathfinder(10.0);
+        let result = pathfinder.pathfind(
+            Vector2::new(5.0, 5.0),
+            Vector2::new(0.0, 0.0),
+            circle_obstacle(Vector2::new(5.0, 5.0), 1.0),
+        );
+        assert_eq!(result.status, PathfindingStatus::StartInCollision);
+        assert!(result.path.is_empty());
+
+        let result = pathfinder.pathfind(
+            Vector2::new(0.0, 0.0),
+            Vector2::new(5.5, 5.0),
+            circle_obstacle(Vector2::new(5.0, 5.0), 1.0),
+        );
+        assert_eq!(result.status, PathfindingStatus::GoalInCollision);
+        assert!(result.path.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_bounds_pathfind() {
+        let mut pathfinder = pathfinder(10.0);
+        let result =
+            pathfinder.pathfind(Vector2::new(-1.0, 0.0), Vector2::new(5.0, 5.0), |_, _| true);
+        assert_eq!(result.status, PathfindingStatus::StartOutOfBounds);
+
+        let result =
+            pathfinder.pathfind(Vector2::new(0.0, 0.0), Vector2::new(5.0, 10.5), |_, _| true);
+        assert_eq!(result.status, PathfindingStatus::GoalOutOfBounds);
+        assert!(result.path.is_empty());
+    }
+
+    #[test]
+    fn test_budget_pathfind() {
+        let mut pathfinder = pathfinder(20.0);
+        pathfinder.budget.max_expanded_nodes = Some(3);
+        let result = pathfinder.pathfind(
+            Vector2::new(0.0, 0.0),
+            Vector2::new(10.0, 0.0),
+            circle_obstacle(Vector2::new(5.0, 0.0), 2.0),
+        );
+        assert_eq!(result.status, PathfindingStatus::BudgetExhausted);
+        assert_eq!(result.stats.expanded_nodes, 3);
+        assert_eq!(result.path.first(), Some(&Vector2::new(0.0, 0.0)));
+        assert_eq!(result.path.last(), Some(&Vector2::new(2.0, 0.0)));
+
+        pathfinder.budget = SearchBudget {
+            max_duration: Some(Duration::ZERO),
+            max_expanded_nodes: None,
+        };
+        let result = pathfinder.pathfind(
+            Vector2::new(0.0, 0.0),
+            Vector2::new(10.0, 0.0),
+            circle_obstacle(Vector2::new(5.0, 0.0), 2.0),
+        );
+        assert_eq!(result.status, PathfindingStatus::BudgetExhausted);
+        assert_eq!(result.path, [Vector2::new(0.0, 0.0)]);
+    }
+
+    #[test]
+    fn test_bidirectional_pathfind() {
+        let pathfinder = pathfinder(20.0);
+        let is_safe = circle_obstacle(Vector2::new(10.0, 10.0), 3.0);
+        let result = pathfinder.pathfind_bidirectional(
+            Vector2::new(2.0, 3.0),
+            Vector2::new(18.0, 17.5),
+            is_safe,
+        );
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(result.path.first(), Some(&Vector2::new(2.0, 3.0)));
+        assert_eq!(result.path.last(), Some(&Vector2::new(18.0, 17.5)));
+        for pair in result.path.windows(2) {
+            assert!(is_safe(pair[0], pair[1]));
+        }
+
+        let result = pathfinder.pathfind_bidirectional(
+            Vector2::new(2.0, 3.0),
+            Vector2::new(2.0, 3.0),
+            is_safe,
+        );
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(result.path, [Vector2::new(2.0, 3.0)]);
+    }
+
+    #[test]
+    fn test_bidirectional_partial_pathfind() {
+        let result = pathfinder(10.0).pathfind_bidirectional(
+            Vector2::new(1.0, 5.0),
+            Vector2::new(8.0, 5.0),
+            |from: Vector2<f64>, to: Vector2<f64>| {
+                from == to || from.x.max(to.x) < 5.0 || from.x.min(to.x) > 5.0
+            },
+        );
+        assert_eq!(result.status, PathfindingStatus::Partial);
+        assert_eq!(result.path.last(), Some(&Vector2::new(4.0, 5.0)));
+    }
+
+    /// Returns an `is_safe` function for a map with a wall along `x = 5.0`, which has a narrow gap
+    /// between `y = 2.0` and `y = 3.0`, and a wide gap between `y = 6.5` and `y = 9.5`.
+    fn wall_with_gaps() -> impl Fn(Vector2<f64>, Vector2<f64>) -> bool + Sync + Copy {
+        |from: Vector2<f64>, to: Vector2<f64>| {
+            let samples = ((to - from).magnitude() / 0.05).ceil().max(1.0) as usize;
+            (0..=samples).all(|i| {
+                let point = from.lerp(&to, i as f64 / samples as f64);
+                (point.x - 5.0).abs() > 0.25
+                    || (point.y > 2.0 && point.y < 3.0)
+                    || (point.y > 6.5 && point.y < 9.5)
+            })
+        }
+    }
+
+    #[test]
+    fn test_footprint_pathfind() {
+        let mut pathfinder = Pathfinder::<()>::new(Vector2::new(10.0, 10.0), 0.5);
+        let start = Vector2::new(1.5, 2.5);
+        let goal = Vector2::new(8.5, 2.5);
+
+        let result = pathfinder.pathfind(start, goal, wall_with_gaps());
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert!(result.path.iter().all(|point| point.y < 4.0));
+
+        // The robot is too wide for the narrow gap, so it must go around through the wide one
+        pathfinder.footprint = Some(Footprint::new(1.5, 1.5));
+        let result = pathfinder.pathfind(start, goal, wall_with_gaps());
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert!(result.path.iter().any(|point| point.y > 6.5));
+        assert!(footprint::path_is_safe(
+            pathfinder.footprint,
+            &result.path,
+            pathfinder.step_size,
+            wall_with_gaps()
+        ));
+    }
+
+    #[test]
+    fn test_footprint_start_in_collision() {
+        let mut pathfinder = Pathfinder::<()>::new(Vector2::new(10.0, 10.0), 0.5);
+        let start = Vector2::new(4.0, 5.0);
+        let goal = Vector2::new(1.0, 5.0);
+        assert!(pathfinder
+            .pathfind(start, goal, wall_with_gaps())
+            .is_success());
+
+        // The robot cannot turn around without hitting the wall
+        pathfinder.footprint = Some(Footprint::new(2.0, 1.0));
+        assert_eq!(
+            pathfinder.pathfind(start, goal, wall_with_gaps()).status,
+            PathfindingStatus::StartInCollision
+        );
+    }
+
+    #[test]
+    fn test_footprint_heading_searched() {
+        let wall = [Vector2::new(0.75, 0.25), Vector2::new(0.0, 1.0)];
+        let is_safe = |from: Vector2<f64>, to: Vector2<f64>| {
+            let cross = |a: Vector2<f64>, b: Vector2<f64>| a.x * b.y - a.y * b.x;
+            let path_side = |point| cross(to - from, point - from) > 0.0;
+            let wall_side = |point| cross(wall[1] - wall[0], point - wall[0]) > 0.0;
+            path_side(wall[0]) == path_side(wall[1]) || wall_side(from) == wall_side(to)
+        };
+        let mut pathfinder = Pathfinder::<()>::new(Vector2::new(3.0, 3.0), 0.5);
+        pathfinder.footprint = Some(Footprint::new(1.2, 0.0));
+        let start = Vector2::new(1.5, 1.0);
+        let goal = Vector2::new(0.0, 0.0);
+
+        // Arriving at (1.0, 0.0) diagonally, the robot is far enough from the wall to turn towards the goal
+        let safe_path = [start, Vector2::new(1.5, 0.5), Vector2::new(1.0, 0.0), goal];
+        assert!(footprint::path_is_safe(
+            pathfinder.footprint,
+            &safe_path,
+            pathfinder.step_size,
+            is_safe
+        ));
+
+        // The cheapest way to (0.5, 0.0) is diagonal, and turning there swings the back of the robot
+        // into the wall, so the point must be searched again with another heading
+        let result = pathfinder.pathfind(start, goal, is_safe);
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert!(footprint::path_is_safe(
+            pathfinder.footprint,
+            &result.path,
+            pathfinder.step_size,
+            is_safe
+        ));
+
+        let result = pathfinder.pathfind_bidirectional(start, goal, is_safe);
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert!(footprint::path_is_safe(
+            pathfinder.footprint,
+            &result.path,
+            pathfinder.step_size,
+            is_safe
+        ));
+    }
+}
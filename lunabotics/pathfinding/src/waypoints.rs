@@ -0,0 +1,448 @@
+use std::{collections::VecDeque, time::Instant};
+
+use fxhash::FxHashMap;
+use nalgebra::Vector2;
+
+use crate::{
+    check_endpoints, footprint, pathfind, Pathfinder, PathfindingResult, PathfindingStats,
+    PathfindingStatus, Settings,
+};
+
+type CacheKey = (Vector2<i64>, Vector2<i64>);
+
+/// Remembers successful paths so that repeated trips between the same points are not replanned.
+///
+/// Paths are looked up by the grid cells of their start and goal. Before a cached path is reused, the
+/// start and goal are checked just like they are without a cache, and every segment in the path (and
+/// every turn, if the pathfinder has a footprint) is checked with `is_safe` again. Paths that fail
+/// these checks are forgotten, and the path is replanned instead.
+#[derive(Clone, Debug)]
+pub struct PathCache {
+    max_entries: usize,
+    grid: Option<(Vector2<f64>, Vector2<f64>, f64)>,
+    paths: FxHashMap<CacheKey, Vec<Vector2<f64>>>,
+    order: VecDeque<CacheKey>,
+    hits: usize,
+    misses: usize,
+}
+
+impl Default for PathCache {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl PathCache {
+    /// Creates an empty cache that stores at most `max_entries` paths, forgetting the oldest paths first.
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            grid: None,
+            paths: Default::default(),
+            order: Default::default(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The number of times a cached path was reused.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// The number of times a path had to be planned.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Forgets every path, such as when the map changes completely.
+    pub fn clear(&mut self) {
+        self.paths.clear();
+        self.order.clear();
+    }
+
+    fn pathfind(
+        &mut self,
+        start: Vector2<f64>,
+        goal: Vector2<f64>,
+        settings: Settings,
+        mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> PathfindingResult {
+        let Settings {
+            map_dimension,
+            offset,
+            step_size,
+            footprint,
+            ..
+        } = settings;
+        // Cached paths are only valid for the grid they were planned on
+        if self.grid != Some((map_dimension, offset, step_size)) {
+            self.clear();
+            self.grid = Some((map_dimension, offset, step_size));
+        }
+        let to_cell =
+            |point: Vector2<f64>| ((point - offset) / step_size).map(|x| x.round() as i64);
+        let key = (to_cell(start), to_cell(goal));
+
+        // A start or goal slightly outside of the map, or one that has become occupied, can still
+        // round to the same cells as a cached path
+        if let Err(result) = check_endpoints(start, goal, settings, Instant::now(), &mut is_safe) {
+            self.remove(&key);
+            return result;
+        }
+
+        if let Some(cached) = self.paths.get(&key) {
+            let mut path = cached.clone();
+            path[0] = start;
+            *path.last_mut().unwrap() = goal;
+            if footprint::path_is_safe(footprint, &path, step_size, &mut is_safe) {
+                self.hits += 1;
+                return PathfindingResult {
+                    status: PathfindingStatus::Success,
+                    path,
+                    stats: PathfindingStats::default(),
+                };
+            }
+            self.remove(&key);
+        }
+
+        self.misses += 1;
+        let result = pathfind(start, goal, settings, is_safe);
+        if result.is_success() && result.path.len() >= 2 && self.max_entries > 0 {
+            if self.paths.insert(key, result.path.clone()).is_none() {
+                self.order.push_back(key);
+            }
+            while self.paths.len() > self.max_entries {
+                let Some(oldest) = self.order.pop_front() else {
+                    break;
+                };
+                self.paths.remove(&oldest);
+            }
+        }
+        result
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if self.paths.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+}
+
+/// The result of choosing between several candidate goals.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MultiGoalResult {
+    /// The index of the chosen goal, or `None` if no goals were given.
+    pub goal_index: Option<usize>,
+    /// The path to the chosen goal.
+    pub result: PathfindingResult,
+}
+
+fn path_length(path: &[Vector2<f64>]) -> f64 {
+    path.windows(2)
+        .map(|pair| (pair[1] - pair[0]).magnitude())
+        .sum()
+}
+
+fn pathfind_maybe_cached(
+    cache: &mut Option<&mut PathCache>,
+    start: Vector2<f64>,
+    goal: Vector2<f64>,
+    settings: Settings,
+    is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> PathfindingResult {
+    match cache {
+        Some(cache) => cache.pathfind(start, goal, settings, is_safe),
+        None => pathfind(start, goal, settings, is_safe),
+    }
+}
+
+fn pathfind_waypoints(
+    waypoints: &[Vector2<f64>],
+    mut cache: Option<&mut PathCache>,
+    settings: Settings,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> PathfindingResult {
+    let mut combined = PathfindingResult {
+        status: PathfindingStatus::Success,
+        path: waypoints.first().into_iter().copied().collect(),
+        stats: PathfindingStats::default(),
+    };
+
+    for pair in waypoints.windows(2) {
+        let result = pathfind_maybe_cached(&mut cache, pair[0], pair[1], settings, &mut is_safe);
+        combined.stats.expanded_nodes += result.stats.expanded_nodes;
+        combined.stats.duration += result.stats.duration;
+        combined.status = result.status;
+        if result.path.len() > 1 {
+            combined.path.extend_from_slice(&result.path[1..]);
+        }
+        if !result.is_success() {
+            if result.path.is_empty() && combined.path.len() == 1 {
+                // Nothing was traversable, so keep the same convention as `pathfind`
+                combined.path.clear();
+            }
+            break;
+        }
+    }
+
+    combined
+}
+
+fn pathfind_to_any(
+    start: Vector2<f64>,
+    goals: &[Vector2<f64>],
+    mut cache: Option<&mut PathCache>,
+    settings: Settings,
+    mut is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+) -> MultiGoalResult {
+    let mut best: Option<(usize, PathfindingResult, (u8, f64))> = None;
+    let mut stats = PathfindingStats::default();
+
+    for (i, &goal) in goals.iter().enumerate() {
+        let result = pathfind_maybe_cached(&mut cache, start, goal, settings, &mut is_safe);
+        stats.expanded_nodes += result.stats.expanded_nodes;
+        stats.duration += result.stats.duration;
+
+        // Successful paths are ranked by length, partial paths by how close they get to their goal,
+        // and anything else is only used if nothing better is found.
+        let score = match result.status {
+            PathfindingStatus::Success => (0, path_length(&result.path)),
+            PathfindingStatus::Partial => {
+                let closest = result.path.last().copied().unwrap_or(start);
+                (1, (goal - closest).magnitude())
+            }
+            _ => (2, 0.0),
+        };
+        if best
+            .as_ref()
+            .map(|(_, _, best_score)| score < *best_score)
+            .unwrap_or(true)
+        {
+            best = Some((i, result, score));
+        }
+    }
+
+    match best {
+        Some((i, mut result, _)) => {
+            result.stats = stats;
+            MultiGoalResult {
+                goal_index: Some(i),
+                result,
+            }
+        }
+        None => MultiGoalResult {
+            goal_index: None,
+            result: PathfindingResult {
+                status: PathfindingStatus::GoalOutOfBounds,
+                path: vec![],
+                stats,
+            },
+        },
+    }
+}
+
+impl<F: FnMut(Vector2<f64>, Vector2<f64>) -> bool> Pathfinder<F> {
+    /// Plans a path that visits each of the given waypoints in order, starting at the first.
+    ///
+    /// If any leg cannot be completed, the returned path ends where that leg ended, and the
+    /// status is the status of that leg.
+    pub fn pathfind_waypoints(
+        &mut self,
+        waypoints: &[Vector2<f64>],
+        cache: Option<&mut PathCache>,
+    ) -> PathfindingResult {
+        pathfind_waypoints(waypoints, cache, self.settings(), &mut self.is_safe)
+    }
+
+    /// Plans a path to whichever of the given goals is best.
+    ///
+    /// The best goal is the one with the shortest complete path. If no goal can be reached, the
+    /// goal whose partial path gets closest to it is chosen instead.
+    pub fn pathfind_to_any(
+        &mut self,
+        start: Vector2<f64>,
+        goals: &[Vector2<f64>],
+        cache: Option<&mut PathCache>,
+    ) -> MultiGoalResult {
+        pathfind_to_any(start, goals, cache, self.settings(), &mut self.is_safe)
+    }
+}
+
+impl Pathfinder<()> {
+    /// Plans a path that visits each of the given waypoints in order, starting at the first.
+    ///
+    /// If any leg cannot be completed, the returned path ends where that leg ended, and the
+    /// status is the status of that leg.
+    pub fn pathfind_waypoints(
+        &mut self,
+        waypoints: &[Vector2<f64>],
+        cache: Option<&mut PathCache>,
+        is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> PathfindingResult {
+        pathfind_waypoints(waypoints, cache, self.settings(), is_safe)
+    }
+
+    /// Plans a path to whichever of the given goals is best.
+    ///
+    /// The best goal is the one with the shortest complete path. If no goal can be reached, the
+    /// goal whose partial path gets closest to it is chosen instead.
+    pub fn pathfind_to_any(
+        &mut self,
+        start: Vector2<f64>,
+        goals: &[Vector2<f64>],
+        cache: Option<&mut PathCache>,
+        is_safe: impl FnMut(Vector2<f64>, Vector2<f64>) -> bool,
+    ) -> MultiGoalResult {
+        pathfind_to_any(start, goals, cache, self.settings(), is_safe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pathfinder() -> Pathfinder {
+        Pathfinder::<()>::new(Vector2::new(10.0, 10.0), 1.0)
+    }
+
+    /// A wall along x = 5 from y = 0 to y = 8.
+    fn wall(from: Vector2<f64>, to: Vector2<f64>) -> bool {
+        let crosses = (from.x - 5.0) * (to.x - 5.0) <= 0.0;
+        if !crosses {
+            return true;
+        }
+        let y = if from.x == to.x {
+            from.y.min(to.y)
+        } else {
+            from.y + (5.0 - from.x) / (to.x - from.x) * (to.y - from.y)
+        };
+        y > 8.0
+    }
+
+    #[test]
+    fn test_waypoints() {
+        let result = pathfinder().pathfind_waypoints(
+            &[
+                Vector2::new(0.0, 0.0),
+                Vector2::new(4.0, 0.0),
+                Vector2::new(4.0, 4.0),
+            ],
+            None,
+            |_, _| true,
+        );
+        assert_eq!(result.status, PathfindingStatus::Success);
+        assert_eq!(
+            result.path,
+            [
+                Vector2::new(0.0, 0.0),
+                Vector2::new(4.0, 0.0),
+                Vector2::new(4.0, 4.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_waypoints_failure() {
+        let result = pathfinder().pathfind_waypoints(
+            &[
+                Vector2::new(0.0, 0.0),
+                Vector2::new(4.0, 0.0),
+                Vector2::new(4.0, 20.0),
+                Vector2::new(0.0, 0.0),
+            ],
+            None,
+            |_, _| true,
+        );
+        assert_eq!(result.status, PathfindingStatus::GoalOutOfBounds);
+        assert_eq!(
+            result.path,
+            [Vector2::new(0.0, 0.0), Vector2::new(4.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn test_to_any() {
+        let goals = [
+            Vector2::new(9.0, 0.0),
+            Vector2::new(9.0, 9.0),
+            Vector2::new(4.0, 9.0),
+        ];
+        let result = pathfinder().pathfind_to_any(Vector2::new(0.0, 0.0), &goals, None, wall);
+        assert_eq!(result.goal_index, Some(2));
+        assert_eq!(result.result.status, PathfindingStatus::Success);
+
+        let result = pathfinder().pathfind_to_any(
+            Vector2::new(0.0, 0.0),
+            &[Vector2::new(9.0, 0.0), Vector2::new(6.0, 9.0)],
+            None,
+            |from, to| from == to || from.x.max(to.x) < 5.0,
+        );
+        assert_eq!(result.goal_index, Some(1));
+        assert_eq!(result.result.status, PathfindingStatus::Partial);
+
+        let result = pathfinder().pathfind_to_any(Vector2::new(0.0, 0.0), &[], None, wall);
+        assert_eq!(result.goal_index, None);
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut pathfinder = pathfinder();
+        let mut cache = PathCache::default();
+        let dig = Vector2::new(1.0, 1.0);
+        let dump = Vector2::new(8.0, 1.0);
+
+        let first = pathfinder.pathfind_waypoints(&[dig, dump, dig], Some(&mut cache), wall);
+        assert_eq!(first.status, PathfindingStatus::Success);
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+        assert!(first.stats.expanded_nodes > 0);
+
+        // Starting slightly off of the dig site still reuses the cached paths
+        let second = pathfinder.pathfind_waypoints(
+            &[Vector2::new(1.1, 0.9), dump, dig],
+            Some(&mut cache),
+            wall,
+        );
+        assert_eq!(second.status, PathfindingStatus::Success);
+        assert_eq!((cache.hits(), cache.misses()), (2, 2));
+        assert_eq!(second.stats.expanded_nodes, 0);
+        assert_eq!(second.path[0], Vector2::new(1.1, 0.9));
+        assert_eq!(second.path[1..], first.path[1..]);
+
+        // The wall grew, so the cached paths are no longer safe
+        let third = pathfinder.pathfind_waypoints(&[dig, dump], Some(&mut cache), |from, to| {
+            wall(from, to) && from.y.max(to.y) < 9.0
+        });
+        assert_eq!(third.status, PathfindingStatus::Partial);
+        assert_eq!((cache.hits(), cache.misses()), (2, 3));
+    }
+
+    #[test]
+    fn test_cache_checks_endpoints() {
+        let mut pathfinder = pathfinder();
+        let mut cache = PathCache::default();
+        let start = Vector2::new(0.0, 1.0);
+        let dump = Vector2::new(8.0, 1.0);
+
+        let first = pathfinder.pathfind_waypoints(&[start, dump], Some(&mut cache), wall);
+        assert_eq!(first.status, PathfindingStatus::Success);
+
+        // Rounds to the same cell as the cached start, but is outside of the map
+        let outside =
+            pathfinder.pathfind_waypoints(&[Vector2::new(-0.4, 1.0), dump], Some(&mut cache), wall);
+        assert_eq!(outside.status, PathfindingStatus::StartOutOfBounds);
+
+        // The start has become occupied, even though the segments of the cached path are still safe
+        let blocked =
+            pathfinder.pathfind_waypoints(&[start, dump], Some(&mut cache), |from, to| {
+                wall(from, to) && (from != to || from != start)
+            });
+        assert_eq!(blocked.status, PathfindingStatus::StartInCollision);
+        assert_eq!(cache.hits(), 0);
+
+        // The stale path was forgotten
+        let replanned = pathfinder.pathfind_waypoints(&[start, dump], Some(&mut cache), wall);
+        assert_eq!(replanned.status, PathfindingStatus::Success);
+        assert_eq!((cache.hits(), cache.misses()), (0, 2));
+    }
+}
@@ -0,0 +1,57 @@
+//! Polls a state machine with arbitrary datagrams, which must never panic or keep it from waiting.
+//!
+//! The input is a sequence of datagrams, each preceded by its length and followed by the number of
+//! 20 millisecond steps that pass before the next one.
+#![no_main]
+
+use std::time::{Duration, Instant};
+
+use cakap2::{
+    channel::{Channel, Priority, Reliability},
+    Event, PeerStateMachine, RecommendedAction,
+};
+use libfuzzer_sys::fuzz_target;
+
+/// Takes every action the state machine recommends until it waits.
+fn drain(state_machine: &mut PeerStateMachine, event: Event, now: Instant) {
+    let mut action = state_machine.poll(event, now);
+    for _ in 0..100_000 {
+        if matches!(
+            action,
+            RecommendedAction::WaitForData | RecommendedAction::WaitForDuration(_)
+        ) {
+            return;
+        }
+        action = state_machine.poll(Event::NoEvent, now);
+    }
+    panic!("The state machine never waited");
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut now = Instant::now();
+    let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 64);
+
+    // Packets in flight, so that arbitrary acknowledgements have something to hit
+    let builder = state_machine.get_packet_builder();
+    let ordered = builder.with_channel(Channel::new(1, Priority::Control, Reliability::Ordered));
+    let packets = [
+        builder.new_reliable(vec![1; 8].into()),
+        builder.new_reliable(vec![2; 4000].into()),
+        ordered.new_ordered(vec![3; 8].into()),
+    ];
+    for packet in packets {
+        drain(&mut state_machine, Event::Action(packet.unwrap().into()), now);
+    }
+
+    let mut rest = data;
+    while let Some((&len, tail)) = rest.split_first() {
+        let (datagram, tail) = tail.split_at((len as usize).min(tail.len()));
+        drain(&mut state_machine, Event::IncomingData(datagram), now);
+        let Some((&steps, tail)) = tail.split_first() else {
+            break;
+        };
+        now += Duration::from_millis(steps as u64 * 20);
+        drain(&mut state_machine, Event::NoEvent, now);
+        rest = tail;
+    }
+});
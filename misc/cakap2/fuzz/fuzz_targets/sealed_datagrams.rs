@@ -0,0 +1,34 @@
+//! Opens arbitrary datagrams interleaved with datagrams sealed by the peer, which must always open to
+//! exactly what the peer sealed.
+//!
+//! The first byte of the input chooses the protection. The rest is a sequence of datagrams, each preceded by
+//! its length, with an odd length meaning that the datagram is sealed by the peer instead of taken as is.
+#![no_main]
+
+use cakap2::auth::{Authenticator, PreSharedKey, Protection};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&protection, mut rest)) = data.split_first() else {
+        return;
+    };
+    let protection = if protection % 2 == 0 {
+        Protection::Authenticate
+    } else {
+        Protection::Encrypt
+    };
+    let key = PreSharedKey::new([7; 32]);
+    let mut peer = Authenticator::new(&key, protection);
+    let mut authenticator = Authenticator::new(&key, protection);
+
+    while let Some((&len, tail)) = rest.split_first() {
+        let (datagram, tail) = tail.split_at((len as usize / 2).min(tail.len()));
+        rest = tail;
+        if len % 2 == 1 {
+            let mut sealed = peer.seal(datagram);
+            assert_eq!(authenticator.open(&mut sealed), Ok(datagram));
+        } else {
+            let _ = authenticator.open(&mut datagram.to_vec());
+        }
+    }
+});
@@ -0,0 +1,346 @@
+//! Optional authentication and encryption of packets with a pre-shared key.
+//!
+//! An [`Authenticator`] sits between the transport layer and the [`PeerStateMachine`](crate::PeerStateMachine).
+//! Every packet that the state machine recommends sending is passed through [`Authenticator::seal`] first, and
+//! every datagram from the peer is passed through [`Authenticator::open`] before it is given to the state machine.
+//! Like the state machine, it does not perform any I/O.
+//!
+//! A sealed packet starts with a salt that is chosen randomly when the authenticator is created, followed by a
+//! big-endian `u64` counter. Together, they form the XChaCha20-Poly1305 nonce. The packet ends with the
+//! authentication tag. Packets that fail authentication, and packets that were already received, are rejected.
+//!
+//! A peer that restarts picks a new salt, and the receiver switches over to it. Packets with a salt that was used
+//! before are rejected, so recorded packets from older sessions cannot be replayed, unless they were recorded
+//! before the receiver was created. Rejecting those as well would require a handshake.
+use std::{collections::VecDeque, fmt::Debug};
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, OsRng},
+    AeadInPlace, KeyInit, Tag, XChaCha20Poly1305, XNonce,
+};
+
+use crate::error::AuthError;
+
+const SALT_SIZE: usize = 16;
+const HEADER_SIZE: usize = SALT_SIZE + 8;
+const TAG_SIZE: usize = 16;
+/// The number of bytes that sealing adds to a packet.
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+/// The number of salts from previous sessions of the peer to remember.
+const MAX_PREVIOUS_SALTS: usize = 32;
+
+/// A 256-bit key that is shared by both peers ahead of time.
+#[derive(Clone)]
+pub struct PreSharedKey([u8; 32]);
+
+impl PreSharedKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    /// Parses a key from 64 hexadecimal digits.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim().as_bytes();
+        if hex.len() != 64 {
+            return None;
+        }
+        let mut bytes = [0u8; 32];
+        for (byte, pair) in bytes.iter_mut().zip(hex.chunks_exact(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(Self(bytes))
+    }
+}
+
+impl Debug for PreSharedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PreSharedKey(..)")
+    }
+}
+
+/// How packets are protected. Both peers must use the same protection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protection {
+    /// Packets are authenticated, but sent in plain text.
+    Authenticate,
+    /// Packets are authenticated and encrypted.
+    Encrypt,
+}
+
+/// Counts of the datagrams accepted and rejected by an [`Authenticator`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AuthStats {
+    pub accepted: u64,
+    /// Datagrams that were too small to be sealed packets.
+    pub malformed: u64,
+    /// Datagrams that failed authentication.
+    pub forged: u64,
+    /// Authentic packets that were already received, or that were sent by this peer.
+    pub replayed: u64,
+    /// Authentic packets from a previous session of the peer.
+    pub stale_session: u64,
+}
+
+/// Tracks which of the most recent 64 counters have been received.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set iff `highest - i` has been received.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn contains(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            return false;
+        }
+        let age = self.highest - counter;
+        age >= 64 || self.seen & (1 << age) != 0
+    }
+
+    fn insert(&mut self, counter: u64) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.highest = counter;
+            self.seen |= 1;
+        } else {
+            self.seen |= 1 << (self.highest - counter);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PeerSession {
+    salt: [u8; SALT_SIZE],
+    window: ReplayWindow,
+}
+
+/// Seals outgoing packets and opens incoming ones.
+pub struct Authenticator {
+    cipher: XChaCha20Poly1305,
+    protection: Protection,
+    salt: [u8; SALT_SIZE],
+    /// Counters start at 1, so that a new [`ReplayWindow`] has not seen any counter.
+    next_counter: u64,
+    peer: Option<PeerSession>,
+    previous_salts: VecDeque<[u8; SALT_SIZE]>,
+    stats: AuthStats,
+}
+
+impl Authenticator {
+    pub fn new(key: &PreSharedKey, protection: Protection) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.0.into()),
+            protection,
+            salt,
+            next_counter: 1,
+            peer: None,
+            previous_salts: VecDeque::new(),
+            stats: AuthStats::default(),
+        }
+    }
+
+    /// Returns the given packet with a header and authentication tag, encrypting it if required.
+    pub fn seal(&mut self, packet: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(packet.len() + OVERHEAD);
+        sealed.extend_from_slice(&self.salt);
+        sealed.extend_from_slice(&self.next_counter.to_be_bytes());
+        sealed.extend_from_slice(packet);
+        self.next_counter += 1;
+
+        let nonce = XNonce::clone_from_slice(&sealed[..HEADER_SIZE]);
+        let tag = match self.protection {
+            Protection::Authenticate => {
+                self.cipher
+                    .encrypt_in_place_detached(&nonce, &sealed, &mut [])
+            }
+            Protection::Encrypt => {
+                let (header, body) = sealed.split_at_mut(HEADER_SIZE);
+                self.cipher.encrypt_in_place_detached(&nonce, header, body)
+            }
+        }
+        .expect("Packet should be small enough to seal");
+        sealed.extend_from_slice(&tag);
+        sealed
+    }
+
+    /// Authenticates the given datagram from the peer, returning the packet inside of it.
+    ///
+    /// If the datagram is encrypted, it is decrypted in place.
+    pub fn open<'a>(&mut self, datagram: &'a mut [u8]) -> Result<&'a [u8], AuthError> {
+        let result = self.open_inner(datagram);
+        match &result {
+            Ok(_) => self.stats.accepted += 1,
+            Err(AuthError::Malformed) => self.stats.malformed += 1,
+            Err(AuthError::Forged) => self.stats.forged += 1,
+            Err(AuthError::Replayed) => self.stats.replayed += 1,
+            Err(AuthError::StaleSession) => self.stats.stale_session += 1,
+        }
+        result
+    }
+
+    fn open_inner<'a>(&mut self, datagram: &'a mut [u8]) -> Result<&'a [u8], AuthError> {
+        if datagram.len() < OVERHEAD {
+            return Err(AuthError::Malformed);
+        }
+        let salt: [u8; SALT_SIZE] = datagram[..SALT_SIZE].try_into().unwrap();
+        let counter = u64::from_be_bytes(datagram[SALT_SIZE..HEADER_SIZE].try_into().unwrap());
+        let is_current_session = match &self.peer {
+            Some(peer) if peer.salt == salt => {
+                if peer.window.contains(counter) {
+                    return Err(AuthError::Replayed);
+                }
+                true
+            }
+            _ => {
+                if salt == self.salt {
+                    // Our own packet, reflected back at us
+                    return Err(AuthError::Replayed);
+                }
+                if self.previous_salts.contains(&salt) {
+                    return Err(AuthError::StaleSession);
+                }
+                false
+            }
+        };
+
+        let nonce = XNonce::clone_from_slice(&datagram[..HEADER_SIZE]);
+        let (datagram, tag) = datagram.split_at_mut(datagram.len() - TAG_SIZE);
+        let tag = Tag::clone_from_slice(tag);
+        let result = match self.protection {
+            Protection::Authenticate => {
+                self.cipher
+                    .decrypt_in_place_detached(&nonce, datagram, &mut [], &tag)
+            }
+            Protection::Encrypt => {
+                let (header, body) = datagram.split_at_mut(HEADER_SIZE);
+                self.cipher
+                    .decrypt_in_place_detached(&nonce, header, body, &tag)
+            }
+        };
+        if result.is_err() {
+            return Err(AuthError::Forged);
+        }
+
+        if !is_current_session {
+            // The peer has restarted
+            if let Some(previous) = self.peer.take() {
+                if self.previous_salts.len() >= MAX_PREVIOUS_SALTS {
+                    self.previous_salts.pop_front();
+                }
+                self.previous_salts.push_back(previous.salt);
+            }
+            self.peer = Some(PeerSession {
+                salt,
+                window: ReplayWindow::default(),
+            });
+        }
+        self.peer.as_mut().unwrap().window.insert(counter);
+        Ok(&datagram[HEADER_SIZE..])
+    }
+
+    pub fn stats(&self) -> AuthStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(protection: Protection) -> (Authenticator, Authenticator) {
+        let key = PreSharedKey::new([7; 32]);
+        (
+            Authenticator::new(&key, protection),
+            Authenticator::new(&key, protection),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        for protection in [Protection::Authenticate, Protection::Encrypt] {
+            let (mut a, mut b) = pair(protection);
+            let mut sealed = a.seal(b"steering");
+            assert_eq!(sealed.len(), 8 + OVERHEAD);
+            assert_eq!(
+                sealed.windows(8).any(|w| w == b"steering"),
+                protection == Protection::Authenticate
+            );
+            assert_eq!(b.open(&mut sealed), Ok(b"steering".as_slice()));
+        }
+    }
+
+    #[test]
+    fn reject_forged() {
+        let (mut a, mut b) = pair(Protection::Encrypt);
+        let mut sealed = a.seal(b"steering");
+        sealed[HEADER_SIZE] ^= 1;
+        assert_eq!(b.open(&mut sealed), Err(AuthError::Forged));
+
+        let mut other = Authenticator::new(&PreSharedKey::new([8; 32]), Protection::Encrypt);
+        let mut sealed = other.seal(b"steering");
+        assert_eq!(b.open(&mut sealed), Err(AuthError::Forged));
+        assert_eq!(b.open(&mut [0; 8]), Err(AuthError::Malformed));
+
+        // A peer with a different protection cannot be understood
+        let (mut a, _) = pair(Protection::Authenticate);
+        let mut sealed = a.seal(b"steering");
+        assert_eq!(b.open(&mut sealed), Err(AuthError::Forged));
+        assert_eq!(
+            b.stats(),
+            AuthStats {
+                forged: 3,
+                malformed: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reject_replayed() {
+        let (mut a, mut b) = pair(Protection::Authenticate);
+        let first = a.seal(b"1");
+        let second = a.seal(b"2");
+        assert!(b.open(&mut second.clone()).is_ok());
+        // Reordering is fine, but duplicates are not
+        assert!(b.open(&mut first.clone()).is_ok());
+        assert_eq!(b.open(&mut first.clone()), Err(AuthError::Replayed));
+        assert_eq!(b.open(&mut second.clone()), Err(AuthError::Replayed));
+        // Packets from this peer cannot be reflected back at it
+        assert_eq!(a.open(&mut first.clone()), Err(AuthError::Replayed));
+
+        // Counters too far behind the window are rejected
+        let old = a.seal(b"old");
+        for _ in 0..64 {
+            let mut sealed = a.seal(b"new");
+            b.open(&mut sealed).unwrap();
+        }
+        assert_eq!(b.open(&mut old.clone()), Err(AuthError::Replayed));
+    }
+
+    #[test]
+    fn peer_restart() {
+        let (mut a, mut b) = pair(Protection::Encrypt);
+        let before_restart = a.seal(b"1");
+        b.open(&mut before_restart.clone()).unwrap();
+
+        let mut a = Authenticator::new(&PreSharedKey::new([7; 32]), Protection::Encrypt);
+        let mut after_restart = a.seal(b"1");
+        assert_eq!(b.open(&mut after_restart), Ok(b"1".as_slice()));
+        assert_eq!(
+            b.open(&mut before_restart.clone()),
+            Err(AuthError::StaleSession)
+        );
+    }
+
+    #[test]
+    fn key_from_hex() {
+        let key = PreSharedKey::from_hex(&"0f".repeat(32)).unwrap();
+        assert_eq!(key.0, [15; 32]);
+        assert!(PreSharedKey::from_hex("0f").is_none());
+        assert!(PreSharedKey::from_hex(&"0g".repeat(32)).is_none());
+    }
+}
@@ -0,0 +1,131 @@
+//! Logical channels that are multiplexed over the same peer.
+//!
+//! The id of the channel of a packet is stored in bits 56 to 60 of its index, which reliable indices never
+//! reach, so unreliable packets and acknowledgements carry it as well. Each channel has its own stream of
+//! ordered packets, so a lost packet on one channel does not hold back ordered packets on other channels.
+//!
+//! The priority and retransmission policy of a channel only affect the sender. When packets are held back by
+//! the send rate limit, or are waiting to be retransmitted, packets of a higher priority are always sent first.
+use std::num::NonZeroU32;
+
+/// The number of channels, so channel ids range from 0 to 31.
+pub const CHANNEL_COUNT: usize = 32;
+pub(crate) const CHANNEL_SHIFT: u32 = 56;
+/// The bits of an index that hold the channel id.
+pub(crate) const CHANNEL_MASK: u64 = (CHANNEL_COUNT as u64 - 1) << CHANNEL_SHIFT;
+
+/// Returns the id of the channel of the packet with the given index.
+pub(crate) fn channel_of(index: u64) -> u8 {
+    ((index & CHANNEL_MASK) >> CHANNEL_SHIFT) as u8
+}
+
+/// Which packets are sent first when they cannot all be sent immediately, in order of decreasing priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Commands that must get through, such as stopping.
+    Control,
+    /// Periodic state, such as sensor readings.
+    Telemetry,
+    /// Large transfers that can wait, such as logs or maps.
+    Bulk,
+}
+
+impl Priority {
+    pub(crate) const COUNT: usize = 3;
+}
+
+/// How packets on a channel are delivered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reliability {
+    /// Packets may be lost, and are handled in whatever order they arrive.
+    Unreliable,
+    /// Packets are retransmitted until they are acknowledged, and are handled in whatever order they arrive.
+    Reliable,
+    /// Packets are retransmitted until they are acknowledged, and are handled in the order they were sent
+    /// relative to other ordered packets on the same channel.
+    Ordered,
+    /// Only the newest packet on each key is retransmitted, and packets that arrive after a newer packet on the
+    /// same key are ignored. Packets built with
+    /// [`PacketBuilder::new_message`](crate::packet::PacketBuilder::new_message) use key 0.
+    Latest,
+}
+
+/// The id, priority and delivery of a logical channel.
+///
+/// Give a channel to [`PacketBuilder::with_channel`](crate::packet::PacketBuilder::with_channel) to build packets
+/// on it. Both peers must agree on the ids of channels, but the rest only matters to the sender.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Channel {
+    id: u8,
+    priority: Priority,
+    reliability: Reliability,
+    max_transmissions: Option<NonZeroU32>,
+}
+
+impl Channel {
+    /// The channel that packets are built on unless another one is chosen.
+    pub const DEFAULT: Self = Self::new(0, Priority::Telemetry, Reliability::Reliable);
+
+    /// # Panics
+    /// Panics if `id` is not less than [`CHANNEL_COUNT`].
+    pub const fn new(id: u8, priority: Priority, reliability: Reliability) -> Self {
+        assert!((id as usize) < CHANNEL_COUNT, "Channel id is too large");
+        Self {
+            id,
+            priority,
+            reliability,
+            max_transmissions: None,
+        }
+    }
+
+    /// Gives up on reliable packets on this channel after they have been sent `max` times without being
+    /// acknowledged, which suits data that is soon outdated.
+    ///
+    /// Ordered packets that are given up on are cancelled, so the peer will skip them instead of waiting.
+    /// By default, packets are retransmitted until they are acknowledged.
+    pub const fn with_max_transmissions(mut self, max: NonZeroU32) -> Self {
+        self.max_transmissions = Some(max);
+        self
+    }
+
+    pub const fn id(&self) -> u8 {
+        self.id
+    }
+
+    pub const fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub const fn reliability(&self) -> Reliability {
+        self.reliability
+    }
+
+    pub const fn max_transmissions(&self) -> Option<NonZeroU32> {
+        self.max_transmissions
+    }
+
+    /// Returns the bits to add to the index of packets on this channel.
+    pub(crate) const fn index_bits(&self) -> u64 {
+        (self.id as u64) << CHANNEL_SHIFT
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_bits() {
+        let channel = Channel::new(31, Priority::Bulk, Reliability::Ordered);
+        let index = 5 | channel.index_bits() | crate::ordered::ORDERED_FLAG;
+        assert_eq!(channel_of(index), 31);
+        assert_eq!(index & !CHANNEL_MASK, 5 | crate::ordered::ORDERED_FLAG);
+        assert_eq!(channel_of(crate::packet::MAX_RELIABLE_INDEX), 0);
+    }
+}
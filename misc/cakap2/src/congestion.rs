@@ -0,0 +1,157 @@
+//! Round-trip time estimation and send-rate pacing.
+//!
+//! The retransmission timeout is estimated from acknowledgements as described in RFC 6298. Only packets that
+//! were transmitted once are sampled, since the acknowledgement of a retransmitted packet could belong to any
+//! of its transmissions. Every retransmission of a packet doubles its timeout, up to a maximum.
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub(crate) struct RttEstimator {
+    smoothed: Option<Duration>,
+    variation: Duration,
+    rto: Duration,
+    pub(crate) min_rto: Duration,
+    pub(crate) max_rto: Duration,
+}
+
+impl RttEstimator {
+    /// Creates an estimator that uses `initial_rto` until the first sample.
+    pub(crate) fn new(initial_rto: Duration) -> Self {
+        Self {
+            smoothed: None,
+            variation: Duration::ZERO,
+            rto: initial_rto,
+            min_rto: Duration::from_millis(50),
+            max_rto: Duration::from_secs(5),
+        }
+    }
+
+    pub(crate) fn sample(&mut self, rtt: Duration) {
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variation = rtt / 2;
+            }
+            Some(smoothed) => {
+                self.variation = (self.variation * 3 + smoothed.abs_diff(rtt)) / 4;
+                self.smoothed = Some((smoothed * 7 + rtt) / 8);
+            }
+        }
+        self.rto = (self.smoothed.unwrap() + self.variation * 4).clamp(self.min_rto, self.max_rto);
+    }
+
+    /// Returns the smoothed round-trip time, if any acknowledgement has been sampled.
+    pub(crate) fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// Returns the mean deviation of the round-trip time.
+    pub(crate) fn variation(&self) -> Duration {
+        self.variation
+    }
+
+    pub(crate) fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Returns how long to wait for an acknowledgement after the given number of transmissions of a packet.
+    pub(crate) fn timeout(&self, transmissions: u32) -> Duration {
+        let backoff = 1u32 << transmissions.saturating_sub(1).min(16);
+        self.rto
+            .saturating_mul(backoff)
+            .min(self.max_rto.max(self.rto))
+    }
+}
+
+/// A token bucket that limits the rate at which bytes are sent.
+#[derive(Debug)]
+pub(crate) struct Pacer {
+    bytes_per_second: f64,
+    burst_bytes: f64,
+    /// May become negative, as a packet is sent whenever there are any tokens left.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    pub(crate) fn new(bytes_per_second: u64, burst_bytes: u64, now: Instant) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second as f64,
+            burst_bytes: burst_bytes as f64,
+            tokens: burst_bytes as f64,
+            last_refill: now,
+        }
+    }
+
+    /// Returns how long to wait before sending anything, or `None` if a packet can be sent now.
+    pub(crate) fn delay(&mut self, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = self.last_refill.max(now);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.bytes_per_second).min(self.burst_bytes);
+        if self.tokens >= 0.0 {
+            None
+        } else if self.bytes_per_second > 0.0 {
+            Some(Duration::from_secs_f64(
+                -self.tokens / self.bytes_per_second,
+            ))
+        } else {
+            Some(Duration::MAX)
+        }
+    }
+
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_estimation() {
+        let mut estimator = RttEstimator::new(Duration::from_millis(150));
+        assert_eq!(estimator.smoothed(), None);
+        assert_eq!(estimator.rto(), Duration::from_millis(150));
+
+        estimator.sample(Duration::from_millis(40));
+        assert_eq!(estimator.smoothed(), Some(Duration::from_millis(40)));
+        assert_eq!(estimator.rto(), Duration::from_millis(120));
+
+        estimator.sample(Duration::from_millis(40));
+        assert_eq!(estimator.rto(), Duration::from_millis(100));
+
+        // The timeout never goes below the minimum
+        for _ in 0..100 {
+            estimator.sample(Duration::from_millis(1));
+        }
+        assert_eq!(estimator.rto(), estimator.min_rto);
+    }
+
+    #[test]
+    fn backoff() {
+        let mut estimator = RttEstimator::new(Duration::from_millis(100));
+        estimator.max_rto = Duration::from_millis(500);
+        assert_eq!(estimator.timeout(1), Duration::from_millis(100));
+        assert_eq!(estimator.timeout(2), Duration::from_millis(200));
+        assert_eq!(estimator.timeout(3), Duration::from_millis(400));
+        assert_eq!(estimator.timeout(4), Duration::from_millis(500));
+        assert_eq!(estimator.timeout(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn token_bucket() {
+        let now = Instant::now();
+        let mut pacer = Pacer::new(1000, 100, now);
+        assert_eq!(pacer.delay(now), None);
+        pacer.consume(150);
+        assert_eq!(pacer.delay(now), Some(Duration::from_millis(50)));
+        assert_eq!(pacer.delay(now + Duration::from_millis(50)), None);
+        // Tokens do not accumulate beyond the burst size
+        let later = now + Duration::from_secs(10);
+        assert_eq!(pacer.delay(later), None);
+        pacer.consume(200);
+        assert_eq!(pacer.delay(later), Some(Duration::from_millis(100)));
+    }
+}
@@ -22,6 +22,32 @@ impl Display for CakapError {
 
 impl std::error::Error for CakapError {}
 
+/// The reason that a datagram was rejected by an [`Authenticator`](crate::auth::Authenticator).
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// The datagram was too small to be a sealed packet.
+    Malformed,
+    /// The datagram failed authentication.
+    Forged,
+    /// The packet was already received, or was sent by this peer.
+    Replayed,
+    /// The packet is from a previous session of the peer.
+    StaleSession,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "Datagram from peer was too small to be authenticated"),
+            Self::Forged => write!(f, "Datagram from peer failed authentication"),
+            Self::Replayed => write!(f, "Packet from peer was replayed"),
+            Self::StaleSession => write!(f, "Packet from peer was from a previous session"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 #[derive(Debug, thiserror::Error)]
 pub enum BuildPacketError {
     #[error("Buffer too large, max packet size: {max_packet_size}")]
@@ -0,0 +1,318 @@
+//! Fragmentation of messages that are too large for a single packet.
+//!
+//! Each fragment is a packet with [`FRAGMENT_FLAG`] set in its index. Before the index, it carries a header of
+//! the message id as a big-endian `u64`, followed by the index of the fragment and the number of fragments as
+//! big-endian `u32`s.
+//!
+//! Reliable fragments are given consecutive reliable indices, and the message id is the index of the first
+//! fragment. Unreliable fragments have no reliable index, so the index of an unreliable fragment is just the flag
+//! and the channel id, and message ids are counted separately.
+use std::{
+    collections::VecDeque,
+    mem::size_of,
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+
+/// Set in the index of fragments.
+pub(crate) const FRAGMENT_FLAG: u64 = 1 << 61;
+/// The size of the header that comes before the index of a fragment.
+pub(crate) const FRAGMENT_HEADER_SIZE: usize = 16;
+/// Partial reliable messages are kept for at least this many of the longest retransmission timeouts, as the
+/// fragments that were acknowledged will never be sent again.
+pub(crate) const RELIABLE_TIMEOUT_RTOS: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FragmentHeader {
+    pub(crate) message_id: u64,
+    pub(crate) fragment_index: u32,
+    pub(crate) fragment_count: u32,
+}
+
+impl FragmentHeader {
+    pub(crate) fn to_bytes(self) -> [u8; FRAGMENT_HEADER_SIZE] {
+        let mut bytes = [0; FRAGMENT_HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.message_id.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.fragment_index.to_be_bytes());
+        bytes[12..16].copy_from_slice(&self.fragment_count.to_be_bytes());
+        bytes
+    }
+
+    /// Reads the header of a fragment, where `data` is the whole packet, returning the header and the payload.
+    ///
+    /// Returns `None` if `data` is too small to be a fragment, or the header is invalid.
+    pub(crate) fn read(data: &[u8]) -> Option<(Self, &[u8])> {
+        let header_start = data.len().checked_sub(8 + FRAGMENT_HEADER_SIZE)?;
+        let bytes = &data[header_start..data.len() - 8];
+        let header = Self {
+            message_id: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            fragment_index: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            fragment_count: u32::from_be_bytes(bytes[12..16].try_into().unwrap()),
+        };
+        if header_start == 0 || header.fragment_index >= header.fragment_count {
+            return None;
+        }
+        Some((header, &data[..header_start]))
+    }
+}
+
+/// Returned when a fragment could not be stored.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Rejected {
+    /// Storing the fragment would exceed the memory cap.
+    Full,
+    /// The fragment does not agree with the other fragments of its message.
+    Invalid,
+}
+
+type Slot = Option<Box<[u8]>>;
+
+#[derive(Debug)]
+struct PartialMessage {
+    channel: u8,
+    fragments: Box<[Slot]>,
+    remaining: u32,
+    /// The total size of the fragments received so far.
+    bytes: usize,
+    last_received: Instant,
+}
+
+impl PartialMessage {
+    /// Returns the number of bytes counted against the memory cap, including the space for missing fragments.
+    fn memory(&self) -> usize {
+        self.bytes + self.fragments.len() * size_of::<Slot>()
+    }
+}
+
+/// Reassembles messages from fragments, with a timeout for each partial message and a cap on their total size.
+#[derive(Debug)]
+pub(crate) struct Reassembler {
+    /// Partial messages, keyed by whether they are reliable, and their message id.
+    partial: FxHashMap<(bool, u64), PartialMessage>,
+    /// The total memory of all partial messages.
+    bytes: usize,
+    /// Reassembled messages, along with their channel.
+    ready: VecDeque<(u8, Box<[u8]>)>,
+    /// The reassembled message that is currently being handled.
+    handling: Option<(u8, Box<[u8]>)>,
+    pub(crate) timeout: Duration,
+    pub(crate) max_bytes: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new(timeout: Duration, max_bytes: usize) -> Self {
+        Self {
+            partial: Default::default(),
+            bytes: 0,
+            ready: Default::default(),
+            handling: None,
+            timeout,
+            max_bytes,
+        }
+    }
+
+    /// Stores the given fragment. If it completes its message, the message can be taken with `pop_ready`.
+    ///
+    /// The channel of a message is the channel of its first fragment to arrive.
+    pub(crate) fn receive(
+        &mut self,
+        reliable: bool,
+        channel: u8,
+        header: FragmentHeader,
+        payload: &[u8],
+        now: Instant,
+    ) -> Result<(), Rejected> {
+        let key = (reliable, header.message_id);
+        let partial = match self.partial.get_mut(&key) {
+            Some(partial) => {
+                if partial.fragments.len() != header.fragment_count as usize {
+                    return Err(Rejected::Invalid);
+                }
+                partial
+            }
+            None => {
+                let slots_size = header.fragment_count as usize * size_of::<Slot>();
+                if self.bytes + slots_size > self.max_bytes {
+                    return Err(Rejected::Full);
+                }
+                self.bytes += slots_size;
+                self.partial.entry(key).or_insert(PartialMessage {
+                    channel,
+                    fragments: vec![None; header.fragment_count as usize].into_boxed_slice(),
+                    remaining: header.fragment_count,
+                    bytes: 0,
+                    last_received: now,
+                })
+            }
+        };
+        let slot = &mut partial.fragments[header.fragment_index as usize];
+        if slot.is_some() {
+            // A duplicate fragment
+            return Ok(());
+        }
+        if self.bytes + payload.len() > self.max_bytes {
+            if partial.remaining == header.fragment_count {
+                self.bytes -= partial.memory();
+                self.partial.remove(&key);
+            }
+            return Err(Rejected::Full);
+        }
+        *slot = Some(payload.into());
+        partial.remaining -= 1;
+        partial.bytes += payload.len();
+        partial.last_received = now;
+        self.bytes += payload.len();
+
+        if partial.remaining == 0 {
+            let partial = self.partial.remove(&key).unwrap();
+            self.bytes -= partial.memory();
+            let mut message = Vec::with_capacity(partial.bytes);
+            for fragment in partial.fragments.into_vec() {
+                message.extend_from_slice(&fragment.unwrap());
+            }
+            self.ready
+                .push_back((partial.channel, message.into_boxed_slice()));
+        }
+        Ok(())
+    }
+
+    /// Drops partial messages that have not received a fragment within the timeout, or within
+    /// `reliable_timeout` for reliable messages if that is longer.
+    pub(crate) fn expire(&mut self, now: Instant, reliable_timeout: Duration) {
+        let timeout = self.timeout;
+        let mut freed = 0;
+        self.partial.retain(|&(reliable, _), partial| {
+            let timeout = if reliable {
+                timeout.max(reliable_timeout)
+            } else {
+                timeout
+            };
+            let keep = now.duration_since(partial.last_received) < timeout;
+            if !keep {
+                freed += partial.memory();
+            }
+            keep
+        });
+        self.bytes -= freed;
+    }
+
+    /// Returns the channel and contents of the next reassembled message.
+    pub(crate) fn pop_ready(&mut self) -> Option<(u8, &[u8])> {
+        self.handling = Some(self.ready.pop_front()?);
+        self.handling()
+    }
+
+    /// Returns the message that was last returned by `pop_ready`.
+    pub(crate) fn handling(&self) -> Option<(u8, &[u8])> {
+        self.handling
+            .as_ref()
+            .map(|(channel, message)| (*channel, &**message))
+    }
+
+    /// Forgets all partial and reassembled messages.
+    pub(crate) fn clear(&mut self) {
+        self.partial.clear();
+        self.ready.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(message_id: u64, fragment_index: u32, fragment_count: u32) -> FragmentHeader {
+        FragmentHeader {
+            message_id,
+            fragment_index,
+            fragment_count,
+        }
+    }
+
+    #[test]
+    fn reassemble() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 1024);
+        reassembler
+            .receive(true, 0, header(1, 2, 3), &[5], now)
+            .unwrap();
+        // A fragment of an unreliable message with the same id does not mix in
+        reassembler
+            .receive(false, 0, header(1, 0, 2), &[9], now)
+            .unwrap();
+        reassembler
+            .receive(true, 0, header(1, 0, 3), &[1, 2], now)
+            .unwrap();
+        assert_eq!(reassembler.pop_ready(), None);
+        reassembler
+            .receive(true, 0, header(1, 1, 3), &[3, 4], now)
+            .unwrap();
+        assert_eq!(
+            reassembler.pop_ready(),
+            Some((0, [1, 2, 3, 4, 5].as_slice()))
+        );
+        assert_eq!(reassembler.pop_ready(), None);
+        assert_eq!(
+            reassembler.receive(false, 0, header(1, 1, 3), &[9], now),
+            Err(Rejected::Invalid)
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 2 * size_of::<Slot>() + 4);
+        reassembler
+            .receive(false, 0, header(1, 0, 2), &[1, 2, 3], now)
+            .unwrap();
+        assert_eq!(
+            reassembler.receive(false, 0, header(2, 0, 2), &[1, 2], now),
+            Err(Rejected::Full)
+        );
+        reassembler.expire(now + Duration::from_secs(1), Duration::ZERO);
+        reassembler
+            .receive(false, 0, header(2, 0, 2), &[1, 2], now)
+            .unwrap();
+        reassembler
+            .receive(false, 0, header(2, 1, 2), &[3, 4], now)
+            .unwrap();
+        assert_eq!(reassembler.pop_ready(), Some((0, [1, 2, 3, 4].as_slice())));
+    }
+
+    #[test]
+    fn reliable_timeout() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new(Duration::from_secs(1), 1024);
+        reassembler
+            .receive(true, 0, header(1, 0, 2), &[1], now)
+            .unwrap();
+        reassembler
+            .receive(false, 0, header(1, 0, 2), &[1], now)
+            .unwrap();
+        // Only the unreliable message is dropped, as the first fragment of the reliable one will not be resent
+        reassembler.expire(now + Duration::from_secs(2), Duration::from_secs(3));
+        assert_eq!(
+            reassembler.receive(false, 0, header(1, 1, 2), &[2], now),
+            Ok(())
+        );
+        assert_eq!(reassembler.pop_ready(), None);
+        reassembler
+            .receive(true, 0, header(1, 1, 2), &[2], now)
+            .unwrap();
+        assert_eq!(reassembler.pop_ready(), Some((0, [1, 2].as_slice())));
+    }
+
+    #[test]
+    fn read_header() {
+        let mut data = vec![7];
+        data.extend_from_slice(&header(3, 1, 2).to_bytes());
+        data.extend_from_slice(&FRAGMENT_FLAG.to_be_bytes());
+        assert_eq!(
+            FragmentHeader::read(&data),
+            Some((header(3, 1, 2), [7].as_slice()))
+        );
+        data[13..17].copy_from_slice(&1u32.to_be_bytes());
+        assert_eq!(FragmentHeader::read(&data), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! State for latest-value packets, which implement eventually reliable communication.
+//!
+//! Latest-value packets are reliable packets with [`LATEST_FLAG`] set in their index. Before the index, they carry
+//! a key as a big-endian `u64`. Sending a packet on a key cancels the packet that was previously sent on the same
+//! key and channel if it has not been acknowledged yet, so only the newest value is retransmitted. Since reliable
+//! indices only increase, the receiver handles a packet only if its index is greater than that of the last packet
+//! it handled on the same key and channel, so values that arrive late are acknowledged but not handled.
+use std::num::NonZeroU64;
+
+use fxhash::FxHashMap;
+
+/// Set in the index of latest-value packets.
+pub(crate) const LATEST_FLAG: u64 = 1 << 55;
+/// The size of the header that comes before the index of a latest-value packet.
+pub(crate) const LATEST_HEADER_SIZE: usize = 8;
+
+/// Reads the key of a latest-value packet, where `data` is the whole packet, returning the key and the payload.
+///
+/// Returns `None` if `data` is too small to be a latest-value packet.
+pub(crate) fn read_header(data: &[u8]) -> Option<(u64, &[u8])> {
+    let header_start = data.len().checked_sub(8 + LATEST_HEADER_SIZE)?;
+    let key = u64::from_be_bytes(
+        data[header_start..header_start + LATEST_HEADER_SIZE]
+            .try_into()
+            .unwrap(),
+    );
+    Some((key, &data[..header_start]))
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LatestSender {
+    /// The index of the newest packet sent on each channel and key, until it is acknowledged.
+    newest: FxHashMap<(u8, u64), NonZeroU64>,
+}
+
+impl LatestSender {
+    /// Records that a packet is being sent on the given channel and key, returning the index of the packet
+    /// that it supersedes, if any.
+    ///
+    /// If a newer packet was already sent on the key, the given packet is not recorded.
+    pub(crate) fn send(&mut self, channel: u8, key: u64, index: NonZeroU64) -> Option<NonZeroU64> {
+        let newest = self.newest.entry((channel, key)).or_insert(index);
+        if *newest < index {
+            Some(std::mem::replace(newest, index))
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn acknowledged(&mut self, channel: u8, key: u64, index: NonZeroU64) {
+        if self.newest.get(&(channel, key)) == Some(&index) {
+            self.newest.remove(&(channel, key));
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct LatestReceiver {
+    /// The index of the newest packet handled on each channel and key.
+    newest: FxHashMap<(u8, u64), NonZeroU64>,
+}
+
+impl LatestReceiver {
+    /// Returns `true` if the packet is newer than every packet handled on the same channel and key, and so
+    /// should be handled.
+    pub(crate) fn receive(&mut self, channel: u8, key: u64, index: NonZeroU64) -> bool {
+        if self
+            .newest
+            .get(&(channel, key))
+            .is_some_and(|&newest| newest >= index)
+        {
+            return false;
+        }
+        self.newest.insert((channel, key), index);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(index: u64) -> NonZeroU64 {
+        NonZeroU64::new(index | LATEST_FLAG).unwrap()
+    }
+
+    #[test]
+    fn supersede() {
+        let mut sender = LatestSender::default();
+        assert_eq!(sender.send(0, 7, index(1)), None);
+        assert_eq!(sender.send(0, 7, index(3)), Some(index(1)));
+        // Other keys and channels are independent
+        assert_eq!(sender.send(0, 8, index(4)), None);
+        assert_eq!(sender.send(1, 7, index(5)), None);
+        // The old packet is already superseded, so its acknowledgement changes nothing
+        sender.acknowledged(0, 7, index(1));
+        assert_eq!(sender.send(0, 7, index(6)), Some(index(3)));
+        sender.acknowledged(0, 7, index(6));
+        assert_eq!(sender.send(0, 7, index(9)), None);
+    }
+
+    #[test]
+    fn discard_stale() {
+        let mut receiver = LatestReceiver::default();
+        assert!(receiver.receive(0, 7, index(3)));
+        assert!(!receiver.receive(0, 7, index(1)));
+        assert!(!receiver.receive(0, 7, index(3)));
+        assert!(receiver.receive(0, 8, index(2)));
+        assert!(receiver.receive(0, 7, index(4)));
+    }
+}
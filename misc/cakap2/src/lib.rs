@@ -4,80 +4,244 @@
 //! protocol will be unordered as well. If the transport layer is something like a WebRTC ordered and unreliable
 //! data channel, then this protocol will be ordered as well.
 //!
+//! Reliable packets can also be sent in order regardless of the transport layer by building them with
+//! [`PacketBuilder::new_ordered`]. The peer holds back ordered packets that arrive early until the ordered
+//! packets before them have been handled, and then produces them with [`RecommendedAction::HandleBufferedData`].
+//!
+//! Eventually reliable packets are built with [`PacketBuilder::new_latest`]. Each one is the newest value of a key,
+//! so sending one stops the retransmission of the previous value of the same key, and the peer ignores values that
+//! arrive after a newer one.
+//!
+//! Reliable packets are retransmitted after a timeout that adapts to the measured round-trip time, and that
+//! doubles with every retransmission of the same packet. Sending can also be paced to a maximum rate with
+//! [`PeerStateMachine::set_send_rate_limit`], which is useful on weak links that are easily flooded.
+//!
+//! Packets can be sent on up to 32 logical [`channel`]s, each with its own priority, reliability and retransmission
+//! policy. Packets of a higher priority are sent first when not everything can be sent at once, and the channel of
+//! received data is given alongside it.
+//!
 //! # Usage
 //! This crate provides just the state machine for the protocol without any I/O. To use it, you must create
 //! an event loop for each unique connection and poll the state machine with incoming events. The state machine
 //! will then produce a [`RecommendedAction`] that you should take.
 //!
+//! With the `udp` feature, the `udp` module provides ready-made event loops over UDP sockets: one that is polled
+//! without blocking, which suits game loops, and with the `tokio` feature, one that runs as a tokio task.
+//!
 //! # Security
 //! Since this protocol is handshakeless and connectionless, it is vulnerable to abuse. This protocol is not intended
 //! to be used on the open internet in production, but rather in a controlled environment without bad actors. The Utah
 //! Student Robotics club uses this protocol to communicate between an operator and a robot in a network with no other
 //! clients.
+//!
+//! On a network that is shared with other clients, packets can be authenticated, and optionally encrypted, with a
+//! pre-shared key using the [`auth`] module. This prevents other clients from spoofing or replaying packets, but does
+//! not prevent them from flooding either peer.
 
 use std::{
-    collections::VecDeque,
-    num::NonZeroU64,
+    collections::{BTreeSet, VecDeque},
+    num::{NonZeroU32, NonZeroU64},
     sync::{atomic::AtomicU64, Arc},
     time::{Duration, Instant},
     u64,
 };
 
+use channel::{channel_of, Channel, Priority, CHANNEL_COUNT, CHANNEL_MASK};
+use congestion::{Pacer, RttEstimator};
 use error::CakapError;
+use fragment::{FragmentHeader, Reassembler, Rejected, FRAGMENT_FLAG, RELIABLE_TIMEOUT_RTOS};
 use fxhash::FxHashMap;
 use indexmap::IndexSet;
+use latest::{LatestReceiver, LatestSender, LATEST_FLAG};
+use ordered::{Arrival, OrderedReceiver, OrderedSender, ORDERED_FLAG};
 use packet::{
     Action, HotPacket, HotPacketInner, PacketBuilder, ReliableIndex, ReliablePacket,
-    UnreliablePacket,
+    UnreliablePacket, RECONNECTION_INDEX,
 };
+use stats::ConnectionStats;
 
+pub mod auth;
+pub mod channel;
+mod congestion;
 pub mod error;
+mod fragment;
+mod latest;
+mod ordered;
 pub mod packet;
+#[cfg(test)]
+mod simulation;
+pub mod stats;
+#[cfg(feature = "udp")]
+pub mod udp;
 
 #[derive(Debug)]
 pub struct Shared {
     reliable_index: AtomicU64,
+    unreliable_message_id: AtomicU64,
     max_packet_size: usize,
 }
 
 #[derive(Debug)]
 struct Retransmit {
     send_at: Instant,
+    /// When this packet was last sent.
+    sent_at: Instant,
+    /// The number of times this packet has been sent.
+    transmissions: u32,
     data: Box<[u8]>,
+    /// The sequence number, if this is an ordered packet.
+    ordered_seq: Option<u64>,
+    /// The key, if this is a latest-value packet.
+    latest_key: Option<u64>,
+    /// The index of the first fragment, if this is a fragment of a larger message.
+    message_index: Option<NonZeroU64>,
+    priority: Priority,
+    /// Set from the channel, and cleared once the packet has been given up on.
+    max_transmissions: Option<NonZeroU32>,
+}
+
+/// Packets of one priority that are waiting to be sent.
+#[derive(Debug, Default)]
+struct Lane {
+    /// The indices of packets to retransmit, ordered by when to retransmit them.
+    ///
+    /// Entries are not removed when a packet is acknowledged or rescheduled, so an entry is only valid if
+    /// its time matches the `send_at` of the packet.
+    retransmission_queue: BTreeSet<(Instant, NonZeroU64)>,
+    /// Fragments of reliable messages, and reliable packets held back by the pacer, that have not been sent yet.
+    pending_reliable: VecDeque<NonZeroU64>,
+    /// Fragments of unreliable messages, and unreliable packets held back by the pacer, that have not been sent yet.
+    pending_unreliable: VecDeque<Box<[u8]>>,
 }
 
 pub struct PeerStateMachine {
     shared: Arc<Shared>,
-    retransmission_duration: Duration,
+    rtt: RttEstimator,
+    /// Limits the rate at which packets are sent, if set.
+    pacer: Option<Pacer>,
     retransmission_map: FxHashMap<NonZeroU64, Retransmit>,
-    retransmission_queue: VecDeque<NonZeroU64>,
+    /// Indexed by priority.
+    lanes: [Lane; Priority::COUNT],
     received_set: IndexSet<NonZeroU64>,
     max_received_set_size: usize,
+    /// Indexed by channel id.
+    ordered_senders: [OrderedSender; CHANNEL_COUNT],
+    /// Indexed by channel id.
+    ordered_receivers: [OrderedReceiver; CHANNEL_COUNT],
+    latest_sender: LatestSender,
+    latest_receiver: LatestReceiver,
+    reassembler: Reassembler,
+    /// Only the counters are kept up to date. The rest is filled in by `stats`.
+    stats: ConnectionStats,
 }
 
 impl PeerStateMachine {
     /// Creates a new [`PeerStateMachine`] with the given retransmission duration and maximum received set size.
     ///
     /// The retransmission duration is the amount of time to wait before retransmitting a packet that has not been
-    /// acknowledged. The maximum received set size should be proportional to the number of reliable packets sent per second,
-    /// and varies based on the unreliability of the transport layer. If you are intending on sending many reliable packets
+    /// acknowledged, until the round-trip time has been measured. The maximum received set size should be proportional
+    /// to the number of reliable packets sent per second, and varies based on the unreliability of the transport layer. If you are intending on sending many reliable packets
     /// over a very unreliable transport layer, you should set this to a higher value, which comes at the cost of approximately
     /// 32 bytes per unit. That is, if `max_received_set_size` is 100, then the received set will consume approximately up to 3200 bytes.
     /// Setting this value too low may cause this peer to acknowledge reliable packets that have already been received (thus handling
-    /// them twice).
+    /// them twice). This is also the maximum number of ordered packets that will be held back while waiting for
+    /// the ordered packets before them.
     ///
     /// The returned [`RecommendedAction`] is an action that should be taken immediately after creating the state machine.
     pub fn new(retransmission_duration: Duration, max_received_set_size: usize) -> Self {
         Self {
-            retransmission_duration,
+            rtt: RttEstimator::new(retransmission_duration),
+            pacer: None,
             max_received_set_size,
             shared: Arc::new(Shared {
                 reliable_index: AtomicU64::new(1),
+                unreliable_message_id: AtomicU64::new(0),
                 max_packet_size: 1400,
             }),
             retransmission_map: Default::default(),
-            retransmission_queue: Default::default(),
+            lanes: Default::default(),
             received_set: Default::default(),
+            ordered_senders: Default::default(),
+            ordered_receivers: Default::default(),
+            latest_sender: Default::default(),
+            latest_receiver: Default::default(),
+            reassembler: Reassembler::new(Duration::from_secs(5), 16 * 1024 * 1024),
+            stats: Default::default(),
+        }
+    }
+
+    /// Sets how long a partially received message is kept after the last time one of its fragments was received,
+    /// and the maximum number of bytes that all partially received messages may use together.
+    ///
+    /// Fragments of reliable messages that do not fit are not acknowledged, so the peer will retransmit them later.
+    /// Fragments of unreliable messages that do not fit are dropped.
+    ///
+    /// Partially received reliable messages are kept for at least 8 times the maximum retransmission timeout,
+    /// regardless of `timeout`, since the peer only retransmits the fragments that were not acknowledged.
+    ///
+    /// By default, the timeout is 5 seconds and the maximum is 16 MiB.
+    pub fn set_reassembly_limits(&mut self, timeout: Duration, max_bytes: usize) {
+        self.reassembler.timeout = timeout;
+        self.reassembler.max_bytes = max_bytes;
+    }
+
+    /// Sets the bounds of the retransmission timeout, which is otherwise estimated from the round-trip time.
+    ///
+    /// The timeout also doubles with every retransmission of the same packet, up to `max`. Setting `min` and `max`
+    /// to the same duration retransmits packets at a fixed interval.
+    ///
+    /// By default, the minimum is 50 milliseconds and the maximum is 5 seconds.
+    pub fn set_retransmission_timeout_bounds(&mut self, min: Duration, max: Duration) {
+        self.rtt.min_rto = min;
+        self.rtt.max_rto = max;
+    }
+
+    /// Limits the rate at which packets are sent to `bytes_per_second`, allowing bursts of up to `burst_bytes`.
+    ///
+    /// When the limit is reached, packets are held back and [`RecommendedAction::WaitForDuration`] is returned
+    /// until they can be sent. Held back packets are never dropped, including unreliable ones.
+    /// Acknowledgements are not limited, as they are small and delaying them causes needless retransmissions.
+    pub fn set_send_rate_limit(&mut self, bytes_per_second: u64, burst_bytes: u64, now: Instant) {
+        self.pacer = Some(Pacer::new(bytes_per_second, burst_bytes, now));
+    }
+
+    /// Removes the limit set with [`PeerStateMachine::set_send_rate_limit`].
+    pub fn remove_send_rate_limit(&mut self) {
+        self.pacer = None;
+    }
+
+    /// Returns the smoothed round-trip time, if any acknowledgement has been received yet.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        self.rtt.smoothed()
+    }
+
+    /// Returns the current retransmission timeout, before any backoff.
+    pub fn retransmission_timeout(&self) -> Duration {
+        self.rtt.rto()
+    }
+
+    /// Returns a snapshot of the statistics of the link to the peer.
+    pub fn stats(&self) -> ConnectionStats {
+        let in_flight = self
+            .retransmission_map
+            .values()
+            .filter(|retransmit| retransmit.transmissions > 0);
+        let (packets_in_flight, bytes_in_flight) = in_flight
+            .fold((0, 0), |(packets, bytes), retransmit| {
+                (packets + 1, bytes + retransmit.data.len())
+            });
+        ConnectionStats {
+            smoothed_rtt: self.rtt.smoothed(),
+            jitter: self.rtt.variation(),
+            retransmission_timeout: self.rtt.rto(),
+            packets_in_flight,
+            bytes_in_flight,
+            packets_queued: self
+                .lanes
+                .iter()
+                .map(|lane| lane.pending_reliable.len() + lane.pending_unreliable.len())
+                .sum(),
+            ..self.stats
         }
     }
 
@@ -85,15 +249,10 @@ impl PeerStateMachine {
         &'a mut self,
         now: Instant,
     ) -> (RecommendedAction<'a, 'static>, ReliableIndex) {
-        let index = !(1u64 << 63);
-        let data = Box::new(index.to_be_bytes());
-        let index = ReliableIndex(NonZeroU64::new(index).unwrap());
-
+        let packet = reconnection_packet();
+        let index = packet.index;
         (
-            self.poll(
-                Event::Action(Action::SendReliable(ReliablePacket { index, data })),
-                now,
-            ),
+            self.poll(Event::Action(Action::SendReliable(packet)), now),
             index,
         )
     }
@@ -101,11 +260,51 @@ impl PeerStateMachine {
     pub fn get_packet_builder(&self) -> PacketBuilder {
         PacketBuilder {
             shared: self.shared.clone(),
+            channel: Channel::DEFAULT,
         }
     }
 
+    /// Returns `true` iff the packet with the given index has not been acknowledged or cancelled.
+    ///
+    /// Cancelled ordered packets are still retransmitting without their payload until they are acknowledged.
     pub fn is_packet_retransmitting(&self, index: ReliableIndex) -> bool {
-        self.retransmission_map.contains_key(&index.0)
+        if index.is_fragmented() {
+            self.retransmission_map
+                .values()
+                .any(|retransmit| retransmit.message_index == Some(index.0))
+        } else {
+            self.retransmission_map.contains_key(&index.0)
+        }
+    }
+
+    /// Records that the packet with the given index was just sent, and schedules its next retransmission.
+    fn mark_sent(&mut self, index: NonZeroU64, now: Instant) {
+        let retransmit = self.retransmission_map.get_mut(&index).unwrap();
+        retransmit.transmissions += 1;
+        if retransmit.transmissions == 1 {
+            self.stats.packets_sent += 1;
+        } else {
+            self.stats.packets_retransmitted += 1;
+        }
+        retransmit.sent_at = now;
+        retransmit.send_at = now + self.rtt.timeout(retransmit.transmissions);
+        self.lanes[retransmit.priority as usize]
+            .retransmission_queue
+            .insert((retransmit.send_at, index));
+        if let Some(pacer) = &mut self.pacer {
+            pacer.consume(retransmit.data.len());
+        }
+    }
+
+    /// Adds the given index to the received set, returning `false` if it was already present.
+    fn remember_received(&mut self, index: NonZeroU64) -> bool {
+        if !self.received_set.insert(index) {
+            return false;
+        }
+        if self.received_set.len() > self.max_received_set_size {
+            self.received_set.shift_remove_index(0);
+        }
+        true
     }
 
     /// Digests the given [`Event`] according to the given [`Instant`] and produces a [`RecommendedAction`] that should be taken.
@@ -121,8 +320,11 @@ impl PeerStateMachine {
                 }
 
                 let index = u64::from_be_bytes(data[data.len() - 8..].try_into().unwrap());
+                if index >> 63 == 0 {
+                    self.stats.packets_received += 1;
+                }
 
-                if index == !(1 << 63) {
+                if index == RECONNECTION_INDEX {
                     // The maximum safe index is 2^63 - 1
                     if data.len() != 8 {
                         return RecommendedAction::HandleError(CakapError::InvalidPacket);
@@ -134,24 +336,117 @@ impl PeerStateMachine {
                     // The max index is the least likely index to be in the `received_set`, so
                     // it is a good choice for this purpose.
                     self.received_set.clear();
+                    // The peer has also forgotten the sequence numbers of its ordered packets,
+                    // and the ids of its fragmented messages
+                    self.ordered_receivers = Default::default();
+                    self.latest_receiver = Default::default();
+                    self.reassembler.clear();
                     return RecommendedAction::SendData(HotPacket {
                         inner: HotPacketInner::Index(u64::MAX.to_be_bytes()),
                     });
-                } else if let Some(index) = NonZeroU64::new(index) {
+                } else if index & !CHANNEL_MASK == FRAGMENT_FLAG {
+                    // A fragment of an unreliable message from peer
+                    let Some((header, received)) = FragmentHeader::read(data) else {
+                        return RecommendedAction::HandleError(CakapError::InvalidPacket);
+                    };
+                    if self
+                        .reassembler
+                        .receive(false, channel_of(index), header, received, now)
+                        == Err(Rejected::Invalid)
+                    {
+                        return RecommendedAction::HandleError(CakapError::InvalidPacket);
+                    }
+                } else if let Some(index) =
+                    NonZeroU64::new(index).filter(|index| index.get() & !CHANNEL_MASK != 0)
+                {
                     // A reliable packet from peer
                     let msb = index.get() >> 63;
+                    let channel = channel_of(index.get());
                     if msb == 0 {
                         let reply_index = index | (1 << 63);
 
-                        // New packet from peer
-                        if self.received_set.insert(index) {
-                            if self.received_set.len() > self.max_received_set_size {
-                                self.received_set.shift_remove_index(0);
+                        if index.get() & ORDERED_FLAG != 0 {
+                            let Some((seq, oldest, received)) = ordered::read_header(data) else {
+                                return RecommendedAction::HandleError(CakapError::InvalidPacket);
+                            };
+                            let arrival = if self.received_set.contains(&index) {
+                                Arrival::Acknowledge
+                            } else {
+                                self.ordered_receivers[channel as usize].receive(
+                                    seq,
+                                    oldest,
+                                    received,
+                                    self.max_received_set_size,
+                                )
+                            };
+                            match arrival {
+                                Arrival::Handle => {
+                                    self.remember_received(index);
+                                    return RecommendedAction::HandleDataAndSend {
+                                        received,
+                                        to_send: reply_index.get().to_be_bytes(),
+                                        channel,
+                                    };
+                                }
+                                Arrival::Acknowledge => {
+                                    self.remember_received(index);
+                                    return RecommendedAction::SendData(HotPacket {
+                                        inner: HotPacketInner::Index(
+                                            reply_index.get().to_be_bytes(),
+                                        ),
+                                    });
+                                }
+                                // Not acknowledging the packet makes the peer retransmit it later
+                                Arrival::Drop => {}
                             }
-
+                        } else if index.get() & LATEST_FLAG != 0 {
+                            let Some((key, received)) = latest::read_header(data) else {
+                                return RecommendedAction::HandleError(CakapError::InvalidPacket);
+                            };
+                            if self.latest_receiver.receive(channel, key, index) {
+                                return RecommendedAction::HandleDataAndSend {
+                                    received,
+                                    to_send: reply_index.get().to_be_bytes(),
+                                    channel,
+                                };
+                            }
+                            // A value older than one that was already handled, just acknowledge
+                            return RecommendedAction::SendData(HotPacket {
+                                inner: HotPacketInner::Index(reply_index.get().to_be_bytes()),
+                            });
+                        } else if index.get() & FRAGMENT_FLAG != 0 {
+                            let Some((header, received)) = FragmentHeader::read(data) else {
+                                return RecommendedAction::HandleError(CakapError::InvalidPacket);
+                            };
+                            let result = if self.received_set.contains(&index) {
+                                Ok(())
+                            } else {
+                                self.reassembler
+                                    .receive(true, channel, header, received, now)
+                            };
+                            match result {
+                                Ok(()) => {
+                                    self.remember_received(index);
+                                    return RecommendedAction::SendData(HotPacket {
+                                        inner: HotPacketInner::Index(
+                                            reply_index.get().to_be_bytes(),
+                                        ),
+                                    });
+                                }
+                                Err(Rejected::Invalid) => {
+                                    return RecommendedAction::HandleError(
+                                        CakapError::InvalidPacket,
+                                    );
+                                }
+                                // Not acknowledging the fragment makes the peer retransmit it later
+                                Err(Rejected::Full) => {}
+                            }
+                        } else if self.remember_received(index) {
+                            // New packet from peer
                             return RecommendedAction::HandleDataAndSend {
                                 received: &data[0..data.len() - 8],
                                 to_send: reply_index.get().to_be_bytes(),
+                                channel,
                             };
                         } else {
                             // Duplicate packet from peer, just acknowledge
@@ -165,69 +460,282 @@ impl PeerStateMachine {
                         let Some(true_index) = NonZeroU64::new(true_index) else {
                             return RecommendedAction::HandleError(CakapError::InvalidPacket);
                         };
-                        self.retransmission_map.remove(&true_index);
+                        if let Some(retransmit) = self.retransmission_map.remove(&true_index) {
+                            self.stats.packets_acknowledged += 1;
+                            if retransmit.transmissions == 1 {
+                                self.rtt
+                                    .sample(now.saturating_duration_since(retransmit.sent_at));
+                            }
+                            if let Some(seq) = retransmit.ordered_seq {
+                                self.ordered_senders[channel as usize].acknowledged(seq);
+                            }
+                            if let Some(key) = retransmit.latest_key {
+                                self.latest_sender.acknowledged(channel, key, true_index);
+                            }
+                        }
                     }
                 } else {
                     // Unreliable packet from peer
-                    return RecommendedAction::HandleData(&data[0..data.len() - 8]);
+                    return RecommendedAction::HandleData {
+                        received: &data[0..data.len() - 8],
+                        channel: channel_of(index),
+                    };
                 }
             }
             Event::Action(action) => match action {
-                Action::SendReliable(ReliablePacket { index, data }) => {
+                Action::SendReliable(ReliablePacket {
+                    index,
+                    mut data,
+                    extra_fragments,
+                    priority,
+                    max_transmissions,
+                }) => {
+                    let ordered_seq = index.is_ordered().then(|| {
+                        let sender = &mut self.ordered_senders[index.channel() as usize];
+                        let seq = sender.next_seq();
+                        ordered::write_header(&mut data, seq, sender.oldest());
+                        seq
+                    });
+                    let latest_key = index
+                        .is_latest()
+                        .then(|| latest::read_header(&data).unwrap().0);
+                    if let Some(key) = latest_key {
+                        if let Some(superseded) =
+                            self.latest_sender.send(index.channel(), key, index.0)
+                        {
+                            if self.retransmission_map.remove(&superseded).is_some() {
+                                self.stats.packets_superseded += 1;
+                            }
+                        }
+                    }
+                    let message_index = index.is_fragmented().then_some(index.0);
                     let index = index.0;
                     let option = self.retransmission_map.insert(
                         index,
                         Retransmit {
-                            send_at: now + self.retransmission_duration,
+                            // Scheduled when actually sent
+                            send_at: now,
+                            sent_at: now,
+                            transmissions: 0,
                             data,
+                            ordered_seq,
+                            latest_key,
+                            message_index,
+                            priority,
+                            max_transmissions,
                         },
                     );
                     debug_assert!(option.is_none());
-                    self.retransmission_queue.push_back(index);
+                    let deferred = self.pacing_delay(now).is_some();
+                    let lane = &mut self.lanes[priority as usize];
+                    if deferred {
+                        lane.pending_reliable.push_back(index);
+                    }
 
-                    return RecommendedAction::SendData(HotPacket {
-                        inner: HotPacketInner::Borrowed(
-                            &self.retransmission_map.get(&index).unwrap().data,
-                        ),
-                    });
+                    for fragment in extra_fragments {
+                        let fragment_index =
+                            u64::from_be_bytes(fragment[fragment.len() - 8..].try_into().unwrap());
+                        let fragment_index = NonZeroU64::new(fragment_index).unwrap();
+                        self.retransmission_map.insert(
+                            fragment_index,
+                            Retransmit {
+                                send_at: now,
+                                sent_at: now,
+                                transmissions: 0,
+                                data: fragment,
+                                ordered_seq: None,
+                                latest_key: None,
+                                message_index,
+                                priority,
+                                max_transmissions,
+                            },
+                        );
+                        lane.pending_reliable.push_back(fragment_index);
+                    }
+
+                    if !deferred {
+                        self.mark_sent(index, now);
+                        return RecommendedAction::SendData(HotPacket {
+                            inner: HotPacketInner::Borrowed(
+                                &self.retransmission_map.get(&index).unwrap().data,
+                            ),
+                        });
+                    }
+                }
+                Action::CancelReliable(index) if index.is_fragmented() => {
+                    self.retransmission_map
+                        .retain(|_, retransmit| retransmit.message_index != Some(index.0));
                 }
                 Action::CancelReliable(ReliableIndex(index)) => {
-                    self.retransmission_map.remove(&index);
+                    if let Some(retransmit) = self.retransmission_map.get_mut(&index) {
+                        if retransmit.ordered_seq.is_some() {
+                            retransmit.data = ordered::into_tombstone(&retransmit.data);
+                        } else {
+                            self.retransmission_map.remove(&index);
+                        }
+                    }
                 }
                 Action::CancelAllReliable => {
-                    self.retransmission_map.clear();
-                    self.retransmission_queue.clear();
+                    self.retransmission_map.retain(|_, retransmit| {
+                        if retransmit.ordered_seq.is_none() {
+                            return false;
+                        }
+                        retransmit.data = ordered::into_tombstone(&retransmit.data);
+                        true
+                    });
+                    let retransmission_map = &self.retransmission_map;
+                    for lane in &mut self.lanes {
+                        lane.retransmission_queue
+                            .retain(|(_, index)| retransmission_map.contains_key(index));
+                    }
                 }
-                Action::SendUnreliable(UnreliablePacket { data }) => {
-                    return RecommendedAction::SendData(HotPacket {
-                        inner: HotPacketInner::Owned(data),
-                    })
+                Action::SendUnreliable(UnreliablePacket {
+                    data,
+                    extra_fragments,
+                    priority,
+                }) => {
+                    let deferred = self.pacing_delay(now).is_some();
+                    let lane = &mut self.lanes[priority as usize];
+                    if deferred {
+                        lane.pending_unreliable.push_back(data);
+                        lane.pending_unreliable.extend(extra_fragments);
+                    } else {
+                        lane.pending_unreliable.extend(extra_fragments);
+                        if let Some(pacer) = &mut self.pacer {
+                            pacer.consume(data.len());
+                        }
+                        self.stats.packets_sent += 1;
+                        return RecommendedAction::SendData(HotPacket {
+                            inner: HotPacketInner::Owned(data),
+                        });
+                    }
                 }
             },
             Event::NoEvent => {}
         }
-        loop {
-            let Some(&first_index) = self.retransmission_queue.front() else {
-                break RecommendedAction::WaitForData;
-            };
-            let Some(retransmit) = self.retransmission_map.get_mut(&first_index) else {
-                self.retransmission_queue.pop_front();
-                continue;
+        // Borrowing the data again keeps `self` free to borrow if there is no data
+        if let Some(channel) = self
+            .ordered_receivers
+            .iter_mut()
+            .position(|receiver| receiver.pop_ready().is_some())
+        {
+            return RecommendedAction::HandleBufferedData {
+                received: self.ordered_receivers[channel].handling().unwrap(),
+                channel: channel as u8,
             };
-            if retransmit.send_at <= now {
-                self.retransmission_queue.pop_front();
-                self.retransmission_queue.push_back(first_index);
-                retransmit.send_at = now + self.retransmission_duration;
-                // To please the borrow checker
-                let retransmit = self.retransmission_map.get(&first_index).unwrap();
-                break RecommendedAction::SendData(HotPacket {
-                    inner: HotPacketInner::Borrowed(&retransmit.data),
+        }
+        self.reassembler.expire(
+            now,
+            self.rtt.max_rto.max(self.rtt.rto()) * RELIABLE_TIMEOUT_RTOS,
+        );
+        if self.reassembler.pop_ready().is_some() {
+            let (channel, received) = self.reassembler.handling().unwrap();
+            return RecommendedAction::HandleBufferedData { received, channel };
+        }
+        // Packets that were cancelled before being sent do not need to wait for the pacer
+        let retransmission_map = &self.retransmission_map;
+        for lane in &mut self.lanes {
+            lane.pending_reliable
+                .retain(|index| retransmission_map.contains_key(index));
+        }
+        let pacing_delay = self.pacing_delay(now);
+        // How long until the next retransmission of any priority
+        let mut wait: Option<Duration> = None;
+        for priority in 0..Priority::COUNT {
+            let lane = &mut self.lanes[priority];
+            if let Some(delay) = pacing_delay {
+                if !lane.pending_unreliable.is_empty() || !lane.pending_reliable.is_empty() {
+                    return RecommendedAction::WaitForDuration(delay);
+                }
+            }
+            if let Some(packet) = lane.pending_unreliable.pop_front() {
+                self.stats.packets_sent += 1;
+                if let Some(pacer) = &mut self.pacer {
+                    pacer.consume(packet.len());
+                }
+                return RecommendedAction::SendData(HotPacket {
+                    inner: HotPacketInner::Owned(packet),
+                });
+            }
+            if let Some(index) = lane.pending_reliable.pop_front() {
+                self.mark_sent(index, now);
+                return RecommendedAction::SendData(HotPacket {
+                    inner: HotPacketInner::Borrowed(&self.retransmission_map[&index].data),
                 });
-            } else {
-                break RecommendedAction::WaitForDuration(retransmit.send_at - now);
             }
+            loop {
+                let lane = &mut self.lanes[priority];
+                let Some(&(send_at, first_index)) = lane.retransmission_queue.first() else {
+                    break;
+                };
+                let Some(retransmit) = self
+                    .retransmission_map
+                    .get_mut(&first_index)
+                    .filter(|retransmit| retransmit.send_at == send_at)
+                else {
+                    // Acknowledged, cancelled or rescheduled
+                    lane.retransmission_queue.pop_first();
+                    continue;
+                };
+                if send_at > now {
+                    let delay = send_at - now;
+                    wait = Some(wait.map_or(delay, |wait| wait.min(delay)));
+                    break;
+                }
+                if let Some(delay) = pacing_delay {
+                    return RecommendedAction::WaitForDuration(delay);
+                }
+                lane.retransmission_queue.pop_first();
+                if retransmit
+                    .max_transmissions
+                    .is_some_and(|max| retransmit.transmissions >= max.get())
+                {
+                    self.stats.packets_expired += 1;
+                    if retransmit.ordered_seq.is_some() {
+                        // The peer still has to be told to skip the packet
+                        retransmit.data = ordered::into_tombstone(&retransmit.data);
+                        retransmit.max_transmissions = None;
+                    } else if let Some(message_index) = retransmit.message_index {
+                        self.retransmission_map.retain(|_, retransmit| {
+                            retransmit.message_index != Some(message_index)
+                        });
+                        continue;
+                    } else {
+                        self.retransmission_map.remove(&first_index);
+                        continue;
+                    }
+                }
+                if let Some(seq) = retransmit.ordered_seq {
+                    let sender = &self.ordered_senders[channel_of(first_index.get()) as usize];
+                    ordered::write_header(&mut retransmit.data, seq, sender.oldest());
+                }
+                self.mark_sent(first_index, now);
+                return RecommendedAction::SendData(HotPacket {
+                    inner: HotPacketInner::Borrowed(&self.retransmission_map[&first_index].data),
+                });
+            }
+        }
+        match wait {
+            Some(delay) => RecommendedAction::WaitForDuration(delay),
+            None => RecommendedAction::WaitForData,
         }
     }
+
+    /// Returns how long the pacer requires to wait before sending anything, or `None` if there is no need to wait.
+    fn pacing_delay(&mut self, now: Instant) -> Option<Duration> {
+        self.pacer.as_mut()?.delay(now)
+    }
+}
+
+/// Returns the packet that asks the peer to clear its received set.
+pub(crate) fn reconnection_packet() -> ReliablePacket {
+    ReliablePacket {
+        index: ReliableIndex(NonZeroU64::new(RECONNECTION_INDEX).unwrap()),
+        data: Box::new(RECONNECTION_INDEX.to_be_bytes()),
+        extra_fragments: vec![],
+        priority: Priority::Control,
+        max_transmissions: None,
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -244,16 +752,24 @@ pub enum RecommendedAction<'a, 'b> {
     /// Handle the given error (by logging or otherwise) and poll the state machine again
     /// with `NoEvent`.
     HandleError(CakapError),
-    /// Handle the given data from the peer.
-    HandleData(&'b [u8]),
-    /// Handle `received` from the peer, and send `to_send` to the peer.
+    /// Handle the given data from the peer, which was sent on the channel with the given id.
+    HandleData { received: &'b [u8], channel: u8 },
+    /// Handle `received` from the peer, which was sent on the channel with the given id, and send `to_send`
+    /// to the peer.
     ///
     /// If the given message is not valid for whatever reason, you can choose to not
     /// send `to_send` and *not* poll the state machine with `NoEvent`.
     HandleDataAndSend {
         received: &'b [u8],
         to_send: [u8; 8],
+        channel: u8,
     },
+    /// Handle the given data from the peer, which was held back by the state machine, and poll the state
+    /// machine again with `NoEvent`.
+    ///
+    /// This is either ordered data that arrived before the ordered data preceding it, or a message that
+    /// was reassembled from fragments. This data has already been acknowledged.
+    HandleBufferedData { received: &'a [u8], channel: u8 },
     /// Send the given data to the peer.
     SendData(HotPacket<'a>),
 }
@@ -295,6 +811,7 @@ mod tests {
     use std::ops::Deref;
 
     use super::*;
+    use channel::Reliability;
 
     #[test]
     fn send_unreliable_1() {
@@ -323,7 +840,32 @@ mod tests {
         let action = other_state_machine.poll(event, Instant::now());
 
         // `other_state_machine` handles the unreliable packet
-        assert_eq!(action, RecommendedAction::HandleData(&[217]));
+        assert_eq!(
+            action,
+            RecommendedAction::HandleData {
+                received: &[217],
+                channel: 0
+            }
+        );
+    }
+
+    #[test]
+    fn reconnection() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut other_state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let (action, index) = state_machine.send_reconnection_msg(now);
+        // The reconnection index has the ordered and fragment flags set, but is neither
+        assert!(!index.is_ordered() && !index.is_fragmented());
+        let reconnection = action.get_hot_packet().to_vec();
+        assert_eq!(reconnection, RECONNECTION_INDEX.to_be_bytes());
+
+        let action = other_state_machine.poll(Event::IncomingData(&reconnection), now);
+        let ack = action.get_hot_packet().to_vec();
+        assert_eq!(ack, u64::MAX.to_be_bytes());
+        state_machine.poll(Event::NoEvent, now);
+        state_machine.poll(Event::IncomingData(&ack), now);
+        assert!(!state_machine.is_packet_retransmitting(index));
     }
 
     #[test]
@@ -362,7 +904,8 @@ mod tests {
             action,
             RecommendedAction::HandleDataAndSend {
                 received: &[15],
-                to_send
+                to_send,
+                channel: 0
             }
         );
 
@@ -422,7 +965,8 @@ mod tests {
             action,
             RecommendedAction::HandleDataAndSend {
                 received: &[15],
-                to_send
+                to_send,
+                channel: 0
             }
         );
 
@@ -466,4 +1010,633 @@ mod tests {
 
         assert_eq!(action, RecommendedAction::WaitForData);
     }
+
+    /// An in-memory transport that randomly drops, delays and reorders packets.
+    struct LossyLink {
+        rng_state: u64,
+        loss: f64,
+        max_delay: Duration,
+        in_flight: Vec<(Instant, Box<[u8]>)>,
+    }
+
+    impl LossyLink {
+        fn new(seed: u64, loss: f64, max_delay: Duration) -> Self {
+            Self {
+                rng_state: seed,
+                loss,
+                max_delay,
+                in_flight: vec![],
+            }
+        }
+
+        /// Returns a pseudo-random number in `[0, 1)` using xorshift.
+        fn random(&mut self) -> f64 {
+            self.rng_state ^= self.rng_state << 13;
+            self.rng_state ^= self.rng_state >> 7;
+            self.rng_state ^= self.rng_state << 17;
+            (self.rng_state >> 11) as f64 / (1u64 << 53) as f64
+        }
+
+        fn send(&mut self, data: &[u8], now: Instant) {
+            if self.random() < self.loss {
+                return;
+            }
+            let arrive_at = now + self.max_delay.mul_f64(self.random());
+            self.in_flight.push((arrive_at, data.into()));
+        }
+
+        /// Removes a packet that has arrived by `now`, if any.
+        fn recv(&mut self, now: Instant) -> Option<Box<[u8]>> {
+            let i = self
+                .in_flight
+                .iter()
+                .position(|(arrive_at, _)| *arrive_at <= now)?;
+            Some(self.in_flight.swap_remove(i).1)
+        }
+    }
+
+    /// Polls `state_machine` with `event` and follows the recommended actions until it has to wait,
+    /// sending packets over `link` and collecting handled data in `handled`.
+    fn drive(
+        state_machine: &mut PeerStateMachine,
+        event: Event,
+        now: Instant,
+        link: &mut LossyLink,
+        handled: &mut Vec<Vec<u8>>,
+    ) {
+        let mut action = state_machine.poll(event, now);
+        loop {
+            match action {
+                RecommendedAction::WaitForData | RecommendedAction::WaitForDuration(_) => break,
+                RecommendedAction::HandleError(e) => panic!("{e}"),
+                RecommendedAction::HandleData { received, .. }
+                | RecommendedAction::HandleBufferedData { received, .. } => {
+                    handled.push(received.to_vec());
+                }
+                RecommendedAction::HandleDataAndSend {
+                    received, to_send, ..
+                } => {
+                    handled.push(received.to_vec());
+                    link.send(&to_send, now);
+                }
+                RecommendedAction::SendData(hot_packet) => link.send(&hot_packet, now),
+            }
+            action = state_machine.poll(Event::NoEvent, now);
+        }
+    }
+
+    /// Sends `count` packets built with `build` from `sender` to `receiver` over a lossy, reordering link,
+    /// and returns the data handled by `receiver` once everything has been acknowledged.
+    ///
+    /// `build` is given the number of the packet to build.
+    fn send_over_lossy_link(
+        count: u32,
+        mut build: impl FnMut(&PacketBuilder, u32) -> Action,
+    ) -> Vec<Vec<u8>> {
+        let mut sender = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut receiver = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut to_receiver = LossyLink::new(0x2545F4914F6CDD1D, 0.3, Duration::from_millis(50));
+        let mut to_sender = LossyLink::new(0x9E3779B97F4A7C15, 0.3, Duration::from_millis(50));
+        let mut handled = vec![];
+        let mut now = Instant::now();
+
+        for tick in 0..120_000 {
+            if tick >= count && sender.retransmission_map.is_empty() {
+                break;
+            }
+            if tick < count {
+                let action = build(&sender.get_packet_builder(), tick);
+                drive(
+                    &mut sender,
+                    action.into(),
+                    now,
+                    &mut to_receiver,
+                    &mut vec![],
+                );
+            }
+            drive(
+                &mut sender,
+                Event::NoEvent,
+                now,
+                &mut to_receiver,
+                &mut vec![],
+            );
+            while let Some(data) = to_receiver.recv(now) {
+                let event = Event::IncomingData(&data);
+                drive(&mut receiver, event, now, &mut to_sender, &mut handled);
+            }
+            while let Some(data) = to_sender.recv(now) {
+                let event = Event::IncomingData(&data);
+                drive(&mut sender, event, now, &mut to_receiver, &mut vec![]);
+            }
+            now += Duration::from_millis(1);
+        }
+        assert!(sender.retransmission_map.is_empty());
+
+        handled
+    }
+
+    #[test]
+    fn ordered_over_lossy_link() {
+        let handled = send_over_lossy_link(200, |builder, i| {
+            let body = i.to_be_bytes().into_iter().collect();
+            builder.new_ordered(body).unwrap().into()
+        });
+        let expected: Vec<_> = (0..200u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(handled, expected);
+    }
+
+    #[test]
+    fn unordered_over_lossy_link() {
+        let mut handled = send_over_lossy_link(200, |builder, i| {
+            let body = i.to_be_bytes().into_iter().collect();
+            builder.new_reliable(body).unwrap().into()
+        });
+        let expected: Vec<_> = (0..200u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        // Every packet is still handled exactly once, but the link reorders them
+        assert_ne!(handled, expected);
+        handled.sort();
+        assert_eq!(handled, expected);
+    }
+
+    #[test]
+    fn cancelled_ordered_packet() {
+        let now = Instant::now();
+        let mut sender = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut receiver = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let builder = sender.get_packet_builder();
+
+        let mut packets = vec![];
+        for i in 0..3u8 {
+            let packet = builder.new_ordered([i].into_iter().collect()).unwrap();
+            let index = packet.get_index();
+            assert!(index.is_ordered());
+            packets.push((
+                index,
+                sender
+                    .poll(Action::SendReliable(packet).into(), now)
+                    .get_hot_packet()
+                    .to_vec(),
+            ));
+        }
+
+        // The first packet is lost, so the others are held back
+        for (_, data) in &packets[1..] {
+            let action = receiver.poll(Event::IncomingData(data), now);
+            assert_eq!(action.get_hot_packet().len(), 8);
+        }
+        assert_eq!(
+            receiver.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForData
+        );
+
+        // Cancelling the first packet lets the peer skip it
+        sender.poll(Action::CancelReliable(packets[0].0).into(), now);
+        let later = now + Duration::from_millis(100);
+        let tombstone = sender.poll(Event::NoEvent, later).get_hot_packet().to_vec();
+        assert_eq!(tombstone.len(), 24);
+        let action = receiver.poll(Event::IncomingData(&tombstone), later);
+        assert_eq!(action.get_hot_packet().len(), 8);
+        assert_eq!(
+            receiver.poll(Event::NoEvent, later),
+            RecommendedAction::HandleBufferedData {
+                received: &[1],
+                channel: 0
+            }
+        );
+        assert_eq!(
+            receiver.poll(Event::NoEvent, later),
+            RecommendedAction::HandleBufferedData {
+                received: &[2],
+                channel: 0
+            }
+        );
+        assert_eq!(
+            receiver.poll(Event::NoEvent, later),
+            RecommendedAction::WaitForData
+        );
+    }
+
+    /// Returns a body that is too large for a single packet.
+    fn large_body(i: u32) -> Vec<u8> {
+        (0..5000u32).map(|j| (i * 7 + j) as u8).collect()
+    }
+
+    #[test]
+    fn fragmented_over_lossy_link() {
+        let mut handled = send_over_lossy_link(20, |builder, i| {
+            let packet = builder.new_reliable(large_body(i).into()).unwrap();
+            assert!(packet.get_index().is_fragmented());
+            packet.into()
+        });
+        let mut expected: Vec<_> = (0..20).map(large_body).collect();
+        handled.sort();
+        expected.sort();
+        assert_eq!(handled, expected);
+    }
+
+    #[test]
+    fn fragmented_unreliable() {
+        let now = Instant::now();
+        let mut sender = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut receiver = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut link = LossyLink::new(1, 0.0, Duration::ZERO);
+        let packet = sender
+            .get_packet_builder()
+            .new_unreliable(large_body(0).into())
+            .unwrap();
+        drive(
+            &mut sender,
+            Action::SendUnreliable(packet).into(),
+            now,
+            &mut link,
+            &mut vec![],
+        );
+        assert_eq!(link.in_flight.len(), 4);
+        assert!(link.in_flight.iter().all(|(_, data)| data.len() <= 1408));
+
+        let mut handled = vec![];
+        while let Some(data) = link.recv(now) {
+            drive(
+                &mut receiver,
+                Event::IncomingData(&data),
+                now,
+                &mut link,
+                &mut handled,
+            );
+        }
+        assert_eq!(handled, [large_body(0)]);
+    }
+
+    #[test]
+    fn cancel_fragmented() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let packet = state_machine
+            .get_packet_builder()
+            .new_reliable(large_body(0).into())
+            .unwrap();
+        let index = packet.get_index();
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+        assert!(state_machine.is_packet_retransmitting(index));
+
+        state_machine.poll(Action::CancelReliable(index).into(), now);
+        assert!(!state_machine.is_packet_retransmitting(index));
+        // The fragments that were not sent yet are not sent at all
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForData
+        );
+    }
+
+    #[test]
+    fn retransmission_backoff() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let packet = state_machine
+            .get_packet_builder()
+            .new_reliable([15].into_iter().collect())
+            .unwrap();
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForDuration(Duration::from_millis(100))
+        );
+
+        let now = now + Duration::from_millis(100);
+        state_machine.poll(Event::NoEvent, now).get_hot_packet();
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForDuration(Duration::from_millis(200))
+        );
+
+        let now = now + Duration::from_millis(200);
+        state_machine.poll(Event::NoEvent, now).get_hot_packet();
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForDuration(Duration::from_millis(400))
+        );
+
+        // Acknowledging a retransmitted packet does not affect the round-trip time
+        let ack = (1u64 | (1 << 63)).to_be_bytes();
+        state_machine.poll(Event::IncomingData(&ack), now + Duration::from_millis(1));
+        assert_eq!(state_machine.round_trip_time(), None);
+    }
+
+    #[test]
+    fn round_trip_time() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let packet = state_machine
+            .get_packet_builder()
+            .new_reliable([15].into_iter().collect())
+            .unwrap();
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+        let ack = (1u64 | (1 << 63)).to_be_bytes();
+        state_machine.poll(Event::IncomingData(&ack), now + Duration::from_millis(20));
+        assert_eq!(
+            state_machine.round_trip_time(),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(
+            state_machine.retransmission_timeout(),
+            Duration::from_millis(60)
+        );
+
+        // The next packet uses the estimated timeout
+        let packet = state_machine
+            .get_packet_builder()
+            .new_reliable([16].into_iter().collect())
+            .unwrap();
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForDuration(Duration::from_millis(60))
+        );
+    }
+
+    #[test]
+    fn send_rate_limit() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        state_machine.set_send_rate_limit(1000, 100, now);
+        let builder = state_machine.get_packet_builder();
+        let send = |state_machine: &mut PeerStateMachine, byte: u8| {
+            let packet = builder.new_unreliable(vec![byte; 100].into()).unwrap();
+            state_machine.poll(Action::SendUnreliable(packet).into(), now);
+        };
+
+        // The first packet fits in the burst, leaving the bucket 8 bytes short
+        send(&mut state_machine, 1);
+        send(&mut state_machine, 2);
+        send(&mut state_machine, 3);
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForDuration(Duration::from_millis(8))
+        );
+
+        let now = now + Duration::from_millis(8);
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now).get_hot_packet()[0],
+            2
+        );
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForDuration(Duration::from_millis(108))
+        );
+
+        let now = now + Duration::from_millis(108);
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now).get_hot_packet()[0],
+            3
+        );
+
+        // Acknowledgements are not held back
+        let data = [15, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert!(matches!(
+            state_machine.poll(Event::IncomingData(&data), now),
+            RecommendedAction::HandleDataAndSend { .. }
+        ));
+    }
+
+    #[test]
+    fn channel_priority() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        state_machine.set_send_rate_limit(1000, 100, now);
+        let builder = state_machine.get_packet_builder();
+        let bulk = builder.with_channel(Channel::new(2, Priority::Bulk, Reliability::Unreliable));
+        let control =
+            builder.with_channel(Channel::new(1, Priority::Control, Reliability::Reliable));
+
+        for byte in 0..3 {
+            let action = bulk.new_message(vec![byte; 100].into()).unwrap();
+            state_machine.poll(action.into(), now);
+        }
+        let action = control.new_message(vec![9; 100].into()).unwrap();
+        state_machine.poll(action.into(), now);
+        let RecommendedAction::WaitForDuration(delay) = state_machine.poll(Event::NoEvent, now)
+        else {
+            panic!("Expected WaitForDuration");
+        };
+
+        // The control packet jumps the queue of bulk packets
+        let now = now + delay;
+        let packet = state_machine
+            .poll(Event::NoEvent, now)
+            .get_hot_packet()
+            .to_vec();
+        assert_eq!(packet[0], 9);
+
+        // The receiver is told the channel of each packet
+        let mut other_state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let RecommendedAction::HandleDataAndSend { channel, .. } =
+            other_state_machine.poll(Event::IncomingData(&packet), now)
+        else {
+            panic!("Expected HandleDataAndSend");
+        };
+        assert_eq!(channel, 1);
+        let mut unreliable = vec![0; 100];
+        unreliable.extend_from_slice(&(2u64 << 56).to_be_bytes());
+        assert_eq!(
+            other_state_machine.poll(Event::IncomingData(&unreliable), now),
+            RecommendedAction::HandleData {
+                received: &[0; 100],
+                channel: 2
+            }
+        );
+    }
+
+    #[test]
+    fn ordered_channels_are_independent() {
+        let now = Instant::now();
+        let mut sender = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut receiver = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let builder = sender.get_packet_builder();
+        let telemetry =
+            builder.with_channel(Channel::new(1, Priority::Telemetry, Reliability::Ordered));
+        let control =
+            builder.with_channel(Channel::new(2, Priority::Control, Reliability::Ordered));
+
+        let lost = telemetry.new_message(vec![1].into()).unwrap();
+        sender.poll(lost.into(), now);
+        let held_back = telemetry.new_message(vec![2].into()).unwrap();
+        let held_back = sender.poll(held_back.into(), now).get_hot_packet().to_vec();
+        let stop = control.new_message(vec![3].into()).unwrap();
+        let stop = sender.poll(stop.into(), now).get_hot_packet().to_vec();
+
+        assert!(matches!(
+            receiver.poll(Event::IncomingData(&held_back), now),
+            RecommendedAction::SendData(_)
+        ));
+        assert_eq!(
+            receiver.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForData
+        );
+        // The lost telemetry packet does not hold back the control packet
+        assert!(matches!(
+            receiver.poll(Event::IncomingData(&stop), now),
+            RecommendedAction::HandleDataAndSend {
+                received: [3],
+                channel: 2,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn max_transmissions() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let channel = Channel::new(1, Priority::Telemetry, Reliability::Reliable)
+            .with_max_transmissions(NonZeroU32::new(2).unwrap());
+        let builder = state_machine.get_packet_builder().with_channel(channel);
+        let packet = builder.new_reliable(vec![1].into()).unwrap();
+        let index = packet.get_index();
+        assert_eq!(index.channel(), 1);
+
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+        let now = now + Duration::from_millis(100);
+        assert!(matches!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::SendData(_)
+        ));
+        // The packet is given up on instead of being sent a third time
+        let now = now + Duration::from_millis(200);
+        assert_eq!(
+            state_machine.poll(Event::NoEvent, now),
+            RecommendedAction::WaitForData
+        );
+        assert!(!state_machine.is_packet_retransmitting(index));
+        assert_eq!(state_machine.stats().packets_expired, 1);
+    }
+
+    #[test]
+    fn latest_value() {
+        let now = Instant::now();
+        let mut sender = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut receiver = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let channel = Channel::new(2, Priority::Control, Reliability::Latest);
+        let builder = sender.get_packet_builder().with_channel(channel);
+
+        let mut packets = vec![];
+        for value in 1..=2u8 {
+            let packet = builder.new_latest(7, vec![value].into()).unwrap();
+            let index = packet.get_index();
+            assert!(index.is_latest() && !index.is_ordered());
+            let data = sender
+                .poll(Action::SendReliable(packet).into(), now)
+                .get_hot_packet()
+                .to_vec();
+            assert_eq!(data.len(), 1 + 8 + 8);
+            packets.push((index, data));
+        }
+        // Sending the second value stops the first from being retransmitted
+        assert!(!sender.is_packet_retransmitting(packets[0].0));
+        assert!(sender.is_packet_retransmitting(packets[1].0));
+        assert_eq!(sender.stats().packets_superseded, 1);
+
+        assert_eq!(
+            receiver.poll(Event::IncomingData(&packets[1].1), now),
+            RecommendedAction::HandleDataAndSend {
+                received: &[2],
+                to_send: (packets[1].0 .0.get() | (1 << 63)).to_be_bytes(),
+                channel: 2
+            }
+        );
+        // The first value arrives late, so it is acknowledged but not handled
+        let action = receiver.poll(Event::IncomingData(&packets[0].1), now);
+        assert_eq!(action.get_hot_packet().len(), 8);
+
+        // Acknowledging the newest value lets the next value on the key be sent without superseding anything
+        let ack = (packets[1].0 .0.get() | (1 << 63)).to_be_bytes();
+        sender.poll(Event::IncomingData(&ack), now);
+        assert!(!sender.is_packet_retransmitting(packets[1].0));
+        let Action::SendReliable(packet) = builder.new_message(vec![3].into()).unwrap() else {
+            panic!("Expected a reliable packet");
+        };
+        assert!(packet.get_index().is_latest());
+        sender.poll(Action::SendReliable(packet).into(), now);
+        assert_eq!(sender.stats().packets_superseded, 1);
+    }
+
+    #[test]
+    fn connection_stats() {
+        let now = Instant::now();
+        let mut state_machine = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let builder = state_machine.get_packet_builder();
+        let packet = builder.new_reliable([15].into_iter().collect()).unwrap();
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+        let packet = builder.new_reliable(large_body(0).into()).unwrap();
+        state_machine.poll(Action::SendReliable(packet).into(), now);
+
+        let stats = state_machine.stats();
+        assert_eq!(stats.packets_sent, 2);
+        assert_eq!(stats.packets_in_flight, 2);
+        assert_eq!(stats.bytes_in_flight, 9 + 1408);
+        assert_eq!(stats.packets_queued, 3);
+
+        let mut sent = 0;
+        while let RecommendedAction::SendData(_) = state_machine.poll(Event::NoEvent, now) {
+            sent += 1;
+        }
+        assert_eq!(sent, 3);
+        // The first packet and the first fragment are retransmitted
+        let now = now + Duration::from_millis(100);
+        state_machine.poll(Event::NoEvent, now).get_hot_packet();
+        let ack = (1u64 | (1 << 63)).to_be_bytes();
+        state_machine
+            .poll(Event::IncomingData(&ack), now)
+            .get_hot_packet();
+        state_machine.poll(Event::IncomingData(&[1, 0, 0, 0, 0, 0, 0, 0, 0]), now);
+
+        let stats = state_machine.stats();
+        assert_eq!(stats.packets_sent, 5);
+        assert_eq!(stats.packets_retransmitted, 2);
+        assert_eq!(stats.packets_acknowledged, 1);
+        assert_eq!(stats.packets_received, 1);
+        assert_eq!(stats.packets_in_flight, 4);
+        assert_eq!(stats.packets_queued, 0);
+        assert_eq!(stats.smoothed_rtt, None);
+        assert!((stats.retransmission_rate() - 2.0 / 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn authenticated_link() {
+        use auth::{Authenticator, PreSharedKey, Protection};
+
+        let now = Instant::now();
+        let key = PreSharedKey::new([3; 32]);
+        let mut sender = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut receiver = PeerStateMachine::new(Duration::from_millis(100), 256);
+        let mut sender_auth = Authenticator::new(&key, Protection::Encrypt);
+        let mut receiver_auth = Authenticator::new(&key, Protection::Encrypt);
+
+        let packet = sender
+            .get_packet_builder()
+            .new_reliable([15].into_iter().collect())
+            .unwrap();
+        let action = sender.poll(Action::SendReliable(packet).into(), now);
+        let mut datagram = sender_auth.seal(action.get_hot_packet());
+        let received = receiver_auth.open(&mut datagram).unwrap();
+        let RecommendedAction::HandleDataAndSend {
+            received, to_send, ..
+        } = receiver.poll(Event::IncomingData(received), now)
+        else {
+            panic!("Expected HandleDataAndSend");
+        };
+        assert_eq!(received, [15]);
+
+        let mut ack = receiver_auth.seal(&to_send);
+        let ack = sender_auth.open(&mut ack).unwrap();
+        assert_eq!(
+            sender.poll(Event::IncomingData(ack), now),
+            RecommendedAction::WaitForData
+        );
+
+        // A spoofed packet never reaches the state machine
+        let mut spoofed = [16, 0, 0, 0, 0, 0, 0, 0, 2].repeat(5);
+        assert!(receiver_auth.open(&mut spoofed).is_err());
+        assert_eq!(receiver_auth.stats().forged, 1);
+    }
 }
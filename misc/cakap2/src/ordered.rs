@@ -0,0 +1,185 @@
+//! State for ordered reliable packets.
+//!
+//! Ordered packets are reliable packets with [`ORDERED_FLAG`] set in their index. Before the index, they carry
+//! a header of two big-endian `u64`s: the sequence number of the packet, and the oldest sequence number that the
+//! sender has not yet had acknowledged. The receiver holds back packets that arrive before the packets preceding
+//! them, and uses the oldest sequence number to skip over packets it will never receive (for example, if it was
+//! restarted). Each channel has its own sequence numbers.
+//!
+//! Cancelling an ordered packet replaces it with a tombstone, which is the same packet without a payload. The
+//! tombstone is retransmitted until it is acknowledged, so that the receiver is never left waiting for a packet
+//! that will not arrive.
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Set in the index of ordered packets.
+pub(crate) const ORDERED_FLAG: u64 = 1 << 62;
+/// The size of the header that comes before the index of an ordered packet.
+pub(crate) const ORDERED_HEADER_SIZE: usize = 16;
+
+/// Writes the header of an ordered packet, where `data` is the whole packet.
+pub(crate) fn write_header(data: &mut [u8], seq: u64, oldest: u64) {
+    let header_start = data.len() - 8 - ORDERED_HEADER_SIZE;
+    data[header_start..header_start + 8].copy_from_slice(&seq.to_be_bytes());
+    data[header_start + 8..header_start + 16].copy_from_slice(&oldest.to_be_bytes());
+}
+
+/// Reads the header of an ordered packet, where `data` is the whole packet, returning the sequence number,
+/// the oldest sequence number and the payload.
+///
+/// Returns `None` if `data` is too small to be an ordered packet.
+pub(crate) fn read_header(data: &[u8]) -> Option<(u64, u64, &[u8])> {
+    let header_start = data.len().checked_sub(8 + ORDERED_HEADER_SIZE)?;
+    let seq = u64::from_be_bytes(data[header_start..header_start + 8].try_into().unwrap());
+    let oldest = u64::from_be_bytes(
+        data[header_start + 8..header_start + 16]
+            .try_into()
+            .unwrap(),
+    );
+    Some((seq, oldest, &data[..header_start]))
+}
+
+/// Strips the payload from the given ordered packet.
+pub(crate) fn into_tombstone(data: &[u8]) -> Box<[u8]> {
+    data[data.len() - 8 - ORDERED_HEADER_SIZE..].into()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OrderedSender {
+    next_seq: u64,
+    /// The sequence numbers of ordered packets that have not been acknowledged yet.
+    in_flight: BTreeSet<u64>,
+}
+
+impl OrderedSender {
+    /// Assigns a sequence number to a packet that is about to be sent for the first time.
+    pub(crate) fn next_seq(&mut self) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.in_flight.insert(seq);
+        seq
+    }
+
+    /// Returns the oldest sequence number that has not been acknowledged.
+    pub(crate) fn oldest(&self) -> u64 {
+        self.in_flight.first().copied().unwrap_or(self.next_seq)
+    }
+
+    pub(crate) fn acknowledged(&mut self, seq: u64) {
+        self.in_flight.remove(&seq);
+    }
+}
+
+/// What the receiver should do with an ordered packet.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Arrival {
+    /// The packet is next in order, so it should be handled now.
+    Handle,
+    /// The packet was buffered, or it was a tombstone, or it was already handled.
+    /// It should only be acknowledged.
+    Acknowledge,
+    /// There is no room to buffer the packet. It should not be acknowledged, so that the peer retransmits it later.
+    Drop,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OrderedReceiver {
+    /// The sequence number of the next packet to handle.
+    next_seq: u64,
+    /// All packets before this sequence number have been acknowledged or cancelled by the peer.
+    skip_to: u64,
+    /// Packets that arrived before the packets preceding them. Tombstones are stored with empty payloads.
+    buffer: BTreeMap<u64, Box<[u8]>>,
+    /// The buffered payload that is currently being handled.
+    handling: Option<Box<[u8]>>,
+}
+
+impl OrderedReceiver {
+    pub(crate) fn receive(
+        &mut self,
+        seq: u64,
+        oldest: u64,
+        payload: &[u8],
+        max_buffered: usize,
+    ) -> Arrival {
+        self.skip_to = self.skip_to.max(oldest);
+        if seq < self.next_seq || self.buffer.contains_key(&seq) {
+            return Arrival::Acknowledge;
+        }
+        let blocked = self.buffer.range(..seq).next().is_some();
+        if !blocked && (seq == self.next_seq || seq <= self.skip_to) {
+            self.next_seq = seq + 1;
+            return if payload.is_empty() {
+                Arrival::Acknowledge
+            } else {
+                Arrival::Handle
+            };
+        }
+        if self.buffer.len() >= max_buffered {
+            return Arrival::Drop;
+        }
+        self.buffer.insert(seq, payload.into());
+        Arrival::Acknowledge
+    }
+
+    /// Returns the next buffered payload that is ready to be handled, skipping tombstones.
+    pub(crate) fn pop_ready(&mut self) -> Option<&[u8]> {
+        loop {
+            let entry = self.buffer.first_entry()?;
+            let seq = *entry.key();
+            if seq != self.next_seq && seq > self.skip_to {
+                return None;
+            }
+            let payload = entry.remove();
+            self.next_seq = seq + 1;
+            if !payload.is_empty() {
+                self.handling = Some(payload);
+                return self.handling.as_deref();
+            }
+        }
+    }
+
+    /// Returns the payload that was last returned by `pop_ready`.
+    pub(crate) fn handling(&self) -> Option<&[u8]> {
+        self.handling.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reorder() {
+        let mut receiver = OrderedReceiver::default();
+        assert_eq!(receiver.receive(1, 0, &[1], 8), Arrival::Acknowledge);
+        assert_eq!(receiver.receive(2, 0, &[], 8), Arrival::Acknowledge);
+        assert_eq!(receiver.receive(3, 0, &[3], 8), Arrival::Acknowledge);
+        assert_eq!(receiver.pop_ready(), None);
+
+        assert_eq!(receiver.receive(0, 0, &[0], 8), Arrival::Handle);
+        assert_eq!(receiver.pop_ready(), Some([1].as_slice()));
+        // The tombstone is skipped
+        assert_eq!(receiver.pop_ready(), Some([3].as_slice()));
+        assert_eq!(receiver.pop_ready(), None);
+
+        assert_eq!(receiver.receive(1, 0, &[1], 8), Arrival::Acknowledge);
+        assert_eq!(receiver.receive(4, 4, &[4], 8), Arrival::Handle);
+    }
+
+    #[test]
+    fn skip_to_oldest() {
+        let mut receiver = OrderedReceiver::default();
+        assert_eq!(receiver.receive(11, 10, &[11], 8), Arrival::Acknowledge);
+        assert_eq!(receiver.receive(10, 10, &[10], 8), Arrival::Handle);
+        assert_eq!(receiver.pop_ready(), Some([11].as_slice()));
+        assert_eq!(receiver.receive(3, 0, &[3], 8), Arrival::Acknowledge);
+        assert_eq!(receiver.pop_ready(), None);
+    }
+
+    #[test]
+    fn full_buffer() {
+        let mut receiver = OrderedReceiver::default();
+        assert_eq!(receiver.receive(1, 0, &[1], 1), Arrival::Acknowledge);
+        assert_eq!(receiver.receive(2, 0, &[2], 1), Arrival::Drop);
+    }
+}
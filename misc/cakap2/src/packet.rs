@@ -1,11 +1,18 @@
 use std::{
     fmt::Debug,
-    num::NonZeroU64,
+    num::{NonZeroU32, NonZeroU64},
     ops::Deref,
     sync::{atomic::Ordering, Arc},
 };
 
-use crate::{error::BuildPacketError, Shared};
+use crate::{
+    channel::{channel_of, Channel, Priority, Reliability},
+    error::BuildPacketError,
+    fragment::{FragmentHeader, FRAGMENT_FLAG, FRAGMENT_HEADER_SIZE},
+    latest::{LATEST_FLAG, LATEST_HEADER_SIZE},
+    ordered::{ORDERED_FLAG, ORDERED_HEADER_SIZE},
+    Shared,
+};
 
 #[derive(Debug)]
 pub enum Action {
@@ -31,6 +38,10 @@ impl From<UnreliablePacket> for Action {
 pub struct ReliablePacket {
     pub(crate) index: ReliableIndex,
     pub(crate) data: Box<[u8]>,
+    /// The fragments after the first, if the body was too large for a single packet.
+    pub(crate) extra_fragments: Vec<Box<[u8]>>,
+    pub(crate) priority: Priority,
+    pub(crate) max_transmissions: Option<NonZeroU32>,
 }
 
 impl ReliablePacket {
@@ -42,11 +53,43 @@ impl ReliablePacket {
 #[derive(Clone, Debug)]
 pub struct UnreliablePacket {
     pub(crate) data: Box<[u8]>,
+    /// The fragments after the first, if the body was too large for a single packet.
+    pub(crate) extra_fragments: Vec<Box<[u8]>>,
+    pub(crate) priority: Priority,
 }
 
 #[derive(Clone, Copy, Debug)]
 pub struct ReliableIndex(pub(crate) NonZeroU64);
 
+impl ReliableIndex {
+    /// Returns `true` iff this is the index of a packet made with [`PacketBuilder::new_ordered`].
+    pub fn is_ordered(&self) -> bool {
+        self.0.get() != RECONNECTION_INDEX && self.0.get() & ORDERED_FLAG != 0
+    }
+
+    /// Returns `true` iff this is the index of a reliable message that was too large for a single packet.
+    ///
+    /// Cancelling this index cancels all fragments of the message.
+    pub fn is_fragmented(&self) -> bool {
+        self.0.get() != RECONNECTION_INDEX && self.0.get() & FRAGMENT_FLAG != 0
+    }
+
+    /// Returns `true` iff this is the index of a packet made with [`PacketBuilder::new_latest`].
+    pub fn is_latest(&self) -> bool {
+        self.0.get() != RECONNECTION_INDEX && self.0.get() & LATEST_FLAG != 0
+    }
+
+    /// Returns the id of the channel that the packet was built on.
+    pub fn channel(&self) -> u8 {
+        channel_of(self.0.get())
+    }
+}
+
+/// Reliable indices must stay below this, as the bits above are used as flags.
+pub(crate) const MAX_RELIABLE_INDEX: u64 = LATEST_FLAG - 1;
+/// The index of the packet that asks the peer to clear its received set, which has every flag set.
+pub(crate) const RECONNECTION_INDEX: u64 = !(1 << 63);
+
 pub struct PacketBody {
     pub data: Vec<u8>,
 }
@@ -73,30 +116,70 @@ impl FromIterator<u8> for PacketBody {
 }
 
 /// Used to create reliable and unreliable packets.
+///
+/// Packets are built on [`Channel::DEFAULT`] unless another channel is chosen with [`PacketBuilder::with_channel`].
 #[derive(Clone, Debug)]
 pub struct PacketBuilder {
     pub(crate) shared: Arc<Shared>,
+    pub(crate) channel: Channel,
 }
 
 impl PacketBuilder {
+    /// Returns a builder that builds packets on the given channel.
+    pub fn with_channel(&self, channel: Channel) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            channel,
+        }
+    }
+
+    /// Returns the channel that packets are built on.
+    pub fn channel(&self) -> Channel {
+        self.channel
+    }
+
+    /// Builds a packet with the reliability of the channel of this builder.
+    pub fn new_message(&self, body: PacketBody) -> Result<Action, BuildPacketError> {
+        Ok(match self.channel.reliability() {
+            Reliability::Unreliable => self.new_unreliable(body)?.into(),
+            Reliability::Reliable => self.new_reliable(body)?.into(),
+            Reliability::Ordered => self.new_ordered(body)?.into(),
+            Reliability::Latest => self.new_latest(0, body)?.into(),
+        })
+    }
+
     /// Sends the given bytes unreliably.
     ///
     /// The last 8 bytes of the given message will be overwritten with zeroes, so leave space for that.
     /// If the given bytes are shorter than 9, the bytes will be returned. This means packets cannot
     /// have a zero-sized payload.
+    ///
+    /// Bodies larger than the maximum packet size are split into fragments, which the peer reassembles.
+    /// If any fragment is lost, the whole message is lost.
     pub fn new_unreliable(&self, body: PacketBody) -> Result<UnreliablePacket, BuildPacketError> {
-        if body.data.is_empty() {
-            return Err(BuildPacketError::EmptyBuffer { buffer: body.data });
-        }
-        if body.data.len() > self.shared.max_packet_size {
-            return Err(BuildPacketError::BufferTooLarge {
-                buffer: body.data,
-                max_packet_size: self.shared.max_packet_size,
+        let body = self.check_body(body, usize::MAX)?;
+        let channel_bits = self.channel.index_bits();
+        if body.data.len() <= self.shared.max_packet_size {
+            return Ok(UnreliablePacket {
+                data: body.into_bytes(&channel_bits.to_be_bytes()),
+                extra_fragments: vec![],
+                priority: self.channel.priority(),
             });
         }
 
+        let fragment_count = self.fragment_count(&body)?;
+        let message_id = self
+            .shared
+            .unreliable_message_id
+            .fetch_add(1, Ordering::Relaxed);
+        let mut fragments = self.fragment(&body, fragment_count, message_id, |_| {
+            FRAGMENT_FLAG | channel_bits
+        });
+
         Ok(UnreliablePacket {
-            data: body.into_bytes(&[0; 8]),
+            data: fragments.remove(0),
+            extra_fragments: fragments,
+            priority: self.channel.priority(),
         })
     }
 
@@ -106,28 +189,161 @@ impl PacketBuilder {
     /// If the given bytes are shorter than 9, the bytes will be returned. This means packets cannot
     /// have a zero-sized payload.
     ///
+    /// Bodies larger than the maximum packet size are split into fragments, each of which is sent reliably,
+    /// and which the peer reassembles before handling the message.
+    ///
     /// # Safety
-    /// Strictly speaking, unexpected behavior can occur if this method is called 2^63 - 1 times per struct due to overflow.
-    /// However, this is hopefully not a practical concern.
+    /// Strictly speaking, unexpected behavior can occur if reliable indices are allocated 2^55 - 1 times per struct
+    /// due to overflow. Fragmented messages allocate one index per fragment. However, this is hopefully not a
+    /// practical concern.
     pub fn new_reliable(&self, body: PacketBody) -> Result<ReliablePacket, BuildPacketError> {
+        let body = self.check_body(body, usize::MAX)?;
+        if body.data.len() <= self.shared.max_packet_size {
+            let reliable_index = self.next_reliable_indices(1);
+            return Ok(ReliablePacket {
+                data: body.into_bytes(&reliable_index.get().to_be_bytes()),
+                index: ReliableIndex(reliable_index),
+                extra_fragments: vec![],
+                priority: self.channel.priority(),
+                max_transmissions: self.channel.max_transmissions(),
+            });
+        }
+
+        let fragment_count = self.fragment_count(&body)?;
+        let first_index = self.next_reliable_indices(fragment_count);
+        let mut fragments = self.fragment(&body, fragment_count, first_index.get(), |i| {
+            (first_index.get() + i as u64) | FRAGMENT_FLAG
+        });
+
+        Ok(ReliablePacket {
+            data: fragments.remove(0),
+            index: ReliableIndex(first_index | FRAGMENT_FLAG),
+            extra_fragments: fragments,
+            priority: self.channel.priority(),
+            max_transmissions: self.channel.max_transmissions(),
+        })
+    }
+
+    /// Sends the given bytes reliably, such that the peer handles them after all ordered packets that were sent
+    /// before them on the same channel.
+    ///
+    /// Order is determined by when the packet is given to the state machine with [`Action::SendReliable`],
+    /// not when it is built. Unordered reliable and unreliable packets are not held back by ordered packets,
+    /// and neither are ordered packets on other channels.
+    ///
+    /// Cancelling an ordered packet will still send a small packet to the peer until it is acknowledged, so
+    /// that the peer does not wait for the cancelled packet.
+    ///
+    /// Ordered packets are not fragmented, and have room for slightly less data than other packets.
+    pub fn new_ordered(&self, body: PacketBody) -> Result<ReliablePacket, BuildPacketError> {
+        let body = self.check_body(body, self.shared.max_packet_size - ORDERED_HEADER_SIZE)?;
+        let reliable_index = self.next_reliable_indices(1) | ORDERED_FLAG;
+        // The header is written by the state machine when the packet is sent
+        let mut extra = [0; ORDERED_HEADER_SIZE + 8];
+        extra[ORDERED_HEADER_SIZE..].copy_from_slice(&reliable_index.get().to_be_bytes());
+
+        Ok(ReliablePacket {
+            data: body.into_bytes(&extra),
+            index: ReliableIndex(reliable_index),
+            extra_fragments: vec![],
+            priority: self.channel.priority(),
+            max_transmissions: self.channel.max_transmissions(),
+        })
+    }
+
+    /// Sends the given bytes eventually reliably, as the newest value of `key`.
+    ///
+    /// Sending the packet cancels the packet that was last sent on the same key and channel if it has not been
+    /// acknowledged, and the peer ignores packets that arrive after a newer packet on the same key and channel.
+    /// This suits state that is replaced as a whole, such as setpoints, where only the latest value matters but
+    /// it must eventually arrive.
+    ///
+    /// Packets are newer if they were built later. Latest-value packets are not fragmented, and have room for
+    /// slightly less data than other packets.
+    pub fn new_latest(
+        &self,
+        key: u64,
+        body: PacketBody,
+    ) -> Result<ReliablePacket, BuildPacketError> {
+        let body = self.check_body(body, self.shared.max_packet_size - LATEST_HEADER_SIZE)?;
+        let reliable_index = self.next_reliable_indices(1) | LATEST_FLAG;
+        let mut extra = [0; LATEST_HEADER_SIZE + 8];
+        extra[..LATEST_HEADER_SIZE].copy_from_slice(&key.to_be_bytes());
+        extra[LATEST_HEADER_SIZE..].copy_from_slice(&reliable_index.get().to_be_bytes());
+
+        Ok(ReliablePacket {
+            data: body.into_bytes(&extra),
+            index: ReliableIndex(reliable_index),
+            extra_fragments: vec![],
+            priority: self.channel.priority(),
+            max_transmissions: self.channel.max_transmissions(),
+        })
+    }
+
+    fn check_body(
+        &self,
+        body: PacketBody,
+        max_size: usize,
+    ) -> Result<PacketBody, BuildPacketError> {
         if body.data.is_empty() {
             return Err(BuildPacketError::EmptyBuffer { buffer: body.data });
         }
-        if body.data.len() > self.shared.max_packet_size {
+        if body.data.len() > max_size {
             return Err(BuildPacketError::BufferTooLarge {
                 buffer: body.data,
-                max_packet_size: self.shared.max_packet_size,
+                max_packet_size: max_size,
             });
         }
+        Ok(body)
+    }
+
+    /// Returns the number of fragments needed to send the given body.
+    fn fragment_count(&self, body: &PacketBody) -> Result<u32, BuildPacketError> {
+        let fragment_size = self.shared.max_packet_size - FRAGMENT_HEADER_SIZE;
+        let Ok(fragment_count) = u32::try_from(body.data.len().div_ceil(fragment_size)) else {
+            return Err(BuildPacketError::BufferTooLarge {
+                buffer: body.data.clone(),
+                max_packet_size: fragment_size * u32::MAX as usize,
+            });
+        };
+        Ok(fragment_count)
+    }
 
-        let reliable_index = self.shared.reliable_index.fetch_add(1, Ordering::Relaxed);
-        let bytes = body.into_bytes(&reliable_index.to_be_bytes());
-        let reliable_index = NonZeroU64::new(reliable_index).expect("Reliable Index has overflowed. Consider reconstructing the state machine earlier to avoid this");
+    /// Splits the given body into fragments, where `index` returns the index to put at the end of each fragment.
+    fn fragment(
+        &self,
+        body: &PacketBody,
+        fragment_count: u32,
+        message_id: u64,
+        index: impl Fn(u32) -> u64,
+    ) -> Vec<Box<[u8]>> {
+        let fragment_size = self.shared.max_packet_size - FRAGMENT_HEADER_SIZE;
+        body.data
+            .chunks(fragment_size)
+            .zip(0..)
+            .map(|(chunk, fragment_index)| {
+                let header = FragmentHeader {
+                    message_id,
+                    fragment_index,
+                    fragment_count,
+                };
+                let mut fragment = Vec::with_capacity(chunk.len() + FRAGMENT_HEADER_SIZE + 8);
+                fragment.extend_from_slice(chunk);
+                fragment.extend_from_slice(&header.to_bytes());
+                fragment.extend_from_slice(&index(fragment_index).to_be_bytes());
+                fragment.into_boxed_slice()
+            })
+            .collect()
+    }
 
-        Ok(ReliablePacket {
-            data: bytes,
-            index: ReliableIndex(reliable_index),
-        })
+    /// Allocates `count` consecutive reliable indices on the channel of this builder, returning the first.
+    fn next_reliable_indices(&self, count: u32) -> NonZeroU64 {
+        let reliable_index = self
+            .shared
+            .reliable_index
+            .fetch_add(count as u64, Ordering::Relaxed);
+        assert!(reliable_index + count as u64 - 1 <= MAX_RELIABLE_INDEX, "Reliable Index has overflowed. Consider reconstructing the state machine earlier to avoid this");
+        NonZeroU64::new(reliable_index).unwrap() | self.channel.index_bits()
     }
 }
 
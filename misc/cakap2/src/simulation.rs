@@ -0,0 +1,593 @@
+//! A deterministic simulation of two peers over a virtual link.
+//!
+//! The link loses, duplicates, delays and reorders datagrams according to a seeded random number generator, and
+//! time only advances when the simulation says so, so every run with the same seed is identical. Each test runs
+//! many seeds and checks the guarantees of the protocol rather than specific packets.
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    channel::{Channel, Priority, Reliability},
+    error::CakapError,
+    packet::{Action, PacketBuilder, ReliableIndex},
+    Event, PeerStateMachine, RecommendedAction,
+};
+
+/// The number of seeds each test is run with.
+const SEEDS: u64 = 32;
+
+/// A xorshift64* generator, which is all the randomness the simulation needs.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must never be zero
+        Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns a number in `0..n`.
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Returns `true` with the given probability.
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 <= probability
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LinkConfig {
+    loss: f64,
+    duplication: f64,
+    min_delay: Duration,
+    /// Datagrams are delayed by a random duration between `min_delay` and `max_delay`, so a wide range reorders
+    /// them often.
+    max_delay: Duration,
+}
+
+impl LinkConfig {
+    const PERFECT: Self = Self {
+        loss: 0.0,
+        duplication: 0.0,
+        min_delay: Duration::from_millis(5),
+        max_delay: Duration::from_millis(5),
+    };
+
+    const HOSTILE: Self = Self {
+        loss: 0.25,
+        duplication: 0.1,
+        min_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(80),
+    };
+}
+
+/// A datagram on the link, ordered by arrival time, then by the order it was sent in.
+type InFlight = Reverse<(Instant, u64, Box<[u8]>)>;
+
+/// One direction of the virtual link.
+struct Link {
+    config: LinkConfig,
+    rng: Rng,
+    in_flight: BinaryHeap<InFlight>,
+    sent: u64,
+}
+
+impl Link {
+    fn new(config: LinkConfig, seed: u64) -> Self {
+        Self {
+            config,
+            rng: Rng::new(seed),
+            in_flight: BinaryHeap::new(),
+            sent: 0,
+        }
+    }
+
+    fn send(&mut self, data: &[u8], now: Instant) {
+        if self.rng.chance(self.config.loss) {
+            return;
+        }
+        let copies = if self.rng.chance(self.config.duplication) {
+            2
+        } else {
+            1
+        };
+        let jitter = (self.config.max_delay - self.config.min_delay).as_micros() as u64 + 1;
+        for _ in 0..copies {
+            let delay = self.config.min_delay + Duration::from_micros(self.rng.below(jitter));
+            self.in_flight
+                .push(Reverse((now + delay, self.sent, data.into())));
+            self.sent += 1;
+        }
+    }
+
+    fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.peek().map(|Reverse((at, _, _))| *at)
+    }
+
+    fn recv(&mut self, now: Instant) -> Option<Box<[u8]>> {
+        if self.next_arrival()? > now {
+            return None;
+        }
+        self.in_flight.pop().map(|Reverse((_, _, data))| data)
+    }
+}
+
+struct Peer {
+    state_machine: PeerStateMachine,
+    /// When the state machine last asked to be polled, if it did.
+    wake_at: Option<Instant>,
+    /// The channel and contents of every message handled, in order.
+    handled: Vec<(u8, Vec<u8>)>,
+    errors: Vec<CakapError>,
+}
+
+impl Peer {
+    fn new(state_machine: PeerStateMachine) -> Self {
+        Self {
+            state_machine,
+            wake_at: None,
+            handled: vec![],
+            errors: vec![],
+        }
+    }
+
+    /// Takes every action the state machine recommends until it waits, sending over `link`.
+    fn drive(&mut self, event: Event, now: Instant, link: &mut Link) {
+        let mut action = self.state_machine.poll(event, now);
+        // A bounded loop, so that a state machine that never waits fails the test instead of hanging it
+        for _ in 0..100_000 {
+            match action {
+                RecommendedAction::WaitForData => {
+                    self.wake_at = None;
+                    return;
+                }
+                RecommendedAction::WaitForDuration(duration) => {
+                    self.wake_at = Some(now + duration);
+                    return;
+                }
+                RecommendedAction::HandleError(e) => self.errors.push(e),
+                RecommendedAction::HandleData { received, channel }
+                | RecommendedAction::HandleBufferedData { received, channel } => {
+                    self.handled.push((channel, received.to_vec()));
+                }
+                RecommendedAction::HandleDataAndSend {
+                    received,
+                    to_send,
+                    channel,
+                } => {
+                    self.handled.push((channel, received.to_vec()));
+                    link.send(&to_send, now);
+                }
+                RecommendedAction::SendData(packet) => link.send(&packet, now),
+            }
+            action = self.state_machine.poll(Event::NoEvent, now);
+        }
+        panic!("The state machine never waited");
+    }
+}
+
+/// Two peers, `a` and `b`, connected by a virtual link.
+struct Simulation {
+    now: Instant,
+    a: Peer,
+    b: Peer,
+    a_to_b: Link,
+    b_to_a: Link,
+}
+
+impl Simulation {
+    fn new(config: LinkConfig, seed: u64, max_received_set_size: usize) -> Self {
+        let new_peer = || {
+            Peer::new(PeerStateMachine::new(
+                Duration::from_millis(100),
+                max_received_set_size,
+            ))
+        };
+        Self {
+            now: Instant::now(),
+            a: new_peer(),
+            b: new_peer(),
+            a_to_b: Link::new(config, seed),
+            b_to_a: Link::new(config, !seed),
+        }
+    }
+
+    /// Gives the given action to `a`.
+    fn send(&mut self, action: impl Into<Action>) {
+        self.a
+            .drive(Event::Action(action.into()), self.now, &mut self.a_to_b);
+    }
+
+    fn next_event(&self) -> Option<Instant> {
+        [
+            self.a_to_b.next_arrival(),
+            self.b_to_a.next_arrival(),
+            self.a.wake_at,
+            self.b.wake_at,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Advances to the next arrival or timer, if it is not after `until`.
+    ///
+    /// Returns `false` if there was nothing to do.
+    fn step(&mut self, until: Instant) -> bool {
+        let Some(next) = self.next_event() else {
+            return false;
+        };
+        if next > until {
+            return false;
+        }
+        self.now = self.now.max(next);
+        let now = self.now;
+        while let Some(data) = self.a_to_b.recv(now) {
+            self.b
+                .drive(Event::IncomingData(&data), now, &mut self.b_to_a);
+        }
+        while let Some(data) = self.b_to_a.recv(now) {
+            self.a
+                .drive(Event::IncomingData(&data), now, &mut self.a_to_b);
+        }
+        if self.a.wake_at.is_some_and(|at| at <= now) {
+            self.a.drive(Event::NoEvent, now, &mut self.a_to_b);
+        }
+        if self.b.wake_at.is_some_and(|at| at <= now) {
+            self.b.drive(Event::NoEvent, now, &mut self.b_to_a);
+        }
+        true
+    }
+
+    fn run_for(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        while self.step(until) {}
+        self.now = until;
+    }
+
+    /// Runs until nothing is in flight and neither peer is waiting to retransmit.
+    fn run_until_idle(&mut self) {
+        let deadline = self.now + Duration::from_secs(600);
+        while self.step(deadline) {}
+        assert!(
+            self.next_event().is_none(),
+            "The simulation did not settle within 10 minutes"
+        );
+    }
+}
+
+/// Builds a message that starts with the given id, of a random size that is sometimes fragmented.
+fn message(id: u64, rng: &mut Rng, max_size: usize) -> Vec<u8> {
+    let size = match rng.below(4) {
+        0 => 8 + rng.below(1600) as usize,
+        1 => 8 + rng.below(6000) as usize,
+        _ => 8 + rng.below(32) as usize,
+    }
+    .min(max_size);
+    let mut data = id.to_be_bytes().to_vec();
+    data.extend((8..size).map(|i| (id as usize + i) as u8));
+    data
+}
+
+fn message_id(data: &[u8]) -> u64 {
+    u64::from_be_bytes(data[..8].try_into().unwrap())
+}
+
+/// Counts how many times each message was handled.
+fn handled_counts(handled: &[(u8, Vec<u8>)]) -> HashMap<u64, usize> {
+    let mut counts = HashMap::new();
+    for (_, data) in handled {
+        *counts.entry(message_id(data)).or_default() += 1;
+    }
+    counts
+}
+
+/// Sends `count` reliable messages on `builder`, a few at a time, and returns their indices.
+fn send_in_bursts(
+    sim: &mut Simulation,
+    builder: &PacketBuilder,
+    ids: std::ops::Range<u64>,
+    rng: &mut Rng,
+) -> Vec<ReliableIndex> {
+    let max_size = match builder.channel().reliability() {
+        Reliability::Ordered => 1384,
+        _ => usize::MAX,
+    };
+    let mut indices = vec![];
+    for id in ids {
+        let packet = match builder.channel().reliability() {
+            Reliability::Ordered => builder.new_ordered(message(id, rng, max_size).into()),
+            _ => builder.new_reliable(message(id, rng, max_size).into()),
+        }
+        .unwrap();
+        indices.push(packet.get_index());
+        sim.send(packet);
+        if id % 8 == 7 {
+            sim.run_for(Duration::from_millis(rng.below(40)));
+        }
+    }
+    indices
+}
+
+#[test]
+fn reliable_exactly_once() {
+    for seed in 0..SEEDS {
+        let mut sim = Simulation::new(LinkConfig::HOSTILE, seed, 4096);
+        let mut rng = Rng::new(seed);
+        let builder = sim.a.state_machine.get_packet_builder();
+        let indices = send_in_bursts(&mut sim, &builder, 0..200, &mut rng);
+        sim.run_until_idle();
+
+        let counts = handled_counts(&sim.b.handled);
+        for id in 0..200 {
+            assert_eq!(counts.get(&id), Some(&1), "seed {seed}, message {id}");
+        }
+        assert_eq!(counts.len(), 200, "seed {seed}");
+        assert!(sim.a.errors.is_empty() && sim.b.errors.is_empty());
+        for index in indices {
+            assert!(!sim.a.state_machine.is_packet_retransmitting(index));
+        }
+        assert_eq!(sim.a.state_machine.stats().packets_in_flight, 0);
+    }
+}
+
+#[test]
+fn ordered_in_order() {
+    let channels = [
+        Channel::new(1, Priority::Control, Reliability::Ordered),
+        Channel::new(2, Priority::Bulk, Reliability::Ordered),
+    ];
+    for seed in 0..SEEDS {
+        let mut sim = Simulation::new(LinkConfig::HOSTILE, seed, 1024);
+        let mut rng = Rng::new(seed);
+        let builder = sim.a.state_machine.get_packet_builder();
+        for (i, &channel) in channels.iter().enumerate() {
+            let start = i as u64 * 1000;
+            send_in_bursts(
+                &mut sim,
+                &builder.with_channel(channel),
+                start..start + 100,
+                &mut rng,
+            );
+        }
+        sim.run_until_idle();
+
+        for (i, channel) in channels.iter().enumerate() {
+            let ids: Vec<_> = sim
+                .b
+                .handled
+                .iter()
+                .filter(|(id, _)| *id == channel.id())
+                .map(|(_, data)| message_id(data))
+                .collect();
+            let start = i as u64 * 1000;
+            assert_eq!(ids, (start..start + 100).collect::<Vec<_>>(), "seed {seed}");
+        }
+    }
+}
+
+#[test]
+fn cancellation() {
+    for seed in 0..SEEDS {
+        let mut sim = Simulation::new(LinkConfig::HOSTILE, seed, 1024);
+        let mut rng = Rng::new(seed);
+        let reliable = sim.a.state_machine.get_packet_builder();
+        let ordered =
+            reliable.with_channel(Channel::new(3, Priority::Telemetry, Reliability::Ordered));
+        let mut indices = send_in_bursts(&mut sim, &reliable, 0..50, &mut rng);
+        indices.extend(send_in_bursts(&mut sim, &ordered, 50..100, &mut rng));
+
+        let mut cancelled = [false; 100];
+        for (id, &index) in indices.iter().enumerate() {
+            if rng.chance(0.3) {
+                cancelled[id] = true;
+                sim.send(Action::CancelReliable(index));
+                // Only ordered packets keep being retransmitted, as tombstones
+                if !index.is_ordered() {
+                    assert!(!sim.a.state_machine.is_packet_retransmitting(index));
+                }
+            }
+        }
+        sim.run_until_idle();
+
+        // Cancelled messages may have been handled before they were cancelled, but never twice,
+        // and the rest are handled exactly once
+        let counts = handled_counts(&sim.b.handled);
+        for id in 0..100 {
+            let count = counts.get(&id).copied().unwrap_or(0);
+            if cancelled[id as usize] {
+                assert!(count <= 1, "seed {seed}, message {id}");
+            } else {
+                assert_eq!(count, 1, "seed {seed}, message {id}");
+            }
+        }
+        // The receiver skipped the cancelled ordered packets instead of waiting for them
+        let ordered_ids: Vec<_> = sim
+            .b
+            .handled
+            .iter()
+            .filter(|(channel, _)| *channel == 3)
+            .map(|(_, data)| message_id(data))
+            .collect();
+        assert!(ordered_ids.windows(2).all(|pair| pair[0] < pair[1]));
+        for index in indices {
+            assert!(!sim.a.state_machine.is_packet_retransmitting(index));
+        }
+    }
+}
+
+#[test]
+fn reconnection_clears_state() {
+    for seed in 0..SEEDS {
+        let mut sim = Simulation::new(LinkConfig::HOSTILE, seed, 1024);
+        let mut rng = Rng::new(seed);
+        let channel = Channel::new(4, Priority::Telemetry, Reliability::Ordered);
+        let builder = sim.a.state_machine.get_packet_builder();
+        send_in_bursts(&mut sim, &builder, 0..50, &mut rng);
+        send_in_bursts(&mut sim, &builder.with_channel(channel), 50..100, &mut rng);
+        sim.run_until_idle();
+        assert_eq!(handled_counts(&sim.b.handled).len(), 100);
+
+        // `a` restarts, forgetting its indices and sequence numbers, so without a reconnection
+        // message `b` would ignore its new messages as duplicates
+        sim.a = Peer::new(PeerStateMachine::new(Duration::from_millis(100), 1024));
+        let (action, index) = sim.a.state_machine.send_reconnection_msg(sim.now);
+        sim.a_to_b.send(action.get_hot_packet(), sim.now);
+        sim.a.drive(Event::NoEvent, sim.now, &mut sim.a_to_b);
+        // New messages must not overtake the reconnection message, or be overtaken by a stray copy of it
+        sim.run_until_idle();
+        assert!(!sim.a.state_machine.is_packet_retransmitting(index));
+
+        let builder = sim.a.state_machine.get_packet_builder();
+        send_in_bursts(&mut sim, &builder, 100..150, &mut rng);
+        send_in_bursts(&mut sim, &builder.with_channel(channel), 150..200, &mut rng);
+        sim.run_until_idle();
+
+        let counts = handled_counts(&sim.b.handled);
+        for id in 0..200 {
+            assert_eq!(counts.get(&id), Some(&1), "seed {seed}, message {id}");
+        }
+        let ordered_ids: Vec<_> = sim
+            .b
+            .handled
+            .iter()
+            .filter(|(id, _)| *id == channel.id())
+            .map(|(_, data)| message_id(data))
+            .collect();
+        assert_eq!(ordered_ids, (50..100).chain(150..200).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn latest_values_converge() {
+    let channel = Channel::new(5, Priority::Control, Reliability::Latest);
+    for seed in 0..SEEDS {
+        let mut sim = Simulation::new(LinkConfig::HOSTILE, seed, 64);
+        let mut rng = Rng::new(seed);
+        let builder = sim
+            .a
+            .state_machine
+            .get_packet_builder()
+            .with_channel(channel);
+        let mut keys = vec![];
+        let mut newest = HashMap::new();
+        for id in 0..300 {
+            let key = rng.below(4);
+            let packet = builder.new_latest(key, message(id, &mut rng, 1392).into());
+            sim.send(packet.unwrap());
+            keys.push(key);
+            newest.insert(key, id);
+            sim.run_for(Duration::from_millis(rng.below(20)));
+        }
+        sim.run_until_idle();
+
+        // Values are handled oldest to newest on each key, and the newest value always arrives
+        let mut handled: HashMap<u64, Vec<u64>> = HashMap::new();
+        for (_, data) in &sim.b.handled {
+            let id = message_id(data);
+            handled.entry(keys[id as usize]).or_default().push(id);
+        }
+        for (key, ids) in &handled {
+            assert!(ids.windows(2).all(|pair| pair[0] < pair[1]), "seed {seed}");
+            assert_eq!(ids.last(), newest.get(key), "seed {seed}");
+        }
+        assert_eq!(handled.len(), newest.len());
+        assert_eq!(sim.a.state_machine.stats().packets_in_flight, 0);
+    }
+}
+
+#[test]
+fn perfect_link_never_retransmits() {
+    let mut sim = Simulation::new(LinkConfig::PERFECT, 0, 1024);
+    let mut rng = Rng::new(0);
+    let builder = sim.a.state_machine.get_packet_builder();
+    send_in_bursts(&mut sim, &builder, 0..100, &mut rng);
+    sim.run_until_idle();
+    assert_eq!(handled_counts(&sim.b.handled).len(), 100);
+    assert_eq!(sim.a.state_machine.stats().packets_retransmitted, 0);
+}
+
+/// Feeds arbitrary and mangled datagrams to a state machine, which must never panic or stop waiting.
+///
+/// The fuzz targets in `fuzz/` do the same with coverage guidance, but need a nightly toolchain.
+#[test]
+fn arbitrary_incoming_data() {
+    let interesting_trailers = [
+        0,
+        1,
+        u64::MAX,
+        1 << 63,
+        !(1 << 63),
+        1 << 62,
+        1 << 61,
+        (1 << 62) | (1 << 61),
+        31 << 56,
+        (1 << 63) | (1 << 62) | 5,
+        (1 << 63) | (1 << 61) | 5,
+    ];
+
+    for seed in 0..SEEDS {
+        let mut rng = Rng::new(seed);
+        let mut now = Instant::now();
+        let mut sink = Link::new(LinkConfig::PERFECT, seed);
+        // Real packets to mangle, which are left in flight for arbitrary acknowledgements to hit
+        let mut sender = Peer::new(PeerStateMachine::new(Duration::from_millis(100), 64));
+        let builder = sender.state_machine.get_packet_builder();
+        let ordered =
+            builder.with_channel(Channel::new(1, Priority::Control, Reliability::Ordered));
+        for id in 0..16 {
+            let packet = if id < 8 {
+                builder.new_reliable(message(id, &mut rng, usize::MAX).into())
+            } else {
+                ordered.new_ordered(message(id, &mut rng, 1384).into())
+            };
+            sender.drive(Event::Action(packet.unwrap().into()), now, &mut sink);
+        }
+        let valid: Vec<_> = std::iter::from_fn(|| sink.in_flight.pop())
+            .map(|Reverse((_, _, data))| data)
+            .collect();
+
+        let mut receiver = Peer::new(PeerStateMachine::new(Duration::from_millis(100), 64));
+        for _ in 0..2000 {
+            let mut data = match rng.below(3) {
+                0 => (0..rng.below(48)).map(|_| rng.next_u64() as u8).collect(),
+                1 => {
+                    let mut data = (0..rng.below(48))
+                        .map(|_| rng.next_u64() as u8)
+                        .collect::<Vec<_>>();
+                    let trailer =
+                        interesting_trailers[rng.below(interesting_trailers.len() as u64) as usize];
+                    data.extend(trailer.to_be_bytes());
+                    data
+                }
+                _ => valid[rng.below(valid.len() as u64) as usize].to_vec(),
+            };
+            if !data.is_empty() && rng.chance(0.5) {
+                for _ in 0..1 + rng.below(4) {
+                    let i = rng.below(data.len() as u64) as usize;
+                    data[i] ^= 1 << rng.below(8);
+                }
+            }
+            if rng.chance(0.1) {
+                data.truncate(rng.below(data.len() as u64 + 1) as usize);
+            }
+            receiver.drive(Event::IncomingData(&data), now, &mut sink);
+            sender.drive(Event::IncomingData(&data), now, &mut sink);
+            now += Duration::from_millis(rng.below(10));
+            if rng.chance(0.05) {
+                receiver.drive(Event::NoEvent, now, &mut sink);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Statistics about the link to the peer.
+use std::time::Duration;
+
+/// A snapshot of the statistics of a [`PeerStateMachine`](crate::PeerStateMachine).
+///
+/// Counts are totals since the state machine was created. To get rates, take snapshots periodically
+/// and compare them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// The smoothed round-trip time, if any acknowledgement has been received yet.
+    pub smoothed_rtt: Option<Duration>,
+    /// The mean deviation of the round-trip time.
+    pub jitter: Duration,
+    /// The current retransmission timeout, before any backoff.
+    pub retransmission_timeout: Duration,
+    /// The number of packets sent for the first time, including fragments, but not acknowledgements.
+    pub packets_sent: u64,
+    /// The number of packets received from the peer, including duplicates, but not acknowledgements.
+    pub packets_received: u64,
+    /// The number of reliable packets that the peer has acknowledged.
+    pub packets_acknowledged: u64,
+    /// The number of times that reliable packets were retransmitted.
+    pub packets_retransmitted: u64,
+    /// The number of reliable packets that were given up on after being sent the maximum number of times
+    /// allowed by their [`Channel`](crate::channel::Channel).
+    pub packets_expired: u64,
+    /// The number of latest-value packets that were cancelled before being acknowledged, because a newer value
+    /// was sent on the same key.
+    pub packets_superseded: u64,
+    /// The number of reliable packets that were sent, but have not been acknowledged or cancelled.
+    pub packets_in_flight: usize,
+    /// The total size of the packets in flight.
+    pub bytes_in_flight: usize,
+    /// The number of packets that are waiting to be sent, either because they are fragments of a larger message,
+    /// or because the send rate limit was reached.
+    pub packets_queued: usize,
+}
+
+impl ConnectionStats {
+    /// Returns the fraction of transmissions that were retransmissions, which approximates the loss rate
+    /// of the link in both directions.
+    pub fn retransmission_rate(&self) -> f64 {
+        let transmissions = self.packets_sent + self.packets_retransmitted;
+        if transmissions == 0 {
+            0.0
+        } else {
+            self.packets_retransmitted as f64 / transmissions as f64
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::{
+    auth::{AuthStats, Authenticator},
+    error::BuildPacketError,
+    packet::{Action, PacketBody, PacketBuilder},
+    stats::ConnectionStats,
+    Event, PeerStateMachine,
+};
+
+use super::{is_ignored_recv_error, Link, PeerEvent, Remote, MAX_DATAGRAM_SIZE};
+
+#[derive(Default)]
+struct SharedStats {
+    connection: ConnectionStats,
+    auth: Option<AuthStats>,
+}
+
+/// Runs a [`PeerStateMachine`] over a tokio [`UdpSocket`].
+pub struct AsyncDriver {
+    link: Link,
+    action_rx: UnboundedReceiver<Action>,
+    event_tx: UnboundedSender<PeerEvent>,
+    stats: Arc<Mutex<SharedStats>>,
+}
+
+impl AsyncDriver {
+    /// Creates a driver for the given state machine, along with the handles used to send and receive through it.
+    ///
+    /// The state machine should be fully configured before being given to the driver.
+    pub fn new(
+        state_machine: PeerStateMachine,
+        remote: Remote,
+    ) -> (AsyncDriver, PeerSender, PeerReceiver) {
+        let (action_tx, action_rx) = mpsc::unbounded_channel();
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let stats: Arc<Mutex<SharedStats>> = Default::default();
+        let sender = PeerSender {
+            builder: state_machine.get_packet_builder(),
+            action_tx,
+            stats: stats.clone(),
+        };
+        let driver = AsyncDriver {
+            link: Link::new(state_machine, remote),
+            action_rx,
+            event_tx,
+            stats,
+        };
+        (driver, sender, PeerReceiver { event_rx })
+    }
+
+    /// Seals every outgoing datagram and rejects incoming datagrams that fail to open.
+    ///
+    /// Both peers must use the same key, or none at all.
+    pub fn set_authenticator(&mut self, authenticator: Option<Authenticator>) {
+        self.link.authenticator = authenticator;
+    }
+
+    /// Drives the link until every [`PeerSender`] has been dropped.
+    ///
+    /// Received messages are dropped if the [`PeerReceiver`] has been dropped.
+    pub async fn run(self, socket: UdpSocket) {
+        let Self {
+            mut link,
+            mut action_rx,
+            event_tx,
+            stats,
+        } = self;
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut outgoing = vec![];
+        let mut on_event = |event| {
+            let _ = event_tx.send(event);
+        };
+
+        link.start(Instant::now(), &mut queue(&mut outgoing), &mut on_event);
+
+        loop {
+            flush(&socket, &mut outgoing, &mut on_event).await;
+            {
+                let mut stats = stats.lock().unwrap();
+                stats.connection = link.state_machine.stats();
+                stats.auth = link.authenticator.as_ref().map(Authenticator::stats);
+            }
+            let poll_in = link.poll_in(Instant::now());
+
+            tokio::select! {
+                action = action_rx.recv() => {
+                    let Some(action) = action else {
+                        break;
+                    };
+                    link.pump(Event::Action(action), Instant::now(), &mut queue(&mut outgoing), &mut on_event);
+                }
+                result = socket.recv_from(&mut buf) => {
+                    match result {
+                        Ok((n, from)) => link.incoming(&mut buf[..n], from, Instant::now(), &mut queue(&mut outgoing), &mut on_event),
+                        Err(e) if is_ignored_recv_error(&e) => {}
+                        Err(e) => on_event(PeerEvent::Error(e.into())),
+                    }
+                }
+                _ = tokio::time::sleep(poll_in.unwrap_or_default()), if poll_in.is_some() => {
+                    link.pump(Event::NoEvent, Instant::now(), &mut queue(&mut outgoing), &mut on_event);
+                }
+            }
+        }
+    }
+}
+
+type Outgoing = Vec<(Vec<u8>, Option<SocketAddr>)>;
+
+/// Returns a send function for a [`Link`] that only queues datagrams, since the link cannot wait for the socket.
+fn queue(outgoing: &mut Outgoing) -> impl FnMut(&[u8], Option<SocketAddr>) -> io::Result<()> + '_ {
+    |data, addr| {
+        outgoing.push((data.to_vec(), addr));
+        Ok(())
+    }
+}
+
+/// Sends every queued datagram, waiting for the socket to be writable.
+async fn flush(socket: &UdpSocket, outgoing: &mut Outgoing, on_event: &mut impl FnMut(PeerEvent)) {
+    for (data, addr) in outgoing.drain(..) {
+        let result = match addr {
+            Some(addr) => socket.send_to(&data, addr).await,
+            None => socket.send(&data).await,
+        };
+        if let Err(e) = result {
+            on_event(PeerEvent::Error(e.into()));
+        }
+    }
+}
+
+/// Sends messages through an [`AsyncDriver`].
+#[derive(Clone)]
+pub struct PeerSender {
+    builder: PacketBuilder,
+    action_tx: UnboundedSender<Action>,
+    stats: Arc<Mutex<SharedStats>>,
+}
+
+impl PeerSender {
+    /// Gives the given action to the driver.
+    ///
+    /// Returns `false` if the driver has stopped.
+    pub fn send(&self, action: impl Into<Action>) -> bool {
+        self.action_tx.send(action.into()).is_ok()
+    }
+
+    pub fn packet_builder(&self) -> &PacketBuilder {
+        &self.builder
+    }
+
+    /// Sends the given bytes reliably.
+    pub fn send_reliable(&self, body: impl Into<PacketBody>) -> Result<(), BuildPacketError> {
+        self.send(self.builder.new_reliable(body.into())?);
+        Ok(())
+    }
+
+    /// Sends the given bytes reliably and in order.
+    pub fn send_ordered(&self, body: impl Into<PacketBody>) -> Result<(), BuildPacketError> {
+        self.send(self.builder.new_ordered(body.into())?);
+        Ok(())
+    }
+
+    /// Sends the given bytes unreliably.
+    pub fn send_unreliable(&self, body: impl Into<PacketBody>) -> Result<(), BuildPacketError> {
+        self.send(self.builder.new_unreliable(body.into())?);
+        Ok(())
+    }
+
+    /// Returns the statistics of the link, as of the last time the driver handled an event.
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats.lock().unwrap().connection
+    }
+
+    /// Returns the statistics of the authenticator, if there is one.
+    pub fn auth_stats(&self) -> Option<AuthStats> {
+        self.stats.lock().unwrap().auth
+    }
+}
+
+/// Receives messages and errors from an [`AsyncDriver`].
+pub struct PeerReceiver {
+    event_rx: UnboundedReceiver<PeerEvent>,
+}
+
+impl PeerReceiver {
+    /// Returns the next event, or `None` if the driver has stopped.
+    pub async fn recv(&mut self) -> Option<PeerEvent> {
+        self.event_rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn exchange() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket
+            .connect(server_socket.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let (server, server_tx, mut server_rx) = AsyncDriver::new(
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+            Remote::LastSender,
+        );
+        let (client, client_tx, mut client_rx) = AsyncDriver::new(
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+            Remote::Connected,
+        );
+        tokio::spawn(server.run(server_socket));
+        tokio::spawn(client.run(client_socket));
+
+        client_tx.send_reliable(b"hello".to_vec()).unwrap();
+        let Some(PeerEvent::Received { data, .. }) = server_rx.recv().await else {
+            panic!("Expected a message");
+        };
+        assert_eq!(&*data, b"hello");
+
+        server_tx.send_ordered(b"world".to_vec()).unwrap();
+        let Some(PeerEvent::Received { data, .. }) = client_rx.recv().await else {
+            panic!("Expected a message");
+        };
+        assert_eq!(&*data, b"world");
+
+        // The driver stops once every sender is dropped
+        drop(client_tx);
+        assert!(client_rx.recv().await.is_none());
+    }
+}
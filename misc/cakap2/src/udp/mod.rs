@@ -0,0 +1,357 @@
+//! Drivers that run a [`PeerStateMachine`] over a UDP socket.
+//!
+//! [`PolledPeer`] is polled without blocking, which suits game engine loops that run once per frame.
+//! With the `tokio` feature, [`AsyncDriver`] runs as a task and is used through a [`PeerSender`] and
+//! a [`PeerReceiver`]. [`PolledServer`] is polled like [`PolledPeer`], but talks to any number of peers
+//! over the same socket, with a state machine for each address.
+//!
+//! All drivers send the reconnection message before anything else, and acknowledge every message that they
+//! hand over. Packets that are recommended to be sent before the address of the peer is known are dropped, and
+//! reliable ones are retransmitted once it is known. Datagrams that fail authentication never change the address
+//! of the peer.
+use std::{
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::Authenticator,
+    error::{AuthError, CakapError},
+    packet::Action,
+    reconnection_packet, Event, PeerStateMachine, RecommendedAction,
+};
+
+#[cfg(feature = "tokio")]
+mod driver;
+mod polled;
+mod server;
+
+#[cfg(feature = "tokio")]
+pub use driver::{AsyncDriver, PeerReceiver, PeerSender};
+pub use polled::PolledPeer;
+pub use server::{PolledServer, ServerEvent};
+
+/// The largest datagram that a driver can receive.
+const MAX_DATAGRAM_SIZE: usize = 1408 + crate::auth::OVERHEAD;
+
+/// Where packets are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Remote {
+    /// The socket is already connected to the peer.
+    ///
+    /// The reconnection message is sent as soon as the driver starts.
+    Connected,
+    /// Packets are sent to the address of the last datagram that was accepted from the peer.
+    ///
+    /// The reconnection message is sent when the first datagram is accepted.
+    LastSender,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DriverError {
+    #[error("Socket error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Protocol(CakapError),
+    #[error("Rejected datagram: {0}")]
+    Rejected(AuthError),
+}
+
+/// Something that happened on a link that the user should know about.
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// A message from the peer, and the id of the channel that it was sent on.
+    Received { data: Box<[u8]>, channel: u8 },
+    /// An error that did not stop the driver.
+    Error(DriverError),
+}
+
+/// The I/O independent part of a driver.
+struct Link {
+    state_machine: PeerStateMachine,
+    authenticator: Option<Authenticator>,
+    remote: Remote,
+    /// The address to send to, if the remote is [`Remote::LastSender`].
+    peer_addr: Option<SocketAddr>,
+    /// Whether the reconnection message has been sent.
+    reconnected: bool,
+    /// When the state machine asked to be polled again.
+    poll_at: Option<Instant>,
+    /// When the last datagram was accepted from the peer.
+    last_accepted: Option<Instant>,
+}
+
+impl Link {
+    fn new(state_machine: PeerStateMachine, remote: Remote) -> Self {
+        Self {
+            state_machine,
+            authenticator: None,
+            remote,
+            peer_addr: None,
+            reconnected: false,
+            poll_at: None,
+            last_accepted: None,
+        }
+    }
+
+    /// Sends the reconnection message if the remote is connected and it has not been sent yet.
+    fn start(
+        &mut self,
+        now: Instant,
+        send: &mut impl FnMut(&[u8], Option<SocketAddr>) -> io::Result<()>,
+        on_event: &mut impl FnMut(PeerEvent),
+    ) {
+        if self.remote == Remote::Connected && !self.reconnected {
+            self.reconnected = true;
+            self.pump(
+                Event::Action(Action::SendReliable(reconnection_packet())),
+                now,
+                send,
+                on_event,
+            );
+        }
+    }
+
+    /// Handles a datagram from `from`.
+    fn incoming(
+        &mut self,
+        datagram: &mut [u8],
+        from: SocketAddr,
+        now: Instant,
+        send: &mut impl FnMut(&[u8], Option<SocketAddr>) -> io::Result<()>,
+        on_event: &mut impl FnMut(PeerEvent),
+    ) {
+        let data = match &mut self.authenticator {
+            Some(authenticator) => match authenticator.open(datagram) {
+                Ok(data) => data,
+                Err(e) => {
+                    on_event(PeerEvent::Error(DriverError::Rejected(e)));
+                    return;
+                }
+            },
+            None => datagram,
+        };
+        self.last_accepted = Some(now);
+        if self.remote == Remote::LastSender {
+            self.peer_addr = Some(from);
+            if !self.reconnected {
+                self.reconnected = true;
+                self.pump(
+                    Event::Action(Action::SendReliable(reconnection_packet())),
+                    now,
+                    send,
+                    on_event,
+                );
+            }
+        }
+        self.pump(Event::IncomingData(data), now, send, on_event);
+    }
+
+    /// Polls the state machine with `event`, and follows the recommended actions until it has to wait.
+    fn pump(
+        &mut self,
+        event: Event,
+        now: Instant,
+        send: &mut impl FnMut(&[u8], Option<SocketAddr>) -> io::Result<()>,
+        on_event: &mut impl FnMut(PeerEvent),
+    ) {
+        let mut action = self.state_machine.poll(event, now);
+        loop {
+            match action {
+                RecommendedAction::WaitForData => {
+                    self.poll_at = None;
+                    break;
+                }
+                RecommendedAction::WaitForDuration(duration) => {
+                    self.poll_at = Some(now + duration);
+                    break;
+                }
+                RecommendedAction::HandleError(e) => {
+                    on_event(PeerEvent::Error(DriverError::Protocol(e)));
+                }
+                RecommendedAction::HandleData { received, channel }
+                | RecommendedAction::HandleBufferedData { received, channel } => {
+                    on_event(PeerEvent::Received {
+                        data: received.into(),
+                        channel,
+                    });
+                }
+                RecommendedAction::HandleDataAndSend {
+                    received,
+                    to_send,
+                    channel,
+                } => {
+                    on_event(PeerEvent::Received {
+                        data: received.into(),
+                        channel,
+                    });
+                    seal_and_send(
+                        &mut self.authenticator,
+                        self.remote,
+                        self.peer_addr,
+                        &to_send,
+                        send,
+                        on_event,
+                    );
+                }
+                RecommendedAction::SendData(hot_packet) => {
+                    seal_and_send(
+                        &mut self.authenticator,
+                        self.remote,
+                        self.peer_addr,
+                        &hot_packet,
+                        send,
+                        on_event,
+                    );
+                }
+            }
+            action = self.state_machine.poll(Event::NoEvent, now);
+        }
+    }
+
+    /// Returns how long until the state machine should be polled again, if it has to be.
+    fn poll_in(&self, now: Instant) -> Option<Duration> {
+        self.poll_at
+            .map(|poll_at| poll_at.saturating_duration_since(now))
+    }
+}
+
+/// Sends `data` to the peer, sealing it first if there is an authenticator.
+///
+/// `send` is given the address to send to, or `None` if the socket is connected.
+fn seal_and_send(
+    authenticator: &mut Option<Authenticator>,
+    remote: Remote,
+    peer_addr: Option<SocketAddr>,
+    data: &[u8],
+    send: &mut impl FnMut(&[u8], Option<SocketAddr>) -> io::Result<()>,
+    on_event: &mut impl FnMut(PeerEvent),
+) {
+    let addr = match remote {
+        Remote::Connected => None,
+        Remote::LastSender => {
+            let Some(addr) = peer_addr else {
+                return;
+            };
+            Some(addr)
+        }
+    };
+    let result = match authenticator {
+        Some(authenticator) => send(&authenticator.seal(data), addr),
+        None => send(data, addr),
+    };
+    match result {
+        Ok(()) => {}
+        // Like any other lost packet
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        Err(e) => on_event(PeerEvent::Error(e.into())),
+    }
+}
+
+/// Returns `true` iff the given error from receiving should be ignored.
+///
+/// Connected sockets report that the peer is not listening yet as an error.
+fn is_ignored_recv_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::ConnectionRefused
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{PreSharedKey, Protection};
+
+    type Sent = Vec<(Vec<u8>, Option<SocketAddr>)>;
+
+    fn sender(sent: &mut Sent) -> impl FnMut(&[u8], Option<SocketAddr>) -> io::Result<()> + '_ {
+        |data, addr| {
+            sent.push((data.to_vec(), addr));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn last_sender() {
+        let now = Instant::now();
+        let mut link = Link::new(
+            PeerStateMachine::new(Duration::from_millis(100), 256),
+            Remote::LastSender,
+        );
+        let mut sent = vec![];
+        let mut events = vec![];
+        link.start(now, &mut sender(&mut sent), &mut |event| events.push(event));
+        // Nothing can be sent until the peer is known
+        let packet = link
+            .state_machine
+            .get_packet_builder()
+            .new_reliable(vec![1].into())
+            .unwrap();
+        link.pump(
+            Action::SendReliable(packet).into(),
+            now,
+            &mut sender(&mut sent),
+            &mut |event| events.push(event),
+        );
+        assert!(sent.is_empty());
+        assert_eq!(link.poll_in(now), Some(Duration::from_millis(100)));
+
+        let addr = "127.0.0.1:10600".parse().unwrap();
+        link.incoming(
+            &mut [7, 0, 0, 0, 0, 0, 0, 0, 0],
+            addr,
+            now,
+            &mut sender(&mut sent),
+            &mut |event| events.push(event),
+        );
+        // The reconnection message is sent first
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, (!(1u64 << 63)).to_be_bytes());
+        assert_eq!(sent[0].1, Some(addr));
+        assert!(matches!(&events[..], [PeerEvent::Received { data, channel: 0 }] if **data == [7]));
+    }
+
+    #[test]
+    fn rejected_datagrams_do_not_change_the_peer() {
+        let now = Instant::now();
+        let key = PreSharedKey::new([1; 32]);
+        let mut peer_auth = Authenticator::new(&key, Protection::Authenticate);
+        let mut link = Link::new(
+            PeerStateMachine::new(Duration::from_millis(100), 256),
+            Remote::LastSender,
+        );
+        link.authenticator = Some(Authenticator::new(&key, Protection::Authenticate));
+        let mut sent = vec![];
+        let mut events = vec![];
+
+        let attacker = "10.0.0.66:10600".parse().unwrap();
+        link.incoming(
+            &mut [7, 0, 0, 0, 0, 0, 0, 0, 0].repeat(5),
+            attacker,
+            now,
+            &mut sender(&mut sent),
+            &mut |event| events.push(event),
+        );
+        assert_eq!(link.peer_addr, None);
+        assert!(matches!(
+            &events[..],
+            [PeerEvent::Error(DriverError::Rejected(AuthError::Forged))]
+        ));
+
+        let peer = "10.0.0.45:10600".parse().unwrap();
+        let mut datagram = peer_auth.seal(&[7, 0, 0, 0, 0, 0, 0, 0, 0]);
+        link.incoming(
+            &mut datagram,
+            peer,
+            now,
+            &mut sender(&mut sent),
+            &mut |event| events.push(event),
+        );
+        assert_eq!(link.peer_addr, Some(peer));
+        // The reconnection message is sealed
+        let (reconnection, _) = &mut sent[0];
+        assert_eq!(
+            peer_auth.open(reconnection).unwrap(),
+            (!(1u64 << 63)).to_be_bytes()
+        );
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Instant,
+};
+
+use crate::{
+    auth::{AuthStats, Authenticator},
+    error::BuildPacketError,
+    packet::{Action, PacketBody, PacketBuilder},
+    stats::ConnectionStats,
+    Event, PeerStateMachine,
+};
+
+use super::{is_ignored_recv_error, Link, PeerEvent, Remote, MAX_DATAGRAM_SIZE};
+
+/// A driver that never blocks, for loops that already run periodically, such as game loops.
+///
+/// Actions are queued until the next call to [`PolledPeer::poll`], which also receives every datagram that
+/// has arrived and retransmits whatever is due. Call it at least as often as the smallest retransmission
+/// timeout.
+pub struct PolledPeer {
+    socket: UdpSocket,
+    link: Link,
+    queued: Vec<Action>,
+    buf: Box<[u8]>,
+}
+
+impl PolledPeer {
+    /// Wraps the given socket, which is made non-blocking.
+    ///
+    /// The state machine should be fully configured before being given to the driver.
+    pub fn new(
+        socket: UdpSocket,
+        remote: Remote,
+        state_machine: PeerStateMachine,
+    ) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            link: Link::new(state_machine, remote),
+            queued: vec![],
+            buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+        })
+    }
+
+    /// Seals every outgoing datagram and rejects incoming datagrams that fail to open.
+    ///
+    /// Both peers must use the same key, or none at all.
+    pub fn set_authenticator(&mut self, authenticator: Option<Authenticator>) {
+        self.link.authenticator = authenticator;
+    }
+
+    pub fn packet_builder(&self) -> PacketBuilder {
+        self.link.state_machine.get_packet_builder()
+    }
+
+    /// Queues the given action until the next poll.
+    pub fn send(&mut self, action: impl Into<Action>) {
+        self.queued.push(action.into());
+    }
+
+    /// Queues the given bytes to be sent reliably.
+    pub fn send_reliable(&mut self, body: impl Into<PacketBody>) -> Result<(), BuildPacketError> {
+        let packet = self.packet_builder().new_reliable(body.into())?;
+        self.send(packet);
+        Ok(())
+    }
+
+    /// Queues the given bytes to be sent reliably and in order.
+    pub fn send_ordered(&mut self, body: impl Into<PacketBody>) -> Result<(), BuildPacketError> {
+        let packet = self.packet_builder().new_ordered(body.into())?;
+        self.send(packet);
+        Ok(())
+    }
+
+    /// Queues the given bytes to be sent unreliably.
+    pub fn send_unreliable(&mut self, body: impl Into<PacketBody>) -> Result<(), BuildPacketError> {
+        let packet = self.packet_builder().new_unreliable(body.into())?;
+        self.send(packet);
+        Ok(())
+    }
+
+    /// Sends queued actions, receives every datagram that has arrived, and retransmits what is due.
+    ///
+    /// Returns the messages and errors that occurred, in order.
+    pub fn poll(&mut self, now: Instant) -> Vec<PeerEvent> {
+        let mut events = vec![];
+        let Self {
+            socket,
+            link,
+            queued,
+            buf,
+        } = self;
+        let mut send = |data: &[u8], addr: Option<SocketAddr>| {
+            match addr {
+                Some(addr) => socket.send_to(data, addr),
+                None => socket.send(data),
+            }
+            .map(drop)
+        };
+        let mut on_event = |event| events.push(event);
+
+        link.start(now, &mut send, &mut on_event);
+        for action in queued.drain(..) {
+            link.pump(Event::Action(action), now, &mut send, &mut on_event);
+        }
+        loop {
+            match socket.recv_from(buf) {
+                Ok((n, from)) => link.incoming(&mut buf[..n], from, now, &mut send, &mut on_event),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if is_ignored_recv_error(&e) => {}
+                Err(e) => {
+                    on_event(PeerEvent::Error(e.into()));
+                    break;
+                }
+            }
+        }
+        if link.poll_in(now).is_some_and(|duration| duration.is_zero()) {
+            link.pump(Event::NoEvent, now, &mut send, &mut on_event);
+        }
+        events
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.link.state_machine.stats()
+    }
+
+    /// Returns the statistics of the authenticator, if there is one.
+    pub fn auth_stats(&self) -> Option<AuthStats> {
+        self.link.authenticator.as_ref().map(Authenticator::stats)
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn exchange() {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_socket
+            .connect(server_socket.local_addr().unwrap())
+            .unwrap();
+        let mut server = PolledPeer::new(
+            server_socket,
+            Remote::LastSender,
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+        )
+        .unwrap();
+        let mut client = PolledPeer::new(
+            client_socket,
+            Remote::Connected,
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+        )
+        .unwrap();
+
+        client.send_reliable(b"hello".to_vec()).unwrap();
+        let mut received = vec![];
+        for _ in 0..100 {
+            client.poll(Instant::now());
+            received.extend(server.poll(Instant::now()));
+            if !received.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(matches!(&received[..], [PeerEvent::Received { data, .. }] if **data == *b"hello"));
+
+        // The server now knows where to reply
+        server.send_unreliable(b"world".to_vec()).unwrap();
+        let mut received = vec![];
+        for _ in 0..100 {
+            server.poll(Instant::now());
+            received.extend(client.poll(Instant::now()));
+            if !received.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(matches!(&received[..], [PeerEvent::Received { data, .. }] if **data == *b"world"));
+        assert_eq!(client.stats().packets_in_flight, 0);
+    }
+}
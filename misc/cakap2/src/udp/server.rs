@@ -0,0 +1,403 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    auth::{AuthStats, Authenticator, PreSharedKey, Protection},
+    packet::{Action, PacketBuilder},
+    stats::ConnectionStats,
+    Event, PeerStateMachine,
+};
+
+use super::{is_ignored_recv_error, Link, PeerEvent, Remote, MAX_DATAGRAM_SIZE};
+
+/// Something that happened on a [`PolledServer`].
+#[derive(Debug)]
+pub enum ServerEvent {
+    /// The first datagram was accepted from a new address.
+    Connected(SocketAddr),
+    /// Nothing was accepted from the peer within the peer timeout, so its state machine was dropped.
+    Disconnected(SocketAddr),
+    /// Something happened on the link to a peer.
+    ///
+    /// Datagrams that are rejected from addresses that are not peers are also reported here, but
+    /// do not make them peers.
+    Peer { addr: SocketAddr, event: PeerEvent },
+    /// An error from the socket that is not specific to any peer.
+    Error(io::Error),
+}
+
+struct ServerPeer {
+    link: Link,
+    /// Actions queued until the next poll.
+    queued: Vec<Action>,
+}
+
+/// A driver that talks to any number of peers over one socket, and is polled without blocking like
+/// [`PolledPeer`](super::PolledPeer).
+///
+/// Each address that a datagram is accepted from gets its own [`PeerStateMachine`], and is sent the reconnection
+/// message, so messages from different peers are never confused with each other. A peer that restarts from the
+/// same address sends its own reconnection message, which resets the state machine of the server as usual.
+/// Peers that go quiet for longer than the peer timeout are forgotten, and are treated as new peers if they
+/// come back.
+pub struct PolledServer {
+    socket: UdpSocket,
+    peers: FxHashMap<SocketAddr, ServerPeer>,
+    new_state_machine: Box<dyn FnMut() -> PeerStateMachine + Send>,
+    key: Option<(PreSharedKey, Protection)>,
+    peer_timeout: Duration,
+    max_peers: usize,
+    buf: Box<[u8]>,
+}
+
+impl PolledServer {
+    /// Wraps the given socket, which is made non-blocking.
+    ///
+    /// `new_state_machine` is called for every new peer, and should return a fully configured state machine.
+    pub fn new(
+        socket: UdpSocket,
+        new_state_machine: impl FnMut() -> PeerStateMachine + Send + 'static,
+    ) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            peers: Default::default(),
+            new_state_machine: Box::new(new_state_machine),
+            key: None,
+            peer_timeout: Duration::from_secs(10),
+            max_peers: 16,
+            buf: vec![0; MAX_DATAGRAM_SIZE].into_boxed_slice(),
+        })
+    }
+
+    /// Seals every outgoing datagram and rejects incoming datagrams that fail to open, for current and future peers.
+    ///
+    /// Every peer must use the same key, or none at all.
+    pub fn set_pre_shared_key(&mut self, key: Option<(PreSharedKey, Protection)>) {
+        for peer in self.peers.values_mut() {
+            peer.link.authenticator = key
+                .as_ref()
+                .map(|(key, protection)| Authenticator::new(key, *protection));
+        }
+        self.key = key;
+    }
+
+    /// Sets how long a peer may go without a datagram being accepted from it before it is forgotten.
+    ///
+    /// By default, the timeout is 10 seconds.
+    pub fn set_peer_timeout(&mut self, timeout: Duration) {
+        self.peer_timeout = timeout;
+    }
+
+    /// Sets the maximum number of peers. Datagrams from new addresses are dropped while there are this many peers.
+    ///
+    /// By default, the maximum is 16.
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers;
+    }
+
+    /// Returns the addresses of all peers.
+    pub fn peers(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers.keys().copied()
+    }
+
+    pub fn is_peer(&self, addr: SocketAddr) -> bool {
+        self.peers.contains_key(&addr)
+    }
+
+    /// Returns a builder for packets to the given peer, or `None` if it is not a peer.
+    ///
+    /// Packets must only be sent to the peer whose builder built them.
+    pub fn packet_builder(&self, addr: SocketAddr) -> Option<PacketBuilder> {
+        self.peers
+            .get(&addr)
+            .map(|peer| peer.link.state_machine.get_packet_builder())
+    }
+
+    /// Queues the given action for the given peer until the next poll.
+    ///
+    /// Returns `false` if the address is not a peer.
+    pub fn send(&mut self, addr: SocketAddr, action: impl Into<Action>) -> bool {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return false;
+        };
+        peer.queued.push(action.into());
+        true
+    }
+
+    /// Forgets the given peer, dropping anything that has not been sent to it.
+    ///
+    /// Returns `false` if the address is not a peer.
+    pub fn disconnect(&mut self, addr: SocketAddr) -> bool {
+        self.peers.remove(&addr).is_some()
+    }
+
+    /// Sends queued actions, receives every datagram that has arrived, retransmits what is due, and forgets peers
+    /// that have timed out.
+    ///
+    /// Returns everything that happened, in order.
+    pub fn poll(&mut self, now: Instant) -> Vec<ServerEvent> {
+        let mut events = vec![];
+        let Self {
+            socket,
+            peers,
+            new_state_machine,
+            key,
+            peer_timeout,
+            max_peers,
+            buf,
+        } = self;
+        let mut send = |data: &[u8], addr: Option<SocketAddr>| {
+            socket
+                .send_to(data, addr.expect("Peers should have an address"))
+                .map(drop)
+        };
+
+        for (&addr, peer) in peers.iter_mut() {
+            for action in peer.queued.drain(..) {
+                peer.link
+                    .pump(Event::Action(action), now, &mut send, &mut |event| {
+                        events.push(ServerEvent::Peer { addr, event })
+                    });
+            }
+        }
+        loop {
+            let (n, from) = match socket.recv_from(buf) {
+                Ok(received) => received,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if is_ignored_recv_error(&e) => continue,
+                Err(e) => {
+                    events.push(ServerEvent::Error(e));
+                    break;
+                }
+            };
+            let mut on_event = |event| events.push(ServerEvent::Peer { addr: from, event });
+            if let Some(peer) = peers.get_mut(&from) {
+                peer.link
+                    .incoming(&mut buf[..n], from, now, &mut send, &mut on_event);
+                continue;
+            }
+            if peers.len() >= *max_peers {
+                continue;
+            }
+            let mut link = Link::new(new_state_machine(), Remote::LastSender);
+            link.authenticator = key
+                .as_ref()
+                .map(|(key, protection)| Authenticator::new(key, *protection));
+            // The peer is only known once it is accepted, so its events wait until then
+            let mut link_events = vec![];
+            link.incoming(&mut buf[..n], from, now, &mut send, &mut |event| {
+                link_events.push(event)
+            });
+            if link.last_accepted.is_some() {
+                events.push(ServerEvent::Connected(from));
+                peers.insert(
+                    from,
+                    ServerPeer {
+                        link,
+                        queued: vec![],
+                    },
+                );
+            }
+            events.extend(
+                link_events
+                    .into_iter()
+                    .map(|event| ServerEvent::Peer { addr: from, event }),
+            );
+        }
+
+        peers.retain(|&addr, peer| {
+            let timed_out = peer
+                .link
+                .last_accepted
+                .is_some_and(|last| now.saturating_duration_since(last) >= *peer_timeout);
+            if timed_out {
+                events.push(ServerEvent::Disconnected(addr));
+            }
+            !timed_out
+        });
+        for (&addr, peer) in peers.iter_mut() {
+            if peer
+                .link
+                .poll_in(now)
+                .is_some_and(|duration| duration.is_zero())
+            {
+                peer.link
+                    .pump(Event::NoEvent, now, &mut send, &mut |event| {
+                        events.push(ServerEvent::Peer { addr, event })
+                    });
+            }
+        }
+        events
+    }
+
+    /// Returns the statistics of the link to the given peer, or `None` if it is not a peer.
+    pub fn stats(&self, addr: SocketAddr) -> Option<ConnectionStats> {
+        self.peers
+            .get(&addr)
+            .map(|peer| peer.link.state_machine.stats())
+    }
+
+    /// Returns the statistics of the authenticator of the given peer, if it is a peer and there is an authenticator.
+    pub fn auth_stats(&self, addr: SocketAddr) -> Option<AuthStats> {
+        self.peers
+            .get(&addr)?
+            .link
+            .authenticator
+            .as_ref()
+            .map(Authenticator::stats)
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::PolledPeer;
+
+    fn client(server: &PolledServer) -> PolledPeer {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .connect(server.socket().local_addr().unwrap())
+            .unwrap();
+        PolledPeer::new(
+            socket,
+            Remote::Connected,
+            PeerStateMachine::new(Duration::from_millis(50), 64),
+        )
+        .unwrap()
+    }
+
+    /// Polls everything until `done` returns `true` for the events of the server.
+    fn poll_until(
+        server: &mut PolledServer,
+        clients: &mut [&mut PolledPeer],
+        mut done: impl FnMut(&[ServerEvent]) -> bool,
+    ) -> Vec<ServerEvent> {
+        let mut events = vec![];
+        for _ in 0..100 {
+            for client in clients.iter_mut() {
+                client.poll(Instant::now());
+            }
+            events.extend(server.poll(Instant::now()));
+            if done(&events) {
+                return events;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        panic!("Timed out with {events:?}");
+    }
+
+    fn received(events: &[ServerEvent]) -> Vec<(SocketAddr, &[u8])> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                ServerEvent::Peer {
+                    addr,
+                    event: PeerEvent::Received { data, .. },
+                } => Some((*addr, &**data)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn several_peers() {
+        let mut server = PolledServer::new(UdpSocket::bind("127.0.0.1:0").unwrap(), || {
+            PeerStateMachine::new(Duration::from_millis(50), 64)
+        })
+        .unwrap();
+        let mut first = client(&server);
+        let mut second = client(&server);
+        let first_addr = first.socket().local_addr().unwrap();
+        let second_addr = second.socket().local_addr().unwrap();
+
+        first.send_reliable(b"first".to_vec()).unwrap();
+        second.send_reliable(b"second".to_vec()).unwrap();
+        let events = poll_until(&mut server, &mut [&mut first, &mut second], |events| {
+            received(events).len() == 2
+        });
+        let mut received = received(&events);
+        received.sort();
+        let mut expected = vec![
+            (first_addr, b"first".as_slice()),
+            (second_addr, b"second".as_slice()),
+        ];
+        expected.sort();
+        assert_eq!(received, expected);
+        assert!(server.is_peer(first_addr) && server.is_peer(second_addr));
+
+        // Each peer only receives what was sent to it
+        let builder = server.packet_builder(second_addr).unwrap();
+        assert!(server.send(
+            second_addr,
+            builder.new_reliable(b"reply".to_vec().into()).unwrap()
+        ));
+        let mut replies = vec![];
+        for _ in 0..100 {
+            server.poll(Instant::now());
+            replies.extend(first.poll(Instant::now()));
+            assert!(replies.is_empty());
+            let events = second.poll(Instant::now());
+            if let Some(PeerEvent::Received { data, .. }) = events.first() {
+                assert_eq!(&**data, b"reply");
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(server.stats(second_addr).unwrap().packets_sent, 2);
+    }
+
+    #[test]
+    fn peer_timeout() {
+        let mut server = PolledServer::new(UdpSocket::bind("127.0.0.1:0").unwrap(), || {
+            PeerStateMachine::new(Duration::from_millis(50), 64)
+        })
+        .unwrap();
+        server.set_peer_timeout(Duration::from_millis(50));
+        let mut client = client(&server);
+        let addr = client.socket().local_addr().unwrap();
+        client.send_unreliable(b"hello".to_vec()).unwrap();
+        let events = poll_until(&mut server, &mut [&mut client], |events| {
+            !received(events).is_empty()
+        });
+        assert!(matches!(events[0], ServerEvent::Connected(connected) if connected == addr));
+
+        // The client goes quiet, so it is forgotten
+        std::thread::sleep(Duration::from_millis(60));
+        let events = server.poll(Instant::now());
+        assert!(
+            matches!(&events[..], [ServerEvent::Disconnected(disconnected)] if *disconnected == addr)
+        );
+        assert!(!server.is_peer(addr));
+        assert!(!server.send(addr, Action::CancelAllReliable));
+    }
+
+    #[test]
+    fn max_peers() {
+        let mut server = PolledServer::new(UdpSocket::bind("127.0.0.1:0").unwrap(), || {
+            PeerStateMachine::new(Duration::from_millis(50), 64)
+        })
+        .unwrap();
+        server.set_max_peers(1);
+        let mut first = client(&server);
+        let mut second = client(&server);
+        first.send_unreliable(b"first".to_vec()).unwrap();
+        poll_until(&mut server, &mut [&mut first], |events| {
+            !received(events).is_empty()
+        });
+        second.send_unreliable(b"second".to_vec()).unwrap();
+        second.poll(Instant::now());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(received(&server.poll(Instant::now())).is_empty());
+        assert_eq!(server.peers().count(), 1);
+    }
+}
@@ -15,6 +15,8 @@ use urobotics_core::{
     BlockOn,
 };
 
+pub use apriltag_image::image;
+
 define_callbacks!(DetectionCallbacks => Fn(detection: TagObservation) + Send + Sync);
 fn_alias! {
     pub type DetectionCallbacksRef = CallbacksRef(TagObservation) + Send + Sync
@@ -136,6 +138,14 @@ impl AprilTagDetector {
     pub fn detection_callbacks_ref(&self) -> DetectionCallbacksRef {
         self.detection_callbacks.get_ref()
     }
+
+    /// Creates a callback that feeds images to this detector.
+    ///
+    /// If images arrive faster than they can be processed, the oldest
+    /// images are dropped.
+    pub fn create_image_callback(&self) -> impl Fn(Arc<DynamicImage>) + Send + Sync {
+        self.img_subscriber.create_callback()
+    }
 }
 
 impl AprilTagDetector {